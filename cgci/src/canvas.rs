@@ -0,0 +1,69 @@
+use std::error::Error;
+use crate::Application;
+
+/// Names a layer held by a renderer. The application picks the ids.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct CanvasLayerId(pub u64);
+
+/// How a layer's colors combine with the layers below it. The values are
+/// shared with the engine's compositing shader.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CanvasBlendMode {
+    Normal = 0,
+    Multiply = 1,
+    Screen = 2,
+    Overlay = 3,
+    Darken = 4,
+    Lighten = 5,
+    ColorDodge = 6,
+    ColorBurn = 7,
+    HardLight = 8,
+    SoftLight = 9,
+    Difference = 10,
+    Exclusion = 11,
+    Add = 12,
+    Subtract = 13,
+}
+
+/// How a layer is composited onto the ones below it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CanvasLayer {
+    pub id: CanvasLayerId,
+    pub blend_mode: CanvasBlendMode,
+    pub opacity: f32,
+    pub visible: bool,
+}
+
+/// A backend that holds the document layers and shows them composited,
+/// stretched over its window. Layer pixels are tightly packed, straight
+/// alpha RGBA8 rows. A new renderer holds a single opaque white layer with
+/// id 0.
+pub trait CanvasRenderer: Application {
+    /// Size of the window, or of the frames a headless renderer draws.
+    fn extent(&self) -> (u32, u32);
+
+    fn canvas_extent(&self) -> (u32, u32);
+
+    /// Changes the size of the canvas. Afterwards it holds no layers until
+    /// `set_canvas_layers` is called.
+    fn resize_canvas(&mut self, width: u32, height: u32) -> Result<(), Box<dyn Error>>;
+
+    /// Sets the layers to composite, from bottom to top. Returns the layers
+    /// whose pixels the renderer does not hold yet; those have to be written
+    /// in full with `write_canvas_region`.
+    fn set_canvas_layers(&mut self, layers: &[CanvasLayer]) -> Result<Vec<CanvasLayerId>, Box<dyn Error>>;
+
+    /// Replaces a layer's pixels in the given rectangle.
+    fn write_canvas_region(
+        &mut self,
+        layer: CanvasLayerId,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+    ) -> Result<(), Box<dyn Error>>;
+
+    /// Reads all of a layer's pixels back.
+    fn read_canvas_layer(&mut self, layer: CanvasLayerId) -> Result<Vec<u8>, Box<dyn Error>>;
+}
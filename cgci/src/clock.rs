@@ -0,0 +1,26 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Seconds since the session started, as the main loop last saw them. The
+/// loop sets the time before it hands over each event or frame, and a
+/// replayed session is handed the times it was recorded with, so anything
+/// that reads the time here behaves the same both ways.
+#[derive(Clone, Debug, Default)]
+pub struct Clock {
+    seconds: Rc<Cell<f64>>,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Clock::default()
+    }
+
+    pub fn now(&self) -> f64 {
+        self.seconds.get()
+    }
+
+    /// Moves every copy of this clock to `seconds`.
+    pub fn set(&self, seconds: f64) {
+        self.seconds.set(seconds);
+    }
+}
@@ -0,0 +1,97 @@
+/// Backend-neutral input, independent of the windowing library that produced
+/// it. Positions are in physical pixels from the top-left of the window.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputEvent {
+    PointerDown(PointerEvent),
+    PointerMove(PointerEvent),
+    PointerUp(PointerEvent),
+    Key {
+        key: Key,
+        state: KeyState,
+        modifiers: Modifiers,
+    },
+    Scroll {
+        delta: ScrollDelta,
+        modifiers: Modifiers,
+    },
+    Focus(bool),
+    Resize {
+        width: u32,
+        height: u32,
+    },
+}
+
+pub trait InputHandler {
+    fn handle_input(&mut self, event: &InputEvent);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PointerEvent {
+    pub x: f64,
+    pub y: f64,
+    /// The button that changed for down/up events, or the one held while
+    /// moving. `None` while hovering.
+    pub button: Option<PointerButton>,
+    /// Normalized to 0.0..=1.0. Devices without pressure sensing report 1.0
+    /// while a button is held and 0.0 otherwise.
+    pub pressure: f32,
+    pub modifiers: Modifiers,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum PointerButton {
+    Primary,
+    Secondary,
+    Middle,
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Modifiers {
+    pub shift: bool,
+    pub ctrl: bool,
+    pub alt: bool,
+    /// The Windows key on PC and the Command key on Mac.
+    pub logo: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyState {
+    Pressed,
+    Released,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScrollDelta {
+    Lines { x: f32, y: f32 },
+    Pixels { x: f64, y: f64 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// Letters (lowercase), digits and punctuation keys.
+    Char(char),
+    /// F1 to F24.
+    Function(u8),
+    Escape,
+    Enter,
+    Tab,
+    Space,
+    Backspace,
+    Delete,
+    Insert,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Left,
+    Right,
+    Up,
+    Down,
+    Shift,
+    Ctrl,
+    Alt,
+    Logo,
+    /// A key without a mapping, identified by its platform scancode.
+    Unknown(u32),
+}
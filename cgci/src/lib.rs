@@ -1,3 +1,28 @@
+mod input;
+mod canvas;
+mod clock;
+mod recording;
+
+pub use canvas::{CanvasBlendMode, CanvasLayer, CanvasLayerId, CanvasRenderer};
+pub use clock::Clock;
+pub use input::{
+    InputEvent, InputHandler, Key, KeyState, Modifiers, PointerButton, PointerEvent, ScrollDelta,
+};
+pub use recording::{read_log, LogEntry, LogError, LogWriter, LoggedEvent};
+
 pub trait Draw {
     fn draw_frame(&mut self);
+    fn resize(&mut self, width: u32, height: u32);
+
+    /// Whether a frame would show something new without any further input,
+    /// such as paint that keeps building up while the pointer rests. The
+    /// main loop keeps asking for frames for as long as this holds.
+    fn needs_redraw(&self) -> bool {
+        false
+    }
 }
+
+/// Anything the main loop can drive: it renders frames and consumes input.
+pub trait Application: Draw + InputHandler {}
+
+impl<T: Draw + InputHandler> Application for T {}
@@ -0,0 +1,482 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::str::FromStr;
+use crate::input::{InputEvent, Key, KeyState, Modifiers, PointerButton, PointerEvent, ScrollDelta};
+
+const HEADER: &str = "# input log 1";
+
+/// Something the main loop hands to the application.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoggedEvent {
+    Input(InputEvent),
+    /// The window changed size, handed to `Draw::resize`.
+    Resize {
+        width: u32,
+        height: u32,
+    },
+    Frame,
+}
+
+/// An event with the time it reached the application, in seconds since
+/// the session started.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LogEntry {
+    pub time: f64,
+    pub event: LoggedEvent,
+}
+
+#[derive(Debug)]
+pub enum LogError {
+    Io(io::Error),
+    Parse {
+        line: usize,
+        message: String,
+    },
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogError::Io(error) => write!(f, "{}", error),
+            LogError::Parse { line, message } => write!(f, "line {}: {}", line, message),
+        }
+    }
+}
+
+impl std::error::Error for LogError {}
+
+impl From<io::Error> for LogError {
+    fn from(error: io::Error) -> Self {
+        LogError::Io(error)
+    }
+}
+
+/// Writes entries as text, one per line. Numbers are written with as many
+/// digits as it takes to read back the exact same value, so a replay sees
+/// the very positions, pressures and times that were recorded.
+pub struct LogWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> LogWriter<W> {
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writeln!(writer, "{}", HEADER)?;
+        Ok(LogWriter { writer })
+    }
+
+    /// Writes an entry and flushes it, so a log survives the application
+    /// crashing, which is when it is needed most.
+    pub fn write(&mut self, entry: &LogEntry) -> io::Result<()> {
+        writeln!(self.writer, "{}", entry)?;
+        self.writer.flush()
+    }
+}
+
+/// Reads a whole log, in the order it was written. The first line has to
+/// be the header `LogWriter` writes; after it, lines starting with `#` are
+/// comments.
+pub fn read_log<R: BufRead>(reader: R) -> Result<Vec<LogEntry>, LogError> {
+    let mut entries = vec![];
+    let mut lines = reader.lines();
+    let header = lines.next().transpose()?.unwrap_or_default();
+    if header.trim() != HEADER {
+        return Err(LogError::Parse {
+            line: 1,
+            message: format!("expected the header {}, not an input log", HEADER),
+        });
+    }
+    for (index, line) in lines.enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = line.parse().map_err(|message| LogError::Parse { line: index + 2, message })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+impl fmt::Display for LogEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ", self.time)?;
+        match &self.event {
+            LoggedEvent::Frame => write!(f, "frame"),
+            LoggedEvent::Resize { width, height } => write!(f, "resize {} {}", width, height),
+            LoggedEvent::Input(input) => match input {
+                InputEvent::PointerDown(pointer) => write!(f, "pointer-down {}", Pointer(pointer)),
+                InputEvent::PointerMove(pointer) => write!(f, "pointer-move {}", Pointer(pointer)),
+                InputEvent::PointerUp(pointer) => write!(f, "pointer-up {}", Pointer(pointer)),
+                InputEvent::Key { key, state, modifiers } => {
+                    let state = match state {
+                        KeyState::Pressed => "pressed",
+                        KeyState::Released => "released",
+                    };
+                    write!(f, "key {} {} {}", key_name(*key), state, ModifierNames(modifiers))
+                },
+                InputEvent::Scroll { delta, modifiers } => match delta {
+                    ScrollDelta::Lines { x, y } =>
+                        write!(f, "scroll lines {} {} {}", x, y, ModifierNames(modifiers)),
+                    ScrollDelta::Pixels { x, y } =>
+                        write!(f, "scroll pixels {} {} {}", x, y, ModifierNames(modifiers)),
+                },
+                InputEvent::Focus(focused) => write!(f, "focus {}", if *focused { "on" } else { "off" }),
+                InputEvent::Resize { width, height } => write!(f, "window-resize {} {}", width, height),
+            },
+        }
+    }
+}
+
+impl FromStr for LogEntry {
+    type Err = String;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.len() < 2 {
+            return Err("an entry needs a time and an event".to_string());
+        }
+        let time = number(words[0])?;
+        let fields = &words[2..];
+        let event = match words[1] {
+            "frame" => {
+                expect_fields(fields, 0)?;
+                LoggedEvent::Frame
+            },
+            "resize" => {
+                expect_fields(fields, 2)?;
+                LoggedEvent::Resize { width: number(fields[0])?, height: number(fields[1])? }
+            },
+            "pointer-down" => LoggedEvent::Input(InputEvent::PointerDown(parse_pointer(fields)?)),
+            "pointer-move" => LoggedEvent::Input(InputEvent::PointerMove(parse_pointer(fields)?)),
+            "pointer-up" => LoggedEvent::Input(InputEvent::PointerUp(parse_pointer(fields)?)),
+            "key" => {
+                expect_fields(fields, 3)?;
+                let state = match fields[1] {
+                    "pressed" => KeyState::Pressed,
+                    "released" => KeyState::Released,
+                    state => return Err(format!("unknown key state {}", state)),
+                };
+                LoggedEvent::Input(InputEvent::Key {
+                    key: parse_key(fields[0])?,
+                    state,
+                    modifiers: parse_modifiers(fields[2])?,
+                })
+            },
+            "scroll" => {
+                expect_fields(fields, 4)?;
+                let delta = match fields[0] {
+                    "lines" => ScrollDelta::Lines { x: number(fields[1])?, y: number(fields[2])? },
+                    "pixels" => ScrollDelta::Pixels { x: number(fields[1])?, y: number(fields[2])? },
+                    unit => return Err(format!("unknown scroll unit {}", unit)),
+                };
+                LoggedEvent::Input(InputEvent::Scroll { delta, modifiers: parse_modifiers(fields[3])? })
+            },
+            "focus" => {
+                expect_fields(fields, 1)?;
+                LoggedEvent::Input(InputEvent::Focus(match fields[0] {
+                    "on" => true,
+                    "off" => false,
+                    focus => return Err(format!("expected on or off, not {}", focus)),
+                }))
+            },
+            "window-resize" => {
+                expect_fields(fields, 2)?;
+                LoggedEvent::Input(InputEvent::Resize {
+                    width: number(fields[0])?,
+                    height: number(fields[1])?,
+                })
+            },
+            event => return Err(format!("unknown event {}", event)),
+        };
+        Ok(LogEntry { time, event })
+    }
+}
+
+/// Writes a pointer event as `x y button pressure modifiers`.
+struct Pointer<'a>(&'a PointerEvent);
+
+impl fmt::Display for Pointer<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let button = match self.0.button {
+            None => "none".to_string(),
+            Some(PointerButton::Primary) => "primary".to_string(),
+            Some(PointerButton::Secondary) => "secondary".to_string(),
+            Some(PointerButton::Middle) => "middle".to_string(),
+            Some(PointerButton::Other(index)) => format!("other:{}", index),
+        };
+        write!(
+            f,
+            "{} {} {} {} {}",
+            self.0.x, self.0.y, button, self.0.pressure, ModifierNames(&self.0.modifiers),
+        )
+    }
+}
+
+fn parse_pointer(fields: &[&str]) -> Result<PointerEvent, String> {
+    expect_fields(fields, 5)?;
+    let button = match fields[2] {
+        "none" => None,
+        "primary" => Some(PointerButton::Primary),
+        "secondary" => Some(PointerButton::Secondary),
+        "middle" => Some(PointerButton::Middle),
+        button => match button.strip_prefix("other:") {
+            Some(index) => Some(PointerButton::Other(number(index)?)),
+            None => return Err(format!("unknown button {}", button)),
+        },
+    };
+    Ok(PointerEvent {
+        x: number(fields[0])?,
+        y: number(fields[1])?,
+        button,
+        pressure: number(fields[3])?,
+        modifiers: parse_modifiers(fields[4])?,
+    })
+}
+
+/// Writes held modifiers joined by `+`, or `-` when there are none.
+struct ModifierNames<'a>(&'a Modifiers);
+
+impl fmt::Display for ModifierNames<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let held = [
+            (self.0.shift, "shift"),
+            (self.0.ctrl, "ctrl"),
+            (self.0.alt, "alt"),
+            (self.0.logo, "logo"),
+        ];
+        let names: Vec<&str> = held.iter().filter(|(held, _)| *held).map(|(_, name)| *name).collect();
+        if names.is_empty() {
+            write!(f, "-")
+        } else {
+            write!(f, "{}", names.join("+"))
+        }
+    }
+}
+
+fn parse_modifiers(word: &str) -> Result<Modifiers, String> {
+    let mut modifiers = Modifiers::default();
+    if word == "-" {
+        return Ok(modifiers);
+    }
+    for name in word.split('+') {
+        match name {
+            "shift" => modifiers.shift = true,
+            "ctrl" => modifiers.ctrl = true,
+            "alt" => modifiers.alt = true,
+            "logo" => modifiers.logo = true,
+            _ => return Err(format!("unknown modifier {}", name)),
+        }
+    }
+    Ok(modifiers)
+}
+
+const KEY_NAMES: [(Key, &str); 19] = [
+    (Key::Escape, "escape"),
+    (Key::Enter, "enter"),
+    (Key::Tab, "tab"),
+    (Key::Space, "space"),
+    (Key::Backspace, "backspace"),
+    (Key::Delete, "delete"),
+    (Key::Insert, "insert"),
+    (Key::Home, "home"),
+    (Key::End, "end"),
+    (Key::PageUp, "page-up"),
+    (Key::PageDown, "page-down"),
+    (Key::Left, "left"),
+    (Key::Right, "right"),
+    (Key::Up, "up"),
+    (Key::Down, "down"),
+    (Key::Shift, "shift"),
+    (Key::Ctrl, "ctrl"),
+    (Key::Alt, "alt"),
+    (Key::Logo, "logo"),
+];
+
+/// Characters are written as their code point, so punctuation and
+/// whitespace cannot be mistaken for the log's own separators.
+fn key_name(key: Key) -> String {
+    match key {
+        Key::Char(character) => format!("char:{}", character as u32),
+        Key::Function(number) => format!("f{}", number),
+        Key::Unknown(scancode) => format!("scancode:{}", scancode),
+        key => KEY_NAMES.iter().find(|(named, _)| *named == key).unwrap().1.to_string(),
+    }
+}
+
+fn parse_key(word: &str) -> Result<Key, String> {
+    if let Some(code) = word.strip_prefix("char:") {
+        let character = std::char::from_u32(number(code)?)
+            .ok_or_else(|| format!("{} is not a character", code))?;
+        return Ok(Key::Char(character));
+    }
+    if let Some(scancode) = word.strip_prefix("scancode:") {
+        return Ok(Key::Unknown(number(scancode)?));
+    }
+    if let Some(key) = KEY_NAMES.iter().find(|(_, name)| *name == word) {
+        return Ok(key.0);
+    }
+    match word.strip_prefix('f') {
+        Some(number) if number.parse::<u8>().is_ok() => Ok(Key::Function(number.parse().unwrap())),
+        _ => Err(format!("unknown key {}", word)),
+    }
+}
+
+fn expect_fields(fields: &[&str], count: usize) -> Result<(), String> {
+    if fields.len() != count {
+        return Err(format!("expected {} fields, found {}", count, fields.len()));
+    }
+    Ok(())
+}
+
+fn number<T: FromStr>(word: &str) -> Result<T, String> {
+    word.parse().map_err(|_| format!("{} is not a valid number here", word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pointer(x: f64, y: f64, button: Option<PointerButton>, pressure: f32, modifiers: Modifiers) -> PointerEvent {
+        PointerEvent { x, y, button, pressure, modifiers }
+    }
+
+    fn every_event() -> Vec<LoggedEvent> {
+        let none = Modifiers::default();
+        let all = Modifiers { shift: true, ctrl: true, alt: true, logo: true };
+        let ctrl = Modifiers { ctrl: true, ..none };
+        let mut events = vec![
+            LoggedEvent::Frame,
+            LoggedEvent::Resize { width: 1920, height: 1080 },
+            LoggedEvent::Input(InputEvent::Resize { width: 0, height: u32::MAX }),
+            LoggedEvent::Input(InputEvent::PointerDown(pointer(0.1, 2.0 / 3.0, Some(PointerButton::Primary), 0.3, none))),
+            LoggedEvent::Input(InputEvent::PointerMove(pointer(-1e-9, 1e300, None, 0.0, ctrl))),
+            LoggedEvent::Input(InputEvent::PointerUp(pointer(12.5, 7.0, Some(PointerButton::Secondary), 1.0, all))),
+            LoggedEvent::Input(InputEvent::PointerDown(pointer(1.0, 1.0, Some(PointerButton::Middle), 0.7, none))),
+            LoggedEvent::Input(InputEvent::PointerUp(pointer(1.0, 1.0, Some(PointerButton::Other(9)), f32::MIN_POSITIVE, none))),
+            LoggedEvent::Input(InputEvent::Scroll { delta: ScrollDelta::Lines { x: 0.0, y: -1.5 }, modifiers: none }),
+            LoggedEvent::Input(InputEvent::Scroll { delta: ScrollDelta::Pixels { x: 0.1, y: 3.25 }, modifiers: all }),
+            LoggedEvent::Input(InputEvent::Focus(true)),
+            LoggedEvent::Input(InputEvent::Focus(false)),
+        ];
+        let keys = KEY_NAMES
+            .iter()
+            .map(|(key, _)| *key)
+            .chain(vec![
+                Key::Char('a'),
+                Key::Char(' '),
+                Key::Char('#'),
+                Key::Char('é'),
+                Key::Function(1),
+                Key::Function(24),
+                Key::Unknown(0),
+                Key::Unknown(u32::MAX),
+            ]);
+        for (index, key) in keys.enumerate() {
+            let state = if index % 2 == 0 { KeyState::Pressed } else { KeyState::Released };
+            let modifiers = if index % 3 == 0 { ctrl } else { none };
+            events.push(LoggedEvent::Input(InputEvent::Key { key, state, modifiers }));
+        }
+        events
+    }
+
+    #[test]
+    fn every_event_round_trips() {
+        let times = [0.0, 0.1, 1.0 / 3.0, 12345.678901234567];
+        for (index, event) in every_event().into_iter().enumerate() {
+            let entry = LogEntry { time: times[index % times.len()], event };
+            let line = entry.to_string();
+            assert_eq!(line.parse::<LogEntry>(), Ok(entry), "{}", line);
+        }
+    }
+
+    #[test]
+    fn written_logs_read_back() {
+        let entries: Vec<LogEntry> = every_event()
+            .into_iter()
+            .enumerate()
+            .map(|(index, event)| LogEntry { time: index as f64 * 0.016, event })
+            .collect();
+        let mut bytes = vec![];
+        let mut writer = LogWriter::new(&mut bytes).unwrap();
+        for entry in &entries {
+            writer.write(entry).unwrap();
+        }
+        let text = String::from_utf8(bytes).unwrap();
+        assert!(text.starts_with("# input log 1\n"));
+        assert_eq!(read_log(text.as_bytes()).unwrap(), entries);
+    }
+
+    #[test]
+    fn lines_have_a_fixed_layout() {
+        let entry = LogEntry {
+            time: 0.5,
+            event: LoggedEvent::Input(InputEvent::PointerMove(pointer(
+                1.0, 2.5, Some(PointerButton::Primary), 0.25,
+                Modifiers { shift: true, alt: true, ..Modifiers::default() },
+            ))),
+        };
+        assert_eq!(entry.to_string(), "0.5 pointer-move 1 2.5 primary 0.25 shift+alt");
+        let key = LogEntry {
+            time: 2.0,
+            event: LoggedEvent::Input(InputEvent::Key {
+                key: Key::Char('['),
+                state: KeyState::Released,
+                modifiers: Modifiers::default(),
+            }),
+        };
+        assert_eq!(key.to_string(), "2 key char:91 released -");
+        assert_eq!(LogEntry { time: 1.0, event: LoggedEvent::Resize { width: 3, height: 4 } }.to_string(), "1 resize 3 4");
+    }
+
+    #[test]
+    fn comments_and_blank_lines_are_skipped() {
+        let log = "# input log 1\n\n# a comment\n  0.5 frame  \n";
+        assert_eq!(read_log(log.as_bytes()).unwrap(), [LogEntry { time: 0.5, event: LoggedEvent::Frame }]);
+    }
+
+    fn parse_error(log: &str) -> (usize, String) {
+        match read_log(log.as_bytes()) {
+            Err(LogError::Parse { line, message }) => (line, message),
+            result => panic!("{:?} read as {:?}", log, result),
+        }
+    }
+
+    #[test]
+    fn logs_without_the_header_are_rejected() {
+        assert_eq!(parse_error("").0, 1);
+        assert_eq!(parse_error("0 frame\n").0, 1);
+        assert_eq!(parse_error("# input log 2\n0 frame\n").0, 1);
+    }
+
+    #[test]
+    fn bad_entries_are_rejected_with_their_line() {
+        let bad = [
+            "0",
+            "0 paint",
+            "zero frame",
+            "0 frame extra",
+            "0 resize 10",
+            "0 resize 10 10 10",
+            "0 resize -1 10",
+            "0 window-resize 10",
+            "0 pointer-down 1 2 primary 1",
+            "0 pointer-down 1 2 primary 1 - extra",
+            "0 pointer-down 1 2 left 1 -",
+            "0 pointer-move 1 2 other:256 1 -",
+            "0 pointer-up 1 2 none 1 meta",
+            "0 key char:97 pressed",
+            "0 key char:97 held -",
+            "0 key char:55296 pressed -",
+            "0 key f99x pressed -",
+            "0 key kana pressed -",
+            "0 scroll lines 1 2",
+            "0 scroll pages 1 2 -",
+            "0 focus",
+            "0 focus maybe",
+        ];
+        for line in bad.iter() {
+            let (number, message) = parse_error(&format!("# input log 1\n0 frame\n{}\n", line));
+            assert_eq!(number, 3, "{}: {}", line, message);
+        }
+    }
+}
@@ -1,11 +1,122 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
 
-layout(location = 0) in vec3 fragColor;
+// Has to match MAX_CANVAS_LAYERS in canvas.rs.
+const uint MAX_LAYERS = 64;
+
+// Blend modes, numbered as cgci::CanvasBlendMode.
+const uint NORMAL = 0;
+const uint MULTIPLY = 1;
+const uint SCREEN = 2;
+const uint OVERLAY = 3;
+const uint DARKEN = 4;
+const uint LIGHTEN = 5;
+const uint COLOR_DODGE = 6;
+const uint COLOR_BURN = 7;
+const uint HARD_LIGHT = 8;
+const uint SOFT_LIGHT = 9;
+const uint DIFFERENCE = 10;
+const uint EXCLUSION = 11;
+const uint ADD = 12;
+const uint SUBTRACT = 13;
+
+layout(set = 0, binding = 0) uniform texture2DArray canvasLayers;
+layout(set = 0, binding = 1) uniform sampler canvasSampler;
+// The visible layers from bottom to top. Each is the array layer holding
+// its pixels, its blend mode and its opacity.
+layout(set = 0, binding = 2) uniform LayerParameters {
+    uvec4 layerCount;
+    vec4 layers[MAX_LAYERS];
+} parameters;
+
+layout(location = 0) in vec2 ftex;
 
 layout(location = 0) out vec4 outColor;
 
-void main() {
-    outColor = vec4(fragColor, 1.0);
+float hardLight(float backdrop, float source) {
+    if (source <= 0.5) {
+        return backdrop * (2.0 * source);
+    }
+    float screened = 2.0 * source - 1.0;
+    return backdrop + screened - backdrop * screened;
+}
+
+// Mirrors BlendMode::blend in the paint crate.
+float blend(uint mode, float backdrop, float source) {
+    switch (mode) {
+    case MULTIPLY:
+        return backdrop * source;
+    case SCREEN:
+        return backdrop + source - backdrop * source;
+    case OVERLAY:
+        return hardLight(source, backdrop);
+    case DARKEN:
+        return min(backdrop, source);
+    case LIGHTEN:
+        return max(backdrop, source);
+    case COLOR_DODGE:
+        if (backdrop <= 0.0) {
+            return 0.0;
+        }
+        if (source >= 1.0) {
+            return 1.0;
+        }
+        return min(backdrop / (1.0 - source), 1.0);
+    case COLOR_BURN:
+        if (backdrop >= 1.0) {
+            return 1.0;
+        }
+        if (source <= 0.0) {
+            return 0.0;
+        }
+        return 1.0 - min((1.0 - backdrop) / source, 1.0);
+    case HARD_LIGHT:
+        return hardLight(backdrop, source);
+    case SOFT_LIGHT:
+        if (source <= 0.5) {
+            return backdrop - (1.0 - 2.0 * source) * backdrop * (1.0 - backdrop);
+        } else {
+            float darkened = backdrop <= 0.25
+                ? ((16.0 * backdrop - 12.0) * backdrop + 4.0) * backdrop
+                : sqrt(backdrop);
+            return backdrop + (2.0 * source - 1.0) * (darkened - backdrop);
+        }
+    case DIFFERENCE:
+        return abs(backdrop - source);
+    case EXCLUSION:
+        return backdrop + source - 2.0 * backdrop * source;
+    case ADD:
+        return min(backdrop + source, 1.0);
+    case SUBTRACT:
+        return max(backdrop - source, 0.0);
+    default:
+        return source;
+    }
+}
+
+// Mirrors composite_pixel in the paint crate.
+vec4 composite(vec4 backdrop, vec4 source, float opacity, uint mode) {
+    float sourceAlpha = source.a * opacity;
+    if (sourceAlpha <= 0.0) {
+        return backdrop;
+    }
+    float alpha = sourceAlpha + backdrop.a * (1.0 - sourceAlpha);
+    vec3 color;
+    for (int channel = 0; channel < 3; channel++) {
+        float blended = (1.0 - backdrop.a) * source[channel]
+            + backdrop.a * blend(mode, backdrop[channel], source[channel]);
+        color[channel] = (sourceAlpha * blended
+            + backdrop.a * (1.0 - sourceAlpha) * backdrop[channel]) / alpha;
+    }
+    return vec4(color, alpha);
 }
 
+void main() {
+    vec4 result = vec4(0.0);
+    for (uint i = 0; i < min(parameters.layerCount.x, MAX_LAYERS); i++) {
+        vec4 layer = parameters.layers[i];
+        vec4 source = texture(sampler2DArray(canvasLayers, canvasSampler), vec3(ftex, layer.x));
+        result = composite(result, source, layer.z, uint(layer.y));
+    }
+    outColor = result;
+}
@@ -1,12 +1,6 @@
 #version 450
 #extension GL_ARB_separate_shader_objects : enable
 
-out gl_PerVertex {
-    vec4 gl_Position;
-};
-
-layout(location = 0) out vec3 fragColor;
-
 const vec2 positions[6] = {
     // triangle ABC
     vec2(-1.0,  1.0),
@@ -18,30 +12,22 @@ const vec2 positions[6] = {
     vec2( 1.0,  1.0),
 };
 
-const vec3 colors[6] = {
-    vec3(1.0, 0.0, 0.0),
-    vec3(0.0, 1.0, 0.0),
-    vec3(0.0, 0.0, 1.0),
-    vec3(1.0, 0.0, 0.0),
-    vec3(0.0, 1.0, 0.0),
-    vec3(0.0, 0.0, 1.0)
-};
-
+// Vulkan clip space has y pointing down, so the canvas' first row lands at
+// the top of the window.
 const vec2 uvs[6] = {
     // triangle ABC
-    vec2(0.0, 0.0),
     vec2(0.0, 1.0),
-    vec2(1.0, 1.0),
-    // triangle ACD
     vec2(0.0, 0.0),
-    vec2(1.0, 1.0),
     vec2(1.0, 0.0),
+    // triangle ACD
+    vec2(0.0, 1.0),
+    vec2(1.0, 0.0),
+    vec2(1.0, 1.0),
 };
 
-//layout(location = 0) out vec2 ftex;
+layout(location = 0) out vec2 ftex;
 
 void main() {
     gl_Position = vec4(positions[gl_VertexIndex], 0.0, 1.0);
-    //ftex = uvs[gl_VertexIndex];
-    fragColor = colors[gl_VertexIndex];
-}
\ No newline at end of file
+    ftex = uvs[gl_VertexIndex];
+}
@@ -0,0 +1,536 @@
+use ash::{vk, Device, Instance};
+use ash::version::{DeviceV1_0, InstanceV1_0};
+use std::fmt;
+use std::ptr;
+use crate::error::{vk_error, EngineError};
+
+/// Size of the blocks that small allocations are carved out of. Heaps smaller
+/// than eight blocks get proportionally smaller ones.
+const DEFAULT_BLOCK_SIZE: vk::DeviceSize = 64 * 1024 * 1024;
+
+/// What the CPU does with an allocation, which decides the memory type.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemoryUsage {
+    /// Only touched by the GPU: images, render targets, vertex buffers.
+    GpuOnly,
+    /// Written by the CPU and read by the GPU, like staging buffers. Stays
+    /// mapped for its whole lifetime.
+    CpuToGpu,
+    /// Written by the GPU and read back by the CPU. Stays mapped and prefers
+    /// cached memory.
+    GpuToCpu,
+}
+
+impl MemoryUsage {
+    fn required_flags(self) -> vk::MemoryPropertyFlags {
+        match self {
+            MemoryUsage::GpuOnly => vk::MemoryPropertyFlags::empty(),
+            MemoryUsage::CpuToGpu | MemoryUsage::GpuToCpu =>
+                vk::MemoryPropertyFlags::HOST_VISIBLE | vk::MemoryPropertyFlags::HOST_COHERENT,
+        }
+    }
+
+    fn preferred_flags(self) -> vk::MemoryPropertyFlags {
+        match self {
+            MemoryUsage::GpuOnly => vk::MemoryPropertyFlags::DEVICE_LOCAL,
+            MemoryUsage::CpuToGpu => vk::MemoryPropertyFlags::empty(),
+            MemoryUsage::GpuToCpu => vk::MemoryPropertyFlags::HOST_CACHED,
+        }
+    }
+
+    fn is_mapped(self) -> bool {
+        self != MemoryUsage::GpuOnly
+    }
+}
+
+/// Buffers and linear images must not share a block with optimal-tiled
+/// images, or they would have to be kept `bufferImageGranularity` apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceTiling {
+    Linear,
+    Optimal,
+}
+
+/// A range of device memory handed out by the `Allocator`. It has to be
+/// given back with `Allocator::free`; dropping it leaks the range.
+#[derive(Debug)]
+pub struct Allocation {
+    memory: vk::DeviceMemory,
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+    block_index: usize,
+    mapped: *mut u8,
+}
+
+impl Allocation {
+    pub fn memory(&self) -> vk::DeviceMemory {
+        self.memory
+    }
+
+    pub fn offset(&self) -> vk::DeviceSize {
+        self.offset
+    }
+
+    pub fn size(&self) -> vk::DeviceSize {
+        self.size
+    }
+
+    /// The allocation's bytes, for allocations made with a host visible
+    /// `MemoryUsage`. The memory is coherent, so no flushes are needed.
+    pub fn mapped_slice(&mut self) -> Option<&mut [u8]> {
+        if self.mapped.is_null() {
+            return None;
+        }
+        unsafe { Some(std::slice::from_raw_parts_mut(self.mapped, self.size as usize)) }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FreeRange {
+    offset: vk::DeviceSize,
+    size: vk::DeviceSize,
+}
+
+struct MemoryBlock {
+    memory: vk::DeviceMemory,
+    size: vk::DeviceSize,
+    memory_type_index: u32,
+    tiling: ResourceTiling,
+    /// Sorted by offset, with no two ranges touching.
+    free_ranges: Vec<FreeRange>,
+    allocation_count: usize,
+    /// Whole-block mapping for host visible blocks, null otherwise.
+    mapped: *mut u8,
+    /// Holds a single allocation too large to share a block.
+    dedicated: bool,
+}
+
+impl MemoryBlock {
+    fn used_bytes(&self) -> vk::DeviceSize {
+        self.size - self.free_ranges.iter().map(|range| range.size).sum::<vk::DeviceSize>()
+    }
+
+    /// First fit. Padding in front of an aligned allocation stays free.
+    fn sub_allocate(
+        &mut self,
+        size: vk::DeviceSize,
+        alignment: vk::DeviceSize,
+    ) -> Option<vk::DeviceSize> {
+        let alignment = alignment.max(1);
+        let position = self.free_ranges.iter().position(|range| {
+            let aligned = align_up(range.offset, alignment);
+            aligned + size <= range.offset + range.size
+        })?;
+        let range = self.free_ranges.remove(position);
+        let offset = align_up(range.offset, alignment);
+        let mut insert_at = position;
+        if offset > range.offset {
+            self.free_ranges.insert(insert_at, FreeRange {
+                offset: range.offset,
+                size: offset - range.offset,
+            });
+            insert_at += 1;
+        }
+        let end = offset + size;
+        if end < range.offset + range.size {
+            self.free_ranges.insert(insert_at, FreeRange {
+                offset: end,
+                size: range.offset + range.size - end,
+            });
+        }
+        self.allocation_count += 1;
+        Some(offset)
+    }
+
+    fn release(&mut self, offset: vk::DeviceSize, size: vk::DeviceSize) {
+        let position = self.free_ranges
+            .iter()
+            .position(|range| range.offset > offset)
+            .unwrap_or(self.free_ranges.len());
+        self.free_ranges.insert(position, FreeRange { offset, size });
+        // Merge with the following range, then with the preceding one.
+        if position + 1 < self.free_ranges.len()
+            && self.free_ranges[position].offset + self.free_ranges[position].size
+                == self.free_ranges[position + 1].offset {
+            self.free_ranges[position].size += self.free_ranges[position + 1].size;
+            self.free_ranges.remove(position + 1);
+        }
+        if position > 0
+            && self.free_ranges[position - 1].offset + self.free_ranges[position - 1].size
+                == self.free_ranges[position].offset {
+            self.free_ranges[position - 1].size += self.free_ranges[position].size;
+            self.free_ranges.remove(position);
+        }
+        self.allocation_count -= 1;
+    }
+}
+
+fn align_up(value: vk::DeviceSize, alignment: vk::DeviceSize) -> vk::DeviceSize {
+    value.div_ceil(alignment) * alignment
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct HeapStats {
+    pub size: vk::DeviceSize,
+    pub device_local: bool,
+    /// Bytes taken from the heap with `vkAllocateMemory`.
+    pub reserved_bytes: vk::DeviceSize,
+    /// Bytes of the reserved blocks handed out to allocations.
+    pub used_bytes: vk::DeviceSize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct MemoryStats {
+    pub block_count: usize,
+    pub allocation_count: usize,
+    pub reserved_bytes: vk::DeviceSize,
+    pub used_bytes: vk::DeviceSize,
+    pub heaps: Vec<HeapStats>,
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} allocations in {} blocks, {} of {} bytes used",
+            self.allocation_count, self.block_count, self.used_bytes, self.reserved_bytes
+        )?;
+        for (index, heap) in self.heaps.iter().enumerate() {
+            write!(
+                f,
+                "\n  heap {}{}: {} used, {} reserved, {} total",
+                index,
+                if heap.device_local { " (device local)" } else { "" },
+                heap.used_bytes,
+                heap.reserved_bytes,
+                heap.size
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Sub-allocates buffers and images from a few large `vkDeviceMemory`
+/// blocks, so documents with many layers stay far below
+/// `maxMemoryAllocationCount`. Blocks are kept per memory type and tiling.
+pub struct Allocator {
+    memory_properties: vk::PhysicalDeviceMemoryProperties,
+    blocks: Vec<Option<MemoryBlock>>,
+}
+
+impl Allocator {
+    pub fn new(instance: &Instance, physical_device: vk::PhysicalDevice) -> Self {
+        let memory_properties = unsafe {
+            instance.get_physical_device_memory_properties(physical_device)
+        };
+        Allocator {
+            memory_properties,
+            blocks: vec![],
+        }
+    }
+
+    /// Memory types that fit `type_bits` and `usage`, best first. Types with
+    /// the preferred properties come before the ones without.
+    fn memory_types(&self, type_bits: u32, usage: MemoryUsage) -> Vec<u32> {
+        let required = usage.required_flags();
+        let preferred = usage.preferred_flags();
+        let mut types: Vec<u32> = (0..self.memory_properties.memory_type_count)
+            .filter(|&index| {
+                type_bits & (1 << index) != 0
+                    && self.memory_properties.memory_types[index as usize]
+                        .property_flags
+                        .contains(required)
+            })
+            .collect();
+        types.sort_by_key(|&index| {
+            !self.memory_properties.memory_types[index as usize]
+                .property_flags
+                .contains(preferred)
+        });
+        types
+    }
+
+    fn block_size(&self, memory_type_index: u32) -> vk::DeviceSize {
+        let heap_index = self.memory_properties.memory_types[memory_type_index as usize].heap_index;
+        let heap_size = self.memory_properties.memory_heaps[heap_index as usize].size;
+        DEFAULT_BLOCK_SIZE.min(heap_size / 8)
+    }
+
+    pub fn allocate(
+        &mut self,
+        device: &Device,
+        requirements: vk::MemoryRequirements,
+        usage: MemoryUsage,
+        tiling: ResourceTiling,
+    ) -> Result<Allocation, EngineError> {
+        let memory_types = self.memory_types(requirements.memory_type_bits, usage);
+        if memory_types.is_empty() {
+            return Err(EngineError::NoMemoryType(usage.required_flags()));
+        }
+
+        let mut last_error = None;
+        for memory_type_index in memory_types {
+            if let Some(allocation) = self.allocate_from_blocks(
+                memory_type_index,
+                tiling,
+                requirements,
+                usage,
+            ) {
+                return Ok(allocation);
+            }
+            // A full heap is not fatal while other memory types are left.
+            match self.allocate_block(device, memory_type_index, tiling, requirements, usage) {
+                Ok(allocation) => return Ok(allocation),
+                Err(error) => last_error = Some(error),
+            }
+        }
+        Err(last_error.unwrap())
+    }
+
+    fn allocate_from_blocks(
+        &mut self,
+        memory_type_index: u32,
+        tiling: ResourceTiling,
+        requirements: vk::MemoryRequirements,
+        usage: MemoryUsage,
+    ) -> Option<Allocation> {
+        self.blocks
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, block)| block.as_mut().map(|block| (index, block)))
+            .filter(|(_, block)| {
+                !block.dedicated
+                    && block.memory_type_index == memory_type_index
+                    && block.tiling == tiling
+                    && (!usage.is_mapped() || !block.mapped.is_null())
+            })
+            .find_map(|(block_index, block)| {
+                let offset = block.sub_allocate(requirements.size, requirements.alignment)?;
+                Some(Allocation {
+                    memory: block.memory,
+                    offset,
+                    size: requirements.size,
+                    block_index,
+                    mapped: mapped_at(block.mapped, offset),
+                })
+            })
+    }
+
+    fn allocate_block(
+        &mut self,
+        device: &Device,
+        memory_type_index: u32,
+        tiling: ResourceTiling,
+        requirements: vk::MemoryRequirements,
+        usage: MemoryUsage,
+    ) -> Result<Allocation, EngineError> {
+        let block_size = self.block_size(memory_type_index);
+        // Anything over half a block would waste most of a shared one.
+        let dedicated = requirements.size > block_size / 2;
+        let size = if dedicated { requirements.size } else { block_size };
+        let allocate_info = vk::MemoryAllocateInfo::builder()
+            .allocation_size(size)
+            .memory_type_index(memory_type_index);
+
+        let (memory, mapped) = unsafe {
+            let memory = device
+                .allocate_memory(&allocate_info, None)
+                .map_err(vk_error("allocate memory block"))?;
+            let mapped = if usage.is_mapped() {
+                match device.map_memory(memory, 0, vk::WHOLE_SIZE, vk::MemoryMapFlags::empty()) {
+                    Ok(mapped) => mapped as *mut u8,
+                    Err(error) => {
+                        device.free_memory(memory, None);
+                        return Err(EngineError::Vulkan("map memory block", error));
+                    }
+                }
+            } else {
+                ptr::null_mut()
+            };
+            (memory, mapped)
+        };
+
+        let mut block = MemoryBlock {
+            memory,
+            size,
+            memory_type_index,
+            tiling,
+            free_ranges: vec![FreeRange { offset: 0, size }],
+            allocation_count: 0,
+            mapped,
+            dedicated,
+        };
+        let offset = block
+            .sub_allocate(requirements.size, requirements.alignment)
+            .expect("Fresh memory block is too small!");
+        let block_index = match self.blocks.iter().position(Option::is_none) {
+            Some(index) => {
+                self.blocks[index] = Some(block);
+                index
+            },
+            None => {
+                self.blocks.push(Some(block));
+                self.blocks.len() - 1
+            },
+        };
+        Ok(Allocation {
+            memory,
+            offset,
+            size: requirements.size,
+            block_index,
+            mapped: mapped_at(mapped, offset),
+        })
+    }
+
+    /// Returns an allocation's range to its block. Emptied blocks are kept
+    /// for reuse, unless another empty block of the same kind already is.
+    pub fn free(&mut self, device: &Device, allocation: Allocation) {
+        let block = self.blocks[allocation.block_index]
+            .as_mut()
+            .expect("Allocation freed twice!");
+        block.release(allocation.offset, allocation.size);
+        if block.allocation_count > 0 {
+            return;
+        }
+
+        let (memory_type_index, tiling, dedicated) =
+            (block.memory_type_index, block.tiling, block.dedicated);
+        let spare_exists = self.blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(index, block)| block.as_ref().map(|block| (index, block)))
+            .any(|(index, block)| {
+                index != allocation.block_index
+                    && !block.dedicated
+                    && block.allocation_count == 0
+                    && block.memory_type_index == memory_type_index
+                    && block.tiling == tiling
+            });
+        if dedicated || spare_exists {
+            let block = self.blocks[allocation.block_index].take().unwrap();
+            unsafe {
+                device.free_memory(block.memory, None);
+            }
+        }
+    }
+
+    /// Creates a buffer bound to freshly allocated memory.
+    pub fn create_buffer(
+        &mut self,
+        device: &Device,
+        create_info: &vk::BufferCreateInfo,
+        usage: MemoryUsage,
+    ) -> Result<(vk::Buffer, Allocation), EngineError> {
+        unsafe {
+            let buffer = device
+                .create_buffer(create_info, None)
+                .map_err(vk_error("create buffer"))?;
+            let requirements = device.get_buffer_memory_requirements(buffer);
+            let allocation = match self.allocate(device, requirements, usage, ResourceTiling::Linear) {
+                Ok(allocation) => allocation,
+                Err(error) => {
+                    device.destroy_buffer(buffer, None);
+                    return Err(error);
+                }
+            };
+            if let Err(error) = device.bind_buffer_memory(buffer, allocation.memory, allocation.offset) {
+                device.destroy_buffer(buffer, None);
+                self.free(device, allocation);
+                return Err(EngineError::Vulkan("bind buffer memory", error));
+            }
+            Ok((buffer, allocation))
+        }
+    }
+
+    /// Creates an image bound to freshly allocated memory.
+    pub fn create_image(
+        &mut self,
+        device: &Device,
+        create_info: &vk::ImageCreateInfo,
+        usage: MemoryUsage,
+    ) -> Result<(vk::Image, Allocation), EngineError> {
+        let tiling = match create_info.tiling {
+            vk::ImageTiling::LINEAR => ResourceTiling::Linear,
+            _ => ResourceTiling::Optimal,
+        };
+        unsafe {
+            let image = device
+                .create_image(create_info, None)
+                .map_err(vk_error("create image"))?;
+            let requirements = device.get_image_memory_requirements(image);
+            let allocation = match self.allocate(device, requirements, usage, tiling) {
+                Ok(allocation) => allocation,
+                Err(error) => {
+                    device.destroy_image(image, None);
+                    return Err(error);
+                }
+            };
+            if let Err(error) = device.bind_image_memory(image, allocation.memory, allocation.offset) {
+                device.destroy_image(image, None);
+                self.free(device, allocation);
+                return Err(EngineError::Vulkan("bind image memory", error));
+            }
+            Ok((image, allocation))
+        }
+    }
+
+    pub fn destroy_buffer(&mut self, device: &Device, buffer: vk::Buffer, allocation: Allocation) {
+        unsafe {
+            device.destroy_buffer(buffer, None);
+        }
+        self.free(device, allocation);
+    }
+
+    pub fn destroy_image(&mut self, device: &Device, image: vk::Image, allocation: Allocation) {
+        unsafe {
+            device.destroy_image(image, None);
+        }
+        self.free(device, allocation);
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        let mut stats = MemoryStats {
+            heaps: self.memory_properties.memory_heaps
+                [..self.memory_properties.memory_heap_count as usize]
+                .iter()
+                .map(|heap| HeapStats {
+                    size: heap.size,
+                    device_local: heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL),
+                    ..HeapStats::default()
+                })
+                .collect(),
+            ..MemoryStats::default()
+        };
+        for block in self.blocks.iter().flatten() {
+            let heap_index = self.memory_properties
+                .memory_types[block.memory_type_index as usize]
+                .heap_index as usize;
+            let used_bytes = block.used_bytes();
+            stats.block_count += 1;
+            stats.allocation_count += block.allocation_count;
+            stats.reserved_bytes += block.size;
+            stats.used_bytes += used_bytes;
+            stats.heaps[heap_index].reserved_bytes += block.size;
+            stats.heaps[heap_index].used_bytes += used_bytes;
+        }
+        stats
+    }
+
+    /// Frees every block. All resources bound to them have to be destroyed
+    /// already.
+    pub fn destroy(&mut self, device: &Device) {
+        for block in self.blocks.drain(..).flatten() {
+            unsafe {
+                device.free_memory(block.memory, None);
+            }
+        }
+    }
+}
+
+fn mapped_at(mapped: *mut u8, offset: vk::DeviceSize) -> *mut u8 {
+    if mapped.is_null() {
+        mapped
+    } else {
+        unsafe { mapped.add(offset as usize) }
+    }
+}
@@ -0,0 +1,474 @@
+use ash::{vk, Device};
+use ash::version::DeviceV1_0;
+use cgci::{CanvasBlendMode, CanvasLayer, CanvasLayerId};
+use crate::allocator::{Allocation, Allocator, MemoryUsage};
+use crate::error::{vk_error, EngineError};
+use crate::transfer::{ReadbackTicket, Transfer};
+
+/// Pixel format of the document canvas. Writes take tightly packed RGBA8
+/// rows in this layout.
+pub const CANVAS_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+/// Most layers the canvas composites. Has to match `MAX_LAYERS` in the base
+/// fragment shader.
+pub const MAX_CANVAS_LAYERS: usize = 64;
+
+/// Array layers a new canvas image has room for.
+const INITIAL_CAPACITY: u32 = 4;
+
+/// Binding of the layer images in descriptor set 0 of the base program.
+const CANVAS_TEXTURE_BINDING: u32 = 0;
+/// Binding of the canvas sampler in descriptor set 0 of the base program.
+const CANVAS_SAMPLER_BINDING: u32 = 1;
+/// Binding of the per-layer compositing parameters.
+const CANVAS_PARAMETERS_BINDING: u32 = 2;
+
+/// Size of the parameter block: a `uvec4` count and a `vec4` per layer.
+const PARAMETERS_SIZE: usize = 16 + MAX_CANVAS_LAYERS * 16;
+
+/// The document layers, kept on the GPU for the lifetime of the engine and
+/// composited by the fullscreen quad every frame. Each layer has a slot in
+/// one array image, which stays in `SHADER_READ_ONLY_OPTIMAL` between
+/// writes; reordering layers only changes the parameters, not the slots.
+pub struct Canvas {
+    image: vk::Image,
+    allocation: Option<Allocation>,
+    view: vk::ImageView,
+    sampler: vk::Sampler,
+    extent: vk::Extent2D,
+    /// The layer held in each array layer of the image.
+    slots: Vec<Option<CanvasLayerId>>,
+    parameters: vk::Buffer,
+    parameters_allocation: Option<Allocation>,
+    pub descriptor_set_layout: vk::DescriptorSetLayout,
+    descriptor_pool: vk::DescriptorPool,
+    pub descriptor_set: vk::DescriptorSet,
+}
+
+impl Canvas {
+    /// Creates a canvas holding a single layer with id 0 and queues
+    /// clearing it to opaque white on `transfer`.
+    pub fn new(
+        device: &Device,
+        allocator: &mut Allocator,
+        transfer: &mut Transfer,
+        extent: vk::Extent2D,
+    ) -> Result<Self, EngineError> {
+        let mut canvas = Canvas {
+            image: vk::Image::null(),
+            allocation: None,
+            view: vk::ImageView::null(),
+            sampler: vk::Sampler::null(),
+            extent,
+            slots: vec![],
+            parameters: vk::Buffer::null(),
+            parameters_allocation: None,
+            descriptor_set_layout: vk::DescriptorSetLayout::null(),
+            descriptor_pool: vk::DescriptorPool::null(),
+            descriptor_set: vk::DescriptorSet::null(),
+        };
+        // Destroying null handles is a no-op, so a half built canvas can be
+        // torn down the same way as a complete one.
+        if let Err(error) = canvas.build(device, allocator, transfer) {
+            canvas.destroy(device, allocator);
+            return Err(error);
+        }
+        Ok(canvas)
+    }
+
+    fn build(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        transfer: &mut Transfer,
+    ) -> Result<(), EngineError> {
+        unsafe {
+            // Nearest filtering keeps individual pixels crisp when the canvas
+            // is shown zoomed in.
+            let sampler_create_info = vk::SamplerCreateInfo::builder()
+                .mag_filter(vk::Filter::NEAREST)
+                .min_filter(vk::Filter::LINEAR)
+                .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                .max_lod(0.0);
+            self.sampler = device
+                .create_sampler(&sampler_create_info, None)
+                .map_err(vk_error("create canvas sampler"))?;
+        }
+
+        let buffer_create_info = vk::BufferCreateInfo::builder()
+            .size(PARAMETERS_SIZE as vk::DeviceSize)
+            .usage(vk::BufferUsageFlags::UNIFORM_BUFFER)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let (parameters, parameters_allocation) = allocator.create_buffer(
+            device,
+            &buffer_create_info,
+            MemoryUsage::CpuToGpu,
+        )?;
+        self.parameters = parameters;
+        self.parameters_allocation = Some(parameters_allocation);
+
+        self.create_descriptors(device)?;
+        self.create_image(device, allocator, transfer, INITIAL_CAPACITY)?;
+
+        let background = CanvasLayer {
+            id: CanvasLayerId(0),
+            blend_mode: CanvasBlendMode::Normal,
+            opacity: 1.0,
+            visible: true,
+        };
+        self.slots[0] = Some(background.id);
+        self.write_parameters(&[background]);
+        let white = vec![0xff; self.extent.width as usize * self.extent.height as usize * 4];
+        self.write_region(device, allocator, transfer, background.id, self.whole_region(), &white)
+    }
+
+    /// Creates the array image with room for `capacity` layers, all cleared
+    /// to transparent, and points the descriptor set at it.
+    fn create_image(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        transfer: &mut Transfer,
+        capacity: u32,
+    ) -> Result<(), EngineError> {
+        let image_create_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(CANVAS_FORMAT)
+            .extent(vk::Extent3D { width: self.extent.width, height: self.extent.height, depth: 1 })
+            .mip_levels(1)
+            .array_layers(capacity)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::SAMPLED
+                | vk::ImageUsageFlags::TRANSFER_DST
+                | vk::ImageUsageFlags::TRANSFER_SRC)
+            .sharing_mode(transfer.sharing_mode())
+            .queue_family_indices(transfer.queue_family_indices())
+            .initial_layout(vk::ImageLayout::UNDEFINED);
+
+        let (image, allocation) = allocator.create_image(
+            device,
+            &image_create_info,
+            MemoryUsage::GpuOnly,
+        )?;
+        self.image = image;
+        self.allocation = Some(allocation);
+        self.slots = vec![None; capacity as usize];
+
+        unsafe {
+            let view_create_info = vk::ImageViewCreateInfo::builder()
+                .view_type(vk::ImageViewType::TYPE_2D_ARRAY)
+                .format(CANVAS_FORMAT)
+                .subresource_range(vk::ImageSubresourceRange {
+                    aspect_mask: vk::ImageAspectFlags::COLOR,
+                    base_mip_level: 0,
+                    level_count: 1,
+                    base_array_layer: 0,
+                    layer_count: capacity,
+                })
+                .image(self.image);
+            self.view = device
+                .create_image_view(&view_create_info, None)
+                .map_err(vk_error("create canvas image view"))?;
+
+            let image_infos = [vk::DescriptorImageInfo {
+                sampler: vk::Sampler::null(),
+                image_view: self.view,
+                image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            }];
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.descriptor_set)
+                    .dst_binding(CANVAS_TEXTURE_BINDING)
+                    .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                    .image_info(&image_infos)
+                    .build(),
+            ];
+            device.update_descriptor_sets(&writes, &[]);
+        }
+
+        let clear = vec![0; self.extent.width as usize * self.extent.height as usize * 4];
+        for array_layer in 0..capacity {
+            transfer.upload(
+                device,
+                allocator,
+                self.image,
+                array_layer,
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                self.whole_region(),
+                &clear,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn destroy_image(&mut self, device: &Device, allocator: &mut Allocator) {
+        unsafe {
+            device.destroy_image_view(self.view, None);
+        }
+        self.view = vk::ImageView::null();
+        if let Some(allocation) = self.allocation.take() {
+            allocator.destroy_image(device, self.image, allocation);
+        }
+        self.image = vk::Image::null();
+    }
+
+    fn create_descriptors(&mut self, device: &Device) -> Result<(), EngineError> {
+        let bindings = [
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(CANVAS_TEXTURE_BINDING)
+                .descriptor_type(vk::DescriptorType::SAMPLED_IMAGE)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(CANVAS_SAMPLER_BINDING)
+                .descriptor_type(vk::DescriptorType::SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+            vk::DescriptorSetLayoutBinding::builder()
+                .binding(CANVAS_PARAMETERS_BINDING)
+                .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build(),
+        ];
+        let layout_create_info = vk::DescriptorSetLayoutCreateInfo::builder()
+            .bindings(&bindings);
+        let pool_sizes = [
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLED_IMAGE,
+                descriptor_count: 1,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::SAMPLER,
+                descriptor_count: 1,
+            },
+            vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::UNIFORM_BUFFER,
+                descriptor_count: 1,
+            },
+        ];
+        let pool_create_info = vk::DescriptorPoolCreateInfo::builder()
+            .max_sets(1)
+            .pool_sizes(&pool_sizes);
+
+        unsafe {
+            self.descriptor_set_layout = device
+                .create_descriptor_set_layout(&layout_create_info, None)
+                .map_err(vk_error("create canvas descriptor set layout"))?;
+            self.descriptor_pool = device
+                .create_descriptor_pool(&pool_create_info, None)
+                .map_err(vk_error("create canvas descriptor pool"))?;
+            let set_layouts = [self.descriptor_set_layout];
+            let allocate_info = vk::DescriptorSetAllocateInfo::builder()
+                .descriptor_pool(self.descriptor_pool)
+                .set_layouts(&set_layouts);
+            self.descriptor_set = device
+                .allocate_descriptor_sets(&allocate_info)
+                .map_err(vk_error("allocate canvas descriptor set"))?[0];
+
+            let sampler_infos = [vk::DescriptorImageInfo {
+                sampler: self.sampler,
+                image_view: vk::ImageView::null(),
+                image_layout: vk::ImageLayout::UNDEFINED,
+            }];
+            let buffer_infos = [vk::DescriptorBufferInfo {
+                buffer: self.parameters,
+                offset: 0,
+                range: PARAMETERS_SIZE as vk::DeviceSize,
+            }];
+            let writes = [
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.descriptor_set)
+                    .dst_binding(CANVAS_SAMPLER_BINDING)
+                    .descriptor_type(vk::DescriptorType::SAMPLER)
+                    .image_info(&sampler_infos)
+                    .build(),
+                vk::WriteDescriptorSet::builder()
+                    .dst_set(self.descriptor_set)
+                    .dst_binding(CANVAS_PARAMETERS_BINDING)
+                    .descriptor_type(vk::DescriptorType::UNIFORM_BUFFER)
+                    .buffer_info(&buffer_infos)
+                    .build(),
+            ];
+            device.update_descriptor_sets(&writes, &[]);
+        }
+        Ok(())
+    }
+
+    pub fn extent(&self) -> vk::Extent2D {
+        self.extent
+    }
+
+    fn whole_region(&self) -> vk::Rect2D {
+        vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: self.extent,
+        }
+    }
+
+    /// Makes the canvas composite `layers`, from bottom to top. Returns the
+    /// layers whose pixels the canvas does not hold, which have to be
+    /// written in full before they show. No frame may be in flight.
+    pub fn set_layers(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        transfer: &mut Transfer,
+        layers: &[CanvasLayer],
+    ) -> Result<Vec<CanvasLayerId>, EngineError> {
+        if layers.len() > MAX_CANVAS_LAYERS {
+            return Err(EngineError::TooManyLayers(layers.len()));
+        }
+        for slot in self.slots.iter_mut() {
+            if !slot.is_some_and(|id| layers.iter().any(|layer| layer.id == id)) {
+                *slot = None;
+            }
+        }
+        let mut missing: Vec<CanvasLayerId> = layers
+            .iter()
+            .map(|layer| layer.id)
+            .filter(|id| !self.slots.contains(&Some(*id)))
+            .collect();
+        let free = self.slots.iter().filter(|slot| slot.is_none()).count();
+        if missing.len() > free {
+            // A bigger image starts out empty, so every layer has to be
+            // written again.
+            let capacity = (self.slots.len() * 2).max(layers.len()) as u32;
+            self.destroy_image(device, allocator);
+            self.create_image(device, allocator, transfer, capacity)?;
+            missing = layers.iter().map(|layer| layer.id).collect();
+        }
+        for id in missing.iter() {
+            let slot = self.slots.iter().position(Option::is_none).unwrap();
+            self.slots[slot] = Some(*id);
+        }
+        self.write_parameters(layers);
+        Ok(missing)
+    }
+
+    fn slot(&self, id: CanvasLayerId) -> Option<usize> {
+        self.slots.iter().position(|slot| *slot == Some(id))
+    }
+
+    /// Fills the parameter block with the visible layers. The buffer is
+    /// host coherent, so the write shows in the next frame submitted.
+    fn write_parameters(&mut self, layers: &[CanvasLayer]) {
+        let mut parameters = vec![0u8; PARAMETERS_SIZE];
+        let mut count = 0;
+        for layer in layers.iter().filter(|layer| layer.visible) {
+            let slot = self.slot(layer.id).unwrap();
+            let values = [
+                slot as f32,
+                layer.blend_mode as u32 as f32,
+                layer.opacity.clamp(0.0, 1.0),
+                0.0,
+            ];
+            let offset = 16 + count * 16;
+            for (index, value) in values.iter().enumerate() {
+                parameters[offset + index * 4..offset + index * 4 + 4]
+                    .copy_from_slice(&value.to_ne_bytes());
+            }
+            count += 1;
+        }
+        parameters[..4].copy_from_slice(&(count as u32).to_ne_bytes());
+        let mapped = self.parameters_allocation
+            .as_mut()
+            .and_then(|allocation| allocation.mapped_slice())
+            .unwrap();
+        mapped[..PARAMETERS_SIZE].copy_from_slice(&parameters);
+    }
+
+    /// Queues replacing a rectangle of a layer's pixels with tightly packed
+    /// RGBA8 rows on `transfer`.
+    pub fn write_region(
+        &self,
+        device: &Device,
+        allocator: &mut Allocator,
+        transfer: &mut Transfer,
+        layer: CanvasLayerId,
+        region: vk::Rect2D,
+        rgba: &[u8],
+    ) -> Result<(), EngineError> {
+        let slot = self.slot(layer).ok_or(EngineError::UnknownLayer(layer))?;
+        let fits = |offset: i32, length: u32, limit: u32| {
+            offset >= 0 && offset as u64 + length as u64 <= limit as u64
+        };
+        if !fits(region.offset.x, region.extent.width, self.extent.width)
+            || !fits(region.offset.y, region.extent.height, self.extent.height)
+        {
+            return Err(EngineError::RegionOutOfBounds { region, extent: self.extent });
+        }
+        let expected = region.extent.width as usize * region.extent.height as usize * 4;
+        if rgba.len() != expected {
+            return Err(EngineError::RegionSize { expected, actual: rgba.len() });
+        }
+        if region.extent.width == 0 || region.extent.height == 0 {
+            return Ok(());
+        }
+        transfer.upload(
+            device,
+            allocator,
+            self.image,
+            slot as u32,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            region,
+            rgba,
+        )
+    }
+
+    /// Queues reading a layer's pixels back as tightly packed RGBA8 rows.
+    pub fn read_layer(
+        &self,
+        device: &Device,
+        allocator: &mut Allocator,
+        transfer: &mut Transfer,
+        layer: CanvasLayerId,
+    ) -> Result<ReadbackTicket, EngineError> {
+        let slot = self.slot(layer).ok_or(EngineError::UnknownLayer(layer))?;
+        transfer.download(
+            device,
+            allocator,
+            self.image,
+            slot as u32,
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            self.whole_region(),
+        )
+    }
+
+    /// Changes the size of the canvas. The new image holds no layers, so
+    /// `set_layers` has to be called again. No frame may be in flight.
+    pub fn resize(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        transfer: &mut Transfer,
+        extent: vk::Extent2D,
+    ) -> Result<(), EngineError> {
+        let capacity = self.slots.len() as u32;
+        self.destroy_image(device, allocator);
+        self.extent = extent;
+        self.create_image(device, allocator, transfer, capacity)?;
+        self.write_parameters(&[]);
+        Ok(())
+    }
+
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        unsafe {
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            device.destroy_descriptor_set_layout(self.descriptor_set_layout, None);
+            device.destroy_sampler(self.sampler, None);
+        }
+        self.destroy_image(device, allocator);
+        if let Some(allocation) = self.parameters_allocation.take() {
+            allocator.destroy_buffer(device, self.parameters, allocation);
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_char;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use ash::vk;
+
+/// Target of the log records debug messages are forwarded as.
+pub const LOG_TARGET: &str = "vulkan";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Severity {
+    Verbose,
+    Info,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    /// The most severe of the flags. Messages are meant to carry exactly
+    /// one, but nothing stops a layer from setting more.
+    pub fn from_flags(flags: vk::DebugUtilsMessageSeverityFlagsEXT) -> Self {
+        if flags.contains(vk::DebugUtilsMessageSeverityFlagsEXT::ERROR) {
+            Severity::Error
+        } else if flags.contains(vk::DebugUtilsMessageSeverityFlagsEXT::WARNING) {
+            Severity::Warning
+        } else if flags.contains(vk::DebugUtilsMessageSeverityFlagsEXT::INFO) {
+            Severity::Info
+        } else {
+            Severity::Verbose
+        }
+    }
+
+    pub fn level(self) -> log::Level {
+        match self {
+            Severity::Verbose => log::Level::Trace,
+            Severity::Info => log::Level::Info,
+            Severity::Warning => log::Level::Warn,
+            Severity::Error => log::Level::Error,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Severity::Verbose => "verbose",
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// A Vulkan object a message is about.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DebugObject {
+    pub object_type: vk::ObjectType,
+    pub handle: u64,
+    /// The name given to the object with the debug utils, if any.
+    pub name: Option<String>,
+}
+
+impl fmt::Display for DebugObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?} {:#x}", self.object_type, self.handle)?;
+        if let Some(name) = &self.name {
+            write!(f, " \"{}\"", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// A message from the validation layers or the driver, copied out of the
+/// callback.
+#[derive(Clone, Debug, PartialEq)]
+pub struct DebugMessage {
+    pub severity: Severity,
+    /// Any combination of general, validation and performance.
+    pub types: vk::DebugUtilsMessageTypeFlagsEXT,
+    /// Names the rule that was broken, such as `VUID-vkCmdDraw-None-02699`.
+    pub message_id_name: Option<String>,
+    pub message_id_number: i32,
+    pub message: String,
+    pub objects: Vec<DebugObject>,
+}
+
+impl DebugMessage {
+    /// # Safety
+    ///
+    /// `data` has to be what the debug callback was handed, with every
+    /// pointer in it valid.
+    pub unsafe fn from_callback_data(
+        severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+        types: vk::DebugUtilsMessageTypeFlagsEXT,
+        data: &vk::DebugUtilsMessengerCallbackDataEXT,
+    ) -> Self {
+        let objects = if data.p_objects.is_null() {
+            &[][..]
+        } else {
+            std::slice::from_raw_parts(data.p_objects, data.object_count as usize)
+        };
+        DebugMessage {
+            severity: Severity::from_flags(severity),
+            types,
+            message_id_name: string(data.p_message_id_name),
+            message_id_number: data.message_id_number,
+            message: string(data.p_message).unwrap_or_default(),
+            objects: objects
+                .iter()
+                .map(|object| DebugObject {
+                    object_type: object.object_type,
+                    handle: object.object_handle,
+                    name: string(object.p_object_name),
+                })
+                .collect(),
+        }
+    }
+
+    /// The message types joined by `|`, such as `general|validation`.
+    pub fn type_names(&self) -> String {
+        let names = [
+            (vk::DebugUtilsMessageTypeFlagsEXT::GENERAL, "general"),
+            (vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION, "validation"),
+            (vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE, "performance"),
+        ];
+        let known = names.iter().fold(vk::DebugUtilsMessageTypeFlagsEXT::empty(), |all, (flag, _)| all | *flag);
+        let mut type_names: Vec<&str> = names
+            .iter()
+            .filter(|(flag, _)| self.types.contains(*flag))
+            .map(|(_, name)| *name)
+            .collect();
+        if !(self.types & !known).is_empty() {
+            type_names.push("unknown");
+        }
+        type_names.join("|")
+    }
+
+    /// Hands the message to the `log` crate. The message text is the
+    /// record's message; everything else goes into its key-values.
+    pub fn log(&self) {
+        let types = self.type_names();
+        let objects = self.objects
+            .iter()
+            .map(DebugObject::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        log::log!(
+            target: LOG_TARGET,
+            self.severity.level(),
+            severity = self.severity.name(),
+            types = types.as_str(),
+            message_id = self.message_id_name.as_deref(),
+            message_id_number = self.message_id_number,
+            objects = objects.as_str();
+            "{}",
+            self.message
+        );
+    }
+}
+
+unsafe fn string(pointer: *const c_char) -> Option<String> {
+    if pointer.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(pointer).to_string_lossy().into_owned())
+    }
+}
+
+/// Collects debug messages in memory, for tests that check what a scenario
+/// made the validation layers say. Clones share the same messages; the
+/// callback may be called from any thread. A thread that panicked while
+/// holding the messages cannot have left them half written, so a poisoned
+/// lock is used all the same rather than panicking inside the callback.
+#[derive(Clone, Debug, Default)]
+pub struct MessageCapture {
+    messages: Arc<Mutex<Vec<DebugMessage>>>,
+}
+
+impl MessageCapture {
+    pub fn new() -> Self {
+        MessageCapture::default()
+    }
+
+    pub fn push(&self, message: DebugMessage) {
+        self.lock().push(message);
+    }
+
+    pub fn messages(&self) -> Vec<DebugMessage> {
+        self.lock().clone()
+    }
+
+    /// The messages of error severity.
+    pub fn errors(&self) -> Vec<DebugMessage> {
+        self.lock()
+            .iter()
+            .filter(|message| message.severity == Severity::Error)
+            .cloned()
+            .collect()
+    }
+
+    /// Takes the messages collected so far, leaving none.
+    pub fn take(&self) -> Vec<DebugMessage> {
+        std::mem::take(&mut *self.lock())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<DebugMessage>> {
+        self.messages.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,244 @@
+use ash::{vk, Instance};
+use ash::version::InstanceV1_0;
+use ash::extensions::khr::{Surface, Swapchain};
+use std::env;
+use std::ffi::CStr;
+use std::fmt;
+use crate::offscreen::OFFSCREEN_FORMAT;
+
+/// Environment variable used to force a device when no override is passed in.
+pub const DEVICE_OVERRIDE_ENV: &str = "VULKAN_DEVICE";
+
+/// Forces the engine onto one physical device, by its enumeration index or
+/// by a case-insensitive substring of its name.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceOverride {
+    Index(usize),
+    Name(String),
+}
+
+impl DeviceOverride {
+    pub fn parse(value: &str) -> Self {
+        match value.trim().parse() {
+            Ok(index) => DeviceOverride::Index(index),
+            Err(_) => DeviceOverride::Name(value.trim().to_string()),
+        }
+    }
+
+    pub fn from_env() -> Option<Self> {
+        env::var(DEVICE_OVERRIDE_ENV)
+            .ok()
+            .filter(|value| !value.trim().is_empty())
+            .map(|value| DeviceOverride::parse(&value))
+    }
+
+    fn matches(&self, candidate: &DeviceCandidate) -> bool {
+        match self {
+            DeviceOverride::Index(index) => *index == candidate.index,
+            DeviceOverride::Name(name) => candidate.name
+                .to_lowercase()
+                .contains(&name.to_lowercase()),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RejectionReason {
+    NoGraphicsQueue,
+    NoPresentQueue,
+    MissingExtension(String),
+    NoSurfaceFormats,
+    UnsupportedFormat(vk::Format),
+}
+
+impl fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RejectionReason::NoGraphicsQueue => write!(f, "no graphics queue family"),
+            RejectionReason::NoPresentQueue =>
+                write!(f, "no graphics queue family can present to the surface"),
+            RejectionReason::MissingExtension(name) => write!(f, "missing extension {}", name),
+            RejectionReason::NoSurfaceFormats => write!(f, "surface reports no formats"),
+            RejectionReason::UnsupportedFormat(format) =>
+                write!(f, "{:?} cannot be used as a color attachment", format),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceCandidate {
+    pub index: usize,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub score: u32,
+    pub queue_family_index: Option<u32>,
+    pub rejection: Option<RejectionReason>,
+}
+
+impl fmt::Display for DeviceCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {} ({:?})", self.index, self.name, self.device_type)?;
+        match &self.rejection {
+            Some(reason) => write!(f, ": rejected, {}", reason),
+            None => write!(f, ": score {}", self.score),
+        }
+    }
+}
+
+fn device_type_score(device_type: vk::PhysicalDeviceType) -> u32 {
+    match device_type {
+        vk::PhysicalDeviceType::DISCRETE_GPU => 4000,
+        vk::PhysicalDeviceType::INTEGRATED_GPU => 3000,
+        vk::PhysicalDeviceType::VIRTUAL_GPU => 2000,
+        vk::PhysicalDeviceType::CPU => 1000,
+        _ => 0,
+    }
+}
+
+/// Scores every physical device against what the engine needs. A null
+/// surface means headless rendering, where presentation is not required.
+pub fn evaluate_devices(
+    instance: &Instance,
+    surface_loader: &Surface,
+    surface: vk::SurfaceKHR,
+) -> Result<Vec<(vk::PhysicalDevice, DeviceCandidate)>, vk::Result> {
+    let devices = unsafe { instance.enumerate_physical_devices()? };
+
+    let candidates = devices
+        .into_iter()
+        .enumerate()
+        .map(|(index, device)| {
+            let properties = unsafe { instance.get_physical_device_properties(device) };
+            let name = unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned();
+            let (queue_family_index, rejection) =
+                match check_device(instance, device, surface_loader, surface) {
+                    Ok(queue_family_index) => (Some(queue_family_index), None),
+                    Err(reason) => (None, Some(reason)),
+                };
+            // Larger maximum image sizes break ties between devices of the
+            // same type; they bound how big a canvas can get.
+            let score = device_type_score(properties.device_type)
+                + properties.limits.max_image_dimension2_d / 1024;
+
+            (device, DeviceCandidate {
+                index,
+                name,
+                device_type: properties.device_type,
+                score,
+                queue_family_index,
+                rejection,
+            })
+        })
+        .collect();
+    Ok(candidates)
+}
+
+fn check_device(
+    instance: &Instance,
+    device: vk::PhysicalDevice,
+    surface_loader: &Surface,
+    surface: vk::SurfaceKHR,
+) -> Result<u32, RejectionReason> {
+    let headless = surface == vk::SurfaceKHR::null();
+    let queue_families = unsafe {
+        instance.get_physical_device_queue_family_properties(device)
+    };
+    let graphics_families: Vec<u32> = queue_families
+        .iter()
+        .enumerate()
+        .filter(|(_, info)| info.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+        .map(|(index, _)| index as u32)
+        .collect();
+    if graphics_families.is_empty() {
+        return Err(RejectionReason::NoGraphicsQueue);
+    }
+
+    if headless {
+        let format_properties = unsafe {
+            instance.get_physical_device_format_properties(device, OFFSCREEN_FORMAT)
+        };
+        if !format_properties
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::COLOR_ATTACHMENT) {
+            return Err(RejectionReason::UnsupportedFormat(OFFSCREEN_FORMAT));
+        }
+        return Ok(graphics_families[0]);
+    }
+
+    let extensions = unsafe {
+        instance
+            .enumerate_device_extension_properties(device)
+            .unwrap_or_default()
+    };
+    let has_swapchain = extensions.iter().any(|extension| {
+        let name = unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) };
+        name == Swapchain::name()
+    });
+    if !has_swapchain {
+        return Err(RejectionReason::MissingExtension(
+            Swapchain::name().to_string_lossy().into_owned()
+        ));
+    }
+
+    let present_family = graphics_families.into_iter().find(|&index| unsafe {
+        surface_loader.get_physical_device_surface_support(device, index, surface)
+    });
+    let present_family = match present_family {
+        Some(index) => index,
+        None => return Err(RejectionReason::NoPresentQueue),
+    };
+
+    let surface_formats = unsafe {
+        surface_loader
+            .get_physical_device_surface_formats(device, surface)
+            .unwrap_or_default()
+    };
+    if surface_formats.is_empty() {
+        return Err(RejectionReason::NoSurfaceFormats);
+    }
+
+    Ok(present_family)
+}
+
+/// Picks the best suitable candidate, or the one named by `device_override`.
+/// The error lists every candidate so the caller can tell why none fit.
+pub fn select_device(
+    candidates: &[(vk::PhysicalDevice, DeviceCandidate)],
+    device_override: Option<&DeviceOverride>,
+) -> Result<(u32, vk::PhysicalDevice), String> {
+    let describe = || candidates
+        .iter()
+        .map(|(_, candidate)| format!("\n  {}", candidate))
+        .collect::<String>();
+
+    let chosen = match device_override {
+        Some(device_override) => {
+            let chosen = candidates
+                .iter()
+                .find(|(_, candidate)| device_override.matches(candidate));
+            match chosen {
+                Some((_, candidate)) if candidate.rejection.is_some() =>
+                    return Err(format!("Requested device is not suitable:{}", describe())),
+                Some(chosen) => chosen,
+                None => return Err(format!(
+                    "No device matches {:?}, candidates are:{}", device_override, describe()
+                )),
+            }
+        },
+        None => {
+            let chosen = candidates
+                .iter()
+                .filter(|(_, candidate)| candidate.rejection.is_none())
+                .max_by_key(|(_, candidate)| candidate.score);
+            match chosen {
+                Some(chosen) => chosen,
+                None => return Err(format!("No suitable device found:{}", describe())),
+            }
+        },
+    };
+
+    let (device, candidate) = chosen;
+    Ok((candidate.queue_family_index.unwrap(), *device))
+}
@@ -0,0 +1,98 @@
+use std::ops::Range;
+
+/// Bytes of push constants every pipeline accepts. 128 is the smallest
+/// `maxPushConstantsSize` a Vulkan implementation may report.
+pub const PUSH_CONSTANT_SIZE: u32 = 128;
+
+/// A pipeline registered with the engine, usable in draw lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineId(pub(crate) usize);
+
+impl PipelineId {
+    /// The pipeline built from the base program, which samples the canvas.
+    pub const BASE: PipelineId = PipelineId(0);
+}
+
+/// A rectangle in framebuffer pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: i32,
+    pub y: i32,
+    pub width: u32,
+    pub height: u32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DrawCommand {
+    BindPipeline(PipelineId),
+    /// Limits the following draws to a rectangle, or lifts the limit.
+    SetScissor(Option<Rect>),
+    PushConstants {
+        offset: u32,
+        data: Vec<u8>,
+    },
+    Draw {
+        vertices: Range<u32>,
+        instances: Range<u32>,
+    },
+}
+
+/// The commands recorded into every frame's command buffer, in order. The
+/// list is kept between frames, so static content only needs to be added
+/// once. Each frame starts with no pipeline bound and no scissor limit.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DrawList {
+    commands: Vec<DrawCommand>,
+}
+
+impl DrawList {
+    pub fn new() -> Self {
+        DrawList::default()
+    }
+
+    /// The canvas stretched over the whole framebuffer.
+    pub fn canvas() -> Self {
+        let mut draw_list = DrawList::new();
+        draw_list.bind_pipeline(PipelineId::BASE);
+        draw_list.draw(0..6);
+        draw_list
+    }
+
+    pub fn clear(&mut self) {
+        self.commands.clear();
+    }
+
+    pub fn bind_pipeline(&mut self, pipeline: PipelineId) {
+        self.commands.push(DrawCommand::BindPipeline(pipeline));
+    }
+
+    pub fn set_scissor(&mut self, scissor: Option<Rect>) {
+        self.commands.push(DrawCommand::SetScissor(scissor));
+    }
+
+    /// Sets push constants for the following draws. Both `offset` and the
+    /// length of `data` have to be multiples of four.
+    pub fn push_constants(&mut self, offset: u32, data: &[u8]) {
+        assert!(
+            offset.is_multiple_of(4) && data.len().is_multiple_of(4),
+            "Push constants have to be aligned to four bytes!"
+        );
+        assert!(
+            offset as usize + data.len() <= PUSH_CONSTANT_SIZE as usize,
+            "Push constants are limited to {} bytes!", PUSH_CONSTANT_SIZE
+        );
+        self.commands.push(DrawCommand::PushConstants { offset, data: data.to_vec() });
+    }
+
+    pub fn draw(&mut self, vertices: Range<u32>) {
+        self.draw_instanced(vertices, 0..1);
+    }
+
+    pub fn draw_instanced(&mut self, vertices: Range<u32>, instances: Range<u32>) {
+        self.commands.push(DrawCommand::Draw { vertices, instances });
+    }
+
+    pub fn commands(&self) -> &[DrawCommand] {
+        &self.commands
+    }
+}
@@ -0,0 +1,111 @@
+use ash::vk;
+use std::fmt;
+use cgci::CanvasLayerId;
+use crate::shaders::ShaderError;
+
+#[derive(Debug)]
+pub enum EngineError {
+    /// The Vulkan loader library could not be found or opened.
+    MissingLoader(String),
+    MissingExtension(String),
+    MissingValidationLayer(String),
+    NoSuitableDevice(String),
+    NoMemoryType(vk::MemoryPropertyFlags),
+    Surface(vk::Result),
+    Shader(ShaderError),
+    /// More layers were given to the canvas than it can composite.
+    TooManyLayers(usize),
+    /// A layer that is not on the canvas.
+    UnknownLayer(CanvasLayerId),
+    /// A region reaching past the edge of the canvas.
+    RegionOutOfBounds {
+        region: vk::Rect2D,
+        extent: vk::Extent2D,
+    },
+    /// Pixels that do not fill the region they are written to.
+    RegionSize {
+        expected: usize,
+        actual: usize,
+    },
+    /// A headless frame was read back before any was rendered.
+    NoFrameRendered,
+    /// Frames were read back from an engine presenting to a window.
+    NotHeadless,
+    /// The CPU was asked to record no frames ahead of the GPU at all.
+    NoFramesInFlight,
+    /// The canvas was given no pixels in one of its dimensions.
+    EmptyCanvas {
+        width: u32,
+        height: u32,
+    },
+    /// Any other Vulkan call that failed, named by what it was doing.
+    Vulkan(&'static str, vk::Result),
+}
+
+impl EngineError {
+    /// The underlying Vulkan result code, when there is one.
+    pub fn vk_result(&self) -> Option<vk::Result> {
+        match self {
+            EngineError::Surface(result) | EngineError::Vulkan(_, result) => Some(*result),
+            EngineError::Shader(ShaderError::ModuleCreation(result)) => Some(*result),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::MissingLoader(error) =>
+                write!(f, "Vulkan loader not available: {}", error),
+            EngineError::MissingExtension(name) =>
+                write!(f, "Required extension {} is not available", name),
+            EngineError::MissingValidationLayer(name) =>
+                write!(f, "Validation layer {} requested, but not available", name),
+            EngineError::NoSuitableDevice(details) => write!(f, "{}", details),
+            EngineError::NoMemoryType(properties) =>
+                write!(f, "No memory type with {:?}", properties),
+            EngineError::Surface(result) => write!(f, "Failed creating surface: {}", result),
+            EngineError::Shader(error) => write!(f, "{}", error),
+            EngineError::TooManyLayers(count) => write!(
+                f,
+                "The canvas composites at most {} layers, {} were given",
+                crate::canvas::MAX_CANVAS_LAYERS,
+                count,
+            ),
+            EngineError::UnknownLayer(layer) => write!(f, "Layer {:?} is not on the canvas", layer),
+            EngineError::RegionOutOfBounds { region, extent } => write!(
+                f,
+                "The region of {}x{} at {}, {} is outside of the {}x{} canvas",
+                region.extent.width, region.extent.height, region.offset.x, region.offset.y,
+                extent.width, extent.height,
+            ),
+            EngineError::RegionSize { expected, actual } => write!(
+                f,
+                "The region needs {} bytes of pixels, {} were given",
+                expected, actual,
+            ),
+            EngineError::NoFrameRendered =>
+                write!(f, "No frame has been rendered to read back yet"),
+            EngineError::NotHeadless => write!(f, "Only headless engines can read back frames"),
+            EngineError::NoFramesInFlight => write!(f, "At least one frame has to be in flight"),
+            EngineError::EmptyCanvas { width, height } =>
+                write!(f, "The canvas cannot be {}x{}, it needs at least a pixel", width, height),
+            EngineError::Vulkan(operation, result) =>
+                write!(f, "Failed to {}: {}", operation, result),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
+
+impl From<ShaderError> for EngineError {
+    fn from(error: ShaderError) -> Self {
+        EngineError::Shader(error)
+    }
+}
+
+/// Shorthand for tagging a failed Vulkan call with what it was doing.
+pub(crate) fn vk_error(operation: &'static str) -> impl Fn(vk::Result) -> EngineError {
+    move |result| EngineError::Vulkan(operation, result)
+}
@@ -0,0 +1,241 @@
+use ash::{vk, Device};
+use ash::version::DeviceV1_0;
+use std::thread;
+use std::time::{Duration, Instant};
+use crate::error::{vk_error, EngineError};
+
+/// Frames the CPU may record ahead of the GPU unless configured otherwise.
+pub const DEFAULT_FRAMES_IN_FLIGHT: usize = 2;
+
+/// Timestamps written per frame: one before and one after its commands.
+const QUERIES_PER_FRAME: u32 = 2;
+
+/// Timing of the most recently completed frames.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    /// Time from the start of the previous frame to the start of this one,
+    /// including any sleep imposed by the frame rate cap.
+    pub frame_time: Duration,
+    /// Time the CPU spent blocked on fences before it could record.
+    pub cpu_wait: Duration,
+    /// Time the GPU spent executing the last frame whose results are back.
+    /// `None` when the queue does not support timestamps.
+    pub gpu_time: Option<Duration>,
+}
+
+/// Keeps the CPU at most a configured number of frames ahead of the GPU and
+/// remembers which frame's fence last used each swapchain image, so an
+/// image is never recorded for while an older frame still renders to it.
+pub struct FramePacer {
+    frames_in_flight: usize,
+    current_frame: usize,
+    /// The fence of the frame last submitted for each swapchain image.
+    images_in_flight: Vec<vk::Fence>,
+    frame_rate_cap: Option<f64>,
+    last_frame_start: Option<Instant>,
+    query_pool: vk::QueryPool,
+    /// Nanoseconds per timestamp tick.
+    timestamp_period: f64,
+    timestamp_mask: u64,
+    /// Whether the frame slot's queries were written since it was last read.
+    queries_pending: Vec<bool>,
+    stats: FrameStats,
+}
+
+impl FramePacer {
+    /// `timestamp_valid_bits` of the graphics queue family; zero disables
+    /// GPU timing.
+    pub fn new(
+        device: &Device,
+        frames_in_flight: usize,
+        timestamp_period: f32,
+        timestamp_valid_bits: u32,
+    ) -> Result<Self, EngineError> {
+        assert!(frames_in_flight > 0, "At least one frame has to be in flight!");
+        let query_pool = if timestamp_valid_bits > 0 {
+            let query_pool_create_info = vk::QueryPoolCreateInfo::builder()
+                .query_type(vk::QueryType::TIMESTAMP)
+                .query_count(frames_in_flight as u32 * QUERIES_PER_FRAME);
+            unsafe {
+                device
+                    .create_query_pool(&query_pool_create_info, None)
+                    .map_err(vk_error("create timestamp query pool"))?
+            }
+        } else {
+            vk::QueryPool::null()
+        };
+        let timestamp_mask = match timestamp_valid_bits {
+            0 => 0,
+            bits if bits >= 64 => u64::MAX,
+            bits => (1 << bits) - 1,
+        };
+
+        Ok(FramePacer {
+            frames_in_flight,
+            current_frame: 0,
+            images_in_flight: vec![],
+            frame_rate_cap: None,
+            last_frame_start: None,
+            query_pool,
+            timestamp_period: timestamp_period as f64,
+            timestamp_mask,
+            queries_pending: vec![false; frames_in_flight],
+            stats: FrameStats::default(),
+        })
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.frames_in_flight
+    }
+
+    pub fn current_frame(&self) -> usize {
+        self.current_frame
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    pub fn frame_rate_cap(&self) -> Option<f64> {
+        self.frame_rate_cap
+    }
+
+    /// Limits how often frames start; `None` runs as fast as presentation
+    /// allows.
+    pub fn set_frame_rate_cap(&mut self, frames_per_second: Option<f64>) {
+        self.frame_rate_cap = frames_per_second.filter(|&fps| fps > 0.0);
+    }
+
+    /// Forgets which fences used the old swapchain's images.
+    pub fn reset_images(&mut self, image_count: usize) {
+        self.images_in_flight = vec![vk::Fence::null(); image_count];
+    }
+
+    /// Sleeps as long as the frame rate cap asks for, then starts timing
+    /// a new frame.
+    pub fn begin_frame(&mut self) {
+        let now = Instant::now();
+        let start = match (self.frame_rate_cap, self.last_frame_start) {
+            (Some(fps), Some(last_frame_start)) => {
+                let next_frame = last_frame_start + Duration::from_secs_f64(1.0 / fps);
+                if next_frame > now {
+                    thread::sleep(next_frame - now);
+                }
+                Instant::now()
+            },
+            _ => now,
+        };
+        if let Some(last_frame_start) = self.last_frame_start {
+            self.stats.frame_time = start - last_frame_start;
+        }
+        self.last_frame_start = Some(start);
+        self.stats.cpu_wait = Duration::default();
+    }
+
+    /// Waits until the GPU is done with the current frame slot, then picks
+    /// up its GPU timing.
+    pub fn wait_for_frame(&mut self, device: &Device, fences: &[vk::Fence]) -> Result<(), EngineError> {
+        let wait_start = Instant::now();
+        unsafe {
+            device
+                .wait_for_fences(fences, true, u64::MAX)
+                .map_err(vk_error("wait for frame fence"))?;
+        }
+        self.stats.cpu_wait += wait_start.elapsed();
+        self.read_gpu_time(device);
+        Ok(())
+    }
+
+    /// Waits for the frame that last rendered to `image_index`, when that
+    /// was another frame slot, and hands the image to `fence`.
+    pub fn wait_for_image(
+        &mut self,
+        device: &Device,
+        image_index: usize,
+        fence: vk::Fence,
+    ) -> Result<(), EngineError> {
+        let image_fence = self.images_in_flight[image_index];
+        if image_fence != vk::Fence::null() && image_fence != fence {
+            let wait_start = Instant::now();
+            unsafe {
+                device
+                    .wait_for_fences(&[image_fence], true, u64::MAX)
+                    .map_err(vk_error("wait for image fence"))?;
+            }
+            self.stats.cpu_wait += wait_start.elapsed();
+        }
+        self.images_in_flight[image_index] = fence;
+        Ok(())
+    }
+
+    fn read_gpu_time(&mut self, device: &Device) {
+        if !self.queries_pending[self.current_frame] {
+            return;
+        }
+        self.queries_pending[self.current_frame] = false;
+        let mut timestamps = [0u64; QUERIES_PER_FRAME as usize];
+        let read = unsafe {
+            device.get_query_pool_results(
+                self.query_pool,
+                self.current_frame as u32 * QUERIES_PER_FRAME,
+                QUERIES_PER_FRAME,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            )
+        };
+        if read.is_ok() {
+            let ticks = (timestamps[1] & self.timestamp_mask)
+                .wrapping_sub(timestamps[0] & self.timestamp_mask)
+                & self.timestamp_mask;
+            let nanoseconds = ticks as f64 * self.timestamp_period;
+            self.stats.gpu_time = Some(Duration::from_nanos(nanoseconds as u64));
+        }
+    }
+
+    /// Records the starting timestamp. Has to come before any render pass
+    /// in the frame's command buffer.
+    pub fn begin_gpu_timing(&mut self, device: &Device, command_buffer: vk::CommandBuffer) {
+        if self.query_pool == vk::QueryPool::null() {
+            return;
+        }
+        let first_query = self.current_frame as u32 * QUERIES_PER_FRAME;
+        unsafe {
+            device.cmd_reset_query_pool(command_buffer, self.query_pool, first_query, QUERIES_PER_FRAME);
+            device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::TOP_OF_PIPE,
+                self.query_pool,
+                first_query,
+            );
+        }
+    }
+
+    /// Records the ending timestamp. Has to come after every render pass in
+    /// the frame's command buffer.
+    pub fn end_gpu_timing(&mut self, device: &Device, command_buffer: vk::CommandBuffer) {
+        if self.query_pool == vk::QueryPool::null() {
+            return;
+        }
+        unsafe {
+            device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                self.query_pool,
+                self.current_frame as u32 * QUERIES_PER_FRAME + 1,
+            );
+        }
+        self.queries_pending[self.current_frame] = true;
+    }
+
+    /// Moves on to the next frame slot after a submission.
+    pub fn advance(&mut self) {
+        self.current_frame = (self.current_frame + 1) % self.frames_in_flight;
+    }
+
+    pub fn destroy(&mut self, device: &Device) {
+        unsafe {
+            device.destroy_query_pool(self.query_pool, None);
+        }
+        self.query_pool = vk::QueryPool::null();
+    }
+}
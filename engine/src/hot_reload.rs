@@ -0,0 +1,177 @@
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::time::{Duration, Instant, SystemTime};
+use crate::shaders::{ShaderError, ShaderLibrary, ShaderStage};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const DEFAULT_COMPILER: &str = "glslangValidator";
+
+#[derive(Debug)]
+pub enum ReloadError {
+    CompilerLaunch(io::Error),
+    Cache(PathBuf, io::Error),
+    Compile { source: PathBuf, log: String },
+    Shader(ShaderError),
+    /// More than one source failed to compile in the same poll.
+    Several(Vec<ReloadError>),
+}
+
+impl fmt::Display for ReloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReloadError::CompilerLaunch(error) =>
+                write!(f, "Failed to launch shader compiler: {}", error),
+            ReloadError::Cache(path, error) =>
+                write!(f, "Failed to update shader cache {}: {}", path.display(), error),
+            ReloadError::Compile { source, log } =>
+                write!(f, "Failed to compile {}:\n{}", source.display(), log),
+            ReloadError::Shader(error) => write!(f, "{}", error),
+            ReloadError::Several(errors) => {
+                let messages: Vec<String> = errors.iter().map(ReloadError::to_string).collect();
+                write!(f, "{}", messages.join("\n"))
+            },
+        }
+    }
+}
+
+impl std::error::Error for ReloadError {}
+
+/// Watches a shader directory laid out like `engine/shaders` (GLSL in `src`,
+/// SPIR-V in `spv`) by polling modification times. Changed GLSL sources are
+/// recompiled the same way `compile.sh` does, and any change yields a freshly
+/// loaded `ShaderLibrary`.
+///
+/// The watched directories are only ever read. Compiler output goes to a
+/// per-process cache directory, seeded from `spv`, which the reloaded library
+/// is loaded from and which is removed when the watcher is dropped.
+pub struct ShaderWatcher {
+    source_dir: PathBuf,
+    spirv_dir: PathBuf,
+    cache_dir: PathBuf,
+    compiler: String,
+    last_poll: Instant,
+    modified: HashMap<PathBuf, SystemTime>,
+}
+
+impl ShaderWatcher {
+    pub fn new(shader_root: &Path) -> Self {
+        let mut watcher = Self {
+            source_dir: shader_root.join("src"),
+            spirv_dir: shader_root.join("spv"),
+            cache_dir: env::temp_dir().join(format!("paint-shader-cache-{}", process::id())),
+            compiler: env::var("GLSLANG_VALIDATOR").unwrap_or_else(|_| DEFAULT_COMPILER.to_string()),
+            last_poll: Instant::now(),
+            modified: HashMap::new(),
+        };
+        changed_files(&mut watcher.modified, &watcher.source_dir);
+        changed_files(&mut watcher.modified, &watcher.spirv_dir);
+        watcher
+    }
+
+    /// Returns `None` until something in the watched directories changes.
+    /// Every changed source is compiled, even after one fails, so that a
+    /// typo in one stage does not leave the others stale.
+    pub fn poll(&mut self) -> Option<Result<ShaderLibrary, ReloadError>> {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return None;
+        }
+        self.last_poll = Instant::now();
+
+        let changed_sources = changed_files(&mut self.modified, &self.source_dir);
+        let changed_spirv = changed_files(&mut self.modified, &self.spirv_dir);
+        if changed_sources.is_empty() && changed_spirv.is_empty() {
+            return None;
+        }
+
+        if let Err(error) = self.update_cache(&changed_spirv) {
+            return Some(Err(error));
+        }
+        let mut errors: Vec<ReloadError> = changed_sources
+            .iter()
+            .filter_map(|source| self.compile(source).err())
+            .collect();
+        match errors.len() {
+            0 => (),
+            1 => return Some(Err(errors.remove(0))),
+            _ => return Some(Err(ReloadError::Several(errors))),
+        }
+
+        Some(ShaderLibrary::from_dir(&self.cache_dir).map_err(ReloadError::Shader))
+    }
+
+    /// Copies changed SPIR-V from `spv` into the cache, along with any file the
+    /// cache does not have yet, so that it always holds a complete library.
+    fn update_cache(&self, changed_spirv: &[PathBuf]) -> Result<(), ReloadError> {
+        let cache_error = |path: &Path| {
+            let path = path.to_path_buf();
+            move |error| ReloadError::Cache(path, error)
+        };
+        fs::create_dir_all(&self.cache_dir).map_err(cache_error(&self.cache_dir))?;
+        let entries = fs::read_dir(&self.spirv_dir).map_err(cache_error(&self.spirv_dir))?;
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let cached = self.cache_dir.join(entry.file_name());
+            if changed_spirv.contains(&path) || !cached.exists() {
+                fs::copy(&path, &cached).map_err(cache_error(&cached))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn compile(&self, source: &Path) -> Result<(), ReloadError> {
+        let stage = match source
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .and_then(ShaderStage::from_extension) {
+            Some(stage) => stage,
+            None => return Ok(()),
+        };
+        let output = Command::new(&self.compiler)
+            .arg("-V")
+            .arg(source)
+            .arg("-o")
+            .arg(self.cache_dir.join(stage.spirv_file_name()))
+            .output()
+            .map_err(ReloadError::CompilerLaunch)?;
+
+        if output.status.success() {
+            Ok(())
+        } else {
+            let mut log = String::from_utf8_lossy(&output.stdout).into_owned();
+            log.push_str(&String::from_utf8_lossy(&output.stderr));
+            Err(ReloadError::Compile { source: source.to_path_buf(), log })
+        }
+    }
+}
+
+impl Drop for ShaderWatcher {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.cache_dir);
+    }
+}
+
+/// Records the modification time of every file in `dir` and returns the ones
+/// that differ from what was recorded before.
+fn changed_files(modified_times: &mut HashMap<PathBuf, SystemTime>, dir: &Path) -> Vec<PathBuf> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut changed = vec![];
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let modified = match entry.metadata().and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(_) => continue,
+        };
+        if modified_times.insert(path.clone(), modified) != Some(modified) {
+            changed.push(path);
+        }
+    }
+    changed
+}
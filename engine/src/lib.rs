@@ -5,13 +5,39 @@ use ash::extensions::khr::{Surface, Swapchain};
 use std::ptr;
 use std::ffi::{CString, CStr};
 use std::os::raw::c_void;
-use platforms::required_extension_names;
-use gui;
-use cgci::Draw;
+use platforms::{headless_extension_names, required_extension_names};
+use cgci::{CanvasLayer, CanvasLayerId, CanvasRenderer, Draw, InputEvent, InputHandler};
+use shaders::ShaderLibrary;
+use hot_reload::ShaderWatcher;
+use std::path::Path;
+use std::error::Error;
+use device_selection::{DeviceCandidate, DeviceOverride};
+use canvas::Canvas;
+pub use canvas::MAX_CANVAS_LAYERS;
+use allocator::{Allocation, Allocator, MemoryStats};
+use transfer::Transfer;
+use draw_list::{DrawCommand, DrawList, PipelineId, Rect};
+use frame_pacing::{FramePacer, FrameStats};
+use error::vk_error;
+pub use error::EngineError;
+pub use validation::{MessageId, ValidationConfig};
+pub use debug_messages::{DebugMessage, DebugObject, MessageCapture, Severity};
+use validation::LayerNames;
 
 mod platforms;
 mod validation;
+mod debug_messages;
 mod pipeline;
+mod offscreen;
+mod canvas;
+mod error;
+pub mod allocator;
+pub mod transfer;
+pub mod draw_list;
+pub mod frame_pacing;
+pub mod device_selection;
+pub mod shaders;
+pub mod hot_reload;
 
 const APPLICATION_VERSION: u32 = vk_make_version!(1, 0, 0);
 const ENGINE_VERSION: u32 = vk_make_version!(1, 0, 0);
@@ -19,20 +45,26 @@ const API_VERSION: u32 = vk_make_version!(1, 2, 183);
 
 const ENGINE_NAME: &str = "PaintGraphicsEngine";
 
-const MAX_FRAMES_IN_FLIGHT: usize = 2;
+/// Target of the log records about reloaded shaders.
+const SHADER_RELOAD_TARGET: &str = "shader_reload";
 
 pub struct VulkanEngine {
-    entry: Entry,
+    _entry: Entry,
     instance: Instance,
     device: Device,
+    physical_device: vk::PhysicalDevice,
+    /// Queue family of the graphics queue.
     device_index: u32,
+    device_candidates: Vec<DeviceCandidate>,
     graphics_queue: vk::Queue,
     present_queue: vk::Queue,
     surface_loader: Surface,
     surface: vk::SurfaceKHR,
-    validation_layers_enabled: bool,
     debug_utils_loader: ash::extensions::ext::DebugUtils,
     debug_messenger: vk::DebugUtilsMessengerEXT,
+    /// Read by the debug callback. Fields are only dropped after `drop` has
+    /// destroyed the instance, so it outlives every message.
+    validation: Option<Box<ValidationConfig>>,
     swapchain_loader: Swapchain,
     swapchain: vk::SwapchainKHR,
     swapchain_format: vk::Format,
@@ -41,128 +73,955 @@ pub struct VulkanEngine {
     swapchain_imageviews: Vec<vk::ImageView>,
     swapchain_framebuffers: Vec<vk::Framebuffer>,
     pipeline_layout: vk::PipelineLayout,
-    pipeline: vk::Pipeline,
+    /// Indexed by `PipelineId`, built from the programs of the same index.
+    pipelines: Vec<vk::Pipeline>,
+    pipeline_programs: Vec<String>,
+    draw_list: DrawList,
+    shaders: ShaderLibrary,
+    shader_watcher: Option<ShaderWatcher>,
     render_pass: vk::RenderPass,
-    command_buffers: Vec<vk::CommandBuffer>,
-    command_pool: vk::CommandPool,
+    frame_commands: Vec<FrameCommands>,
     image_available_semaphores: Vec<vk::Semaphore>,
     render_finished_semaphores: Vec<vk::Semaphore>,
     in_flight_fences: Vec<vk::Fence>,
-    current_frame: usize,
+    allocator: Allocator,
+    transfer: Transfer,
+    canvas: Canvas,
+    frame_pacer: FramePacer,
+    headless: bool,
+    offscreen_allocation: Option<Allocation>,
+    /// Whether the offscreen image holds a frame, and so is laid out for
+    /// reading back. A new image holds nothing until the next frame.
+    frame_rendered: bool,
+    window_extent: vk::Extent2D,
+    framebuffer_resized: bool,
 }
 
 impl VulkanEngine {
-    pub fn new(app_name: &str, validation_layers: bool, window: &gui::MainWindow) -> Self {
-        let entry = unsafe { Entry::new() }.unwrap();
-        let instance = VulkanEngine::create_instance(app_name, &entry, validation_layers);
-        let (debug_utils_loader, debug_messenger) = VulkanEngine::setup_debug_utils(
+    pub fn new(
+        app_name: &str,
+        validation: Option<ValidationConfig>,
+        window: &gui::MainWindow,
+        shaders: ShaderLibrary,
+        device_override: Option<DeviceOverride>,
+    ) -> Result<Self, EngineError> {
+        let entry = Entry::new().map_err(|error| EngineError::MissingLoader(error.to_string()))?;
+        // Boxed so the address the debug callback is handed stays put.
+        let validation = validation.map(Box::new);
+        let instance = VulkanEngine::create_instance(
+            app_name,
             &entry,
-            &instance,
-            validation_layers
-        );
-        let surface_bundle = VulkanEngine::create_surface(&entry, &instance, &window);
-        let device_bundle = VulkanEngine::create_device(
-            &instance,
-            validation_layers,
-            &surface_bundle.surface_loader,
+            validation.as_deref(),
+            &required_extension_names(),
+        )?;
+        let surface_bundle = match VulkanEngine::create_surface(&entry, &instance, window) {
+            Ok(surface_bundle) => surface_bundle,
+            Err(error) => {
+                unsafe {
+                    instance.destroy_instance(None);
+                }
+                return Err(error);
+            }
+        };
+        VulkanEngine::assemble(
+            entry,
+            instance,
+            validation,
+            surface_bundle,
+            shaders,
+            device_override,
+        )
+    }
+
+    /// Creates an engine without a window, surface or swapchain. Frames are
+    /// rendered into an offscreen image that `read_rgba8` copies back.
+    pub fn new_headless(
+        app_name: &str,
+        validation: Option<ValidationConfig>,
+        width: u32,
+        height: u32,
+        shaders: ShaderLibrary,
+        device_override: Option<DeviceOverride>,
+    ) -> Result<Self, EngineError> {
+        let entry = Entry::new().map_err(|error| EngineError::MissingLoader(error.to_string()))?;
+        let validation = validation.map(Box::new);
+        let instance = VulkanEngine::create_instance(
+            app_name,
+            &entry,
+            validation.as_deref(),
+            &headless_extension_names(),
+        )?;
+        let surface_bundle = SurfaceBundle {
+            surface_loader: Surface::new(&entry, &instance),
+            surface: vk::SurfaceKHR::null(),
+            width,
+            height,
+        };
+        VulkanEngine::assemble(
+            entry,
+            instance,
+            validation,
+            surface_bundle,
+            shaders,
+            device_override,
+        )
+    }
+
+    /// Builds everything past the instance. A null surface selects headless
+    /// rendering into an offscreen image. On failure, whatever was created,
+    /// the instance and surface included, is released before returning.
+    fn assemble(
+        entry: Entry,
+        instance: Instance,
+        validation: Option<Box<ValidationConfig>>,
+        surface_bundle: SurfaceBundle,
+        shaders: ShaderLibrary,
+        device_override: Option<DeviceOverride>,
+    ) -> Result<Self, EngineError> {
+        let headless = surface_bundle.surface == vk::SurfaceKHR::null();
+        let window_extent = vk::Extent2D {
+            width: surface_bundle.width,
+            height: surface_bundle.height,
+        };
+        let debug_utils_loader = ash::extensions::ext::DebugUtils::new(&entry, &instance);
+        // Declared after `entry` and `validation`, so it is dropped before
+        // the loader library is unloaded and the debug callback loses its
+        // configuration.
+        let mut assembly = Assembly::new(
+            instance,
+            surface_bundle.surface_loader,
             surface_bundle.surface,
+            debug_utils_loader,
         );
-        let swapchain_bundle = VulkanEngine::create_swapchain(
-            &device_bundle,
-            &instance,
-            &surface_bundle,
+        shaders.program(shaders::BASE_PROGRAM)?;
+        assembly.debug_messenger = VulkanEngine::setup_debug_utils(
+            &assembly.debug_utils_loader,
+            validation.as_deref(),
+        )?;
+        let device_bundle = VulkanEngine::create_device(
+            &assembly.instance,
+            validation.as_deref(),
+            &assembly.surface_loader,
+            assembly.surface,
+            device_override,
+        )?;
+        let device = assembly.device.insert(device_bundle.logical_device.clone());
+        let allocator = assembly.allocator.insert(
+            Allocator::new(&assembly.instance, device_bundle.physical_device),
         );
-        let render_pass = pipeline::create_render_pass(
-            &device_bundle.logical_device,
+        let transfer = assembly.transfer.insert(Transfer::new(
+            device,
+            device_bundle.physical_device_index,
+            device_bundle.queue,
+            device_bundle.transfer_queue,
+        )?);
+        let (swapchain_bundle, offscreen_allocation) = VulkanEngine::create_render_target(
+            &assembly.instance,
+            device,
+            allocator,
+            transfer,
+            device_bundle.physical_device,
+            &assembly.surface_loader,
+            assembly.surface,
+            window_extent,
+        )?;
+        assembly.offscreen_allocation = offscreen_allocation;
+        let swapchain_bundle = assembly.swapchain.insert(swapchain_bundle);
+        assembly.render_pass = pipeline::create_render_pass(
+            device,
             swapchain_bundle.swapchain_format,
-        );
+            VulkanEngine::final_layout(headless),
+        )?;
 
-        let swapchain_image_views = VulkanEngine::create_image_views(
-            &device_bundle.logical_device,
+        assembly.image_views = VulkanEngine::create_image_views(
+            device,
             swapchain_bundle.swapchain_format,
             &swapchain_bundle.swapchain_images,
-        );
-
-        let (pipeline_layout, pipeline) = pipeline::create_graphics_pipeline(
-            &device_bundle.logical_device,
+        )?;
+
+        let canvas = assembly.canvas.insert(Canvas::new(
+            device,
+            allocator,
+            transfer,
+            window_extent,
+        )?);
+        transfer.flush(device, allocator)?;
+
+        assembly.pipeline_layout = pipeline::create_pipeline_layout(
+            device,
+            &[canvas.descriptor_set_layout],
+        )?;
+        let pipeline_programs = vec![shaders::BASE_PROGRAM.to_string()];
+        assembly.pipelines = VulkanEngine::create_pipelines(
+            device,
             swapchain_bundle.swapchain_extent,
-            render_pass,
-        );
-
-        let framebuffers = VulkanEngine::create_framebuffers(
-            &device_bundle.logical_device,
-            render_pass,
-            &swapchain_image_views,
+            assembly.render_pass,
+            assembly.pipeline_layout,
+            &shaders,
+            &pipeline_programs,
+        )?;
+
+        assembly.framebuffers = VulkanEngine::create_framebuffers(
+            device,
+            assembly.render_pass,
+            &assembly.image_views,
             swapchain_bundle.swapchain_extent,
-        );
+        )?;
 
-        let command_bundle = VulkanEngine::create_command_buffers(
-            &device_bundle.logical_device,
+        assembly.frame_commands = VulkanEngine::create_frame_commands(
+            device,
             device_bundle.physical_device_index,
-            pipeline,
-            &framebuffers,
-            render_pass,
-            swapchain_bundle.swapchain_extent,
-        );
-
-        let sync_bundle = VulkanEngine::create_sync_objects(&device_bundle.logical_device);
-
-        Self {
-            entry,
-            instance,
+            frame_pacing::DEFAULT_FRAMES_IN_FLIGHT,
+        )?;
+
+        assembly.sync = Some(VulkanEngine::create_sync_objects(
+            device,
+            frame_pacing::DEFAULT_FRAMES_IN_FLIGHT,
+        )?);
+
+        let mut frame_pacer = VulkanEngine::create_frame_pacer(
+            &assembly.instance,
+            device,
+            device_bundle.physical_device,
+            device_bundle.physical_device_index,
+            frame_pacing::DEFAULT_FRAMES_IN_FLIGHT,
+        )?;
+        frame_pacer.reset_images(swapchain_bundle.swapchain_images.len());
+
+        // Nothing past here can fail, so the engine takes everything over.
+        assembly.armed = false;
+        let swapchain_bundle = assembly.swapchain.take().unwrap();
+        let sync_bundle = assembly.sync.take().unwrap();
+        Ok(Self {
+            _entry: entry,
+            instance: assembly.instance.clone(),
             device: device_bundle.logical_device,
+            physical_device: device_bundle.physical_device,
+            device_index: device_bundle.physical_device_index,
+            device_candidates: device_bundle.candidates,
             graphics_queue: device_bundle.queue,
             present_queue: device_bundle.present_queue,
-            device_index: device_bundle.physical_device_index,
-            surface_loader: surface_bundle.surface_loader,
-            surface: surface_bundle.surface,
-            validation_layers_enabled: validation_layers,
-            debug_utils_loader,
-            debug_messenger,
+            surface_loader: assembly.surface_loader.clone(),
+            surface: assembly.surface,
+            debug_utils_loader: assembly.debug_utils_loader.clone(),
+            debug_messenger: assembly.debug_messenger,
+            validation,
             swapchain_loader: swapchain_bundle.swapchain_loader,
             swapchain: swapchain_bundle.swapchain,
             swapchain_format: swapchain_bundle.swapchain_format,
             swapchain_images: swapchain_bundle.swapchain_images,
             swapchain_extent: swapchain_bundle.swapchain_extent,
-            swapchain_imageviews: swapchain_image_views,
-            swapchain_framebuffers: framebuffers,
-            pipeline_layout,
-            pipeline,
-            render_pass,
-            command_buffers: command_bundle.command_buffers,
-            command_pool: command_bundle.command_pool,
+            swapchain_imageviews: std::mem::take(&mut assembly.image_views),
+            swapchain_framebuffers: std::mem::take(&mut assembly.framebuffers),
+            pipeline_layout: assembly.pipeline_layout,
+            pipelines: std::mem::take(&mut assembly.pipelines),
+            pipeline_programs,
+            draw_list: DrawList::canvas(),
+            shaders,
+            shader_watcher: None,
+            render_pass: assembly.render_pass,
+            frame_commands: std::mem::take(&mut assembly.frame_commands),
             image_available_semaphores: sync_bundle.image_available_semaphores,
             render_finished_semaphores: sync_bundle.render_finished_semaphores,
             in_flight_fences: sync_bundle.inflight_fences,
-            current_frame: 1,
+            allocator: assembly.allocator.take().unwrap(),
+            transfer: assembly.transfer.take().unwrap(),
+            canvas: assembly.canvas.take().unwrap(),
+            frame_pacer,
+            headless,
+            offscreen_allocation: assembly.offscreen_allocation.take(),
+            frame_rendered: false,
+            window_extent,
+            framebuffer_resized: false,
+        })
+    }
+
+    /// Tears down everything that depends on the swapchain extent or format
+    /// and builds it again for the current window size. After a failure the
+    /// next frame tries again before drawing anything.
+    fn recreate_swapchain(&mut self) -> Result<(), EngineError> {
+        self.framebuffer_resized = true;
+        unsafe {
+            self.device
+                .device_wait_idle()
+                .map_err(vk_error("wait for device idle"))?;
         }
+        self.cleanup_swapchain();
+        self.create_swapchain_objects()?;
+        self.framebuffer_resized = false;
+        Ok(())
     }
 
-    fn setup_debug_utils(
-        entry: &Entry,
+    /// Objects are stored as soon as they exist, so `cleanup_swapchain`
+    /// releases them even when a later one fails.
+    fn create_swapchain_objects(&mut self) -> Result<(), EngineError> {
+        let (swapchain_bundle, offscreen_allocation) = VulkanEngine::create_render_target(
+            &self.instance,
+            &self.device,
+            &mut self.allocator,
+            &self.transfer,
+            self.physical_device,
+            &self.surface_loader,
+            self.surface,
+            self.window_extent,
+        )?;
+        self.swapchain_loader = swapchain_bundle.swapchain_loader;
+        self.swapchain = swapchain_bundle.swapchain;
+        self.swapchain_format = swapchain_bundle.swapchain_format;
+        self.swapchain_images = swapchain_bundle.swapchain_images;
+        self.frame_pacer.reset_images(self.swapchain_images.len());
+        self.offscreen_allocation = offscreen_allocation;
+        self.frame_rendered = false;
+        self.swapchain_extent = swapchain_bundle.swapchain_extent;
+
+        self.render_pass = pipeline::create_render_pass(
+            &self.device,
+            self.swapchain_format,
+            VulkanEngine::final_layout(self.headless),
+        )?;
+        self.swapchain_imageviews = VulkanEngine::create_image_views(
+            &self.device,
+            self.swapchain_format,
+            &self.swapchain_images,
+        )?;
+        self.pipelines = VulkanEngine::create_pipelines(
+            &self.device,
+            self.swapchain_extent,
+            self.render_pass,
+            self.pipeline_layout,
+            &self.shaders,
+            &self.pipeline_programs,
+        )?;
+        self.swapchain_framebuffers = VulkanEngine::create_framebuffers(
+            &self.device,
+            self.render_pass,
+            &self.swapchain_imageviews,
+            self.swapchain_extent,
+        )?;
+        Ok(())
+    }
+
+    fn cleanup_swapchain(&mut self) {
+        unsafe {
+            for &framebuffer in self.swapchain_framebuffers.iter() {
+                self.device.destroy_framebuffer(framebuffer, None);
+            }
+            for &pipeline in self.pipelines.iter() {
+                self.device.destroy_pipeline(pipeline, None);
+            }
+            self.device.destroy_render_pass(self.render_pass, None);
+            for &imageview in self.swapchain_imageviews.iter() {
+                self.device.destroy_image_view(imageview, None);
+            }
+            if !self.headless {
+                self.swapchain_loader
+                    .destroy_swapchain(self.swapchain, None);
+            }
+        }
+        if let Some(allocation) = self.offscreen_allocation.take() {
+            self.allocator.destroy_image(&self.device, self.swapchain_images[0], allocation);
+        }
+        self.render_pass = vk::RenderPass::null();
+        self.swapchain = vk::SwapchainKHR::null();
+        self.swapchain_framebuffers.clear();
+        self.swapchain_imageviews.clear();
+        self.swapchain_images.clear();
+        self.pipelines.clear();
+    }
+
+    /// Starts watching a shader directory laid out like `engine/shaders`.
+    /// Pipelines are rebuilt between frames whenever its shaders change.
+    pub fn watch_shaders(&mut self, shader_root: &Path) {
+        self.shader_watcher = Some(ShaderWatcher::new(shader_root));
+    }
+
+    /// Rebuilds every pipeline from reloaded shaders, keeping the current
+    /// ones if any of the new shaders fail to compile or link.
+    fn reload_shaders(&mut self) -> Result<(), EngineError> {
+        let reloaded = match self.shader_watcher.as_mut().and_then(|watcher| watcher.poll()) {
+            Some(reloaded) => reloaded,
+            None => return Ok(()),
+        };
+        let shaders = match reloaded {
+            Ok(shaders) => shaders,
+            Err(error) => {
+                log::error!(target: SHADER_RELOAD_TARGET, "{}", error);
+                return Ok(());
+            }
+        };
+        let pipelines = match VulkanEngine::create_pipelines(
+            &self.device,
+            self.swapchain_extent,
+            self.render_pass,
+            self.pipeline_layout,
+            &shaders,
+            &self.pipeline_programs,
+        ) {
+            Ok(pipelines) => pipelines,
+            Err(error) => {
+                log::error!(target: SHADER_RELOAD_TARGET, "{}", error);
+                return Ok(());
+            }
+        };
+
+        unsafe {
+            if let Err(error) = self.device.device_wait_idle() {
+                for &pipeline in pipelines.iter() {
+                    self.device.destroy_pipeline(pipeline, None);
+                }
+                return Err(EngineError::Vulkan("wait for device idle", error));
+            }
+            for &pipeline in self.pipelines.iter() {
+                self.device.destroy_pipeline(pipeline, None);
+            }
+        }
+        self.shaders = shaders;
+        self.pipelines = pipelines;
+        log::info!(target: SHADER_RELOAD_TARGET, "Pipelines rebuilt");
+        Ok(())
+    }
+
+    /// Builds one pipeline per program name. Nothing is left behind when
+    /// one of them fails.
+    fn create_pipelines(
+        device: &Device,
+        extent: vk::Extent2D,
+        render_pass: vk::RenderPass,
+        pipeline_layout: vk::PipelineLayout,
+        shaders: &ShaderLibrary,
+        programs: &[String],
+    ) -> Result<Vec<vk::Pipeline>, EngineError> {
+        let mut pipelines = vec![];
+        for program in programs.iter() {
+            let pipeline = shaders
+                .program(program)
+                .map_err(EngineError::from)
+                .and_then(|program| pipeline::create_graphics_pipeline(
+                    device,
+                    extent,
+                    render_pass,
+                    pipeline_layout,
+                    program,
+                ));
+            match pipeline {
+                Ok(pipeline) => pipelines.push(pipeline),
+                Err(error) => {
+                    for &pipeline in pipelines.iter() {
+                        unsafe {
+                            device.destroy_pipeline(pipeline, None);
+                        }
+                    }
+                    return Err(error);
+                }
+            }
+        }
+        Ok(pipelines)
+    }
+
+    /// Builds a pipeline from a program of the shader library for use in
+    /// draw lists. It is rebuilt along with the others on shader reloads.
+    pub fn create_pipeline(&mut self, program: &str) -> Result<PipelineId, EngineError> {
+        let pipeline = pipeline::create_graphics_pipeline(
+            &self.device,
+            self.swapchain_extent,
+            self.render_pass,
+            self.pipeline_layout,
+            self.shaders.program(program)?,
+        )?;
+        self.pipelines.push(pipeline);
+        self.pipeline_programs.push(program.to_string());
+        Ok(PipelineId(self.pipelines.len() - 1))
+    }
+
+    /// The commands recorded into each frame. Starts out drawing the canvas.
+    pub fn draw_list_mut(&mut self) -> &mut DrawList {
+        &mut self.draw_list
+    }
+
+    pub fn set_draw_list(&mut self, draw_list: DrawList) {
+        self.draw_list = draw_list;
+    }
+
+    /// Creates the swapchain, or the offscreen image standing in for it when
+    /// there is no surface. The allocation is only set for the offscreen image.
+    #[allow(clippy::too_many_arguments)]
+    fn create_render_target(
         instance: &Instance,
-        validation_layers_enabled: bool
-    ) -> (ash::extensions::ext::DebugUtils, vk::DebugUtilsMessengerEXT) {
-        let debug_utils_loader = ash::extensions::ext::DebugUtils::new(entry, instance);
-    
-        if !validation_layers_enabled {
-            (debug_utils_loader, ash::vk::DebugUtilsMessengerEXT::null())
+        device: &Device,
+        allocator: &mut Allocator,
+        transfer: &Transfer,
+        physical_device: vk::PhysicalDevice,
+        surface_loader: &Surface,
+        surface: vk::SurfaceKHR,
+        extent: vk::Extent2D,
+    ) -> Result<(SwapchainBundle, Option<Allocation>), EngineError> {
+        if surface != vk::SurfaceKHR::null() {
+            let swapchain_bundle = VulkanEngine::create_swapchain(
+                instance,
+                device,
+                physical_device,
+                surface_loader,
+                surface,
+                extent,
+            )?;
+            return Ok((swapchain_bundle, None));
+        }
+
+        let offscreen_image = offscreen::create_offscreen_image(
+            device,
+            allocator,
+            transfer,
+            extent,
+        )?;
+        let swapchain_bundle = SwapchainBundle {
+            swapchain_loader: Swapchain::new(instance, device),
+            swapchain: vk::SwapchainKHR::null(),
+            swapchain_format: offscreen::OFFSCREEN_FORMAT,
+            swapchain_images: vec![offscreen_image.image],
+            swapchain_extent: extent,
+        };
+        Ok((swapchain_bundle, Some(offscreen_image.allocation)))
+    }
+
+    fn final_layout(headless: bool) -> vk::ImageLayout {
+        if headless {
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL
         } else {
-            let messenger_ci = populate_debug_messenger_create_info();
-    
-            let utils_messenger = unsafe {
+            vk::ImageLayout::PRESENT_SRC_KHR
+        }
+    }
+
+    /// Reads the last frame rendered by a headless engine back to host
+    /// memory as tightly packed RGBA8 rows. Fails until a frame has been
+    /// drawn at the current size.
+    pub fn read_rgba8(&mut self) -> Result<Vec<u8>, EngineError> {
+        if !self.headless {
+            return Err(EngineError::NotHeadless);
+        }
+        if !self.frame_rendered {
+            return Err(EngineError::NoFrameRendered);
+        }
+        unsafe {
+            self.device
+                .device_wait_idle()
+                .map_err(vk_error("wait for device idle"))?;
+        }
+        let region = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: self.swapchain_extent,
+        };
+        let ticket = self.transfer.download(
+            &self.device,
+            &mut self.allocator,
+            self.swapchain_images[0],
+            0,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            region,
+        )?;
+        Ok(self.transfer.flush(&self.device, &mut self.allocator)?.take(ticket))
+    }
+
+    /// Submits queued canvas writes before the frame that shows them.
+    fn flush_transfers(&mut self) -> Result<(), EngineError> {
+        if !self.transfer.has_pending() {
+            return Ok(());
+        }
+        // Barriers on a dedicated transfer queue cannot wait for frames
+        // still sampling the canvas on the graphics queue.
+        if self.transfer.is_dedicated() {
+            unsafe {
+                self.device
+                    .wait_for_fences(&self.in_flight_fences, true, u64::MAX)
+                    .map_err(vk_error("wait for frame fences"))?;
+            }
+        }
+        self.transfer.flush(&self.device, &mut self.allocator)?;
+        Ok(())
+    }
+
+    /// Every physical device considered at creation, including the reason
+    /// each rejected one was unsuitable.
+    pub fn device_candidates(&self) -> &[DeviceCandidate] {
+        &self.device_candidates
+    }
+
+    /// Lists the physical devices a headless engine could run on, without
+    /// creating one.
+    pub fn list_devices(app_name: &str) -> Result<Vec<DeviceCandidate>, EngineError> {
+        let entry = Entry::new().map_err(|error| EngineError::MissingLoader(error.to_string()))?;
+        let instance = VulkanEngine::create_instance(
+            app_name,
+            &entry,
+            None,
+            &headless_extension_names(),
+        )?;
+        let surface_loader = Surface::new(&entry, &instance);
+        let candidates = device_selection::evaluate_devices(
+            &instance,
+            &surface_loader,
+            vk::SurfaceKHR::null(),
+        );
+        unsafe {
+            instance.destroy_instance(None);
+        }
+        let candidates = candidates.map_err(vk_error("enumerate physical devices"))?;
+        Ok(candidates
+            .into_iter()
+            .map(|(_, candidate)| candidate)
+            .collect())
+    }
+
+    /// The validation the engine was created with, if any.
+    pub fn validation(&self) -> Option<&ValidationConfig> {
+        self.validation.as_deref()
+    }
+
+    pub fn extent(&self) -> (u32, u32) {
+        (self.swapchain_extent.width, self.swapchain_extent.height)
+    }
+
+    /// Device memory handed out to buffers and images so far.
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.allocator.stats()
+    }
+
+    /// Timing of the most recent frame. GPU time lags a few frames behind,
+    /// as it is only read once the frame's fence has signalled.
+    pub fn frame_stats(&self) -> FrameStats {
+        self.frame_pacer.stats()
+    }
+
+    /// Limits how many frames per second are drawn; `None` lifts the limit.
+    pub fn set_frame_rate_cap(&mut self, frames_per_second: Option<f64>) {
+        self.frame_pacer.set_frame_rate_cap(frames_per_second);
+    }
+
+    pub fn frames_in_flight(&self) -> usize {
+        self.frame_pacer.frames_in_flight()
+    }
+
+    /// Changes how many frames the CPU may record ahead of the GPU. More
+    /// frames smooth out uneven frame times at the cost of latency.
+    pub fn set_frames_in_flight(&mut self, frames_in_flight: usize) -> Result<(), EngineError> {
+        if frames_in_flight == 0 {
+            return Err(EngineError::NoFramesInFlight);
+        }
+        if frames_in_flight == self.frame_pacer.frames_in_flight() {
+            return Ok(());
+        }
+        unsafe {
+            self.device
+                .device_wait_idle()
+                .map_err(vk_error("wait for device idle"))?;
+        }
+        // The new objects are all created before the old ones go, so a
+        // failure leaves the engine running with the frames it had.
+        let mut frame_pacer = VulkanEngine::create_frame_pacer(
+            &self.instance,
+            &self.device,
+            self.physical_device,
+            self.device_index,
+            frames_in_flight,
+        )?;
+        let frame_commands = match VulkanEngine::create_frame_commands(
+            &self.device,
+            self.device_index,
+            frames_in_flight,
+        ) {
+            Ok(frame_commands) => frame_commands,
+            Err(error) => {
+                frame_pacer.destroy(&self.device);
+                return Err(error);
+            },
+        };
+        let sync_bundle = match VulkanEngine::create_sync_objects(&self.device, frames_in_flight) {
+            Ok(sync_bundle) => sync_bundle,
+            Err(error) => {
+                frame_pacer.destroy(&self.device);
+                for commands in frame_commands {
+                    unsafe { self.device.destroy_command_pool(commands.command_pool, None) };
+                }
+                return Err(error);
+            },
+        };
+        frame_pacer.set_frame_rate_cap(self.frame_pacer.frame_rate_cap());
+        frame_pacer.reset_images(self.swapchain_images.len());
+        self.destroy_frame_objects();
+
+        self.frame_pacer = frame_pacer;
+        self.frame_commands = frame_commands;
+        self.image_available_semaphores = sync_bundle.image_available_semaphores;
+        self.render_finished_semaphores = sync_bundle.render_finished_semaphores;
+        self.in_flight_fences = sync_bundle.inflight_fences;
+        Ok(())
+    }
+
+    /// Size of the document canvas; the size of the first frame until it is
+    /// resized.
+    pub fn canvas_extent(&self) -> (u32, u32) {
+        let extent = self.canvas.extent();
+        (extent.width, extent.height)
+    }
+
+    /// Sets the layers the canvas composites, from bottom to top. Returns
+    /// the layers whose pixels the canvas does not hold yet; those have to
+    /// be written in full with `write_canvas_region`. A new engine holds a
+    /// single opaque white layer with id 0.
+    pub fn set_canvas_layers(&mut self, layers: &[CanvasLayer]) -> Result<Vec<CanvasLayerId>, EngineError> {
+        // The canvas image may be replaced, so queued writes go out first
+        // and no frame may still sample it.
+        self.transfer.flush(&self.device, &mut self.allocator)?;
+        unsafe {
+            self.device
+                .device_wait_idle()
+                .map_err(vk_error("wait for device idle"))?;
+        }
+        self.canvas.set_layers(&self.device, &mut self.allocator, &mut self.transfer, layers)
+    }
+
+    /// Changes the size of the document canvas. Afterwards the canvas holds
+    /// no layers until `set_canvas_layers` is called.
+    pub fn resize_canvas(&mut self, width: u32, height: u32) -> Result<(), EngineError> {
+        if width == 0 || height == 0 {
+            return Err(EngineError::EmptyCanvas { width, height });
+        }
+        self.transfer.flush(&self.device, &mut self.allocator)?;
+        unsafe {
+            self.device
+                .device_wait_idle()
+                .map_err(vk_error("wait for device idle"))?;
+        }
+        self.canvas.resize(
+            &self.device,
+            &mut self.allocator,
+            &mut self.transfer,
+            vk::Extent2D { width, height },
+        )
+    }
+
+    /// Reads a layer's pixels back from the canvas as tightly packed RGBA8
+    /// rows, including any writes still queued. Works with and without a
+    /// window.
+    pub fn read_canvas_layer(&mut self, layer: CanvasLayerId) -> Result<Vec<u8>, EngineError> {
+        // Queued writes are recorded ahead of the copy in the same batch,
+        // but frames still sampling the canvas have to finish first.
+        unsafe {
+            self.device
+                .device_wait_idle()
+                .map_err(vk_error("wait for device idle"))?;
+        }
+        let ticket = self.canvas.read_layer(
+            &self.device,
+            &mut self.allocator,
+            &mut self.transfer,
+            layer,
+        )?;
+        Ok(self.transfer.flush(&self.device, &mut self.allocator)?.take(ticket))
+    }
+
+    /// Replaces a layer's pixels in the given rectangle with tightly packed
+    /// RGBA8 rows. Writes are batched and uploaded before the next frame.
+    pub fn write_canvas_region(
+        &mut self,
+        layer: CanvasLayerId,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+    ) -> Result<(), EngineError> {
+        let region = vk::Rect2D {
+            offset: vk::Offset2D { x: x as i32, y: y as i32 },
+            extent: vk::Extent2D { width, height },
+        };
+        self.canvas.write_region(
+            &self.device,
+            &mut self.allocator,
+            &mut self.transfer,
+            layer,
+            region,
+            rgba,
+        )
+    }
+
+    fn draw_offscreen_frame(&mut self) -> Result<(), EngineError> {
+        let current_fence = [self.in_flight_fences[self.frame_pacer.current_frame()]];
+
+        // There is only one offscreen image, so every frame in flight has to
+        // be done with it.
+        self.frame_pacer.begin_frame();
+        self.frame_pacer.wait_for_frame(&self.device, &self.in_flight_fences)?;
+        let command_buffers = [self.record_frame(self.swapchain_framebuffers[0])?];
+        let submit_infos = [vk::SubmitInfo::builder()
+            .command_buffers(&command_buffers)
+            .build()];
+
+        unsafe {
+            self.device
+                .reset_fences(&current_fence)
+                .map_err(vk_error("reset frame fence"))?;
+            self.device
+                .queue_submit(self.graphics_queue, &submit_infos, current_fence[0])
+                .map_err(vk_error("submit frame"))?;
+        }
+
+        self.frame_pacer.advance();
+        self.frame_rendered = true;
+        Ok(())
+    }
+
+    fn render_frame(&mut self) -> Result<(), EngineError> {
+        if self.is_minimized() {
+            return Ok(());
+        }
+        self.reload_shaders()?;
+        self.flush_transfers()?;
+        if self.framebuffer_resized {
+            self.recreate_swapchain()?;
+        }
+        if self.headless {
+            return self.draw_offscreen_frame();
+        }
+
+        let current_frame = self.frame_pacer.current_frame();
+        let wait_fences = [self.in_flight_fences[current_frame]];
+
+        self.frame_pacer.begin_frame();
+        self.frame_pacer.wait_for_frame(&self.device, &wait_fences)?;
+        let acquire_result = unsafe {
+            self.swapchain_loader
+                .acquire_next_image(
+                    self.swapchain,
+                    u64::MAX,
+                    self.image_available_semaphores[current_frame],
+                    vk::Fence::null(),
+                )
+        };
+        let image_index = match acquire_result {
+            Ok((image_index, _is_sub_optimal)) => image_index,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => return self.recreate_swapchain(),
+            Err(error) => return Err(EngineError::Vulkan("acquire next image", error)),
+        };
+        self.frame_pacer.wait_for_image(&self.device, image_index as usize, wait_fences[0])?;
+
+        let command_buffer = self.record_frame(self.swapchain_framebuffers[image_index as usize])?;
+        let wait_semaphores = [self.image_available_semaphores[current_frame]];
+        let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
+        let signal_semaphores = [self.render_finished_semaphores[current_frame]];
+
+        let submit_infos = [vk::SubmitInfo {
+            s_type: vk::StructureType::SUBMIT_INFO,
+            p_next: ptr::null(),
+            wait_semaphore_count: wait_semaphores.len() as u32,
+            p_wait_semaphores: wait_semaphores.as_ptr(),
+            p_wait_dst_stage_mask: wait_stages.as_ptr(),
+            command_buffer_count: 1,
+            p_command_buffers: &command_buffer,
+            signal_semaphore_count: signal_semaphores.len() as u32,
+            p_signal_semaphores: signal_semaphores.as_ptr(),
+        }];
+
+        unsafe {
+            self.device
+                .reset_fences(&wait_fences)
+                .map_err(vk_error("reset frame fence"))?;
+
+            self.device
+                .queue_submit(
+                    self.graphics_queue,
+                    &submit_infos,
+                    wait_fences[0],
+                )
+                .map_err(vk_error("submit frame"))?;
+        }
+
+        let swapchains = [self.swapchain];
+
+        let present_info = vk::PresentInfoKHR {
+            s_type: vk::StructureType::PRESENT_INFO_KHR,
+            p_next: ptr::null(),
+            wait_semaphore_count: 1,
+            p_wait_semaphores: signal_semaphores.as_ptr(),
+            swapchain_count: 1,
+            p_swapchains: swapchains.as_ptr(),
+            p_image_indices: &image_index,
+            p_results: ptr::null_mut(),
+        };
+
+        let present_result = unsafe {
+            self.swapchain_loader
+                .queue_present(self.present_queue, &present_info)
+        };
+        let swapchain_outdated = match present_result {
+            Ok(is_sub_optimal) => is_sub_optimal,
+            Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => true,
+            Err(error) => return Err(EngineError::Vulkan("present frame", error)),
+        };
+
+        self.frame_pacer.advance();
+
+        if swapchain_outdated || self.framebuffer_resized {
+            self.recreate_swapchain()?;
+        }
+        Ok(())
+    }
+
+    fn is_minimized(&self) -> bool {
+        self.window_extent.width == 0 || self.window_extent.height == 0
+    }
+
+    fn setup_debug_utils(
+        debug_utils_loader: &ash::extensions::ext::DebugUtils,
+        validation: Option<&ValidationConfig>,
+    ) -> Result<vk::DebugUtilsMessengerEXT, EngineError> {
+        if let Some(validation) = validation {
+            let messenger_ci = populate_debug_messenger_create_info(validation);
+
+            unsafe {
                 debug_utils_loader
                     .create_debug_utils_messenger(&messenger_ci, None)
-                    .expect("Debug Utils Callback")
-            };
-    
-            (debug_utils_loader, utils_messenger)
+                    .map_err(vk_error("create debug utils messenger"))
+            }
+        } else {
+            Ok(ash::vk::DebugUtilsMessengerEXT::null())
         }
     }
 
-    fn create_instance(app_name: &str, entry: &Entry, validation_layers_enabled: bool) -> Instance {
-        if validation_layers_enabled && !validation::check_validation_layer_support(&entry) {
-            panic!("Validation layers requested, but not available!")
+    fn destroy_debug_utils(
+        debug_utils_loader: &ash::extensions::ext::DebugUtils,
+        debug_messenger: vk::DebugUtilsMessengerEXT,
+    ) {
+        if debug_messenger != vk::DebugUtilsMessengerEXT::null() {
+            unsafe {
+                debug_utils_loader.destroy_debug_utils_messenger(debug_messenger, None);
+            }
+        }
+    }
+
+    fn create_instance(
+        app_name: &str,
+        entry: &Entry,
+        validation: Option<&ValidationConfig>,
+        enabled_extension_names: &[*const i8],
+    ) -> Result<Instance, EngineError> {
+        if let Some(validation) = validation {
+            if let Some(layer) = validation::missing_validation_layer(entry, validation) {
+                return Err(EngineError::MissingValidationLayer(layer));
+            }
+        }
+        let available_extensions = entry
+            .enumerate_instance_extension_properties()
+            .map_err(vk_error("enumerate instance extensions"))?;
+        for &extension_name in enabled_extension_names.iter() {
+            let extension_name = unsafe { CStr::from_ptr(extension_name) };
+            let available = available_extensions.iter().any(|extension| {
+                unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) == extension_name }
+            });
+            if !available {
+                return Err(EngineError::MissingExtension(
+                    extension_name.to_string_lossy().into_owned()
+                ));
+            }
         }
         let app_name = CString::new(app_name).unwrap();
         let engine_name = CString::new(ENGINE_NAME).unwrap();
@@ -174,221 +1033,216 @@ impl VulkanEngine {
             .engine_version(ENGINE_VERSION)
             .api_version(API_VERSION);
 
-        let enabled_extension_names = required_extension_names();
-        let validation_layer_names = validation::get_validation_layers();
-        let mut debug_utils_create_info = populate_debug_messenger_create_info();
+        let validation_layer_names = LayerNames::new(validation);
+        let validation_features = validation.map(ValidationConfig::enabled_features).unwrap_or_default();
+        let mut extension_names = enabled_extension_names.to_vec();
+        if !validation_features.is_empty() {
+            let extension_name = vk::ExtValidationFeaturesFn::name();
+            if !validation::layers_provide_extension(entry, &validation_layer_names, extension_name) {
+                return Err(EngineError::MissingExtension(extension_name.to_string_lossy().into_owned()));
+            }
+            extension_names.push(extension_name.as_ptr());
+        }
+        let mut validation_features_info = vk::ValidationFeaturesEXT::builder()
+            .enabled_validation_features(&validation_features);
 
         let mut create_info = vk::InstanceCreateInfo::builder()
             .application_info(&app_info)
-            .enabled_extension_names(&enabled_extension_names);
-        
-        if validation_layers_enabled {
+            .enabled_extension_names(&extension_names);
+
+        // Chained to the instance as well, so creating and destroying it is
+        // reported too.
+        let mut debug_utils_create_info;
+        if let Some(validation) = validation {
+            debug_utils_create_info = populate_debug_messenger_create_info(validation);
             create_info = create_info
                 .push_next(&mut debug_utils_create_info)
-                .enabled_layer_names(&validation_layer_names);
+                .enabled_layer_names(validation_layer_names.as_ptrs());
+            if !validation_features.is_empty() {
+                create_info = create_info.push_next(&mut validation_features_info);
+            }
         }
-    
-        let instance: Instance = unsafe {
+
+        unsafe {
             entry
                 .create_instance(&create_info, None)
-                .expect("Failed to create instance!")
-        };
-        instance
-    }
-
-    fn pick_physical_device(instance: &Instance, surface_loader: &Surface, surface: vk::SurfaceKHR) -> (u32, vk::PhysicalDevice) {
-        let devices = unsafe { 
-            instance
-                .enumerate_physical_devices()
-                .expect("Error while enumerating physical devices!") };
-        if devices.len() <= 0 {
-            panic!("No suitable physical device found!")
-        }
-        
-        let mut integrated_device = None;
-        let physical_device_with_index = devices.iter()
-            .map(|device|{
-                unsafe {
-                    (instance.get_physical_device_properties(*device),
-                    device)
-                }
-            })
-            .map(|(device_properties, device)| {
-                
-                unsafe { 
-                    instance.get_physical_device_queue_family_properties(*device) 
-                }
-                    .iter()
-                    .enumerate()
-                    .filter_map(|(index, ref info)| {
-                        let supports_graphic_and_surface = info
-                            .queue_flags
-                            .contains(vk::QueueFlags::GRAPHICS)
-                            && unsafe {
-                                    surface_loader.get_physical_device_surface_support(
-                                    *device,
-                                    index as u32,
-                                    surface,
-                                )
-                            };
-                        match supports_graphic_and_surface {
-                            true => match device_properties.device_type {
-                                vk::PhysicalDeviceType::DISCRETE_GPU => Some((index, *device)),
-                                vk::PhysicalDeviceType::INTEGRATED_GPU => {
-                                    integrated_device = Some((index, *device));
-                                    None
-                                },
-                                _ => None
-                            },
-                            _ => None,
-                        }
-                    })
-                    .nth(0)
-            })
-            .filter_map(|v| v)
-            .nth(0);
-
-        match physical_device_with_index {
-            Some((index, device)) => (index as u32, device),
-            None => match integrated_device {
-                Some((index, device)) => (index as u32, device),
-                None => panic!("No suitable device found"),
-            }
+                .map_err(|error| match error {
+                    ash::InstanceError::VkError(result) => EngineError::Vulkan("create instance", result),
+                    ash::InstanceError::LoadError(names) => EngineError::MissingLoader(names.join(", ")),
+                })
         }
     }
 
-    fn create_device(instance: &Instance, validation_layers: bool, surface_loader: &Surface, surface: vk::SurfaceKHR) 
-        -> DeviceBundle {
-        
+    fn create_device(
+        instance: &Instance,
+        validation: Option<&ValidationConfig>,
+        surface_loader: &Surface,
+        surface: vk::SurfaceKHR,
+        device_override: Option<DeviceOverride>,
+    ) -> Result<DeviceBundle, EngineError> {
+
         unsafe {
-            let (queue_index, physical_device) = VulkanEngine::pick_physical_device(instance, surface_loader, surface);
+            let candidates = device_selection::evaluate_devices(instance, surface_loader, surface)
+                .map_err(vk_error("enumerate physical devices"))?;
+            let device_override = device_override.or_else(DeviceOverride::from_env);
+            let (queue_index, physical_device) = device_selection::select_device(
+                &candidates,
+                device_override.as_ref(),
+            ).map_err(EngineError::NoSuitableDevice)?;
             let queue_priorities = [1.0];
+            let transfer_family = transfer::dedicated_transfer_family(instance, physical_device);
             let mut physical_device_features = vk::PhysicalDeviceFeatures2::default();
             instance
                 .fp_v1_1()
                 .get_physical_device_features2(physical_device, &mut physical_device_features);
-            let queue_infos = [vk::DeviceQueueCreateInfo::builder()
+            let mut queue_infos = vec![vk::DeviceQueueCreateInfo::builder()
                 .queue_family_index(queue_index)
                 .queue_priorities(&queue_priorities)
                 .build()];
+            if let Some(transfer_family) = transfer_family {
+                queue_infos.push(vk::DeviceQueueCreateInfo::builder()
+                    .queue_family_index(transfer_family)
+                    .queue_priorities(&queue_priorities)
+                    .build());
+            }
     
-            let device_extensions = vec![
-                Swapchain::name().as_ptr(),
-            ];
+            let device_extensions = if surface == vk::SurfaceKHR::null() {
+                vec![]
+            } else {
+                vec![Swapchain::name().as_ptr()]
+            };
     
             let mut device_create_info = vk::DeviceCreateInfo::builder()
                 .queue_create_infos(&queue_infos)
                 .enabled_extension_names(&device_extensions)
                 .enabled_features(&physical_device_features.features);
     
-            let validation_layer_names = validation::get_validation_layers();
-            if validation_layers {
-                device_create_info = device_create_info
-                    .enabled_layer_names(&validation_layer_names)
-            }
+            // Device layers are deprecated, but older loaders still expect
+            // them to match the instance's.
+            let validation_layer_names = LayerNames::new(validation);
+            device_create_info = device_create_info
+                .enabled_layer_names(validation_layer_names.as_ptrs());
             let logical_device = instance
                 .create_device(physical_device, &device_create_info, None)
-                .unwrap();
-            let present_queue = logical_device.get_device_queue(queue_index as u32, 0);
-
-            //
-            // let graphics_queue =
-            //     unsafe { device_bundle.  device.get_device_queue(family_indices.graphics_family.unwrap(), 0) };
-            // let present_queue =
-            //     unsafe { device.get_device_queue(family_indices.present_family.unwrap(), 0) };
+                .map_err(vk_error("create logical device"))?;
+            let present_queue = logical_device.get_device_queue(queue_index, 0);
+            let transfer_queue = transfer_family
+                .map(|family| (family, logical_device.get_device_queue(family, 0)));
 
-            DeviceBundle {
-                physical_device: physical_device,
+            Ok(DeviceBundle {
+                physical_device,
                 physical_device_index: queue_index,
-                logical_device: logical_device,
-                present_queue: present_queue,
+                logical_device,
+                present_queue,
                 queue: present_queue,
-            }
+                transfer_queue,
+                candidates: candidates
+                    .into_iter()
+                    .map(|(_, candidate)| candidate)
+                    .collect(),
+            })
         }
     }
 
     fn create_surface(
         entry: &Entry, 
         instance: &Instance, 
-        window: &gui::MainWindow) -> SurfaceBundle {
+        window: &gui::MainWindow) -> Result<SurfaceBundle, EngineError> {
 
         let surface = unsafe { platforms::create_surface(entry, instance, &window.window) }
-            .expect("Failed creating surface!");
+            .map_err(EngineError::Surface)?;
         let surface_loader = Surface::new(entry, instance);
-        
-        SurfaceBundle {
-            surface_loader: surface_loader,
-            surface: surface,
+
+        Ok(SurfaceBundle {
+            surface_loader,
+            surface,
             width: window.window_width,
             height: window.window_height,
-        }
+        })
     }
 
-    fn create_swapchain(device_bundle: &DeviceBundle, 
-        instance: &Instance, 
-        surface_bundle: &SurfaceBundle) -> SwapchainBundle {
-        
-        unsafe {
-            let present_modes = surface_bundle.surface_loader.get_physical_device_surface_present_modes(device_bundle.physical_device, surface_bundle.surface).unwrap();
-            let surface_formats = surface_bundle.surface_loader
-                .get_physical_device_surface_formats(device_bundle.physical_device, surface_bundle.surface)
-                .expect("Failed to query for surface formats.");
+    fn create_swapchain(
+        instance: &Instance,
+        device: &Device,
+        physical_device: vk::PhysicalDevice,
+        surface_loader: &Surface,
+        surface: vk::SurfaceKHR,
+        window_extent: vk::Extent2D,
+    ) -> Result<SwapchainBundle, EngineError> {
 
-            let mut surface_format = surface_formats.first().unwrap().clone();
+        unsafe {
+            let present_modes = surface_loader
+                .get_physical_device_surface_present_modes(physical_device, surface)
+                .map_err(EngineError::Surface)?;
+            let surface_formats = surface_loader
+                .get_physical_device_surface_formats(physical_device, surface)
+                .map_err(EngineError::Surface)?;
+
+            let mut surface_format = *surface_formats
+                .first()
+                .ok_or(EngineError::Surface(vk::Result::ERROR_FORMAT_NOT_SUPPORTED))?;
             for sf in surface_formats.iter() {
                 if sf.format == vk::Format::B8G8R8A8_SRGB
                     && sf.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR {
-                        surface_format = sf.clone();
+                        surface_format = *sf;
                 }
             };
-            let surface_capabilities = surface_bundle.surface_loader
-                .get_physical_device_surface_capabilities(device_bundle.physical_device, surface_bundle.surface)
-                .unwrap();
+            let surface_capabilities = surface_loader
+                .get_physical_device_surface_capabilities(physical_device, surface)
+                .map_err(EngineError::Surface)?;
             let mut desired_image_count = surface_capabilities.min_image_count + 1;
             if surface_capabilities.max_image_count > 0
                     && desired_image_count > surface_capabilities.max_image_count
             {
                 desired_image_count = surface_capabilities.max_image_count;
             }
-    
+
             let present_mode = present_modes
                 .iter()
                 .cloned()
                 .find(|&mode| mode == vk::PresentModeKHR::MAILBOX)
                 .unwrap_or(vk::PresentModeKHR::FIFO);
-            
-            let swapchain_loader = Swapchain::new(instance, &device_bundle.logical_device);
+
+            // A current extent of u32::MAX means the surface size is decided
+            // by the swapchain, so fall back to the window size within limits.
+            let extent = match surface_capabilities.current_extent.width {
+                u32::MAX => vk::Extent2D {
+                    width: window_extent.width.max(surface_capabilities.min_image_extent.width)
+                        .min(surface_capabilities.max_image_extent.width),
+                    height: window_extent.height.max(surface_capabilities.min_image_extent.height)
+                        .min(surface_capabilities.max_image_extent.height),
+                },
+                _ => surface_capabilities.current_extent,
+            };
+
+            let swapchain_loader = Swapchain::new(instance, device);
             let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
-                .surface(surface_bundle.surface)
+                .surface(surface)
                 .min_image_count(desired_image_count)
                 .image_color_space(surface_format.color_space)
                 .image_format(surface_format.format)
+                .image_extent(extent)
                 .image_usage(vk::ImageUsageFlags::COLOR_ATTACHMENT)
                 .image_sharing_mode(vk::SharingMode::EXCLUSIVE)
+                .pre_transform(surface_capabilities.current_transform)
+                .composite_alpha(vk::CompositeAlphaFlagsKHR::OPAQUE)
                 .present_mode(present_mode)
                 .clipped(true)
                 .image_array_layers(1);
             let swapchain = swapchain_loader
                 .create_swapchain(&swapchain_create_info, None)
-                .unwrap();
+                .map_err(vk_error("create swapchain"))?;
             let swapchain_images = swapchain_loader
                 .get_swapchain_images(swapchain)
-                .expect("Failed to fetch swapchain images.");
-            let extent = match surface_capabilities.current_extent.width {
-                u32::MAX => vk::Extent2D {
-                    width: surface_bundle.width,
-                    height: surface_bundle.height,
-                },
-                _ => surface_capabilities.current_extent,
-            };
+                .map_err(vk_error("fetch swapchain images"))?;
 
-            SwapchainBundle {
-                swapchain_loader: swapchain_loader,
-                swapchain: swapchain,
+            Ok(SwapchainBundle {
+                swapchain_loader,
+                swapchain,
                 swapchain_format: surface_format.format,
-                swapchain_images: swapchain_images,
+                swapchain_images,
                 swapchain_extent: extent,
-            }
+            })
         }
     }
 
@@ -397,7 +1251,7 @@ impl VulkanEngine {
         swapchain_format: vk::Format,
         image: vk::Image,
         view_type: vk::ImageViewType
-    ) -> vk::ImageView {
+    ) -> Result<vk::ImageView, EngineError> {
         let create_info = vk::ImageViewCreateInfo::builder()
             .view_type(view_type)
             .format(swapchain_format)
@@ -423,15 +1277,15 @@ impl VulkanEngine {
         unsafe {
             device
                 .create_image_view(&create_info, None)
-                .unwrap()
+                .map_err(vk_error("create image view"))
         }
     }
 
     fn create_image_views(
         device: &Device,
         surface_format: vk::Format,
-        swapchain_images: &Vec<vk::Image>,
-    ) -> Vec<vk::ImageView>{
+        swapchain_images: &[vk::Image],
+    ) -> Result<Vec<vk::ImageView>, EngineError> {
         let mut swapchain_image_views = vec![];
         for &image in swapchain_images.iter() {
             let image_view = VulkanEngine::create_image_view(
@@ -439,18 +1293,18 @@ impl VulkanEngine {
                 surface_format,
                 image,
                 vk::ImageViewType::TYPE_2D,
-            );
+            )?;
             swapchain_image_views.push(image_view)
         }
-        swapchain_image_views
+        Ok(swapchain_image_views)
     }
 
     fn create_framebuffers(
         device: &Device,
         render_pass: vk::RenderPass,
-        swapchain_imageviews: &Vec<vk::ImageView>,
+        swapchain_imageviews: &[vk::ImageView],
         swapchain_extent: vk::Extent2D,
-    ) -> Vec<vk::Framebuffer> {
+    ) -> Result<Vec<vk::Framebuffer>, EngineError> {
         let mut swapchain_framebuffers = vec![];
 
         for &imageview in swapchain_imageviews.iter() {
@@ -463,237 +1317,413 @@ impl VulkanEngine {
 
             let framebuffer = unsafe {
                 device.create_framebuffer(&framebuffer_create_info, None)
-                    .expect("Failed to create framebuffer!")
+                    .map_err(vk_error("create framebuffer"))?
             };
             swapchain_framebuffers.push(framebuffer);
         }
 
-        swapchain_framebuffers
+        Ok(swapchain_framebuffers)
     }
 
-    fn create_command_pool(device: &Device, device_index: u32) -> vk::CommandPool {
+    fn create_command_pool(device: &Device, device_index: u32) -> Result<vk::CommandPool, EngineError> {
         let command_pool_create_info = vk::CommandPoolCreateInfo::builder()
             .queue_family_index(device_index)
-            .flags(vk::CommandPoolCreateFlags::empty());
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
 
         unsafe {
             device
                 .create_command_pool(&command_pool_create_info, None)
-                .unwrap()
+                .map_err(vk_error("create command pool"))
         }
     }
 
-    fn create_command_buffers(
+    /// One command pool with a single command buffer per frame in flight.
+    /// A frame's pool is reset once its fence shows the GPU is done with it.
+    fn create_frame_commands(
         device: &Device,
         device_index: u32,
-        pipeline: vk::Pipeline,
-        swapchain_framebuffers: &Vec<vk::Framebuffer>,
-        render_pass: vk::RenderPass,
-        swapchain_extent: vk::Extent2D,
-    ) -> CommandBundle {
-        let command_pool = VulkanEngine::create_command_pool(device, device_index);
-        let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
-            .command_pool(command_pool)
-            .command_buffer_count(swapchain_framebuffers.len() as u32);
-        let command_buffers = unsafe {
-            device
-                .allocate_command_buffers(&command_buffer_allocate_info)
-                .expect("Failed to create command buffer!")
-        };
+        frames_in_flight: usize,
+    ) -> Result<Vec<FrameCommands>, EngineError> {
+        let mut frame_commands = vec![];
+        for _ in 0..frames_in_flight {
+            let created = VulkanEngine::create_command_pool(device, device_index).and_then(|command_pool| {
+                let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
+                    .command_pool(command_pool)
+                    .level(vk::CommandBufferLevel::PRIMARY)
+                    .command_buffer_count(1);
+                let command_buffer = unsafe { device.allocate_command_buffers(&command_buffer_allocate_info) };
+                match command_buffer {
+                    Ok(command_buffers) => Ok(FrameCommands { command_pool, command_buffer: command_buffers[0] }),
+                    Err(result) => {
+                        unsafe { device.destroy_command_pool(command_pool, None) };
+                        Err(EngineError::Vulkan("allocate command buffers", result))
+                    },
+                }
+            });
+            match created {
+                Ok(commands) => frame_commands.push(commands),
+                Err(error) => {
+                    // Destroying a pool frees its command buffers as well.
+                    for commands in frame_commands {
+                        unsafe { device.destroy_command_pool(commands.command_pool, None) };
+                    }
+                    return Err(error);
+                },
+            }
+        }
+        Ok(frame_commands)
+    }
 
-        for (i, &cb) in command_buffers.iter().enumerate() {
-            let cb_begin_info = vk::CommandBufferBeginInfo::builder();
-            unsafe {
-                device.begin_command_buffer(cb, &cb_begin_info)
-                    .expect("Failed to start command buffer!");
+    /// Records the draw list into the current frame's command buffer. The
+    /// frame's fence has to be signalled already.
+    fn record_frame(&mut self, framebuffer: vk::Framebuffer) -> Result<vk::CommandBuffer, EngineError> {
+        let frame_commands = &self.frame_commands[self.frame_pacer.current_frame()];
+        let cb = frame_commands.command_buffer;
+        let extent = self.swapchain_extent;
+        let full_scissor = vk::Rect2D::builder()
+            .extent(extent)
+            .build();
+        let cb_begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        let clear_values = [
+            vk::ClearValue{
+                color: vk::ClearColorValue{
+                    float32: [0.0, 0.0, 0.0, 1.0],
+                }
             }
+        ];
+        let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
+            .render_pass(self.render_pass)
+            .framebuffer(framebuffer)
+            .render_area(full_scissor)
+            .clear_values(&clear_values);
 
-            let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
-                .render_pass(render_pass)
-                .framebuffer(swapchain_framebuffers[i])
-                .render_area(
-                    vk::Rect2D::builder()
-                        .extent(swapchain_extent)
-                        .build()
-                )
-                .clear_values(
-                    &[
-                        vk::ClearValue{
-                            color: vk::ClearColorValue{
-                                float32: [0.0, 0.0, 0.0, 1.0],
-                            }
-                        }
-                    ]
-                );
-            unsafe {
-                device.cmd_begin_render_pass(
-                    cb,
-                    &render_pass_begin_info,
-                    vk::SubpassContents::INLINE,
-                );
-                device.cmd_bind_pipeline(
-                    cb,
-                    vk::PipelineBindPoint::GRAPHICS,
-                    pipeline,
-                );
-                device.cmd_draw(cb, 4, 1, 0, 0);
-                device.cmd_end_render_pass(cb);
-                device.end_command_buffer(cb)
-                    .expect("Failed to end command buffer!");
+        unsafe {
+            self.device
+                .reset_command_pool(frame_commands.command_pool, vk::CommandPoolResetFlags::empty())
+                .map_err(vk_error("reset command pool"))?;
+            self.device.begin_command_buffer(cb, &cb_begin_info)
+                .map_err(vk_error("begin command buffer"))?;
+            self.frame_pacer.begin_gpu_timing(&self.device, cb);
+            self.device.cmd_begin_render_pass(
+                cb,
+                &render_pass_begin_info,
+                vk::SubpassContents::INLINE,
+            );
+            self.device.cmd_bind_descriptor_sets(
+                cb,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.pipeline_layout,
+                0,
+                &[self.canvas.descriptor_set],
+                &[],
+            );
+            self.device.cmd_set_viewport(cb, 0, &[
+                vk::Viewport::builder()
+                    .width(extent.width as f32)
+                    .height(extent.height as f32)
+                    .min_depth(0.0)
+                    .max_depth(1.0)
+                    .build()
+            ]);
+            self.device.cmd_set_scissor(cb, 0, &[full_scissor]);
+
+            // Draws before the first pipeline bind have nothing to run.
+            let mut pipeline_bound = false;
+            for command in self.draw_list.commands() {
+                match command {
+                    DrawCommand::BindPipeline(pipeline) => {
+                        self.device.cmd_bind_pipeline(
+                            cb,
+                            vk::PipelineBindPoint::GRAPHICS,
+                            self.pipelines[pipeline.0],
+                        );
+                        pipeline_bound = true;
+                    },
+                    DrawCommand::SetScissor(scissor) => {
+                        let scissor = scissor
+                            .map(|scissor| clamp_scissor(scissor, extent))
+                            .unwrap_or(full_scissor);
+                        self.device.cmd_set_scissor(cb, 0, &[scissor]);
+                    },
+                    DrawCommand::PushConstants { offset, data } => {
+                        self.device.cmd_push_constants(
+                            cb,
+                            self.pipeline_layout,
+                            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                            *offset,
+                            data,
+                        );
+                    },
+                    DrawCommand::Draw { vertices, instances } if pipeline_bound => {
+                        self.device.cmd_draw(
+                            cb,
+                            vertices.end.saturating_sub(vertices.start),
+                            instances.end.saturating_sub(instances.start),
+                            vertices.start,
+                            instances.start,
+                        );
+                    },
+                    DrawCommand::Draw { .. } => {},
+                }
             }
+
+            self.device.cmd_end_render_pass(cb);
+            self.frame_pacer.end_gpu_timing(&self.device, cb);
+            self.device.end_command_buffer(cb)
+                .map_err(vk_error("end command buffer"))?;
         }
-        CommandBundle {
-            command_buffers,
-            command_pool,
-        }
+        Ok(cb)
     }
 
-    fn create_sync_objects(device: &Device) -> SyncBundle {
-        let semaphore_create_info = vk::SemaphoreCreateInfo::builder().build();
-        let fence_create_info = vk::FenceCreateInfo::builder()
-            .flags(vk::FenceCreateFlags::SIGNALED)
-            .build();
-
+    fn create_sync_objects(device: &Device, frames_in_flight: usize) -> Result<SyncBundle, EngineError> {
         let mut sync_bundle = SyncBundle {
             image_available_semaphores: vec![],
             render_finished_semaphores: vec![],
             inflight_fences: vec![],
         };
+        if let Err(error) = sync_bundle.build(device, frames_in_flight) {
+            sync_bundle.destroy(device);
+            return Err(error);
+        }
+        Ok(sync_bundle)
+    }
 
-        for _ in 0..MAX_FRAMES_IN_FLIGHT {
-            unsafe {
-                let image_available_semaphore = device.create_semaphore(
-                    &semaphore_create_info,
-                    None,
-                ).expect("Failed to create semaphore for image availability");
-
-                let render_finished_semaphore = device.create_semaphore(
-                    &semaphore_create_info,
-                    None,
-                ).expect("Failed to create semaphore for finished rendering");
-
-                let inflight_fence = device.create_fence(
-                    &fence_create_info,
-                    None,
-                ).expect("Failed to create fence for inflight images");
+    /// GPU timing uses the timestamp resolution of the graphics queue family.
+    fn create_frame_pacer(
+        instance: &Instance,
+        device: &Device,
+        physical_device: vk::PhysicalDevice,
+        device_index: u32,
+        frames_in_flight: usize,
+    ) -> Result<FramePacer, EngineError> {
+        let (timestamp_period, timestamp_valid_bits) = unsafe {
+            let properties = instance.get_physical_device_properties(physical_device);
+            let queue_families = instance.get_physical_device_queue_family_properties(physical_device);
+            (
+                properties.limits.timestamp_period,
+                queue_families[device_index as usize].timestamp_valid_bits,
+            )
+        };
+        FramePacer::new(device, frames_in_flight, timestamp_period, timestamp_valid_bits)
+    }
 
-                sync_bundle.image_available_semaphores.push(image_available_semaphore);
-                sync_bundle.render_finished_semaphores.push(render_finished_semaphore);
-                sync_bundle.inflight_fences.push(inflight_fence);
+    /// Destroys the per frame command pools, semaphores, fences and timing
+    /// queries. The device has to be idle.
+    fn destroy_frame_objects(&mut self) {
+        unsafe {
+            for frame_commands in self.frame_commands.drain(..) {
+                self.device.destroy_command_pool(frame_commands.command_pool, None);
+            }
+            for semaphore in self.image_available_semaphores.drain(..) {
+                self.device.destroy_semaphore(semaphore, None);
+            }
+            for semaphore in self.render_finished_semaphores.drain(..) {
+                self.device.destroy_semaphore(semaphore, None);
+            }
+            for fence in self.in_flight_fences.drain(..) {
+                self.device.destroy_fence(fence, None);
             }
         }
-
-        sync_bundle
+        self.frame_pacer.destroy(&self.device);
     }
 }
 
 impl Draw for VulkanEngine {
+    /// A frame that fails is skipped, so a failed resize does not take the
+    /// application down. A swapchain that failed to rebuild is retried on
+    /// the next frame.
     fn draw_frame(&mut self) {
-        let wait_fences = [self.in_flight_fences[self.current_frame]];
+        if let Err(error) = self.render_frame() {
+            log::error!("Skipped a frame: {}", error);
+        }
+    }
 
-        let (image_index, _is_sub_optimal) = unsafe {
-            self.device
-                .wait_for_fences(&wait_fences, true, std::u64::MAX)
-                .expect("Failed to wait for Fence!");
+    fn resize(&mut self, width: u32, height: u32) {
+        self.window_extent = vk::Extent2D { width, height };
+        self.framebuffer_resized = true;
+    }
+}
 
-            self.swapchain_loader
-                .acquire_next_image(
-                    self.swapchain,
-                    std::u64::MAX,
-                    self.image_available_semaphores[self.current_frame],
-                    vk::Fence::null(),
-                )
-                .expect("Failed to acquire next image.")
-        };
+impl CanvasRenderer for VulkanEngine {
+    fn extent(&self) -> (u32, u32) {
+        VulkanEngine::extent(self)
+    }
 
-        let wait_semaphores = [self.image_available_semaphores[self.current_frame]];
-        let wait_stages = [vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT];
-        let signal_semaphores = [self.render_finished_semaphores[self.current_frame]];
+    fn canvas_extent(&self) -> (u32, u32) {
+        VulkanEngine::canvas_extent(self)
+    }
 
-        let submit_infos = [vk::SubmitInfo {
-            s_type: vk::StructureType::SUBMIT_INFO,
-            p_next: ptr::null(),
-            wait_semaphore_count: wait_semaphores.len() as u32,
-            p_wait_semaphores: wait_semaphores.as_ptr(),
-            p_wait_dst_stage_mask: wait_stages.as_ptr(),
-            command_buffer_count: 1,
-            p_command_buffers: &self.command_buffers[image_index as usize],
-            signal_semaphore_count: signal_semaphores.len() as u32,
-            p_signal_semaphores: signal_semaphores.as_ptr(),
-        }];
+    fn resize_canvas(&mut self, width: u32, height: u32) -> Result<(), Box<dyn Error>> {
+        Ok(VulkanEngine::resize_canvas(self, width, height)?)
+    }
 
+    fn set_canvas_layers(&mut self, layers: &[CanvasLayer]) -> Result<Vec<CanvasLayerId>, Box<dyn Error>> {
+        Ok(VulkanEngine::set_canvas_layers(self, layers)?)
+    }
+
+    fn write_canvas_region(
+        &mut self,
+        layer: CanvasLayerId,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        Ok(VulkanEngine::write_canvas_region(self, layer, x, y, width, height, rgba)?)
+    }
+
+    fn read_canvas_layer(&mut self, layer: CanvasLayerId) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(VulkanEngine::read_canvas_layer(self, layer)?)
+    }
+}
+
+impl InputHandler for VulkanEngine {
+    /// The engine only renders; applications embedding it interpret input.
+    fn handle_input(&mut self, _event: &InputEvent) {}
+}
+
+impl Drop for VulkanEngine {
+    fn drop(&mut self) {
         unsafe {
-            self.device
-                .reset_fences(&wait_fences)
-                .expect("Failed to reset Fence!");
+            if let Err(error) = self.device.device_wait_idle() {
+                log::error!("Failed to wait for the device before destroying it: {}", error);
+            }
+        }
+        self.destroy_frame_objects();
 
+        self.cleanup_swapchain();
+        unsafe {
             self.device
-                .queue_submit(
-                    self.graphics_queue,
-                    &submit_infos,
-                    self.in_flight_fences[self.current_frame],
-                )
-                .expect("Failed to execute queue submit.");
+                .destroy_pipeline_layout(self.pipeline_layout, None);
         }
-
-        let swapchains = [self.swapchain];
-
-        let present_info = vk::PresentInfoKHR {
-            s_type: vk::StructureType::PRESENT_INFO_KHR,
-            p_next: ptr::null(),
-            wait_semaphore_count: 1,
-            p_wait_semaphores: signal_semaphores.as_ptr(),
-            swapchain_count: 1,
-            p_swapchains: swapchains.as_ptr(),
-            p_image_indices: &image_index,
-            p_results: ptr::null_mut(),
-        };
+        self.transfer.destroy(&self.device, &mut self.allocator);
+        self.canvas.destroy(&self.device, &mut self.allocator);
+        self.allocator.destroy(&self.device);
 
         unsafe {
-            self.swapchain_loader
-                .queue_present(self.present_queue, &present_info)
-                .expect("Failed to execute queue present.");
+            self.device.destroy_device(None);
+            if !self.headless {
+                self.surface_loader.destroy_surface(self.surface, None);
+            }
+
+            VulkanEngine::destroy_debug_utils(&self.debug_utils_loader, self.debug_messenger);
+            self.instance.destroy_instance(None);
         }
+    }
+}
 
-        self.current_frame = (self.current_frame + 1) % MAX_FRAMES_IN_FLIGHT;
+/// What `VulkanEngine::assemble` has created so far. Unless disarmed once
+/// the engine owns it all, dropping it destroys everything in the reverse
+/// order of creation, the same order the engine's own `drop` uses. Null
+/// handles and empty lists stand for objects not created yet.
+struct Assembly {
+    armed: bool,
+    instance: Instance,
+    surface_loader: Surface,
+    surface: vk::SurfaceKHR,
+    debug_utils_loader: ash::extensions::ext::DebugUtils,
+    debug_messenger: vk::DebugUtilsMessengerEXT,
+    device: Option<Device>,
+    allocator: Option<Allocator>,
+    transfer: Option<Transfer>,
+    swapchain: Option<SwapchainBundle>,
+    offscreen_allocation: Option<Allocation>,
+    render_pass: vk::RenderPass,
+    image_views: Vec<vk::ImageView>,
+    canvas: Option<Canvas>,
+    pipeline_layout: vk::PipelineLayout,
+    pipelines: Vec<vk::Pipeline>,
+    framebuffers: Vec<vk::Framebuffer>,
+    frame_commands: Vec<FrameCommands>,
+    sync: Option<SyncBundle>,
+}
+
+impl Assembly {
+    fn new(
+        instance: Instance,
+        surface_loader: Surface,
+        surface: vk::SurfaceKHR,
+        debug_utils_loader: ash::extensions::ext::DebugUtils,
+    ) -> Self {
+        Assembly {
+            armed: true,
+            instance,
+            surface_loader,
+            surface,
+            debug_utils_loader,
+            debug_messenger: vk::DebugUtilsMessengerEXT::null(),
+            device: None,
+            allocator: None,
+            transfer: None,
+            swapchain: None,
+            offscreen_allocation: None,
+            render_pass: vk::RenderPass::null(),
+            image_views: vec![],
+            canvas: None,
+            pipeline_layout: vk::PipelineLayout::null(),
+            pipelines: vec![],
+            framebuffers: vec![],
+            frame_commands: vec![],
+            sync: None,
+        }
     }
 }
 
-impl Drop for VulkanEngine {
+impl Drop for Assembly {
     fn drop(&mut self) {
-        unsafe {
-            self.device.device_wait_idle().unwrap();
-
-            for i in 0..MAX_FRAMES_IN_FLIGHT {
-                self.device
-                    .destroy_semaphore(self.image_available_semaphores[i], None);
-                self.device
-                    .destroy_semaphore(self.render_finished_semaphores[i], None);
-                self.device.destroy_fence(self.in_flight_fences[i], None);
+        if !self.armed {
+            return;
+        }
+        if let Some(device) = &self.device {
+            unsafe {
+                // Uploads flushed while assembling may still be running.
+                let _ = device.device_wait_idle();
+                if let Some(sync) = &mut self.sync {
+                    sync.destroy(device);
+                }
+                for frame_commands in self.frame_commands.iter() {
+                    device.destroy_command_pool(frame_commands.command_pool, None);
+                }
+                for &framebuffer in self.framebuffers.iter() {
+                    device.destroy_framebuffer(framebuffer, None);
+                }
+                for &pipeline in self.pipelines.iter() {
+                    device.destroy_pipeline(pipeline, None);
+                }
+                device.destroy_render_pass(self.render_pass, None);
+                for &image_view in self.image_views.iter() {
+                    device.destroy_image_view(image_view, None);
+                }
+                device.destroy_pipeline_layout(self.pipeline_layout, None);
             }
-
-            self.device.destroy_command_pool(self.command_pool, None);
-
-            for &framebuffer in self.swapchain_framebuffers.iter() {
-                self.device.destroy_framebuffer(framebuffer, None);
+            if let Some(allocator) = &mut self.allocator {
+                if let Some(swapchain) = &self.swapchain {
+                    match self.offscreen_allocation.take() {
+                        Some(allocation) => allocator.destroy_image(device, swapchain.swapchain_images[0], allocation),
+                        None => unsafe { swapchain.swapchain_loader.destroy_swapchain(swapchain.swapchain, None) },
+                    }
+                }
+                if let Some(transfer) = &mut self.transfer {
+                    transfer.destroy(device, allocator);
+                }
+                if let Some(canvas) = &mut self.canvas {
+                    canvas.destroy(device, allocator);
+                }
+                allocator.destroy(device);
             }
-
-            self.device.destroy_pipeline(self.pipeline, None);
-            self.device
-                .destroy_pipeline_layout(self.pipeline_layout, None);
-            self.device.destroy_render_pass(self.render_pass, None);
-            for &imageview in self.swapchain_imageviews.iter() {
-                self.device.destroy_image_view(imageview, None);
+            unsafe {
+                device.destroy_device(None);
             }
-            self.swapchain_loader
-                .destroy_swapchain(self.swapchain, None);
-            self.device.destroy_device(None);
-            self.surface_loader.destroy_surface(self.surface, None);
-
-            if self.validation_layers_enabled {
-                self.debug_utils_loader
-                    .destroy_debug_utils_messenger(self.debug_messenger, None);
+        }
+        unsafe {
+            if self.surface != vk::SurfaceKHR::null() {
+                self.surface_loader.destroy_surface(self.surface, None);
             }
+        }
+        VulkanEngine::destroy_debug_utils(&self.debug_utils_loader, self.debug_messenger);
+        unsafe {
             self.instance.destroy_instance(None);
         }
     }
@@ -705,6 +1735,8 @@ struct DeviceBundle {
     pub logical_device: Device,
     pub present_queue: vk::Queue,
     pub queue: vk::Queue,
+    pub transfer_queue: Option<(u32, vk::Queue)>,
+    pub candidates: Vec<DeviceCandidate>,
 }
 
 struct SurfaceBundle {
@@ -728,55 +1760,98 @@ struct SyncBundle {
     inflight_fences: Vec<vk::Fence>
 }
 
-struct CommandBundle {
-    command_buffers: Vec<vk::CommandBuffer>,
-    command_pool: vk::CommandPool,
+impl SyncBundle {
+    /// Every handle is kept as soon as it is created, so `destroy` also
+    /// cleans up after a failure partway through a frame.
+    fn build(&mut self, device: &Device, frames_in_flight: usize) -> Result<(), EngineError> {
+        let semaphore_create_info = vk::SemaphoreCreateInfo::builder().build();
+        let fence_create_info = vk::FenceCreateInfo::builder()
+            .flags(vk::FenceCreateFlags::SIGNALED)
+            .build();
+
+        for _ in 0..frames_in_flight {
+            unsafe {
+                self.image_available_semaphores.push(device.create_semaphore(
+                    &semaphore_create_info,
+                    None,
+                ).map_err(vk_error("create semaphore for image availability"))?);
+
+                self.render_finished_semaphores.push(device.create_semaphore(
+                    &semaphore_create_info,
+                    None,
+                ).map_err(vk_error("create semaphore for finished rendering"))?);
+
+                self.inflight_fences.push(device.create_fence(
+                    &fence_create_info,
+                    None,
+                ).map_err(vk_error("create fence for inflight images"))?);
+            }
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self, device: &Device) {
+        unsafe {
+            for semaphore in self.image_available_semaphores.drain(..)
+                .chain(self.render_finished_semaphores.drain(..)) {
+                device.destroy_semaphore(semaphore, None);
+            }
+            for fence in self.inflight_fences.drain(..) {
+                device.destroy_fence(fence, None);
+            }
+        }
+    }
 }
 
-#[derive(Clone)]
-struct ImageBundle {
+struct FrameCommands {
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+}
 
+/// Limits a scissor rectangle to the framebuffer; Vulkan rejects negative
+/// scissor offsets.
+fn clamp_scissor(scissor: Rect, extent: vk::Extent2D) -> vk::Rect2D {
+    let x0 = (scissor.x as i64).clamp(0, extent.width as i64);
+    let y0 = (scissor.y as i64).clamp(0, extent.height as i64);
+    let x1 = (scissor.x as i64 + scissor.width as i64).clamp(x0, extent.width as i64);
+    let y1 = (scissor.y as i64 + scissor.height as i64).clamp(y0, extent.height as i64);
+    vk::Rect2D {
+        offset: vk::Offset2D { x: x0 as i32, y: y0 as i32 },
+        extent: vk::Extent2D { width: (x1 - x0) as u32, height: (y1 - y0) as u32 },
+    }
 }
 
-fn populate_debug_messenger_create_info() -> vk::DebugUtilsMessengerCreateInfoEXT {
+/// The callback is handed `validation` as its user data, which therefore
+/// has to stay where it is for as long as the messenger exists.
+fn populate_debug_messenger_create_info(validation: &ValidationConfig) -> vk::DebugUtilsMessengerCreateInfoEXT {
     vk::DebugUtilsMessengerCreateInfoEXT {
         s_type: vk::StructureType::DEBUG_UTILS_MESSENGER_CREATE_INFO_EXT,
         p_next: ptr::null(),
         flags: vk::DebugUtilsMessengerCreateFlagsEXT::empty(),
-        message_severity: vk::DebugUtilsMessageSeverityFlagsEXT::WARNING |
-            // vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE |
-            // vk::DebugUtilsMessageSeverityFlagsEXT::INFO |
-            vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
-        message_type: vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
-            | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
-            | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+        message_severity: validation.severities,
+        message_type: validation.message_types,
         pfn_user_callback: Some(vulkan_debug_utils_callback),
-        p_user_data: ptr::null_mut(),
+        p_user_data: validation as *const ValidationConfig as *mut c_void,
     }
 }
 
-/// the callback function used in Debug Utils.
+/// Forwards messages the validation configuration does not suppress to the
+/// `log` crate, and to its capture when it has one.
 unsafe extern "system" fn vulkan_debug_utils_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     message_type: vk::DebugUtilsMessageTypeFlagsEXT,
     p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _p_user_data: *mut c_void,
+    p_user_data: *mut c_void,
 ) -> vk::Bool32 {
-    let severity = match message_severity {
-        vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE => "[Verbose]",
-        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => "[Warning]",
-        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => "[Error]",
-        vk::DebugUtilsMessageSeverityFlagsEXT::INFO => "[Info]",
-        _ => "[Unknown]",
-    };
-    let types = match message_type {
-        vk::DebugUtilsMessageTypeFlagsEXT::GENERAL => "[General]",
-        vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE => "[Performance]",
-        vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION => "[Validation]",
-        _ => "[Unknown]",
-    };
-    let message = CStr::from_ptr((*p_callback_data).p_message);
-    println!("[Debug]{}{}{:?}", severity, types, message);
+    let validation = &*(p_user_data as *const ValidationConfig);
+    let message = DebugMessage::from_callback_data(message_severity, message_type, &*p_callback_data);
+    if validation.is_suppressed(message.message_id_name.as_deref(), message.message_id_number) {
+        return vk::FALSE;
+    }
+    message.log();
+    if let Some(capture) = &validation.capture {
+        capture.push(message);
+    }
 
     vk::FALSE
 }
@@ -0,0 +1,40 @@
+use ash::{vk, Device};
+use crate::allocator::{Allocation, Allocator, MemoryUsage};
+use crate::error::EngineError;
+use crate::transfer::Transfer;
+
+/// Format of the image a headless engine renders into. Readback hands out
+/// its bytes unchanged, so it has to be RGBA8.
+pub const OFFSCREEN_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+
+pub struct OffscreenImage {
+    pub image: vk::Image,
+    pub allocation: Allocation,
+}
+
+pub fn create_offscreen_image(
+    device: &Device,
+    allocator: &mut Allocator,
+    transfer: &Transfer,
+    extent: vk::Extent2D,
+) -> Result<OffscreenImage, EngineError> {
+    let image_create_info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(OFFSCREEN_FORMAT)
+        .extent(vk::Extent3D { width: extent.width, height: extent.height, depth: 1 })
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
+        .sharing_mode(transfer.sharing_mode())
+        .queue_family_indices(transfer.queue_family_indices())
+        .initial_layout(vk::ImageLayout::UNDEFINED);
+
+    let (image, allocation) = allocator.create_image(
+        device,
+        &image_create_info,
+        MemoryUsage::GpuOnly,
+    )?;
+    Ok(OffscreenImage { image, allocation })
+}
@@ -1,27 +1,63 @@
 use ash::Device;
 use ash::version::DeviceV1_0;
 use ash::vk;
+use std::ffi::CString;
+use crate::draw_list::PUSH_CONSTANT_SIZE;
+use crate::error::{vk_error, EngineError};
+use crate::shaders::{ShaderError, ShaderProgram, ShaderStage};
+
+const SHADER_ENTRY_POINT: &str = "main";
+
+/// The layout shared by every pipeline draw lists can bind, so descriptor
+/// sets and push constants stay bound across pipeline switches.
+pub fn create_pipeline_layout(
+    device: &Device,
+    set_layouts: &[vk::DescriptorSetLayout]) -> Result<vk::PipelineLayout, EngineError> {
+    let push_constant_ranges = [vk::PushConstantRange {
+        stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+        offset: 0,
+        size: PUSH_CONSTANT_SIZE,
+    }];
+    let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::builder()
+        .set_layouts(set_layouts)
+        .push_constant_ranges(&push_constant_ranges);
+    unsafe {
+        device
+            .create_pipeline_layout(&pipeline_layout_create_info, None)
+            .map_err(vk_error("create pipeline layout"))
+    }
+}
 
 pub fn create_graphics_pipeline(
     device: &Device, 
     swapchain_extent: vk::Extent2D,
-    render_pass: vk::RenderPass) -> (ash::vk::PipelineLayout, ash::vk::Pipeline) {
-    let vert_module_create_info = vk::ShaderModuleCreateInfo::builder();
-    let frag_module_create_info = vk::ShaderModuleCreateInfo::builder();
-    let (vert_module, frag_module) = unsafe {
-        (device.create_shader_module(&vert_module_create_info, None).unwrap(),
-        device.create_shader_module(&frag_module_create_info, None).unwrap())
-    };
-    let shader_stage_create_infos = [
-        vk::PipelineShaderStageCreateInfo::builder()
-            .stage(vk::ShaderStageFlags::VERTEX)
-            .module(vert_module)
-            .build(),
-        vk::PipelineShaderStageCreateInfo::builder()
-            .stage(vk::ShaderStageFlags::FRAGMENT)
-            .module(frag_module)
-            .build()
-    ];
+    render_pass: vk::RenderPass,
+    pipeline_layout: vk::PipelineLayout,
+    program: &ShaderProgram) -> Result<vk::Pipeline, EngineError> {
+    let mut shader_modules = vec![];
+    for (stage, spirv) in program.stages.iter() {
+        let module_create_info = vk::ShaderModuleCreateInfo::builder()
+            .code(spirv.words());
+        let module = unsafe { device.create_shader_module(&module_create_info, None) };
+        match module {
+            Ok(module) => shader_modules.push((*stage, module)),
+            Err(error) => {
+                destroy_shader_modules(device, &shader_modules);
+                return Err(ShaderError::ModuleCreation(error).into());
+            }
+        }
+    }
+    let entry_point = CString::new(SHADER_ENTRY_POINT).unwrap();
+    let shader_stage_create_infos: Vec<vk::PipelineShaderStageCreateInfo> = shader_modules
+        .iter()
+        .map(|&(stage, module)| {
+            vk::PipelineShaderStageCreateInfo::builder()
+                .stage(stage.flags())
+                .module(module)
+                .name(&entry_point)
+                .build()
+        })
+        .collect();
     //let vertex_input_description = vk::VertexInputAttributeDescription::builder().build();
     let vertex_input_create_info = vk::PipelineVertexInputStateCreateInfo::builder();
     //    .vertex_attribute_descriptions(&[vertex_input_description]);
@@ -70,12 +106,6 @@ pub fn create_graphics_pipeline(
     let dynamic_state_info = vk::PipelineDynamicStateCreateInfo::builder()
         .flags(vk::PipelineDynamicStateCreateFlags::empty())
         .dynamic_states(&dynamic_state);
-    let pipeline_layout_create_info = vk::PipelineLayoutCreateInfo::builder();
-    let pipeline_layout = unsafe {
-        device.create_pipeline_layout(&pipeline_layout_create_info, None)
-            .unwrap()
-    };
-
     let graphic_pipeline_create_infos = [
         vk::GraphicsPipelineCreateInfo::builder()
             .stages(&shader_stage_create_infos)
@@ -94,18 +124,27 @@ pub fn create_graphics_pipeline(
     let graphic_pipeline = unsafe {
         device
             .create_graphics_pipelines(vk::PipelineCache::null(), &graphic_pipeline_create_infos, None)
-            .expect("Failed to create Graphics Pipeline!.")
     };
 
-    unsafe {
-        device.destroy_shader_module(vert_module, None);
-        device.destroy_shader_module(frag_module, None);
-    }
+    destroy_shader_modules(device, &shader_modules);
+
+    graphic_pipeline
+        .map(|graphic_pipeline| graphic_pipeline[0])
+        .map_err(|(_, error)| EngineError::Vulkan("create graphics pipeline", error))
+}
 
-    (pipeline_layout, graphic_pipeline[0])
+fn destroy_shader_modules(device: &Device, shader_modules: &[(ShaderStage, vk::ShaderModule)]) {
+    for &(_, module) in shader_modules.iter() {
+        unsafe {
+            device.destroy_shader_module(module, None);
+        }
+    }
 }
 
-pub fn create_render_pass(device: &Device, surface_format: vk::Format) -> vk::RenderPass {
+pub fn create_render_pass(
+    device: &Device,
+    surface_format: vk::Format,
+    final_layout: vk::ImageLayout) -> Result<vk::RenderPass, EngineError> {
     let color_attachments = [
         vk::AttachmentDescription::builder()
             .format(surface_format)
@@ -115,7 +154,7 @@ pub fn create_render_pass(device: &Device, surface_format: vk::Format) -> vk::Re
             .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
             .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
             .initial_layout(vk::ImageLayout::UNDEFINED)
-            .final_layout(vk::ImageLayout::PRESENT_SRC_KHR)
+            .final_layout(final_layout)
             .build()
     ];
     let color_attachment_refs = [
@@ -135,6 +174,6 @@ pub fn create_render_pass(device: &Device, surface_format: vk::Format) -> vk::Re
         .subpasses(&subpasses);
     unsafe {
         device.create_render_pass(&renderpass_create_info, None)
-            .expect("Failed to create render pass!")
+            .map_err(vk_error("create render pass"))
     }
 }
\ No newline at end of file
@@ -30,7 +30,7 @@ pub fn required_extension_names() -> Vec<*const i8> {
     ]
 }
 
-#[cfg(all(windows))]
+#[cfg(windows)]
 pub fn required_extension_names() -> Vec<*const i8> {
     vec![
         Surface::name().as_ptr(),
@@ -47,6 +47,12 @@ pub fn required_extension_names() -> Vec<*const i8> {
         DebugUtils::name().as_ptr(),
     ]
 }
+
+pub fn headless_extension_names() -> Vec<*const i8> {
+    vec![
+        DebugUtils::name().as_ptr(),
+    ]
+}
 // ------------------------------------------------------------------------
 
 // create surface ---------------------------------------------------------
@@ -59,8 +65,9 @@ pub unsafe fn create_surface<E: EntryV1_0, I: InstanceV1_0>(
     use std::ptr;
     use winit::platform::unix::WindowExtUnix;
 
-    let x11_display = window.xlib_display().unwrap();
-    let x11_window = window.xlib_window().unwrap();
+    // Not running on X11, e.g. a Wayland session.
+    let x11_display = window.xlib_display().ok_or(vk::Result::ERROR_INITIALIZATION_FAILED)?;
+    let x11_window = window.xlib_window().ok_or(vk::Result::ERROR_INITIALIZATION_FAILED)?;
     let x11_create_info = vk::XlibSurfaceCreateInfoKHR {
         s_type: vk::StructureType::XLIB_SURFACE_CREATE_INFO_KHR,
         p_next: ptr::null(),
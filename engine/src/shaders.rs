@@ -0,0 +1,173 @@
+use ash::vk;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const SPIRV_MAGIC: u32 = 0x0723_0203;
+const SPIRV_HEADER_WORDS: usize = 5;
+
+pub const BASE_PROGRAM: &str = "base";
+
+// (stage, file name under shaders/spv, embedded code) for every stage of the
+// base program. compile.sh writes the files this table points at.
+const BASE_STAGES: [(ShaderStage, &str, &[u8]); 2] = [
+    (ShaderStage::Vertex, "vert.spv", include_bytes!("../shaders/spv/vert.spv")),
+    (ShaderStage::Fragment, "frag.spv", include_bytes!("../shaders/spv/frag.spv")),
+];
+
+#[derive(Debug)]
+pub enum ShaderError {
+    Io(PathBuf, io::Error),
+    MisalignedLength(usize),
+    TooShort(usize),
+    BadMagic(u32),
+    MissingProgram(String),
+    ModuleCreation(vk::Result),
+}
+
+impl fmt::Display for ShaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ShaderError::Io(path, error) =>
+                write!(f, "Failed to read shader {}: {}", path.display(), error),
+            ShaderError::MisalignedLength(len) =>
+                write!(f, "SPIR-V length {} is not a multiple of 4", len),
+            ShaderError::TooShort(len) =>
+                write!(f, "SPIR-V of {} bytes is shorter than its header", len),
+            ShaderError::BadMagic(magic) =>
+                write!(f, "Bad SPIR-V magic number {:#010x}", magic),
+            ShaderError::MissingProgram(name) =>
+                write!(f, "No shader program named {}", name),
+            ShaderError::ModuleCreation(result) =>
+                write!(f, "Failed to create shader module: {}", result),
+        }
+    }
+}
+
+impl std::error::Error for ShaderError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ShaderStage {
+    Vertex,
+    Fragment,
+}
+
+impl ShaderStage {
+    pub fn flags(self) -> vk::ShaderStageFlags {
+        match self {
+            ShaderStage::Vertex => vk::ShaderStageFlags::VERTEX,
+            ShaderStage::Fragment => vk::ShaderStageFlags::FRAGMENT,
+        }
+    }
+
+    /// Maps a GLSL source extension such as `vert` to its stage.
+    pub fn from_extension(extension: &str) -> Option<Self> {
+        match extension {
+            "vert" => Some(ShaderStage::Vertex),
+            "frag" => Some(ShaderStage::Fragment),
+            _ => None,
+        }
+    }
+
+    /// Name of the SPIR-V file holding this stage of the base program.
+    pub fn spirv_file_name(self) -> &'static str {
+        BASE_STAGES
+            .iter()
+            .find(|&&(stage, _, _)| stage == self)
+            .map(|&(_, file_name, _)| file_name)
+            .unwrap()
+    }
+}
+
+/// Validated SPIR-V, stored as native-endian words so it is always
+/// correctly aligned for `vkCreateShaderModule`.
+#[derive(Clone, Debug)]
+pub struct Spirv {
+    words: Vec<u32>,
+}
+
+impl Spirv {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ShaderError> {
+        if !bytes.len().is_multiple_of(4) {
+            return Err(ShaderError::MisalignedLength(bytes.len()));
+        }
+        if bytes.len() < SPIRV_HEADER_WORDS * 4 {
+            return Err(ShaderError::TooShort(bytes.len()));
+        }
+
+        let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let swap = if magic == SPIRV_MAGIC {
+            false
+        } else if magic.swap_bytes() == SPIRV_MAGIC {
+            true
+        } else {
+            return Err(ShaderError::BadMagic(magic));
+        };
+
+        let words = bytes
+            .chunks_exact(4)
+            .map(|chunk| {
+                let word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+                if swap { word.swap_bytes() } else { word }
+            })
+            .collect();
+
+        Ok(Self { words })
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, ShaderError> {
+        let bytes = fs::read(path)
+            .map_err(|error| ShaderError::Io(path.to_path_buf(), error))?;
+        Spirv::from_bytes(&bytes)
+    }
+
+    pub fn words(&self) -> &[u32] {
+        &self.words
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ShaderProgram {
+    pub stages: Vec<(ShaderStage, Spirv)>,
+}
+
+/// The set of shader programs the engine builds its pipelines from, keyed by
+/// program name.
+#[derive(Clone, Debug)]
+pub struct ShaderLibrary {
+    programs: HashMap<String, ShaderProgram>,
+}
+
+impl ShaderLibrary {
+    /// Shaders compiled into the binary at build time.
+    pub fn embedded() -> Result<Self, ShaderError> {
+        let mut stages = vec![];
+        for &(stage, _, code) in BASE_STAGES.iter() {
+            stages.push((stage, Spirv::from_bytes(code)?));
+        }
+        Ok(ShaderLibrary::with_program(BASE_PROGRAM, ShaderProgram { stages }))
+    }
+
+    /// Shaders read from a directory laid out like `engine/shaders/spv`.
+    pub fn from_dir(dir: &Path) -> Result<Self, ShaderError> {
+        let mut stages = vec![];
+        for &(stage, file_name, _) in BASE_STAGES.iter() {
+            stages.push((stage, Spirv::from_file(&dir.join(file_name))?));
+        }
+        Ok(ShaderLibrary::with_program(BASE_PROGRAM, ShaderProgram { stages }))
+    }
+
+    fn with_program(name: &str, program: ShaderProgram) -> Self {
+        let mut programs = HashMap::new();
+        programs.insert(name.to_string(), program);
+        Self { programs }
+    }
+
+    pub fn program(&self, name: &str) -> Result<&ShaderProgram, ShaderError> {
+        self.programs
+            .get(name)
+            .ok_or_else(|| ShaderError::MissingProgram(name.to_string()))
+    }
+}
@@ -0,0 +1,442 @@
+use ash::{vk, Device, Instance};
+use ash::version::{DeviceV1_0, InstanceV1_0};
+use std::mem;
+use crate::allocator::{Allocation, Allocator, MemoryUsage};
+use crate::error::{vk_error, EngineError};
+
+/// Bytes per texel of the RGBA8 images transfers copy to and from.
+const TEXEL_SIZE: usize = 4;
+
+/// An image layout with the accesses and stages that use it.
+type LayoutState = (vk::ImageLayout, vk::AccessFlags, vk::PipelineStageFlags);
+
+const UPLOAD_STATE: LayoutState = (
+    vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+    vk::AccessFlags::TRANSFER_WRITE,
+    vk::PipelineStageFlags::TRANSFER,
+);
+const DOWNLOAD_STATE: LayoutState = (
+    vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+    vk::AccessFlags::TRANSFER_READ,
+    vk::PipelineStageFlags::TRANSFER,
+);
+
+/// A queue family that only does transfers, which many discrete GPUs back
+/// with a separate DMA engine.
+pub fn dedicated_transfer_family(
+    instance: &Instance,
+    physical_device: vk::PhysicalDevice,
+) -> Option<u32> {
+    let queue_families = unsafe {
+        instance.get_physical_device_queue_family_properties(physical_device)
+    };
+    queue_families
+        .iter()
+        .position(|info| {
+            info.queue_count > 0
+                && info.queue_flags.contains(vk::QueueFlags::TRANSFER)
+                && !info.queue_flags.intersects(vk::QueueFlags::GRAPHICS | vk::QueueFlags::COMPUTE)
+        })
+        .map(|index| index as u32)
+}
+
+/// Identifies a readback within the batch that recorded it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadbackTicket(usize);
+
+/// Everything a completed batch read back, in RGBA8 rows.
+#[derive(Debug, Default)]
+pub struct TransferResults {
+    readbacks: Vec<Vec<u8>>,
+}
+
+impl TransferResults {
+    /// Takes the pixels of one readback. Taking the same ticket twice gives
+    /// an empty vector.
+    pub fn take(&mut self, ticket: ReadbackTicket) -> Vec<u8> {
+        mem::take(&mut self.readbacks[ticket.0])
+    }
+}
+
+struct PendingReadback {
+    buffer: vk::Buffer,
+    allocation: Allocation,
+    size: usize,
+}
+
+/// Records buffer-to-image and image-to-buffer copies into one command
+/// buffer and submits them as a batch, signalling a fence on completion.
+/// Only one batch is in flight at a time.
+///
+/// With a dedicated transfer queue the images involved have to be created
+/// with `sharing_mode` and `queue_family_indices`, and the caller has to
+/// make sure the graphics queue is done with them before submitting; the
+/// barriers recorded here can only order work on the transfer queue.
+pub struct Transfer {
+    queue: vk::Queue,
+    queue_family_indices: Vec<u32>,
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    recording: bool,
+    in_flight: bool,
+    staging: Vec<(vk::Buffer, Allocation)>,
+    readbacks: Vec<PendingReadback>,
+}
+
+impl Transfer {
+    /// Uses the dedicated transfer queue when one is given, the graphics
+    /// queue otherwise.
+    pub fn new(
+        device: &Device,
+        graphics_family: u32,
+        graphics_queue: vk::Queue,
+        transfer_queue: Option<(u32, vk::Queue)>,
+    ) -> Result<Self, EngineError> {
+        let (queue_family_index, queue) = transfer_queue.unwrap_or((graphics_family, graphics_queue));
+        let queue_family_indices = if queue_family_index == graphics_family {
+            vec![graphics_family]
+        } else {
+            vec![graphics_family, queue_family_index]
+        };
+        let command_pool_create_info = vk::CommandPoolCreateInfo::builder()
+            .queue_family_index(queue_family_index)
+            .flags(vk::CommandPoolCreateFlags::TRANSIENT);
+        let fence_create_info = vk::FenceCreateInfo::builder();
+
+        unsafe {
+            let command_pool = device
+                .create_command_pool(&command_pool_create_info, None)
+                .map_err(vk_error("create transfer command pool"))?;
+            let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
+                .command_pool(command_pool)
+                .level(vk::CommandBufferLevel::PRIMARY)
+                .command_buffer_count(1);
+            let objects = device
+                .allocate_command_buffers(&command_buffer_allocate_info)
+                .map_err(vk_error("allocate transfer command buffer"))
+                .and_then(|command_buffers| device
+                    .create_fence(&fence_create_info, None)
+                    .map_err(vk_error("create transfer fence"))
+                    .map(|fence| (command_buffers[0], fence)));
+            let (command_buffer, fence) = match objects {
+                Ok(objects) => objects,
+                Err(error) => {
+                    device.destroy_command_pool(command_pool, None);
+                    return Err(error);
+                }
+            };
+
+            Ok(Transfer {
+                queue,
+                queue_family_indices,
+                command_pool,
+                command_buffer,
+                fence,
+                recording: false,
+                in_flight: false,
+                staging: vec![],
+                readbacks: vec![],
+            })
+        }
+    }
+
+    pub fn is_dedicated(&self) -> bool {
+        self.queue_family_indices.len() > 1
+    }
+
+    /// Sharing mode for images that transfers touch.
+    pub fn sharing_mode(&self) -> vk::SharingMode {
+        if self.is_dedicated() {
+            vk::SharingMode::CONCURRENT
+        } else {
+            vk::SharingMode::EXCLUSIVE
+        }
+    }
+
+    /// Queue families to share images between, for `CONCURRENT` sharing.
+    pub fn queue_family_indices(&self) -> &[u32] {
+        &self.queue_family_indices
+    }
+
+    /// Whether anything was recorded that has not been submitted yet.
+    pub fn has_pending(&self) -> bool {
+        self.recording
+    }
+
+    fn begin(&mut self, device: &Device) -> Result<(), EngineError> {
+        assert!(!self.in_flight, "Wait for the submitted transfers before recording more!");
+        if self.recording {
+            return Ok(());
+        }
+        let begin_info = vk::CommandBufferBeginInfo::builder()
+            .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
+        unsafe {
+            device
+                .reset_command_pool(self.command_pool, vk::CommandPoolResetFlags::empty())
+                .map_err(vk_error("reset transfer command pool"))?;
+            device
+                .begin_command_buffer(self.command_buffer, &begin_info)
+                .map_err(vk_error("begin transfer command buffer"))?;
+        }
+        self.recording = true;
+        Ok(())
+    }
+
+    /// Queues a copy of tightly packed RGBA8 rows into `region` of one array
+    /// layer of `image`. That layer moves from `old_layout` to `new_layout`;
+    /// `UNDEFINED` discards what was there before.
+    #[allow(clippy::too_many_arguments)]
+    pub fn upload(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        image: vk::Image,
+        array_layer: u32,
+        old_layout: vk::ImageLayout,
+        new_layout: vk::ImageLayout,
+        region: vk::Rect2D,
+        rgba: &[u8],
+    ) -> Result<(), EngineError> {
+        assert_eq!(
+            rgba.len(),
+            region.extent.width as usize * region.extent.height as usize * TEXEL_SIZE,
+            "Upload data has to be tightly packed RGBA8!"
+        );
+        let buffer_create_info = vk::BufferCreateInfo::builder()
+            .size(rgba.len() as vk::DeviceSize)
+            .usage(vk::BufferUsageFlags::TRANSFER_SRC)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let (buffer, mut allocation) = allocator.create_buffer(
+            device,
+            &buffer_create_info,
+            MemoryUsage::CpuToGpu,
+        )?;
+        allocation.mapped_slice().unwrap()[..rgba.len()].copy_from_slice(rgba);
+        if let Err(error) = self.begin(device) {
+            allocator.destroy_buffer(device, buffer, allocation);
+            return Err(error);
+        }
+
+        let copy_region = buffer_image_copy(region, array_layer);
+        unsafe {
+            self.transition(device, image, array_layer, self.resting_state(old_layout), UPLOAD_STATE);
+            device.cmd_copy_buffer_to_image(
+                self.command_buffer,
+                buffer,
+                image,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                &[copy_region],
+            );
+            self.transition(device, image, array_layer, UPLOAD_STATE, self.resting_state(new_layout));
+        }
+        self.staging.push((buffer, allocation));
+        Ok(())
+    }
+
+    /// Queues a copy of `region` of one array layer of an RGBA8 image into
+    /// host memory. The image is in `layout` before and after the copy.
+    pub fn download(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        image: vk::Image,
+        array_layer: u32,
+        layout: vk::ImageLayout,
+        region: vk::Rect2D,
+    ) -> Result<ReadbackTicket, EngineError> {
+        let size = region.extent.width as usize * region.extent.height as usize * TEXEL_SIZE;
+        let buffer_create_info = vk::BufferCreateInfo::builder()
+            .size(size as vk::DeviceSize)
+            .usage(vk::BufferUsageFlags::TRANSFER_DST)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let (buffer, allocation) = allocator.create_buffer(
+            device,
+            &buffer_create_info,
+            MemoryUsage::GpuToCpu,
+        )?;
+        if let Err(error) = self.begin(device) {
+            allocator.destroy_buffer(device, buffer, allocation);
+            return Err(error);
+        }
+
+        let copy_region = buffer_image_copy(region, array_layer);
+        unsafe {
+            self.transition(device, image, array_layer, self.resting_state(layout), DOWNLOAD_STATE);
+            device.cmd_copy_image_to_buffer(
+                self.command_buffer,
+                image,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+                buffer,
+                &[copy_region],
+            );
+            self.transition(device, image, array_layer, DOWNLOAD_STATE, self.resting_state(layout));
+        }
+        self.readbacks.push(PendingReadback { buffer, allocation, size });
+        Ok(ReadbackTicket(self.readbacks.len() - 1))
+    }
+
+    /// Records a layout transition of one array layer. Transitions between
+    /// identical layouts still act as a memory barrier.
+    unsafe fn transition(
+        &self,
+        device: &Device,
+        image: vk::Image,
+        array_layer: u32,
+        (old_layout, src_access_mask, src_stage): LayoutState,
+        (new_layout, dst_access_mask, dst_stage): LayoutState,
+    ) {
+        let barrier = vk::ImageMemoryBarrier::builder()
+            .src_access_mask(src_access_mask)
+            .dst_access_mask(dst_access_mask)
+            .old_layout(old_layout)
+            .new_layout(new_layout)
+            .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+            .image(image)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: array_layer,
+                layer_count: 1,
+            })
+            .build();
+        device.cmd_pipeline_barrier(
+            self.command_buffer,
+            src_stage,
+            dst_stage,
+            vk::DependencyFlags::empty(),
+            &[],
+            &[],
+            &[barrier],
+        );
+    }
+
+    /// How the rest of the engine uses an image in `layout` between
+    /// transfers. A dedicated transfer queue cannot name graphics stages;
+    /// there the host waits between queues stand in for them.
+    fn resting_state(&self, layout: vk::ImageLayout) -> LayoutState {
+        let (access, stage) = match layout {
+            _ if self.is_dedicated() =>
+                (vk::AccessFlags::empty(), vk::PipelineStageFlags::TOP_OF_PIPE),
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL =>
+                (vk::AccessFlags::SHADER_READ, vk::PipelineStageFlags::FRAGMENT_SHADER),
+            // Offscreen render targets end their render pass in this layout.
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL => (
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            ),
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL => (
+                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            ),
+            _ => (vk::AccessFlags::empty(), vk::PipelineStageFlags::TOP_OF_PIPE),
+        };
+        (layout, access, stage)
+    }
+
+    /// Submits everything recorded since the last submission. The batch's
+    /// fence signals once the copies are done.
+    pub fn submit(&mut self, device: &Device) -> Result<(), EngineError> {
+        if !self.recording {
+            return Ok(());
+        }
+        self.recording = false;
+        let command_buffers = [self.command_buffer];
+        let submit_infos = [vk::SubmitInfo::builder()
+            .command_buffers(&command_buffers)
+            .build()];
+        unsafe {
+            device
+                .end_command_buffer(self.command_buffer)
+                .map_err(vk_error("end transfer command buffer"))?;
+            device
+                .queue_submit(self.queue, &submit_infos, self.fence)
+                .map_err(vk_error("submit transfers"))?;
+        }
+        self.in_flight = true;
+        Ok(())
+    }
+
+    /// Whether the submitted batch has finished, without blocking.
+    pub fn is_complete(&self, device: &Device) -> bool {
+        !self.in_flight || unsafe { device.get_fence_status(self.fence).is_ok() }
+    }
+
+    /// Blocks until the submitted batch is done, releases its staging
+    /// buffers and hands out what it read back.
+    pub fn wait(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+    ) -> Result<TransferResults, EngineError> {
+        if self.in_flight {
+            unsafe {
+                device
+                    .wait_for_fences(&[self.fence], true, u64::MAX)
+                    .map_err(vk_error("wait for transfers"))?;
+                device
+                    .reset_fences(&[self.fence])
+                    .map_err(vk_error("reset transfer fence"))?;
+            }
+            self.in_flight = false;
+        }
+
+        for (buffer, allocation) in self.staging.drain(..) {
+            allocator.destroy_buffer(device, buffer, allocation);
+        }
+        let readbacks = self.readbacks
+            .drain(..)
+            .map(|mut readback| {
+                let pixels = readback.allocation.mapped_slice().unwrap()[..readback.size].to_vec();
+                allocator.destroy_buffer(device, readback.buffer, readback.allocation);
+                pixels
+            })
+            .collect();
+        Ok(TransferResults { readbacks })
+    }
+
+    /// Submits and waits for everything recorded so far.
+    pub fn flush(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+    ) -> Result<TransferResults, EngineError> {
+        self.submit(device)?;
+        self.wait(device, allocator)
+    }
+
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        unsafe {
+            if self.in_flight {
+                let _ = device.wait_for_fences(&[self.fence], true, u64::MAX);
+            }
+            device.destroy_fence(self.fence, None);
+            device.destroy_command_pool(self.command_pool, None);
+        }
+        for (buffer, allocation) in self.staging.drain(..) {
+            allocator.destroy_buffer(device, buffer, allocation);
+        }
+        for readback in self.readbacks.drain(..) {
+            allocator.destroy_buffer(device, readback.buffer, readback.allocation);
+        }
+    }
+}
+
+fn buffer_image_copy(region: vk::Rect2D, array_layer: u32) -> vk::BufferImageCopy {
+    vk::BufferImageCopy::builder()
+        .image_subresource(vk::ImageSubresourceLayers {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            mip_level: 0,
+            base_array_layer: array_layer,
+            layer_count: 1,
+        })
+        .image_offset(vk::Offset3D { x: region.offset.x, y: region.offset.y, z: 0 })
+        .image_extent(vk::Extent3D {
+            width: region.extent.width,
+            height: region.extent.height,
+            depth: 1,
+        })
+        .build()
+}
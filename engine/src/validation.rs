@@ -1,48 +1,183 @@
 use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::ptr;
+use ash::vk;
 use ash::version::EntryV1_0;
+use crate::debug_messages::MessageCapture;
 
-const BUNDLED_LAYERS: [&str; 1] = ["VK_LAYER_KHRONOS_validation"];
+const KHRONOS_VALIDATION_LAYER: &str = "VK_LAYER_KHRONOS_validation";
 
-pub fn check_validation_layer_support(entry: &ash::Entry) -> bool {
-    let layer_properties = entry
-        .enumerate_instance_layer_properties()
-        .expect("Failed to enumerate Instance Layers Properties!");
-
-    if layer_properties.len() <= 0 {
-        eprintln!("Layer properties not found!");
-        return false;
-    }
-
-    for bundled_layer in BUNDLED_LAYERS.iter() {
-        let mut layer_found = false;
-
-        'inner: for property in layer_properties.iter() {
-            let property_name = unsafe { 
-                let pointer = property.layer_name.as_ptr();
-                CStr::from_ptr(pointer)
-            }.to_str()
-            .expect("Failed vulkan raw string conversion!")
-            .to_owned();
-    
-            if property_name == *bundled_layer {
-                layer_found = true;
-                break 'inner;
-            } 
+// Newer than the Vulkan headers ash was generated from.
+const BEST_PRACTICES: i32 = 2;
+const SYNCHRONIZATION_VALIDATION: i32 = 4;
+
+/// A validation message, as the layers identify it: by the name of the
+/// rule it reports, such as `VUID-vkCmdDraw-None-02699`, or by its number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MessageId {
+    Name(String),
+    Number(i32),
+}
+
+impl MessageId {
+    /// A decimal or `0x` prefixed hexadecimal number, otherwise a name.
+    pub fn parse(value: &str) -> Self {
+        let value = value.trim();
+        let number = match value.strip_prefix("0x") {
+            Some(hex) => u32::from_str_radix(hex, 16).map(|number| number as i32).ok(),
+            None => value.parse().ok(),
+        };
+        match number {
+            Some(number) => MessageId::Number(number),
+            None => MessageId::Name(value.to_string()),
         }
-        if layer_found == false {
-            return false;
+    }
+
+    fn matches(&self, name: Option<&str>, number: i32) -> bool {
+        match self {
+            MessageId::Name(id) => name == Some(id.as_str()),
+            MessageId::Number(id) => *id == number,
         }
     }
-    true
 }
 
-pub fn get_validation_layers() -> Vec<*const i8> {
-    let raw_names: Vec<CString> = BUNDLED_LAYERS
-        .iter()
-        .map(|layer_name| CString::new(*layer_name).unwrap())
-        .collect();
-    raw_names
+/// Which layers validate the engine and which of their messages are
+/// reported.
+#[derive(Clone, Debug)]
+pub struct ValidationConfig {
+    /// Enabled in order, on the instance and the device.
+    pub layers: Vec<String>,
+    pub severities: vk::DebugUtilsMessageSeverityFlagsEXT,
+    pub message_types: vk::DebugUtilsMessageTypeFlagsEXT,
+    /// Warns about API use that is valid but likely to be slow.
+    pub best_practices: bool,
+    /// Reports missing or wrong barriers between reads and writes.
+    pub synchronization: bool,
+    /// Instruments shaders to catch out of bounds descriptor access. Takes
+    /// a descriptor set binding slot of its own.
+    pub gpu_assisted: bool,
+    /// Messages that are never reported.
+    pub suppressed: Vec<MessageId>,
+    /// Also keeps the reported messages in memory.
+    pub capture: Option<MessageCapture>,
+}
+
+impl Default for ValidationConfig {
+    /// The Khronos layer with its standard checks, reporting warnings and
+    /// errors of every type.
+    fn default() -> Self {
+        ValidationConfig {
+            layers: vec![KHRONOS_VALIDATION_LAYER.to_string()],
+            severities: vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
+                | vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+            message_types: vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE
+                | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION,
+            best_practices: false,
+            synchronization: false,
+            gpu_assisted: false,
+            suppressed: vec![],
+            capture: None,
+        }
+    }
+}
+
+impl ValidationConfig {
+    pub fn suppress(&mut self, id: MessageId) {
+        self.suppressed.push(id);
+    }
+
+    /// Starts keeping reported messages in memory and returns where they
+    /// are kept.
+    pub fn capture_messages(&mut self) -> MessageCapture {
+        self.capture.get_or_insert_with(MessageCapture::new).clone()
+    }
+
+    pub fn is_suppressed(&self, name: Option<&str>, number: i32) -> bool {
+        self.suppressed.iter().any(|id| id.matches(name, number))
+    }
+
+    /// The optional validation features that are switched on, for
+    /// `VkValidationFeaturesEXT`.
+    pub(crate) fn enabled_features(&self) -> Vec<vk::ValidationFeatureEnableEXT> {
+        let mut features = vec![];
+        if self.best_practices {
+            features.push(vk::ValidationFeatureEnableEXT::from_raw(BEST_PRACTICES));
+        }
+        if self.synchronization {
+            features.push(vk::ValidationFeatureEnableEXT::from_raw(SYNCHRONIZATION_VALIDATION));
+        }
+        if self.gpu_assisted {
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED);
+            features.push(vk::ValidationFeatureEnableEXT::GPU_ASSISTED_RESERVE_BINDING_SLOT);
+        }
+        features
+    }
+}
+
+/// Layer names in the form Vulkan reads them. The pointers stay valid for as
+/// long as this is alive.
+pub(crate) struct LayerNames {
+    _names: Vec<CString>,
+    pointers: Vec<*const c_char>,
+}
+
+impl LayerNames {
+    pub fn new(config: Option<&ValidationConfig>) -> Self {
+        let names: Vec<CString> = config
+            .map_or(&[][..], |config| &config.layers[..])
+            .iter()
+            .map(|layer_name| CString::new(layer_name.as_str()).expect("Layer names cannot contain NUL!"))
+            .collect();
+        // A CString's bytes are on the heap, so moving the vector below does
+        // not move what the pointers point at.
+        let pointers = names.iter().map(|name| name.as_ptr()).collect();
+        LayerNames { _names: names, pointers }
+    }
+
+    pub fn as_ptrs(&self) -> &[*const c_char] {
+        &self.pointers
+    }
+}
+
+/// Returns the first configured layer the loader does not provide, if any.
+pub fn missing_validation_layer(entry: &ash::Entry, config: &ValidationConfig) -> Option<String> {
+    let layer_properties = entry
+        .enumerate_instance_layer_properties()
+        .unwrap_or_default();
+
+    config.layers
         .iter()
-        .map(|layer_name| layer_name.as_ptr())
-        .collect()
-}
\ No newline at end of file
+        .find(|layer| {
+            !layer_properties.iter().any(|property| {
+                let property_name = unsafe {
+                    CStr::from_ptr(property.layer_name.as_ptr())
+                };
+                property_name.to_str() == Ok(layer.as_str())
+            })
+        })
+        .cloned()
+}
+
+/// Whether one of the configured layers implements an instance extension.
+/// Layers such as the Khronos one provide `VK_EXT_validation_features`
+/// themselves, so the loader alone does not list it.
+pub fn layers_provide_extension(entry: &ash::Entry, layers: &LayerNames, extension: &CStr) -> bool {
+    layers.as_ptrs().iter().any(|&layer| unsafe {
+        let mut count = 0;
+        entry.fp_v1_0().enumerate_instance_extension_properties(layer, &mut count, ptr::null_mut());
+        let mut properties = Vec::with_capacity(count as usize);
+        let result = entry.fp_v1_0().enumerate_instance_extension_properties(
+            layer,
+            &mut count,
+            properties.as_mut_ptr(),
+        );
+        if result != vk::Result::SUCCESS && result != vk::Result::INCOMPLETE {
+            return false;
+        }
+        properties.set_len(count as usize);
+        properties.iter().any(|property: &vk::ExtensionProperties| {
+            CStr::from_ptr(property.extension_name.as_ptr()) == extension
+        })
+    })
+}
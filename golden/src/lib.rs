@@ -0,0 +1,352 @@
+//! Golden image tests: scripted painting sessions are replayed on headless
+//! renderers and the frames compared with reference PNGs and with each
+//! other.
+//!
+//! The references in `tests/references` were blessed from the software
+//! renderer, which composites on the CPU with the paint crate and so draws
+//! the same pixels on every machine. What they check on the GPU is that the
+//! Vulkan backend draws the same frames as the software renderer: by
+//! default a check renders with both and fails when the two differ by more
+//! than the tolerance, from each other or from the reference.
+//!
+//! `GOLDEN_BACKEND` picks the renderers: `both` to require both, `vulkan`
+//! or `software` for just one, or unset for both when the Vulkan engine
+//! starts and the software renderer alone otherwise, with a warning logged.
+//! A CPU Vulkan driver such as lavapipe, selected with `VK_ICD_FILENAMES`,
+//! is enough to run the Vulkan backend on a machine without a GPU.
+//! `GOLDEN_BLESS=1` writes the references from the frames rendered, the
+//! software renderer's when there is one, instead of comparing with them.
+//! `GOLDEN_VALIDATION=1` runs the Vulkan backend under the validation layers
+//! and fails a check on any error they report; it never goes without
+//! Vulkan, as the software renderer has no validation to run.
+
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use cgci::Draw;
+use engine::shaders::ShaderLibrary;
+use engine::{DebugMessage, ValidationConfig, VulkanEngine};
+use paint::png::{self, PngOptions};
+use paint::{FileError, Image};
+use software::SoftwareRenderer;
+pub use replay::replay;
+pub use script::{BrushSetting, FillSetting, Operation, Script, ScriptError};
+
+mod replay;
+mod script;
+
+const APP_NAME: &str = "GoldenImages";
+
+/// The largest channel difference allowed when a script does not say
+/// otherwise. The GPU composites in floats the way the paint crate does, so
+/// the two only differ by rounding.
+pub const DEFAULT_TOLERANCE: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    Vulkan,
+    Software,
+}
+
+impl Backend {
+    fn name(self) -> &'static str {
+        match self {
+            Backend::Vulkan => "vulkan",
+            Backend::Software => "software",
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Vulkan => write!(f, "Vulkan"),
+            Backend::Software => write!(f, "software"),
+        }
+    }
+}
+
+/// Which backends a check renders with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backends {
+    Only(Backend),
+    /// Both, which have to agree with each other as well as the reference.
+    Both,
+    /// Both when the Vulkan engine starts, otherwise the software renderer
+    /// alone.
+    Available,
+}
+
+#[derive(Debug)]
+pub enum GoldenError {
+    Script(ScriptError),
+    File(PathBuf, FileError),
+    /// A backend could not be started at all.
+    Unavailable(Backend, Box<dyn Error>),
+    Render(Backend, Box<dyn Error>),
+    /// There is no reference to compare with yet.
+    MissingReference(PathBuf),
+    SizeMismatch {
+        expected: (u32, u32),
+        actual: (u32, u32),
+    },
+    /// Pixels differ by more than the tolerance. The frame rendered and an
+    /// image marking where it differs were written next to each other.
+    Mismatch {
+        /// The backend that rendered the frame, and what it was compared
+        /// with: the reference, or the frame of the other backend.
+        backend: Backend,
+        compared_with: String,
+        difference: u8,
+        tolerance: u8,
+        pixels: usize,
+        actual: PathBuf,
+        diff: PathBuf,
+    },
+    /// The validation layers reported errors while rendering.
+    Validation(Vec<DebugMessage>),
+}
+
+impl fmt::Display for GoldenError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GoldenError::Script(error) => write!(f, "Invalid script: {}", error),
+            GoldenError::File(path, error) => write!(f, "{}: {}", path.display(), error),
+            GoldenError::Unavailable(backend, error) =>
+                write!(f, "Failed to start the {} backend: {}", backend, error),
+            GoldenError::Render(backend, error) =>
+                write!(f, "Failed to render with the {} backend: {}", backend, error),
+            GoldenError::MissingReference(path) => write!(
+                f,
+                "No reference at {}, run with GOLDEN_BLESS=1 to create it",
+                path.display(),
+            ),
+            GoldenError::SizeMismatch { expected, actual } => write!(
+                f,
+                "Rendered {}x{}, but the reference is {}x{}",
+                actual.0, actual.1, expected.0, expected.1,
+            ),
+            GoldenError::Mismatch { backend, compared_with, difference, tolerance, pixels, actual, diff } => write!(
+                f,
+                "{} pixels rendered with the {} backend differ from {} by up to {}, more than the tolerance of {}; see {} and {}",
+                pixels, backend, compared_with, difference, tolerance, actual.display(), diff.display(),
+            ),
+            GoldenError::Validation(errors) => {
+                write!(f, "{} validation errors", errors.len())?;
+                for error in errors {
+                    write!(f, "\n{}", error.message)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+impl Error for GoldenError {}
+
+/// Where scripts, references and results are kept, and how frames are
+/// rendered.
+pub struct Harness {
+    scripts: PathBuf,
+    references: PathBuf,
+    output: PathBuf,
+    backends: Backends,
+    bless: bool,
+    validation: bool,
+}
+
+impl Harness {
+    /// Scripts in `tests/scripts` with references in `tests/references`,
+    /// and results written to `target/golden`.
+    pub fn from_env() -> Self {
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let backends = match std::env::var("GOLDEN_BACKEND").as_deref() {
+            Ok("vulkan") => Backends::Only(Backend::Vulkan),
+            Ok("software") => Backends::Only(Backend::Software),
+            Ok("both") => Backends::Both,
+            Ok(_) => panic!("Wrong value for GOLDEN_BACKEND environmental value"),
+            Err(_) => Backends::Available,
+        };
+        Harness {
+            scripts: root.join("tests").join("scripts"),
+            references: root.join("tests").join("references"),
+            output: root.parent().unwrap_or(root).join("target").join("golden"),
+            backends,
+            bless: std::env::var("GOLDEN_BLESS").map(|value| value == "1").unwrap_or(false),
+            validation: std::env::var("GOLDEN_VALIDATION").map(|value| value == "1").unwrap_or(false),
+        }
+    }
+
+    /// Replays `<name>.script` and compares the frames with `<name>.png`,
+    /// and with each other when both backends rendered one. Returns the
+    /// backends the frames were rendered with.
+    pub fn check(&self, name: &str) -> Result<Vec<Backend>, GoldenError> {
+        let script_path = self.scripts.join(format!("{}.script", name));
+        let source = fs::read_to_string(&script_path)
+            .map_err(|error| GoldenError::File(script_path.clone(), error.into()))?;
+        let script = Script::parse(&source).map_err(GoldenError::Script)?;
+        let frames = self.render_frames(&script)?;
+        let backends: Vec<Backend> = frames.iter().map(|(backend, _)| *backend).collect();
+        let reference_path = self.references.join(format!("{}.png", name));
+        if self.bless {
+            // The software frame comes last when there is one.
+            return write_png(&reference_path, &frames[frames.len() - 1].1).map(|()| backends);
+        }
+        if !reference_path.exists() {
+            return Err(GoldenError::MissingReference(reference_path));
+        }
+        let expected = png::read(&reference_path)
+            .map_err(|error| GoldenError::File(reference_path.clone(), error))?;
+        let tolerance = script.tolerance.unwrap_or(DEFAULT_TOLERANCE);
+        for (backend, actual) in frames.iter() {
+            self.compare(name, *backend, "the reference", &expected, actual, tolerance)?;
+        }
+        if let [(_, vulkan), (_, software)] = &frames[..] {
+            self.compare(name, Backend::Vulkan, "the software renderer", software, vulkan, tolerance)?;
+        }
+        log::info!("{}: matched with {:?}", name, backends);
+        Ok(backends)
+    }
+
+    /// The frames of the backends to check, Vulkan first.
+    fn render_frames(&self, script: &Script) -> Result<Vec<(Backend, Image)>, GoldenError> {
+        let vulkan_required = match self.backends {
+            Backends::Only(Backend::Vulkan) | Backends::Both => true,
+            Backends::Only(Backend::Software) if self.validation => {
+                return Err(GoldenError::Unavailable(
+                    Backend::Software,
+                    "validation needs the Vulkan backend".into(),
+                ));
+            },
+            Backends::Only(Backend::Software) => false,
+            Backends::Available => self.validation,
+        };
+        let mut frames = vec![];
+        if self.backends != Backends::Only(Backend::Software) {
+            let mut validation = None;
+            let mut capture = None;
+            if self.validation {
+                let mut config = ValidationConfig::default();
+                capture = Some(config.capture_messages());
+                validation = Some(config);
+            }
+            match render(script, Backend::Vulkan, validation) {
+                Ok(image) => frames.push((Backend::Vulkan, image)),
+                Err(GoldenError::Unavailable(backend, error)) if !vulkan_required => {
+                    log::warn!("Failed to start the {} backend, only the software renderer is checked: {}", backend, error);
+                },
+                Err(error) => return Err(error),
+            }
+            let errors = capture.map(|capture| capture.errors()).unwrap_or_default();
+            if !errors.is_empty() {
+                return Err(GoldenError::Validation(errors));
+            }
+        }
+        if self.backends != Backends::Only(Backend::Vulkan) {
+            frames.push((Backend::Software, render(script, Backend::Software, None)?));
+        }
+        Ok(frames)
+    }
+
+    /// Compares a frame with what it is expected to look like, writing both
+    /// and where they differ to the output directory when it is not close
+    /// enough.
+    fn compare(
+        &self,
+        name: &str,
+        backend: Backend,
+        compared_with: &str,
+        expected: &Image,
+        actual: &Image,
+        tolerance: u8,
+    ) -> Result<(), GoldenError> {
+        let difference = actual.max_difference(expected).ok_or(GoldenError::SizeMismatch {
+            expected: (expected.width(), expected.height()),
+            actual: (actual.width(), actual.height()),
+        })?;
+        if difference <= tolerance {
+            return Ok(());
+        }
+        let (diff, pixels) = diff_image(expected, actual, tolerance);
+        let actual_path = self.output.join(format!("{}.{}.png", name, backend.name()));
+        let diff_path = self.output.join(format!("{}.{}.diff.png", name, backend.name()));
+        fs::create_dir_all(&self.output)
+            .map_err(|error| GoldenError::File(self.output.clone(), error.into()))?;
+        write_png(&actual_path, actual)?;
+        write_png(&diff_path, &diff)?;
+        Err(GoldenError::Mismatch {
+            backend,
+            compared_with: compared_with.to_string(),
+            difference,
+            tolerance,
+            pixels,
+            actual: actual_path,
+            diff: diff_path,
+        })
+    }
+}
+
+/// Renders the frame a script ends on with one backend, one canvas pixel
+/// per frame pixel. Validation only applies to Vulkan; the software
+/// renderer fails when it is asked for.
+pub fn render(
+    script: &Script,
+    backend: Backend,
+    validation: Option<ValidationConfig>,
+) -> Result<Image, GoldenError> {
+    let (width, height) = (script.width, script.height);
+    match backend {
+        Backend::Vulkan => {
+            let unavailable = |error: Box<dyn Error>| GoldenError::Unavailable(Backend::Vulkan, error);
+            let shaders = ShaderLibrary::embedded().map_err(|error| unavailable(error.into()))?;
+            let mut vulkan_engine = VulkanEngine::new_headless(APP_NAME, validation, width, height, shaders, None)
+                .map_err(|error| unavailable(error.into()))?;
+            let failed = |error: Box<dyn Error>| GoldenError::Render(Backend::Vulkan, error);
+            replay(script, &mut vulkan_engine).map_err(failed)?;
+            vulkan_engine.draw_frame();
+            let pixels = vulkan_engine.read_rgba8().map_err(|error| failed(error.into()))?;
+            Ok(Image::from_pixels(width, height, pixels))
+        },
+        Backend::Software => {
+            if validation.is_some() {
+                return Err(GoldenError::Unavailable(
+                    Backend::Software,
+                    "validation needs the Vulkan backend".into(),
+                ));
+            }
+            let mut software_renderer = SoftwareRenderer::new_headless(width, height);
+            replay(script, &mut software_renderer).map_err(|error| GoldenError::Render(Backend::Software, error))?;
+            software_renderer.draw_frame();
+            Ok(Image::from_pixels(width, height, software_renderer.read_rgba8()))
+        },
+    }
+}
+
+/// The expected image faded to gray, with pixels that differ by more than
+/// the tolerance in red. Also returns how many pixels differ.
+pub fn diff_image(expected: &Image, actual: &Image, tolerance: u8) -> (Image, usize) {
+    let mut diff = Image::new(expected.width(), expected.height());
+    let mut count = 0;
+    for y in 0..expected.height() {
+        for x in 0..expected.width() {
+            let (want, got) = (expected.pixel(x, y), actual.pixel(x, y));
+            let difference = want.iter().zip(got.iter()).map(|(a, b)| a.max(b) - a.min(b)).max().unwrap();
+            let pixel = if difference > tolerance {
+                count += 1;
+                [0xff, 0, 0, 0xff]
+            } else {
+                let luma = (want[0] as u32 * 299 + want[1] as u32 * 587 + want[2] as u32 * 114) / 1000;
+                let faded = (0xc0 + luma / 4) as u8;
+                [faded, faded, faded, 0xff]
+            };
+            diff.set_pixel(x, y, pixel);
+        }
+    }
+    (diff, count)
+}
+
+fn write_png(path: &Path, image: &Image) -> Result<(), GoldenError> {
+    png::write(path, image, &PngOptions::default())
+        .map_err(|error| GoldenError::File(path.to_path_buf(), error))
+}
@@ -0,0 +1,148 @@
+use std::error::Error;
+use cgci::{CanvasBlendMode, CanvasLayer, CanvasLayerId, CanvasRenderer};
+use paint::brush::{render_stroke, BrushSettings};
+use paint::composite::flatten;
+use paint::fill::{flood_fill, FillSettings, FillSource};
+use paint::{BlendMode, Image, Layer, LayerId, LayerStack, Rect};
+use crate::script::{BrushSetting, FillSetting, Operation, Script};
+
+/// Plays a script on a document and hands every change to the renderer the
+/// way the application does: layer changes as a new layer list, pixels as
+/// the rectangles that changed. Returns the document, which is what the
+/// renderer should show.
+pub fn replay(script: &Script, renderer: &mut dyn CanvasRenderer) -> Result<LayerStack, Box<dyn Error>> {
+    if renderer.canvas_extent() != (script.width, script.height) {
+        renderer.resize_canvas(script.width, script.height)?;
+    }
+    let mut player = Player {
+        renderer,
+        layers: LayerStack::new(script.width, script.height, script.background),
+        brush: BrushSettings::default(),
+        fill: FillSettings::default(),
+    };
+    player.sync_layers()?;
+    player.upload(LayerId(0), Rect::new(0, 0, script.width, script.height))?;
+    for operation in script.operations.iter() {
+        player.play(operation)?;
+    }
+    Ok(player.layers)
+}
+
+struct Player<'a> {
+    renderer: &'a mut dyn CanvasRenderer,
+    layers: LayerStack,
+    brush: BrushSettings,
+    fill: FillSettings,
+}
+
+impl Player<'_> {
+    fn play(&mut self, operation: &Operation) -> Result<(), Box<dyn Error>> {
+        let active = self.layers.active();
+        match operation {
+            Operation::Color(color) => {
+                self.brush.color = *color;
+                self.fill.color = *color;
+            },
+            Operation::Brush(setting) => match *setting {
+                BrushSetting::Size(size) => self.brush.size = size,
+                BrushSetting::Hardness(hardness) => self.brush.hardness = hardness,
+                BrushSetting::Opacity(opacity) => self.brush.opacity = opacity,
+                BrushSetting::Flow(flow) => self.brush.flow = flow,
+                BrushSetting::Spacing(spacing) => self.brush.spacing = spacing,
+            },
+            Operation::Fill(setting) => match *setting {
+                FillSetting::Tolerance(tolerance) => self.fill.tolerance = tolerance,
+                FillSetting::Source(source) => self.fill.source = source,
+                FillSetting::Contiguous(contiguous) => self.fill.contiguous = contiguous,
+                FillSetting::Antialias(antialias) => self.fill.antialias = antialias,
+            },
+            Operation::Stroke(samples) => {
+                let image = &mut self.layers.layer_mut(active).unwrap().image;
+                let dirty = render_stroke(image, &self.brush, samples);
+                self.upload(active, dirty)?;
+            },
+            Operation::FillAt(x, y) => {
+                let reference: Image = match self.fill.source {
+                    FillSource::CurrentLayer => self.layers.layer(active).unwrap().image.clone(),
+                    FillSource::AllLayers => flatten(&self.layers),
+                };
+                let image = &mut self.layers.layer_mut(active).unwrap().image;
+                let dirty = flood_fill(image, &reference, *x, *y, &self.fill);
+                self.upload(active, dirty)?;
+            },
+            Operation::AddLayer(name) => {
+                self.layers.add_layer(name);
+                self.sync_layers()?;
+            },
+            Operation::RemoveLayer => {
+                self.layers.remove_layer(active).ok_or("the last layer cannot be removed")?;
+                self.sync_layers()?;
+            },
+            Operation::SelectLayer(index) => {
+                let id = self.layers.layers().get(*index).ok_or("no layer at that index")?.id();
+                self.layers.set_active(id);
+            },
+            Operation::MoveLayer(index) => {
+                self.layers.move_layer(active, *index).ok_or("no layer at that index")?;
+                self.sync_layers()?;
+            },
+            Operation::LayerOpacity(opacity) => {
+                self.layers.layer_mut(active).unwrap().properties.opacity = *opacity;
+                self.sync_layers()?;
+            },
+            Operation::LayerBlend(mode) => {
+                self.layers.layer_mut(active).unwrap().properties.blend_mode = *mode;
+                self.sync_layers()?;
+            },
+            Operation::LayerVisible(visible) => {
+                self.layers.layer_mut(active).unwrap().properties.visible = *visible;
+                self.sync_layers()?;
+            },
+            Operation::Draw => self.renderer.draw_frame(),
+        }
+        Ok(())
+    }
+
+    fn upload(&mut self, id: LayerId, dirty: Rect) -> Result<(), Box<dyn Error>> {
+        if dirty.is_empty() {
+            return Ok(());
+        }
+        let rgba = self.layers.layer(id).unwrap().image.region(dirty);
+        self.renderer.write_canvas_region(CanvasLayerId(id.0), dirty.x, dirty.y, dirty.width, dirty.height, &rgba)
+    }
+
+    fn sync_layers(&mut self) -> Result<(), Box<dyn Error>> {
+        let canvas_layers: Vec<CanvasLayer> = self.layers.layers().iter().map(canvas_layer).collect();
+        let bounds = Rect::new(0, 0, self.layers.width(), self.layers.height());
+        for id in self.renderer.set_canvas_layers(&canvas_layers)? {
+            self.upload(LayerId(id.0), bounds)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the renderer composites a document layer, as the application has it.
+fn canvas_layer(layer: &Layer) -> CanvasLayer {
+    let blend_mode = match layer.properties.blend_mode {
+        BlendMode::Normal => CanvasBlendMode::Normal,
+        BlendMode::Multiply => CanvasBlendMode::Multiply,
+        BlendMode::Screen => CanvasBlendMode::Screen,
+        BlendMode::Overlay => CanvasBlendMode::Overlay,
+        BlendMode::Darken => CanvasBlendMode::Darken,
+        BlendMode::Lighten => CanvasBlendMode::Lighten,
+        BlendMode::ColorDodge => CanvasBlendMode::ColorDodge,
+        BlendMode::ColorBurn => CanvasBlendMode::ColorBurn,
+        BlendMode::HardLight => CanvasBlendMode::HardLight,
+        BlendMode::SoftLight => CanvasBlendMode::SoftLight,
+        BlendMode::Difference => CanvasBlendMode::Difference,
+        BlendMode::Exclusion => CanvasBlendMode::Exclusion,
+        BlendMode::Add => CanvasBlendMode::Add,
+        BlendMode::Subtract => CanvasBlendMode::Subtract,
+    };
+    CanvasLayer {
+        id: CanvasLayerId(layer.id().0),
+        blend_mode,
+        opacity: layer.properties.opacity,
+        visible: layer.properties.visible,
+    }
+}
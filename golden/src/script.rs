@@ -0,0 +1,284 @@
+use std::fmt;
+use std::str::FromStr;
+use paint::brush::Sample;
+use paint::fill::FillSource;
+use paint::BlendMode;
+
+/// Names scripts use for blend modes.
+const BLEND_MODES: [(&str, BlendMode); 14] = [
+    ("normal", BlendMode::Normal),
+    ("multiply", BlendMode::Multiply),
+    ("screen", BlendMode::Screen),
+    ("overlay", BlendMode::Overlay),
+    ("darken", BlendMode::Darken),
+    ("lighten", BlendMode::Lighten),
+    ("color-dodge", BlendMode::ColorDodge),
+    ("color-burn", BlendMode::ColorBurn),
+    ("hard-light", BlendMode::HardLight),
+    ("soft-light", BlendMode::SoftLight),
+    ("difference", BlendMode::Difference),
+    ("exclusion", BlendMode::Exclusion),
+    ("add", BlendMode::Add),
+    ("subtract", BlendMode::Subtract),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BrushSetting {
+    Size(f32),
+    Hardness(f32),
+    Opacity(f32),
+    Flow(f32),
+    Spacing(f32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FillSetting {
+    Tolerance(f32),
+    Source(FillSource),
+    Contiguous(bool),
+    Antialias(bool),
+}
+
+/// One step of a script, in the order it is replayed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Operation {
+    /// The color both the brush and the fill paint with.
+    Color([u8; 4]),
+    Brush(BrushSetting),
+    Fill(FillSetting),
+    /// A brush stroke on the active layer through the given samples.
+    Stroke(Vec<Sample>),
+    /// A fill on the active layer starting at a pixel.
+    FillAt(u32, u32),
+    /// A new layer above the active one, which becomes active.
+    AddLayer(String),
+    RemoveLayer,
+    /// Makes the layer at an index, counted from the bottom, active.
+    SelectLayer(usize),
+    /// Moves the active layer to an index, counted from the bottom.
+    MoveLayer(usize),
+    LayerOpacity(f32),
+    LayerBlend(BlendMode),
+    LayerVisible(bool),
+    /// Draws a frame, so later changes reach a canvas that has been shown.
+    Draw,
+}
+
+/// A scripted painting session, read from a text file with one statement
+/// per line. `#` starts a comment. A script looks like:
+///
+/// ```text
+/// canvas 96 64
+/// background ffffffff
+/// tolerance 2
+/// color ff0000
+/// brush size 8
+/// stroke 10,10 40,30,0.5 80,50
+/// layer add Shading
+/// layer blend multiply
+/// fill source all
+/// fill 5 60
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Script {
+    pub width: u32,
+    pub height: u32,
+    pub background: [u8; 4],
+    /// Largest difference allowed in any channel of any pixel, when the
+    /// script asks for one.
+    pub tolerance: Option<u8>,
+    pub operations: Vec<Operation>,
+}
+
+#[derive(Debug)]
+pub struct ScriptError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+impl Script {
+    pub fn parse(source: &str) -> Result<Self, ScriptError> {
+        let mut canvas = None;
+        let mut script = Script {
+            width: 0,
+            height: 0,
+            background: [0xff; 4],
+            tolerance: None,
+            operations: vec![],
+        };
+        for (index, line) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.split('#').next().unwrap_or("");
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+            let error = |message: String| ScriptError { line: line_number, message };
+            match words[0] {
+                "canvas" => {
+                    if canvas.is_some() {
+                        return Err(error("the canvas size is given twice".to_string()));
+                    }
+                    let [width, height] = arguments::<2>(&words).map_err(error)?;
+                    let size = (number(width).map_err(error)?, number(height).map_err(error)?);
+                    if size.0 == 0 || size.1 == 0 {
+                        return Err(error("the canvas cannot be empty".to_string()));
+                    }
+                    canvas = Some(size);
+                },
+                "background" => {
+                    let [color] = arguments::<1>(&words).map_err(error)?;
+                    script.background = parse_color(color).map_err(error)?;
+                },
+                "tolerance" => {
+                    let [tolerance] = arguments::<1>(&words).map_err(error)?;
+                    script.tolerance = Some(number(tolerance).map_err(error)?);
+                },
+                _ => {
+                    let operation = parse_operation(&words).map_err(error)?;
+                    script.operations.push(operation);
+                },
+            }
+        }
+        let (width, height) = canvas.ok_or(ScriptError {
+            line: 0,
+            message: "the script has no canvas size".to_string(),
+        })?;
+        script.width = width;
+        script.height = height;
+        Ok(script)
+    }
+}
+
+fn parse_operation(words: &[&str]) -> Result<Operation, String> {
+    let operation = match words[0] {
+        "color" => {
+            let [color] = arguments::<1>(words)?;
+            Operation::Color(parse_color(color)?)
+        },
+        "brush" => {
+            let [setting, value] = arguments::<2>(words)?;
+            let value = number(value)?;
+            Operation::Brush(match setting {
+                "size" => BrushSetting::Size(value),
+                "hardness" => BrushSetting::Hardness(value),
+                "opacity" => BrushSetting::Opacity(value),
+                "flow" => BrushSetting::Flow(value),
+                "spacing" => BrushSetting::Spacing(value),
+                _ => return Err(format!("unknown brush setting {}", setting)),
+            })
+        },
+        "stroke" => {
+            if words.len() < 2 {
+                return Err("a stroke needs at least one sample".to_string());
+            }
+            let samples = words[1..].iter().map(|word| parse_sample(word)).collect::<Result<_, _>>()?;
+            Operation::Stroke(samples)
+        },
+        "fill" => {
+            let [first, second] = arguments::<2>(words)?;
+            if let (Ok(x), Ok(y)) = (first.parse(), second.parse()) {
+                return Ok(Operation::FillAt(x, y));
+            }
+            Operation::Fill(match first {
+                "tolerance" => FillSetting::Tolerance(number(second)?),
+                "source" => FillSetting::Source(match second {
+                    "layer" => FillSource::CurrentLayer,
+                    "all" => FillSource::AllLayers,
+                    _ => return Err(format!("unknown fill source {}", second)),
+                }),
+                "contiguous" => FillSetting::Contiguous(switch(second)?),
+                "antialias" => FillSetting::Antialias(switch(second)?),
+                _ => return Err(format!("unknown fill setting {}", first)),
+            })
+        },
+        "layer" => parse_layer_operation(words)?,
+        "draw" => {
+            arguments::<0>(words)?;
+            Operation::Draw
+        },
+        _ => return Err(format!("unknown statement {}", words[0])),
+    };
+    Ok(operation)
+}
+
+fn parse_layer_operation(words: &[&str]) -> Result<Operation, String> {
+    let action = words.get(1).ok_or_else(|| "layer needs an action".to_string())?;
+    let words = &words[1..];
+    let operation = match *action {
+        // Names may contain spaces.
+        "add" => Operation::AddLayer(words[1..].join(" ")),
+        "remove" => {
+            arguments::<0>(words)?;
+            Operation::RemoveLayer
+        },
+        "select" => Operation::SelectLayer(number(arguments::<1>(words)?[0])?),
+        "move" => Operation::MoveLayer(number(arguments::<1>(words)?[0])?),
+        "opacity" => Operation::LayerOpacity(number(arguments::<1>(words)?[0])?),
+        "visible" => Operation::LayerVisible(switch(arguments::<1>(words)?[0])?),
+        "blend" => {
+            let [name] = arguments::<1>(words)?;
+            let mode = BLEND_MODES
+                .iter()
+                .find(|(mode_name, _)| *mode_name == name)
+                .map(|(_, mode)| *mode)
+                .ok_or_else(|| format!("unknown blend mode {}", name))?;
+            Operation::LayerBlend(mode)
+        },
+        _ => return Err(format!("unknown layer action {}", action)),
+    };
+    Ok(operation)
+}
+
+/// The words after the statement's first, which have to be exactly `N`.
+fn arguments<'a, const N: usize>(words: &[&'a str]) -> Result<[&'a str; N], String> {
+    let mut arguments = [""; N];
+    if words.len() != N + 1 {
+        return Err(format!("{} takes {} arguments, {} were given", words[0], N, words.len() - 1));
+    }
+    arguments.copy_from_slice(&words[1..]);
+    Ok(arguments)
+}
+
+fn number<T: FromStr>(word: &str) -> Result<T, String> {
+    word.parse().map_err(|_| format!("{} is not a valid number here", word))
+}
+
+fn switch(word: &str) -> Result<bool, String> {
+    match word {
+        "on" => Ok(true),
+        "off" => Ok(false),
+        _ => Err(format!("expected on or off, not {}", word)),
+    }
+}
+
+/// `RRGGBB` or `RRGGBBAA` in hexadecimal.
+fn parse_color(word: &str) -> Result<[u8; 4], String> {
+    let invalid = || format!("{} is not a color", word);
+    if !(word.len() == 6 || word.len() == 8) || !word.is_ascii() {
+        return Err(invalid());
+    }
+    let mut color = [0xff; 4];
+    for (channel, index) in color.iter_mut().zip((0..word.len()).step_by(2)) {
+        *channel = u8::from_str_radix(&word[index..index + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(color)
+}
+
+/// `x,y` or `x,y,pressure`, in canvas pixels. Pressure defaults to full.
+fn parse_sample(word: &str) -> Result<Sample, String> {
+    let values = word.split(',').map(number).collect::<Result<Vec<f32>, _>>()?;
+    match values.as_slice() {
+        [x, y] => Ok(Sample::new(*x, *y, 1.0)),
+        [x, y, pressure] => Ok(Sample::new(*x, *y, *pressure)),
+        _ => Err(format!("{} is not a sample", word)),
+    }
+}
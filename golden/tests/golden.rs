@@ -0,0 +1,27 @@
+use golden::Harness;
+
+fn check(name: &str) {
+    if let Err(error) = Harness::from_env().check(name) {
+        panic!("{}: {}", name, error);
+    }
+}
+
+#[test]
+fn brush_strokes() {
+    check("brush_strokes");
+}
+
+#[test]
+fn flood_fill() {
+    check("flood_fill");
+}
+
+#[test]
+fn layer_blending() {
+    check("layer_blending");
+}
+
+#[test]
+fn layer_order() {
+    check("layer_order");
+}
@@ -0,0 +1,167 @@
+use cgci::{InputEvent, Key, KeyState, Modifiers, PointerButton, PointerEvent, ScrollDelta};
+use winit::event::{
+    ElementState, ModifiersState, MouseButton, MouseScrollDelta, TouchPhase,
+    VirtualKeyCode, WindowEvent,
+};
+
+/// Turns winit window events into `cgci` input events. winit reports the
+/// cursor position, button state and modifiers separately, so they are
+/// tracked here and attached to every pointer event.
+#[derive(Default)]
+pub struct InputTranslator {
+    x: f64,
+    y: f64,
+    held_button: Option<PointerButton>,
+    pressure: Option<f32>,
+    modifiers: Modifiers,
+}
+
+impl InputTranslator {
+    pub fn set_modifiers(&mut self, modifiers: ModifiersState) {
+        self.modifiers = Modifiers {
+            shift: modifiers.shift(),
+            ctrl: modifiers.ctrl(),
+            alt: modifiers.alt(),
+            logo: modifiers.logo(),
+        };
+    }
+
+    pub fn translate(&mut self, event: &WindowEvent) -> Option<InputEvent> {
+        match event {
+            WindowEvent::CursorMoved { position, .. } => {
+                self.x = position.x as f64;
+                self.y = position.y as f64;
+                Some(InputEvent::PointerMove(self.pointer_event(self.held_button)))
+            },
+            WindowEvent::MouseInput { state, button, .. } => {
+                let button = pointer_button(*button);
+                match state {
+                    ElementState::Pressed => {
+                        self.held_button = Some(button);
+                        Some(InputEvent::PointerDown(self.pointer_event(Some(button))))
+                    },
+                    ElementState::Released => {
+                        self.held_button = None;
+                        self.pressure = None;
+                        Some(InputEvent::PointerUp(self.pointer_event(Some(button))))
+                    },
+                }
+            },
+            WindowEvent::TouchpadPressure { pressure, .. } => {
+                self.pressure = Some(*pressure);
+                None
+            },
+            WindowEvent::Touch(touch) => {
+                self.x = touch.location.x;
+                self.y = touch.location.y;
+                self.pressure = touch.force.map(|force| force.normalized() as f32);
+                match touch.phase {
+                    TouchPhase::Started => {
+                        self.held_button = Some(PointerButton::Primary);
+                        Some(InputEvent::PointerDown(self.pointer_event(self.held_button)))
+                    },
+                    TouchPhase::Moved => {
+                        Some(InputEvent::PointerMove(self.pointer_event(self.held_button)))
+                    },
+                    TouchPhase::Ended | TouchPhase::Cancelled => {
+                        let event = self.pointer_event(Some(PointerButton::Primary));
+                        self.held_button = None;
+                        self.pressure = None;
+                        Some(InputEvent::PointerUp(event))
+                    },
+                }
+            },
+            WindowEvent::MouseWheel { delta, .. } => {
+                let delta = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => ScrollDelta::Lines { x: *x, y: *y },
+                    MouseScrollDelta::PixelDelta(position) =>
+                        ScrollDelta::Pixels { x: position.x, y: position.y },
+                };
+                Some(InputEvent::Scroll { delta, modifiers: self.modifiers })
+            },
+            WindowEvent::KeyboardInput { input, .. } => {
+                let key = input.virtual_keycode
+                    .and_then(key_from_virtual_keycode)
+                    .unwrap_or(Key::Unknown(input.scancode));
+                let state = match input.state {
+                    ElementState::Pressed => KeyState::Pressed,
+                    ElementState::Released => KeyState::Released,
+                };
+                Some(InputEvent::Key { key, state, modifiers: self.modifiers })
+            },
+            WindowEvent::Focused(focused) => Some(InputEvent::Focus(*focused)),
+            // Resizes reach the application through `Draw::resize` only.
+            _ => None,
+        }
+    }
+
+    fn pointer_event(&self, button: Option<PointerButton>) -> PointerEvent {
+        let default_pressure = if self.held_button.is_some() { 1.0 } else { 0.0 };
+        PointerEvent {
+            x: self.x,
+            y: self.y,
+            button,
+            pressure: self.pressure.unwrap_or(default_pressure),
+            modifiers: self.modifiers,
+        }
+    }
+}
+
+fn pointer_button(button: MouseButton) -> PointerButton {
+    match button {
+        MouseButton::Left => PointerButton::Primary,
+        MouseButton::Right => PointerButton::Secondary,
+        MouseButton::Middle => PointerButton::Middle,
+        MouseButton::Other(index) => PointerButton::Other(index),
+    }
+}
+
+fn key_from_virtual_keycode(code: VirtualKeyCode) -> Option<Key> {
+    let index = code as u32;
+    if index >= VirtualKeyCode::A as u32 && index <= VirtualKeyCode::Z as u32 {
+        return Some(Key::Char((b'a' + (index - VirtualKeyCode::A as u32) as u8) as char));
+    }
+    if index >= VirtualKeyCode::Key1 as u32 && index <= VirtualKeyCode::Key9 as u32 {
+        return Some(Key::Char((b'1' + (index - VirtualKeyCode::Key1 as u32) as u8) as char));
+    }
+    if index >= VirtualKeyCode::F1 as u32 && index <= VirtualKeyCode::F24 as u32 {
+        return Some(Key::Function(1 + (index - VirtualKeyCode::F1 as u32) as u8));
+    }
+
+    let key = match code {
+        VirtualKeyCode::Key0 => Key::Char('0'),
+        VirtualKeyCode::Escape => Key::Escape,
+        VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => Key::Enter,
+        VirtualKeyCode::Tab => Key::Tab,
+        VirtualKeyCode::Space => Key::Space,
+        VirtualKeyCode::Back => Key::Backspace,
+        VirtualKeyCode::Delete => Key::Delete,
+        VirtualKeyCode::Insert => Key::Insert,
+        VirtualKeyCode::Home => Key::Home,
+        VirtualKeyCode::End => Key::End,
+        VirtualKeyCode::PageUp => Key::PageUp,
+        VirtualKeyCode::PageDown => Key::PageDown,
+        VirtualKeyCode::Left => Key::Left,
+        VirtualKeyCode::Right => Key::Right,
+        VirtualKeyCode::Up => Key::Up,
+        VirtualKeyCode::Down => Key::Down,
+        VirtualKeyCode::LShift | VirtualKeyCode::RShift => Key::Shift,
+        VirtualKeyCode::LControl | VirtualKeyCode::RControl => Key::Ctrl,
+        VirtualKeyCode::LAlt | VirtualKeyCode::RAlt => Key::Alt,
+        VirtualKeyCode::LWin | VirtualKeyCode::RWin => Key::Logo,
+        VirtualKeyCode::LBracket => Key::Char('['),
+        VirtualKeyCode::RBracket => Key::Char(']'),
+        VirtualKeyCode::Minus | VirtualKeyCode::Subtract => Key::Char('-'),
+        VirtualKeyCode::Equals => Key::Char('='),
+        VirtualKeyCode::Add => Key::Char('+'),
+        VirtualKeyCode::Comma => Key::Char(','),
+        VirtualKeyCode::Period => Key::Char('.'),
+        VirtualKeyCode::Slash => Key::Char('/'),
+        VirtualKeyCode::Backslash => Key::Char('\\'),
+        VirtualKeyCode::Semicolon => Key::Char(';'),
+        VirtualKeyCode::Apostrophe => Key::Char('\''),
+        VirtualKeyCode::Grave => Key::Char('`'),
+        _ => return None,
+    };
+    Some(key)
+}
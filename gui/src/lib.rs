@@ -1,19 +1,130 @@
-use winit::event::{Event, VirtualKeyCode, ElementState, KeyboardInput, WindowEvent};
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+use std::time::Instant;
+use winit::event::{DeviceEvent, Event, WindowEvent};
 use winit::event_loop::{EventLoop, ControlFlow};
-use cgci::Draw;
+use winit::window::Window;
+use cgci::{Application, Clock, LogEntry, LogWriter, LoggedEvent};
+use input::InputTranslator;
+pub use replay::{Pacing, Replay};
 
-pub fn start_main_loop(event_loop: EventLoop<()>, mut engine: Box<dyn Draw>) {
+mod input;
+mod replay;
+
+/// Writes everything the main loop hands to the application to a log that
+/// `Replay` can play back.
+pub struct Recorder {
+    writer: LogWriter<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Recorder { writer: LogWriter::new(BufWriter::new(File::create(path)?))? })
+    }
+
+    pub fn record(&mut self, entry: &LogEntry) -> io::Result<()> {
+        self.writer.write(entry)
+    }
+}
+
+/// Hands an event to the application the way the main loop does, which is
+/// also how a replay hands it over.
+pub fn dispatch(engine: &mut dyn Application, event: &LoggedEvent) {
+    match event {
+        LoggedEvent::Input(input_event) => engine.handle_input(input_event),
+        LoggedEvent::Resize { width, height } => engine.resize(*width, *height),
+        LoggedEvent::Frame => engine.draw_frame(),
+    }
+}
+
+/// Runs the application until its window is closed. `clock` is set to the
+/// seconds since the loop started before every event and frame, which are
+/// also written to `recorder` when there is one.
+pub fn start_main_loop(
+    event_loop: EventLoop<()>,
+    window: Window,
+    mut engine: Box<dyn Application>,
+    clock: Clock,
+    mut recorder: Option<Recorder>,
+) {
+    let mut input_translator = InputTranslator::default();
+    // Nothing asks for frames but the main loop itself, after anything
+    // that may change what is shown and while the application wants them.
+    let mut redraw_due = true;
+    let started = Instant::now();
+    let mut deliver = move |engine: &mut dyn Application, event: LoggedEvent| {
+        let time = started.elapsed().as_secs_f64();
+        clock.set(time);
+        if let Some(writer) = recorder.as_mut() {
+            if let Err(error) = writer.record(&LogEntry { time, event }) {
+                log::warn!("Failed to record input, recording stopped: {}", error);
+                recorder = None;
+            }
+        }
+        dispatch(engine, &event);
+    };
     event_loop.run(move |event, _, control_flow|{
         match event {
             Event::WindowEvent {event, ..} => {
+                if let Some(input_event) = input_translator.translate(&event) {
+                    deliver(&mut *engine, LoggedEvent::Input(input_event));
+                    redraw_due = true;
+                }
                 match event {
                     WindowEvent::CloseRequested => { *control_flow = ControlFlow::Exit }
+                    WindowEvent::Resized(size) => {
+                        deliver(
+                            &mut *engine,
+                            LoggedEvent::Resize { width: size.width, height: size.height },
+                        );
+                        redraw_due = true;
+                    },
                     _ => (),
                 }
             },
+            Event::MainEventsCleared => {
+                if redraw_due || engine.needs_redraw() {
+                    window.request_redraw();
+                    redraw_due = false;
+                }
+            },
+            Event::DeviceEvent { event: DeviceEvent::ModifiersChanged(modifiers), .. } => {
+                input_translator.set_modifiers(modifiers);
+            },
             | Event::RedrawRequested(_window_id) => {
-                engine.draw_frame();
+                deliver(&mut *engine, LoggedEvent::Frame);
+            },
+            | _ => (),
+        }
+    });
+}
+
+/// Runs the application on a recorded session instead of the window's own
+/// input, which is ignored apart from closing it. The window stays open
+/// once the replay is over.
+pub fn start_replay_loop(
+    event_loop: EventLoop<()>,
+    mut engine: Box<dyn Application>,
+    clock: Clock,
+    mut replay: Replay,
+) {
+    event_loop.run(move |event, _, control_flow|{
+        match event {
+            Event::WindowEvent { event: WindowEvent::CloseRequested, .. } => {
+                *control_flow = ControlFlow::Exit;
+            },
+            Event::MainEventsCleared => {
+                replay.play_due(&mut *engine, &clock);
+                *control_flow = match replay.next_due() {
+                    Some(due) => ControlFlow::WaitUntil(due),
+                    None if replay.is_finished() => ControlFlow::Wait,
+                    None => ControlFlow::Poll,
+                };
             },
+            // Drawing again at the time of the last entry changes nothing
+            // the replay depends on.
+            | Event::RedrawRequested(_window_id) => engine.draw_frame(),
             | _ => (),
         }
     });
@@ -37,11 +148,11 @@ impl<'prc> MainWindow<'prc> {
             .expect("Failed to create main window!");
         
         Self {
-            window_title: window_title,
-            window_width: window_width,
-            window_height: window_height,
-            window: window,
-            event_loop: event_loop,
+            window_title,
+            window_width,
+            window_height,
+            window,
+            event_loop,
         }
     }
 
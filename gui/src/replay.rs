@@ -0,0 +1,92 @@
+use std::thread;
+use std::time::{Duration, Instant};
+use cgci::{Application, Clock, LogEntry, LoggedEvent};
+use crate::dispatch;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pacing {
+    /// Entries are played as far apart as they were recorded.
+    RealTime,
+    /// Entries are played as soon as possible, a frame at a time when there
+    /// is a window to show them in.
+    AsFastAsPossible,
+}
+
+/// Plays a recorded session back through `dispatch`, setting the clock to
+/// each entry's recorded time first. What the application does only
+/// depends on the entries and their times, so pacing changes how long a
+/// replay takes, never what it paints.
+pub struct Replay {
+    entries: Vec<LogEntry>,
+    next: usize,
+    pacing: Pacing,
+    started: Option<Instant>,
+}
+
+impl Replay {
+    pub fn new(entries: Vec<LogEntry>, pacing: Pacing) -> Self {
+        Replay {
+            entries,
+            next: 0,
+            pacing,
+            started: None,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.entries.len()
+    }
+
+    /// When the next entry is due, for real-time pacing only.
+    pub fn next_due(&self) -> Option<Instant> {
+        match (self.pacing, self.started, self.entries.get(self.next)) {
+            (Pacing::RealTime, Some(started), Some(entry)) => {
+                Some(started + Duration::from_secs_f64(entry.time.max(0.0)))
+            },
+            _ => None,
+        }
+    }
+
+    /// Plays the entries that are due: those recorded up to now since the
+    /// replay started for real-time pacing, otherwise those up to and
+    /// including the next frame.
+    pub fn play_due(&mut self, engine: &mut dyn Application, clock: &Clock) {
+        let started = *self.started.get_or_insert_with(Instant::now);
+        while let Some(entry) = self.entries.get(self.next) {
+            let due = match self.pacing {
+                Pacing::RealTime => entry.time <= started.elapsed().as_secs_f64(),
+                Pacing::AsFastAsPossible => true,
+            };
+            if !due {
+                break;
+            }
+            let is_frame = entry.event == LoggedEvent::Frame;
+            self.play_next(engine, clock);
+            if is_frame && self.pacing == Pacing::AsFastAsPossible {
+                break;
+            }
+        }
+    }
+
+    /// Plays every entry left, sleeping until each one is due for
+    /// real-time pacing.
+    pub fn play_all(&mut self, engine: &mut dyn Application, clock: &Clock) {
+        self.started.get_or_insert_with(Instant::now);
+        while !self.is_finished() {
+            if let Some(due) = self.next_due() {
+                let now = Instant::now();
+                if due > now {
+                    thread::sleep(due - now);
+                }
+            }
+            self.play_next(engine, clock);
+        }
+    }
+
+    fn play_next(&mut self, engine: &mut dyn Application, clock: &Clock) {
+        let entry = self.entries[self.next];
+        self.next += 1;
+        clock.set(entry.time);
+        dispatch(engine, &entry.event);
+    }
+}
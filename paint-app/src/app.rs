@@ -0,0 +1,510 @@
+use cgci::{
+    CanvasBlendMode, CanvasLayer, CanvasLayerId, CanvasRenderer, Clock, Draw, InputEvent, InputHandler, Key, KeyState,
+    PointerButton, PointerEvent,
+};
+use paint::brush::{BrushSettings, Sample, Stroke};
+use paint::composite::flatten;
+use paint::fill::{flood_fill, FillSettings, FillSource};
+use paint::history::{Changed, History};
+use paint::png::{self, PngOptions};
+use paint::{ora, splash};
+use paint::spray::{Spray, SpraySettings};
+use paint::tile::TileSnapshot;
+use paint::{BlendMode, Document, FileError, Image, Layer, LayerId, LayerProperties, LayerStack, Rect};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tool {
+    Brush,
+    Spray,
+    Fill,
+}
+
+enum ActiveStroke {
+    Brush(Stroke),
+    Spray(Spray),
+}
+
+/// The painting application: owns the document layers, turns pointer input
+/// into strokes on the active layer and keeps the canvas of whichever
+/// renderer it was given in sync.
+pub struct PaintApp {
+    engine: Box<dyn CanvasRenderer>,
+    document: Document,
+    tool: Tool,
+    brush: BrushSettings,
+    spray: SpraySettings,
+    fill: FillSettings,
+    /// The stroke in progress and the layer it paints on.
+    stroke: Option<(LayerId, ActiveStroke)>,
+    history: History,
+    /// Spray strokes are seeded from this and the number of strokes before
+    /// them, so a session replayed with the same seed paints the same.
+    seed: u64,
+    stroke_count: u64,
+    /// Set by the main loop, or by a replay to the recorded times.
+    clock: Clock,
+    /// Where Ctrl+E writes the flattened document.
+    export: Option<(PathBuf, PngOptions)>,
+    /// Where Ctrl+S saves the document.
+    project: Option<PathBuf>,
+}
+
+impl PaintApp {
+    pub fn new(engine: Box<dyn CanvasRenderer>, clock: Clock) -> Self {
+        let (width, height) = engine.canvas_extent();
+        PaintApp {
+            engine,
+            // Matches the white layer renderers start their canvas with.
+            document: Document::new(LayerStack::new(width, height, [0xff; 4])),
+            tool: Tool::Brush,
+            brush: BrushSettings::default(),
+            spray: SpraySettings::default(),
+            fill: FillSettings::default(),
+            stroke: None,
+            history: History::default(),
+            seed: 0,
+            stroke_count: 0,
+            clock,
+            export: None,
+            project: None,
+        }
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn set_tool(&mut self, tool: Tool) {
+        self.tool = tool;
+    }
+
+    /// Caps how many bytes of compressed tiles undo and redo may hold.
+    pub fn set_history_budget(&mut self, budget: usize) {
+        self.history.set_budget(budget);
+    }
+
+    pub fn set_export(&mut self, path: &Path, options: PngOptions) {
+        self.export = Some((path.to_path_buf(), options));
+    }
+
+    pub fn set_project_path(&mut self, path: &Path) {
+        self.project = Some(path.to_path_buf());
+    }
+
+    /// Replaces the document with a PNG, resizing the canvas to fit it.
+    pub fn open_png(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let image = png::read(path)?;
+        let mut layers = LayerStack::new(image.width(), image.height(), [0; 4]);
+        let background = layers.layer_mut(LayerId(0)).unwrap();
+        background.properties.name = layer_name(path);
+        background.image = image;
+        self.replace_document(Document::new(layers))
+    }
+
+    /// Replaces the document with a saved project: OpenRaster for `.ora`
+    /// files, the native format otherwise.
+    pub fn open_project(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let document = if is_open_raster(path) {
+            ora::read(path)?
+        } else {
+            splash::read(path)?
+        };
+        self.replace_document(document)
+    }
+
+    /// Saves the document with all its layers, as OpenRaster for `.ora`
+    /// files and in the native format otherwise.
+    pub fn save_project(&self, path: &Path) -> Result<(), FileError> {
+        if is_open_raster(path) {
+            ora::write(path, &self.document)
+        } else {
+            splash::write(path, &self.document)
+        }
+    }
+
+    /// Starts over on another document, resizing the canvas to fit it.
+    fn replace_document(&mut self, document: Document) -> Result<(), Box<dyn Error>> {
+        if self.engine.canvas_extent() != (document.width(), document.height()) {
+            self.engine.resize_canvas(document.width(), document.height())?;
+        }
+        self.document = document;
+        self.stroke = None;
+        self.history.clear();
+        // Layer ids start over, so ones the canvas still holds may now stand
+        // for other pixels.
+        self.sync_layers();
+        let ids: Vec<LayerId> = self.document.layers.layers().iter().map(Layer::id).collect();
+        let bounds = Rect::new(0, 0, self.document.width(), self.document.height());
+        ids.into_iter().for_each(|id| self.upload(id, bounds));
+        Ok(())
+    }
+
+    /// Adds a PNG as a new layer above the active one. Parts outside the
+    /// document are cut off.
+    pub fn import_png_layer(&mut self, path: &Path) -> Result<LayerId, FileError> {
+        let image = png::read(path)?;
+        let (width, height) = (self.document.width(), self.document.height());
+        let overlap = image.bounds().intersection(&Rect::new(0, 0, width, height));
+        let mut fitted = Image::new(width, height);
+        fitted.write_region(overlap, &image.region(overlap));
+
+        let id = self.document.layers.allocate_id();
+        let index = self.document.layers.index_of(self.document.layers.active()).map_or(0, |index| index + 1);
+        let layer = Layer::new(id, LayerProperties::new(&layer_name(path)), fitted);
+        self.history.insert_layer(&mut self.document.layers, index, layer);
+        self.sync_layers();
+        Ok(id)
+    }
+
+    /// Writes the document, flattened, to a PNG. The layers are read back
+    /// from the engine canvas, so the file holds what the engine shows.
+    pub fn export_png(&mut self, path: &Path, options: &PngOptions) -> Result<(), Box<dyn Error>> {
+        let mut layers = self.document.layers.clone();
+        for layer in self.document.layers.layers() {
+            let pixels = self.engine.read_canvas_layer(CanvasLayerId(layer.id().0))?;
+            layers.layer_mut(layer.id()).unwrap().image =
+                Image::from_pixels(layers.width(), layers.height(), pixels);
+        }
+        png::write(path, &flatten(&layers), options)?;
+        Ok(())
+    }
+
+    /// Seconds since the session started.
+    fn now(&self) -> f64 {
+        self.clock.now()
+    }
+
+    /// The canvas is stretched over the whole window, so window positions
+    /// are scaled to document pixels.
+    fn sample(&self, pointer: &PointerEvent) -> Sample {
+        let (window_width, window_height) = self.engine.extent();
+        let scale_x = self.document.width() as f64 / window_width.max(1) as f64;
+        let scale_y = self.document.height() as f64 / window_height.max(1) as f64;
+        Sample::new(
+            (pointer.x * scale_x) as f32,
+            (pointer.y * scale_y) as f32,
+            pointer.pressure,
+        )
+    }
+
+    /// The active layer, unless it is locked against painting.
+    fn paintable_layer(&self) -> Option<LayerId> {
+        let id = self.document.layers.active();
+        match self.document.layers.layer(id) {
+            Some(layer) if !layer.properties.locked => Some(id),
+            _ => None,
+        }
+    }
+
+    fn begin_stroke(&mut self, pointer: &PointerEvent) {
+        let id = match self.paintable_layer() {
+            Some(id) => id,
+            None => return,
+        };
+        let image = &self.document.layers.layer(id).unwrap().image;
+        let stroke = match self.tool {
+            Tool::Brush => ActiveStroke::Brush(Stroke::new(self.brush, image)),
+            Tool::Spray => {
+                let seed = self.seed.wrapping_add(self.stroke_count);
+                ActiveStroke::Spray(Spray::new(self.spray, image, seed, self.now()))
+            },
+            Tool::Fill => return self.fill(id, pointer),
+        };
+        self.stroke = Some((id, stroke));
+        self.stroke_count += 1;
+        self.paint(pointer);
+    }
+
+    fn paint(&mut self, pointer: &PointerEvent) {
+        let sample = self.sample(pointer);
+        let time = self.now();
+        let (id, stroke) = match self.stroke.as_mut() {
+            Some((id, stroke)) => (*id, stroke),
+            None => return,
+        };
+        let image = &mut self.document.layers.layer_mut(id).unwrap().image;
+        let dirty = match stroke {
+            ActiveStroke::Brush(stroke) => stroke.add_sample(image, sample),
+            ActiveStroke::Spray(spray) => spray.move_to(image, sample, time),
+        };
+        self.upload(id, dirty);
+    }
+
+    fn fill(&mut self, id: LayerId, pointer: &PointerEvent) {
+        let sample = self.sample(pointer);
+        if sample.x < 0.0 || sample.y < 0.0 {
+            return;
+        }
+        let before = self.document.layers.layer(id).unwrap().image.clone();
+        let reference = match self.fill.source {
+            FillSource::CurrentLayer => before.clone(),
+            FillSource::AllLayers => flatten(&self.document.layers),
+        };
+        let image = &mut self.document.layers.layer_mut(id).unwrap().image;
+        let dirty = flood_fill(image, &reference, sample.x as u32, sample.y as u32, &self.fill);
+        let mut snapshot = TileSnapshot::new(before.width(), before.height());
+        snapshot.preserve(&before, dirty);
+        self.history.record_pixels(id, &snapshot, image);
+        self.upload(id, dirty);
+    }
+
+    fn end_stroke(&mut self) {
+        let (id, original) = match self.stroke.take() {
+            Some((id, ActiveStroke::Brush(stroke))) => (id, stroke.original().clone()),
+            Some((id, ActiveStroke::Spray(spray))) => (id, spray.original().clone()),
+            None => return,
+        };
+        if let Some(layer) = self.document.layers.layer(id) {
+            self.history.record_pixels(id, &original, &layer.image);
+        }
+    }
+
+    fn undo(&mut self) {
+        if self.stroke.is_none() {
+            let changed = self.history.undo(&mut self.document.layers);
+            self.apply_change(changed);
+        }
+    }
+
+    fn redo(&mut self) {
+        if self.stroke.is_none() {
+            let changed = self.history.redo(&mut self.document.layers);
+            self.apply_change(changed);
+        }
+    }
+
+    fn apply_change(&mut self, changed: Option<Changed>) {
+        match changed {
+            Some(Changed::Pixels(id, rect)) => self.upload(id, rect),
+            Some(Changed::Layers) => self.sync_layers(),
+            None => {},
+        }
+    }
+
+    /// Lets the spray keep depositing while the pointer rests.
+    fn advance_stroke(&mut self) {
+        let time = self.now();
+        if let Some((id, ActiveStroke::Spray(spray))) = self.stroke.as_mut() {
+            let id = *id;
+            let image = &mut self.document.layers.layer_mut(id).unwrap().image;
+            let dirty = spray.advance(image, time);
+            self.upload(id, dirty);
+        }
+    }
+
+    fn upload(&mut self, id: LayerId, dirty: Rect) {
+        let layer = match self.document.layers.layer(id) {
+            Some(layer) if !dirty.is_empty() => layer,
+            _ => return,
+        };
+        let rgba = layer.image.region(dirty);
+        if let Err(error) = self.engine.write_canvas_region(
+            CanvasLayerId(id.0),
+            dirty.x,
+            dirty.y,
+            dirty.width,
+            dirty.height,
+            &rgba,
+        ) {
+            eprintln!("Failed to update the canvas: {}", error);
+        }
+    }
+
+    /// Hands the layer order and properties to the engine, along with the
+    /// pixels of any layer it does not hold yet.
+    fn sync_layers(&mut self) {
+        let canvas_layers: Vec<CanvasLayer> = self.document.layers
+            .layers()
+            .iter()
+            .map(canvas_layer)
+            .collect();
+        match self.engine.set_canvas_layers(&canvas_layers) {
+            Ok(missing) => {
+                let bounds = Rect::new(0, 0, self.document.width(), self.document.height());
+                missing.into_iter().for_each(|id| self.upload(LayerId(id.0), bounds));
+            },
+            Err(error) => eprintln!("Failed to update the canvas layers: {}", error),
+        }
+    }
+
+    /// Changes the active layer's properties through the history.
+    fn change_active_layer<F: FnOnce(&mut LayerProperties)>(&mut self, change: F) {
+        let id = self.document.layers.active();
+        let mut properties = match self.document.layers.layer(id) {
+            Some(layer) => layer.properties.clone(),
+            None => return,
+        };
+        change(&mut properties);
+        if self.history.set_properties(&mut self.document.layers, id, properties) {
+            self.sync_layers();
+        }
+    }
+
+    /// Selects the layer `offset` places above the active one.
+    fn select_layer(&mut self, offset: isize) {
+        let index = self.document.layers.index_of(self.document.layers.active()).unwrap_or(0) as isize + offset;
+        if index >= 0 {
+            if let Some(id) = self.document.layers.layers().get(index as usize).map(|layer| layer.id()) {
+                self.document.layers.set_active(id);
+            }
+        }
+    }
+
+    /// Moves the active layer `offset` places up the stack.
+    fn move_active_layer(&mut self, offset: isize) {
+        let id = self.document.layers.active();
+        let index = self.document.layers.index_of(id).unwrap_or(0) as isize + offset;
+        if index >= 0 && self.history.move_layer(&mut self.document.layers, id, index as usize) {
+            self.sync_layers();
+        }
+    }
+
+    fn handle_layer_key(&mut self, key: Key) {
+        match key {
+            Key::Char('n') => {
+                let name = format!("Layer {}", self.document.layers.layers().len());
+                self.history.add_layer(&mut self.document.layers, &name);
+                self.sync_layers();
+            },
+            Key::Delete => {
+                let id = self.document.layers.active();
+                if self.history.remove_layer(&mut self.document.layers, id) {
+                    self.sync_layers();
+                }
+            },
+            Key::PageUp => self.select_layer(1),
+            Key::PageDown => self.select_layer(-1),
+            Key::Char(']') => self.move_active_layer(1),
+            Key::Char('[') => self.move_active_layer(-1),
+            Key::Char('v') => self.change_active_layer(|layer| layer.visible = !layer.visible),
+            Key::Char('l') => self.change_active_layer(|layer| layer.locked = !layer.locked),
+            Key::Char('m') => self.change_active_layer(|layer| {
+                let next = BlendMode::ALL
+                    .iter()
+                    .position(|&mode| mode == layer.blend_mode)
+                    .map_or(0, |position| (position + 1) % BlendMode::ALL.len());
+                layer.blend_mode = BlendMode::ALL[next];
+            }),
+            // 1 to 9 set the opacity in tenths, 0 makes the layer opaque.
+            Key::Char(digit @ '0'..='9') => self.change_active_layer(|layer| {
+                let tenths = digit.to_digit(10).unwrap_or(0);
+                layer.opacity = if tenths == 0 { 1.0 } else { tenths as f32 / 10.0 };
+            }),
+            _ => {},
+        }
+    }
+}
+
+impl Draw for PaintApp {
+    fn draw_frame(&mut self) {
+        self.advance_stroke();
+        self.engine.draw_frame();
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.engine.resize(width, height);
+    }
+
+    fn needs_redraw(&self) -> bool {
+        matches!(self.stroke, Some((_, ActiveStroke::Spray(_))))
+    }
+}
+
+impl InputHandler for PaintApp {
+    fn handle_input(&mut self, event: &InputEvent) {
+        match event {
+            InputEvent::PointerDown(pointer) if pointer.button == Some(PointerButton::Primary) => {
+                self.begin_stroke(pointer);
+            },
+            InputEvent::PointerMove(pointer) => self.paint(pointer),
+            InputEvent::PointerUp(pointer) if pointer.button == Some(PointerButton::Primary) => {
+                self.paint(pointer);
+                self.end_stroke();
+            },
+            // Ctrl on most platforms, Command on Mac.
+            InputEvent::Key { key: Key::Char('z'), state: KeyState::Pressed, modifiers }
+                if modifiers.ctrl || modifiers.logo => {
+                if modifiers.shift {
+                    self.redo();
+                } else {
+                    self.undo();
+                }
+            },
+            InputEvent::Key { key: Key::Char('y'), state: KeyState::Pressed, modifiers }
+                if modifiers.ctrl || modifiers.logo => self.redo(),
+            InputEvent::Key { key: Key::Char('s'), state: KeyState::Pressed, modifiers }
+                if modifiers.ctrl || modifiers.logo => {
+                if let Some(path) = self.project.as_ref() {
+                    match self.save_project(path) {
+                        Ok(()) => println!("Saved {}", path.display()),
+                        Err(error) => eprintln!("Failed to save {}: {}", path.display(), error),
+                    }
+                }
+            },
+            InputEvent::Key { key: Key::Char('e'), state: KeyState::Pressed, modifiers }
+                if modifiers.ctrl || modifiers.logo => {
+                if let Some((path, options)) = self.export.clone() {
+                    match self.export_png(&path, &options) {
+                        Ok(()) => println!("Exported {}", path.display()),
+                        Err(error) => eprintln!("Failed to export {}: {}", path.display(), error),
+                    }
+                }
+            },
+            // Layers stay as they are while a stroke paints on one of them.
+            InputEvent::Key { key, state: KeyState::Pressed, modifiers }
+                if !modifiers.ctrl && !modifiers.logo && self.stroke.is_none() => {
+                match key {
+                    Key::Char('b') => self.set_tool(Tool::Brush),
+                    Key::Char('s') => self.set_tool(Tool::Spray),
+                    Key::Char('f') => self.set_tool(Tool::Fill),
+                    key => self.handle_layer_key(*key),
+                }
+            },
+            _ => {},
+        }
+        self.engine.handle_input(event);
+    }
+}
+
+/// How the renderer composites a document layer.
+fn canvas_layer(layer: &Layer) -> CanvasLayer {
+    CanvasLayer {
+        id: CanvasLayerId(layer.id().0),
+        blend_mode: canvas_blend_mode(layer.properties.blend_mode),
+        opacity: layer.properties.opacity,
+        visible: layer.properties.visible,
+    }
+}
+
+fn canvas_blend_mode(mode: BlendMode) -> CanvasBlendMode {
+    match mode {
+        BlendMode::Normal => CanvasBlendMode::Normal,
+        BlendMode::Multiply => CanvasBlendMode::Multiply,
+        BlendMode::Screen => CanvasBlendMode::Screen,
+        BlendMode::Overlay => CanvasBlendMode::Overlay,
+        BlendMode::Darken => CanvasBlendMode::Darken,
+        BlendMode::Lighten => CanvasBlendMode::Lighten,
+        BlendMode::ColorDodge => CanvasBlendMode::ColorDodge,
+        BlendMode::ColorBurn => CanvasBlendMode::ColorBurn,
+        BlendMode::HardLight => CanvasBlendMode::HardLight,
+        BlendMode::SoftLight => CanvasBlendMode::SoftLight,
+        BlendMode::Difference => CanvasBlendMode::Difference,
+        BlendMode::Exclusion => CanvasBlendMode::Exclusion,
+        BlendMode::Add => CanvasBlendMode::Add,
+        BlendMode::Subtract => CanvasBlendMode::Subtract,
+    }
+}
+
+/// Layers opened from a file are named after it.
+fn layer_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "Imported".to_string())
+}
+
+fn is_open_raster(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case(ora::EXTENSION))
+}
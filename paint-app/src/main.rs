@@ -1,23 +1,281 @@
-use gui;
 use std::env;
-use engine::VulkanEngine;
-use cgci::Draw;
+use std::process;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use engine::{EngineError, MessageId, ValidationConfig, VulkanEngine};
+use ash::vk;
+use engine::shaders::ShaderLibrary;
+use cgci::{read_log, Application, CanvasRenderer, Clock, LogEntry, LoggedEvent};
+use gui::{Pacing, Recorder, Replay};
+use software::SoftwareRenderer;
+use paint::png::{BitDepth, PngOptions};
+use app::PaintApp;
+
+mod app;
+mod logger;
 
 const APP_NAME: &str = "PaintApp";
 
 fn main() {
+    // The validation configuration already picks which Vulkan messages are
+    // reported, so by default everything that reaches the logger is shown.
+    let log_level = env::var("LOG_LEVEL")
+        .map(|level| level.parse().expect("Wrong value for LOG_LEVEL environmental value"))
+        .unwrap_or(log::LevelFilter::Trace);
+    logger::init(log_level);
     let validation_layers_env_var: String = env::var("VALIDATION_LAYERS").unwrap_or("0".to_string());
-    let validation_layers = if validation_layers_env_var == "1".to_string() {
+    let validation_layers = if validation_layers_env_var == "1" {
         true
-    } else if validation_layers_env_var == "0".to_string() {
+    } else if validation_layers_env_var == "0" {
         false
     } else {
         panic!("Wrong value for VALIDATION_LAYERS environmental value")
     };
+    let validation = if validation_layers { Some(validation_config()) } else { None };
+    let shader_hot_reload_dir = env::var("SHADER_HOT_RELOAD").ok();
+    if env::var("LIST_DEVICES").map(|value| value == "1").unwrap_or(false) {
+        match VulkanEngine::list_devices(APP_NAME) {
+            Ok(candidates) => candidates.iter().for_each(|candidate| println!("{}", candidate)),
+            Err(error) => eprintln!("Failed to list devices: {}", error),
+        }
+        return;
+    }
+    // Skips Vulkan altogether, as happens anyway when it fails to start.
+    let software_renderer = env::var("SOFTWARE_RENDERER").map(|value| value == "1").unwrap_or(false);
+    if env::var("HEADLESS").map(|value| value == "1").unwrap_or(false) {
+        let renderer: Box<dyn CanvasRenderer> = if software_renderer {
+            Box::new(SoftwareRenderer::new_headless(800, 600))
+        } else {
+            let vulkan_engine = load_shaders(shader_hot_reload_dir.as_deref())
+                .and_then(|shaders| VulkanEngine::new_headless(APP_NAME, validation, 800, 600, shaders, None));
+            match vulkan_engine {
+                Ok(vulkan_engine) => Box::new(configure_engine(vulkan_engine)),
+                Err(error) => {
+                    eprintln!("Failed to start the Vulkan engine: {}", error);
+                    eprintln!("Falling back to the software renderer");
+                    Box::new(SoftwareRenderer::new_headless(800, 600))
+                }
+            }
+        };
+        let clock = Clock::new();
+        let mut app = create_app(renderer, clock.clone());
+        // Without a window there is nothing to interact with, so a headless
+        // run only converts the opened document, after replaying a
+        // recorded session on it if there is one.
+        if let Some(mut replay) = replay_settings() {
+            replay.play_all(&mut app, &clock);
+        }
+        let export = export_settings();
+        let project = env::var("SAVE_PROJECT").ok().map(PathBuf::from);
+        if export.is_none() && project.is_none() {
+            panic!("EXPORT_PNG or SAVE_PROJECT has to be set when running headless");
+        }
+        if let Some((path, options)) = export {
+            if let Err(error) = app.export_png(&path, &options) {
+                eprintln!("Failed to export {}: {}", path.display(), error);
+                process::exit(1);
+            }
+        }
+        if let Some(path) = project {
+            if let Err(error) = app.save_project(&path) {
+                eprintln!("Failed to save {}: {}", path.display(), error);
+                process::exit(1);
+            }
+        }
+        return;
+    }
     let main_window = gui::MainWindow::new(APP_NAME, 800, 600);
-    let engine: Box<dyn Draw> = Box::new(
-        VulkanEngine::new(APP_NAME, validation_layers, &main_window)
-    );
+    let vulkan_engine = if software_renderer {
+        None
+    } else {
+        let vulkan_engine = load_shaders(shader_hot_reload_dir.as_deref())
+            .and_then(|shaders| VulkanEngine::new(APP_NAME, validation, &main_window, shaders, None));
+        match vulkan_engine {
+            Ok(vulkan_engine) => Some(vulkan_engine),
+            Err(error) => {
+                eprintln!("Failed to start the Vulkan engine: {}", error);
+                eprintln!("Falling back to the software renderer");
+                None
+            }
+        }
+    };
+    let renderer: Box<dyn CanvasRenderer> = match vulkan_engine {
+        Some(vulkan_engine) => {
+            let mut vulkan_engine = configure_engine(vulkan_engine);
+            if let Some(shader_root) = shader_hot_reload_dir {
+                vulkan_engine.watch_shaders(Path::new(&shader_root));
+            }
+            Box::new(vulkan_engine)
+        },
+        None => match SoftwareRenderer::new(&main_window) {
+            Ok(software_renderer) => Box::new(software_renderer),
+            Err(error) => {
+                eprintln!("Failed to start the software renderer: {}", error);
+                process::exit(1);
+            }
+        },
+    };
+    let clock = Clock::new();
+    let engine: Box<dyn Application> = Box::new(create_app(renderer, clock.clone()));
     println!("{}", main_window.get_details());
-    gui::start_main_loop(main_window.event_loop, engine);
-}
\ No newline at end of file
+    if let Some(replay) = replay_settings() {
+        gui::start_replay_loop(main_window.event_loop, engine, clock, replay);
+        return;
+    }
+    let recorder = env::var("RECORD_INPUT").ok().map(|path| {
+        let mut recorder = Recorder::create(Path::new(&path))
+            .unwrap_or_else(|error| panic!("Failed to create {}: {}", path, error));
+        // A replay starts at the size the window had, before any resizes.
+        let entry = LogEntry {
+            time: 0.0,
+            event: LoggedEvent::Resize {
+                width: main_window.window_width,
+                height: main_window.window_height,
+            },
+        };
+        recorder.record(&entry)
+            .unwrap_or_else(|error| panic!("Failed to write {}: {}", path, error));
+        recorder
+    });
+    gui::start_main_loop(main_window.event_loop, main_window.window, engine, clock, recorder);
+}
+
+/// The shaders from `SHADER_DIR`, from the hot reload directory, or the
+/// ones built in. Only the Vulkan engine needs them, so failing to load
+/// them falls back to the software renderer like any other engine error.
+fn load_shaders(shader_hot_reload_dir: Option<&str>) -> Result<ShaderLibrary, EngineError> {
+    let shaders = match (env::var("SHADER_DIR"), shader_hot_reload_dir) {
+        (Ok(shader_dir), _) => ShaderLibrary::from_dir(Path::new(&shader_dir)),
+        (Err(_), Some(shader_root)) => ShaderLibrary::from_dir(&Path::new(shader_root).join("spv")),
+        (Err(_), None) => ShaderLibrary::embedded(),
+    };
+    Ok(shaders?)
+}
+
+fn configure_engine(mut vulkan_engine: VulkanEngine) -> VulkanEngine {
+    if let Ok(frames_in_flight) = env::var("FRAMES_IN_FLIGHT") {
+        let frames_in_flight = frames_in_flight.parse()
+            .expect("Wrong value for FRAMES_IN_FLIGHT environmental value");
+        if let Err(error) = vulkan_engine.set_frames_in_flight(frames_in_flight) {
+            eprintln!("Failed to change the number of frames in flight: {}", error);
+        }
+    }
+    if let Ok(frame_rate_cap) = env::var("FRAME_RATE_CAP") {
+        let frame_rate_cap = frame_rate_cap.parse()
+            .expect("Wrong value for FRAME_RATE_CAP environmental value");
+        vulkan_engine.set_frame_rate_cap(Some(frame_rate_cap));
+    }
+    vulkan_engine
+}
+
+fn create_app(renderer: Box<dyn CanvasRenderer>, clock: Clock) -> PaintApp {
+    let mut app = PaintApp::new(renderer, clock);
+    if let Ok(seed) = env::var("PAINT_SEED") {
+        app.set_seed(seed.parse().expect("Wrong value for PAINT_SEED environmental value"));
+    }
+    if let Ok(budget) = env::var("HISTORY_BUDGET_MB") {
+        let budget: usize = budget.parse()
+            .expect("Wrong value for HISTORY_BUDGET_MB environmental value");
+        app.set_history_budget(budget * 1024 * 1024);
+    }
+    if let Ok(path) = env::var("OPEN_PROJECT") {
+        if let Err(error) = app.open_project(Path::new(&path)) {
+            eprintln!("Failed to open {}: {}", path, error);
+        }
+        app.set_project_path(Path::new(&path));
+    }
+    if let Ok(path) = env::var("SAVE_PROJECT") {
+        app.set_project_path(Path::new(&path));
+    }
+    if let Ok(path) = env::var("OPEN_PNG") {
+        if let Err(error) = app.open_png(Path::new(&path)) {
+            eprintln!("Failed to open {}: {}", path, error);
+        }
+    }
+    if let Ok(path) = env::var("IMPORT_PNG") {
+        if let Err(error) = app.import_png_layer(Path::new(&path)) {
+            eprintln!("Failed to import {}: {}", path, error);
+        }
+    }
+    if let Some((path, options)) = export_settings() {
+        app.set_export(&path, options);
+    }
+    app
+}
+
+/// What the validation layers check and report, from `VALIDATION_LAYER_NAMES`,
+/// `VALIDATION_SEVERITY` (the least severe one reported),
+/// `VALIDATION_FEATURES` and `VALIDATION_SUPPRESS`. Lists are separated by
+/// commas.
+fn validation_config() -> ValidationConfig {
+    let mut config = ValidationConfig::default();
+    let layers = env_list("VALIDATION_LAYER_NAMES");
+    if !layers.is_empty() {
+        config.layers = layers;
+    }
+    if let Ok(severity) = env::var("VALIDATION_SEVERITY") {
+        let severities = [
+            ("verbose", vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE),
+            ("info", vk::DebugUtilsMessageSeverityFlagsEXT::INFO),
+            ("warning", vk::DebugUtilsMessageSeverityFlagsEXT::WARNING),
+            ("error", vk::DebugUtilsMessageSeverityFlagsEXT::ERROR),
+        ];
+        let least = severities.iter()
+            .position(|(name, _)| *name == severity)
+            .expect("Wrong value for VALIDATION_SEVERITY environmental value");
+        config.severities = severities[least..]
+            .iter()
+            .fold(vk::DebugUtilsMessageSeverityFlagsEXT::empty(), |flags, (_, flag)| flags | *flag);
+    }
+    for feature in env_list("VALIDATION_FEATURES") {
+        match feature.as_str() {
+            "best-practices" => config.best_practices = true,
+            "synchronization" => config.synchronization = true,
+            "gpu-assisted" => config.gpu_assisted = true,
+            _ => panic!("Wrong value for VALIDATION_FEATURES environmental value"),
+        }
+    }
+    for id in env_list("VALIDATION_SUPPRESS") {
+        config.suppress(MessageId::parse(&id));
+    }
+    config
+}
+
+fn env_list(name: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Where and how the flattened document is exported, from `EXPORT_PNG` and
+/// `EXPORT_PNG_DEPTH`.
+fn export_settings() -> Option<(PathBuf, PngOptions)> {
+    let path = PathBuf::from(env::var("EXPORT_PNG").ok()?);
+    let bit_depth = match env::var("EXPORT_PNG_DEPTH").as_deref() {
+        Ok("16") => BitDepth::Sixteen,
+        Ok("8") | Err(_) => BitDepth::Eight,
+        Ok(_) => panic!("Wrong value for EXPORT_PNG_DEPTH environmental value"),
+    };
+    Some((path, PngOptions { bit_depth, ..PngOptions::default() }))
+}
+
+/// A session recorded with `RECORD_INPUT` to play back, from `REPLAY_INPUT`
+/// and `REPLAY_PACING`. The same seed and starting document are needed to
+/// paint the same canvas again.
+fn replay_settings() -> Option<Replay> {
+    let path = env::var("REPLAY_INPUT").ok()?;
+    let pacing = match env::var("REPLAY_PACING").as_deref() {
+        Ok("realtime") => Pacing::RealTime,
+        Ok("fast") | Err(_) => Pacing::AsFastAsPossible,
+        Ok(_) => panic!("Wrong value for REPLAY_PACING environmental value"),
+    };
+    let file = File::open(&path).unwrap_or_else(|error| panic!("Failed to open {}: {}", path, error));
+    let entries = read_log(BufReader::new(file))
+        .unwrap_or_else(|error| panic!("Failed to read {}: {}", path, error));
+    Some(Replay::new(entries, pacing))
+}
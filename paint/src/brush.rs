@@ -0,0 +1,458 @@
+use crate::image::{over, Image, Rect};
+use crate::tile::{tile_rect, tiles_in, TileMap, TileSnapshot, TILE_SIZE};
+
+/// Dabs are never placed closer together than this many pixels, however
+/// small the brush gets.
+const MIN_SPACING: f32 = 0.5;
+
+/// A pointer position in image pixels with its pen pressure in 0.0..=1.0.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sample {
+    pub x: f32,
+    pub y: f32,
+    pub pressure: f32,
+}
+
+impl Sample {
+    pub fn new(x: f32, y: f32, pressure: f32) -> Self {
+        Sample { x, y, pressure }
+    }
+
+    fn lerp(&self, other: &Sample, t: f32) -> Sample {
+        Sample {
+            x: self.x + (other.x - self.x) * t,
+            y: self.y + (other.y - self.y) * t,
+            pressure: self.pressure + (other.pressure - self.pressure) * t,
+        }
+    }
+}
+
+/// How strongly pen pressure scales each brush parameter. At 0.0 pressure
+/// is ignored, at 1.0 the parameter goes from zero at no pressure to its
+/// full value at full pressure.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PressureDynamics {
+    pub size: f32,
+    pub hardness: f32,
+    pub opacity: f32,
+    pub flow: f32,
+    pub spacing: f32,
+}
+
+impl PressureDynamics {
+    fn scale(amount: f32, pressure: f32) -> f32 {
+        1.0 - amount.clamp(0.0, 1.0) * (1.0 - pressure.clamp(0.0, 1.0))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BrushSettings {
+    pub color: [u8; 4],
+    /// Dab diameter in pixels.
+    pub size: f32,
+    /// Fraction of the radius painted at full strength before the edge
+    /// starts to fade out.
+    pub hardness: f32,
+    /// The most a single stroke can cover, however often it overlaps itself.
+    pub opacity: f32,
+    /// How much each dab adds towards the stroke opacity.
+    pub flow: f32,
+    /// Distance between dabs as a fraction of the dab diameter.
+    pub spacing: f32,
+    pub pressure: PressureDynamics,
+}
+
+impl Default for BrushSettings {
+    fn default() -> Self {
+        BrushSettings {
+            color: [0, 0, 0, 255],
+            size: 12.0,
+            hardness: 0.8,
+            opacity: 1.0,
+            flow: 1.0,
+            spacing: 0.15,
+            pressure: PressureDynamics {
+                size: 1.0,
+                ..PressureDynamics::default()
+            },
+        }
+    }
+}
+
+impl BrushSettings {
+    /// The dab this brush leaves at a sample.
+    pub fn dab(&self, sample: Sample) -> Dab {
+        let pressure = sample.pressure;
+        let scale = |base: f32, amount: f32| base * PressureDynamics::scale(amount, pressure);
+        Dab {
+            x: sample.x,
+            y: sample.y,
+            radius: scale(self.size, self.pressure.size).max(0.0) / 2.0,
+            hardness: scale(self.hardness, self.pressure.hardness).clamp(0.0, 1.0),
+            opacity: scale(self.opacity, self.pressure.opacity).clamp(0.0, 1.0),
+            flow: scale(self.flow, self.pressure.flow).clamp(0.0, 1.0),
+        }
+    }
+
+    /// Distance to the dab after one placed at `pressure`.
+    fn spacing_at(&self, pressure: f32) -> f32 {
+        let diameter = self.size * PressureDynamics::scale(self.pressure.size, pressure);
+        let spacing = self.spacing * PressureDynamics::scale(self.pressure.spacing, pressure);
+        (diameter * spacing).max(MIN_SPACING)
+    }
+}
+
+/// A single stamp of the brush tip.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Dab {
+    pub x: f32,
+    pub y: f32,
+    pub radius: f32,
+    pub hardness: f32,
+    pub opacity: f32,
+    pub flow: f32,
+}
+
+impl Dab {
+    /// Strength of the dab at a distance from its center, before flow.
+    fn falloff(&self, distance: f32) -> f32 {
+        if distance >= self.radius {
+            return 0.0;
+        }
+        // At least a pixel of fade keeps hard brushes anti-aliased.
+        let fade = ((1.0 - self.hardness) * self.radius).max(self.radius.min(1.0));
+        let solid = self.radius - fade;
+        if distance <= solid {
+            return 1.0;
+        }
+        let t = (self.radius - distance) / fade;
+        t * t * (3.0 - 2.0 * t)
+    }
+
+    /// Pixels the dab may touch, cut off at the image edge.
+    pub(crate) fn bounds(&self, image: &Image) -> Rect {
+        let left = (self.x - self.radius).floor().max(0.0) as u32;
+        let top = (self.y - self.radius).floor().max(0.0) as u32;
+        let right = ((self.x + self.radius).ceil().max(0.0) as u32).min(image.width());
+        let bottom = ((self.y + self.radius).ceil().max(0.0) as u32).min(image.height());
+        if right <= left || bottom <= top {
+            return Rect::default();
+        }
+        Rect::new(left, top, right - left, bottom - top)
+    }
+}
+
+/// Composites a single dab straight onto the image, without a stroke to cap
+/// how far overlapping dabs build up.
+pub fn stamp(image: &mut Image, dab: &Dab, color: [u8; 4]) -> Rect {
+    let bounds = dab.bounds(image);
+    if bounds.is_empty() || dab.opacity <= 0.0 || dab.flow <= 0.0 {
+        return Rect::default();
+    }
+    for y in bounds.y..bounds.bottom() {
+        for x in bounds.x..bounds.right() {
+            let dx = x as f32 + 0.5 - dab.x;
+            let dy = y as f32 + 0.5 - dab.y;
+            let falloff = dab.falloff((dx * dx + dy * dy).sqrt());
+            if falloff > 0.0 {
+                let coverage = falloff * dab.flow * dab.opacity;
+                image.set_pixel(x, y, over(image.pixel(x, y), color, coverage));
+            }
+        }
+    }
+    bounds
+}
+
+/// Turns pointer samples into dabs spaced evenly along the path through
+/// them, carrying the leftover distance from one segment to the next.
+#[derive(Clone, Debug, Default)]
+pub struct DabSpacer {
+    last: Option<Sample>,
+    distance_to_next: f32,
+}
+
+impl DabSpacer {
+    pub fn new() -> Self {
+        DabSpacer::default()
+    }
+
+    pub fn add_sample(&mut self, settings: &BrushSettings, sample: Sample) -> Vec<Dab> {
+        let mut dabs = vec![];
+        match self.last {
+            None => {
+                dabs.push(settings.dab(sample));
+                self.distance_to_next = settings.spacing_at(sample.pressure);
+            },
+            Some(last) => {
+                let dx = sample.x - last.x;
+                let dy = sample.y - last.y;
+                let length = (dx * dx + dy * dy).sqrt();
+                let mut travelled = 0.0;
+                while length - travelled >= self.distance_to_next {
+                    travelled += self.distance_to_next;
+                    let point = last.lerp(&sample, travelled / length);
+                    dabs.push(settings.dab(point));
+                    self.distance_to_next = settings.spacing_at(point.pressure);
+                }
+                self.distance_to_next -= length - travelled;
+            },
+        }
+        self.last = Some(sample);
+        dabs
+    }
+}
+
+/// A stroke in progress on one image. Overlapping dabs build up towards the
+/// stroke opacity but never past it, so the stroke keeps the pixels it
+/// painted over and composites onto those.
+pub struct Stroke {
+    settings: BrushSettings,
+    spacer: DabSpacer,
+    /// Image pixels of each touched tile from before the stroke.
+    original: TileSnapshot,
+    /// Stroke opacity reached so far, per pixel of each touched tile.
+    coverage: TileMap<Vec<f32>>,
+}
+
+impl Stroke {
+    pub fn new(settings: BrushSettings, image: &Image) -> Self {
+        Stroke {
+            settings,
+            spacer: DabSpacer::new(),
+            original: TileSnapshot::new(image.width(), image.height()),
+            coverage: TileMap::new(image.width(), image.height()),
+        }
+    }
+
+    pub fn settings(&self) -> &BrushSettings {
+        &self.settings
+    }
+
+    /// The tiles the stroke has touched, as they were before it.
+    pub fn original(&self) -> &TileSnapshot {
+        &self.original
+    }
+
+    /// Extends the stroke to a new sample and returns the pixels that may
+    /// have changed.
+    pub fn add_sample(&mut self, image: &mut Image, sample: Sample) -> Rect {
+        let dabs = self.spacer.add_sample(&self.settings, sample);
+        dabs.iter().fold(Rect::default(), |dirty, dab| {
+            dirty.union(&self.apply_dab(image, dab))
+        })
+    }
+
+    pub fn apply_dab(&mut self, image: &mut Image, dab: &Dab) -> Rect {
+        let bounds = dab.bounds(image);
+        if bounds.is_empty() || dab.opacity <= 0.0 || dab.flow <= 0.0 {
+            return Rect::default();
+        }
+        self.original.preserve(image, bounds);
+        let (columns, rows) = tiles_in(bounds);
+        for row in rows {
+            for column in columns.clone() {
+                let tile = tile_rect(column, row, image.width(), image.height());
+                self.coverage.get_or_insert_with(column, row, || {
+                    vec![0.0; tile.width as usize * tile.height as usize]
+                });
+            }
+        }
+
+        for y in bounds.y..bounds.bottom() {
+            for x in bounds.x..bounds.right() {
+                let dx = x as f32 + 0.5 - dab.x;
+                let dy = y as f32 + 0.5 - dab.y;
+                let falloff = dab.falloff((dx * dx + dy * dy).sqrt());
+                if falloff <= 0.0 {
+                    continue;
+                }
+                let (column, row) = (x / TILE_SIZE, y / TILE_SIZE);
+                let tile_width = tile_rect(column, row, image.width(), image.height()).width;
+                let index = ((y % TILE_SIZE) * tile_width + x % TILE_SIZE) as usize;
+
+                let coverage = &mut self.coverage.get_mut(column, row).unwrap()[index];
+                if dab.opacity <= *coverage {
+                    continue;
+                }
+                *coverage += (dab.opacity - *coverage) * falloff * dab.flow;
+
+                let original = &self.original.tiles().get(column, row).unwrap()[index * 4..index * 4 + 4];
+                let original = [original[0], original[1], original[2], original[3]];
+                image.set_pixel(x, y, over(original, self.settings.color, *coverage));
+            }
+        }
+        bounds
+    }
+}
+
+/// Paints a whole stroke at once. Gives the same pixels as feeding the
+/// samples to a `Stroke` one at a time, which is what the canvas receives.
+pub fn render_stroke(image: &mut Image, settings: &BrushSettings, samples: &[Sample]) -> Rect {
+    let mut stroke = Stroke::new(*settings, image);
+    samples.iter().fold(Rect::default(), |dirty, sample| {
+        dirty.union(&stroke.add_sample(image, *sample))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+    const RED: [u8; 4] = [255, 0, 0, 255];
+
+    fn assert_close(actual: f32, expected: f32) {
+        assert!((actual - expected).abs() < 1e-5, "{} is not {}", actual, expected);
+    }
+
+    fn fixed_brush() -> BrushSettings {
+        BrushSettings {
+            color: RED,
+            size: 10.0,
+            hardness: 1.0,
+            opacity: 1.0,
+            flow: 1.0,
+            spacing: 0.25,
+            pressure: PressureDynamics::default(),
+        }
+    }
+
+    #[test]
+    fn dabs_are_spaced_evenly_along_the_path() {
+        let settings = fixed_brush();
+        let mut spacer = DabSpacer::new();
+        let first = spacer.add_sample(&settings, Sample::new(0.0, 0.0, 1.0));
+        let along = spacer.add_sample(&settings, Sample::new(10.0, 0.0, 1.0));
+        let positions: Vec<f32> = first.iter().chain(along.iter()).map(|dab| dab.x).collect();
+        assert_eq!(positions, vec![0.0, 2.5, 5.0, 7.5, 10.0]);
+        assert!(along.iter().all(|dab| dab.y == 0.0));
+    }
+
+    #[test]
+    fn spacing_carries_over_between_samples() {
+        let settings = fixed_brush();
+        let mut spacer = DabSpacer::new();
+        spacer.add_sample(&settings, Sample::new(0.0, 0.0, 1.0));
+        assert!(spacer.add_sample(&settings, Sample::new(1.0, 0.0, 1.0)).is_empty());
+        assert!(spacer.add_sample(&settings, Sample::new(2.0, 0.0, 1.0)).is_empty());
+        let dabs = spacer.add_sample(&settings, Sample::new(3.0, 0.0, 1.0));
+        assert_eq!(dabs.len(), 1);
+        assert_close(dabs[0].x, 2.5);
+    }
+
+    #[test]
+    fn spacing_never_drops_below_the_minimum() {
+        let settings = BrushSettings { size: 1.0, spacing: 0.01, ..fixed_brush() };
+        let mut spacer = DabSpacer::new();
+        spacer.add_sample(&settings, Sample::new(0.0, 0.0, 1.0));
+        let dabs = spacer.add_sample(&settings, Sample::new(2.0, 0.0, 1.0));
+        assert_eq!(dabs.len(), (2.0 / MIN_SPACING) as usize);
+    }
+
+    #[test]
+    fn pressure_scales_size_opacity_and_flow() {
+        let settings = BrushSettings {
+            size: 20.0,
+            opacity: 0.8,
+            flow: 0.6,
+            pressure: PressureDynamics { size: 1.0, opacity: 0.5, flow: 1.0, ..PressureDynamics::default() },
+            ..fixed_brush()
+        };
+        let full = settings.dab(Sample::new(0.0, 0.0, 1.0));
+        assert_close(full.radius, 10.0);
+        assert_close(full.opacity, 0.8);
+        assert_close(full.flow, 0.6);
+
+        let half = settings.dab(Sample::new(0.0, 0.0, 0.5));
+        assert_close(half.radius, 5.0);
+        assert_close(half.opacity, 0.6);
+        assert_close(half.flow, 0.3);
+        assert_close(half.hardness, 1.0);
+
+        let none = settings.dab(Sample::new(0.0, 0.0, 0.0));
+        assert_close(none.radius, 0.0);
+        assert_close(none.opacity, 0.4);
+        assert_close(none.flow, 0.0);
+    }
+
+    #[test]
+    fn pressure_tightens_spacing_with_size() {
+        let settings = BrushSettings {
+            pressure: PressureDynamics { size: 1.0, ..PressureDynamics::default() },
+            ..fixed_brush()
+        };
+        assert_close(settings.spacing_at(1.0), 2.5);
+        assert_close(settings.spacing_at(0.5), 1.25);
+        assert_close(settings.spacing_at(0.0), MIN_SPACING);
+    }
+
+    #[test]
+    fn soft_dabs_fade_smoothly_to_the_edge() {
+        let dab = Dab { x: 0.0, y: 0.0, radius: 10.0, hardness: 0.5, opacity: 1.0, flow: 1.0 };
+        assert_close(dab.falloff(0.0), 1.0);
+        assert_close(dab.falloff(5.0), 1.0);
+        assert_close(dab.falloff(7.5), 0.5);
+        assert_close(dab.falloff(9.0), 0.104);
+        assert_close(dab.falloff(10.0), 0.0);
+    }
+
+    #[test]
+    fn hard_dabs_keep_a_pixel_of_fade() {
+        let dab = Dab { x: 0.0, y: 0.0, radius: 10.0, hardness: 1.0, opacity: 1.0, flow: 1.0 };
+        assert_close(dab.falloff(9.0), 1.0);
+        assert_close(dab.falloff(9.5), 0.5);
+        assert_close(dab.falloff(10.5), 0.0);
+    }
+
+    #[test]
+    fn overlapping_dabs_build_up_to_the_stroke_opacity() {
+        let settings = BrushSettings { opacity: 0.5, flow: 0.3, ..fixed_brush() };
+        let mut image = Image::filled(32, 32, WHITE);
+        let samples: Vec<Sample> = (0..40).map(|_| Sample::new(16.0, 16.0, 1.0)).collect();
+        let mut stroke = Stroke::new(settings, &image);
+        let dab = settings.dab(samples[0]);
+        stroke.apply_dab(&mut image, &dab);
+        let after_one = image.pixel(16, 16);
+        assert_eq!(after_one, over(WHITE, RED, 0.5 * 0.3));
+        for _ in 0..40 {
+            stroke.apply_dab(&mut image, &dab);
+        }
+        assert_eq!(image.pixel(16, 16), over(WHITE, RED, 0.5));
+    }
+
+    #[test]
+    fn whole_strokes_match_strokes_fed_a_sample_at_a_time() {
+        let settings = BrushSettings {
+            hardness: 0.4,
+            flow: 0.5,
+            pressure: PressureDynamics { size: 1.0, flow: 0.5, ..PressureDynamics::default() },
+            ..fixed_brush()
+        };
+        let samples = [
+            Sample::new(4.0, 4.0, 0.2),
+            Sample::new(20.0, 9.0, 0.9),
+            Sample::new(11.0, 27.0, 0.6),
+            Sample::new(12.0, 26.0, 1.0),
+        ];
+        let mut whole = Image::filled(32, 32, WHITE);
+        let whole_dirty = render_stroke(&mut whole, &settings, &samples);
+
+        let mut incremental = Image::filled(32, 32, WHITE);
+        let mut stroke = Stroke::new(settings, &incremental);
+        let dirty = samples.iter().fold(Rect::default(), |dirty, sample| {
+            dirty.union(&stroke.add_sample(&mut incremental, *sample))
+        });
+        assert_eq!(whole.pixels(), incremental.pixels());
+        assert_eq!(whole_dirty, dirty);
+        assert_ne!(whole.pixels(), Image::filled(32, 32, WHITE).pixels());
+    }
+
+    #[test]
+    fn dabs_are_cut_off_at_the_image_edge() {
+        let mut image = Image::filled(8, 8, WHITE);
+        let dab = Dab { x: 0.0, y: 0.0, radius: 3.0, hardness: 1.0, opacity: 1.0, flow: 1.0 };
+        assert_eq!(stamp(&mut image, &dab, RED), Rect::new(0, 0, 3, 3));
+        assert_eq!(image.pixel(0, 0), RED);
+        assert_eq!(image.pixel(4, 4), WHITE);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::image::{to_u8, Image, Rect};
+use crate::layer::{BlendMode, LayerStack};
+
+/// Composites one straight-alpha source pixel onto a straight-alpha
+/// backdrop, both with channels in 0.0..=1.0. The blended color only shows
+/// where the backdrop is opaque; elsewhere the source keeps its own color.
+///
+/// The engine's compositing shader does the same arithmetic, so the two
+/// differ only by float rounding.
+pub fn composite_pixel(backdrop: [f32; 4], source: [f32; 4], opacity: f32, mode: BlendMode) -> [f32; 4] {
+    let source_alpha = source[3] * opacity;
+    if source_alpha <= 0.0 {
+        return backdrop;
+    }
+    let backdrop_alpha = backdrop[3];
+    let alpha = source_alpha + backdrop_alpha * (1.0 - source_alpha);
+    let mut result = [0.0; 4];
+    for channel in 0..3 {
+        let blended = (1.0 - backdrop_alpha) * source[channel]
+            + backdrop_alpha * mode.blend(backdrop[channel], source[channel]);
+        result[channel] = (source_alpha * blended
+            + backdrop_alpha * (1.0 - source_alpha) * backdrop[channel]) / alpha;
+    }
+    result[3] = alpha;
+    result
+}
+
+fn to_float(pixel: &[u8]) -> [f32; 4] {
+    [
+        pixel[0] as f32 / 255.0,
+        pixel[1] as f32 / 255.0,
+        pixel[2] as f32 / 255.0,
+        pixel[3] as f32 / 255.0,
+    ]
+}
+
+/// The visible layers merged into one image, as the engine shows them. This
+/// is the reference the GPU compositor is checked against.
+pub fn flatten(stack: &LayerStack) -> Image {
+    let mut image = Image::new(stack.width(), stack.height());
+    flatten_region(stack, stack_bounds(stack), &mut image);
+    image
+}
+
+fn stack_bounds(stack: &LayerStack) -> Rect {
+    Rect::new(0, 0, stack.width(), stack.height())
+}
+
+/// Merges the visible layers inside `rect` into the same pixels of
+/// `target`, which has to be the size of the stack.
+pub fn flatten_region(stack: &LayerStack, rect: Rect, target: &mut Image) {
+    let layers: Vec<_> = stack
+        .layers()
+        .iter()
+        .filter(|layer| layer.properties.visible)
+        .map(|layer| (&layer.image, layer.properties.opacity, layer.properties.blend_mode))
+        .collect();
+    composite_region(&layers, rect.intersection(&stack_bounds(stack)), target);
+}
+
+/// Composites images, from bottom to top, each with its opacity and blend
+/// mode, into `rect` of `target`. The images have to be the size of
+/// `target`. For anything that keeps layers outside of a `LayerStack`.
+pub fn composite_region(layers: &[(&Image, f32, BlendMode)], rect: Rect, target: &mut Image) {
+    let rect = rect.intersection(&target.bounds());
+    let layers: Vec<_> = layers.iter().filter(|(_, opacity, _)| *opacity > 0.0).collect();
+    for y in rect.y..rect.bottom() {
+        for x in rect.x..rect.right() {
+            let offset = (y as usize * target.width() as usize + x as usize) * 4;
+            let mut pixel = [0.0; 4];
+            for (image, opacity, mode) in layers.iter() {
+                let source = to_float(&image.pixels()[offset..offset + 4]);
+                pixel = composite_pixel(pixel, source, opacity.clamp(0.0, 1.0), *mode);
+            }
+            target.set_pixel(x, y, [
+                to_u8(pixel[0] * 255.0),
+                to_u8(pixel[1] * 255.0),
+                to_u8(pixel[2] * 255.0),
+                to_u8(pixel[3] * 255.0),
+            ]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layer::LayerProperties;
+
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    /// A one pixel stack of `background` with `color` above it.
+    fn flatten_one(background: [u8; 4], color: [u8; 4], mode: BlendMode, opacity: f32, visible: bool) -> [u8; 4] {
+        let mut stack = LayerStack::new(1, 1, background);
+        let id = stack.add_layer("Layer");
+        let layer = stack.layer_mut(id).unwrap();
+        layer.image.set_pixel(0, 0, color);
+        layer.properties = LayerProperties {
+            opacity,
+            visible,
+            blend_mode: mode,
+            ..LayerProperties::new("Layer")
+        };
+        flatten(&stack).pixel(0, 0)
+    }
+
+    #[test]
+    fn blend_modes_composite_onto_opaque_backdrops() {
+        let cases = [
+            (BlendMode::Normal, [64, 128, 255, 255], [0, 0, 0, 255], [0, 0, 0, 255]),
+            (BlendMode::Multiply, [255, 0, 0, 255], [128, 128, 128, 255], [128, 0, 0, 255]),
+            (BlendMode::Screen, [255, 0, 0, 255], [0, 0, 255, 255], [255, 0, 255, 255]),
+            (BlendMode::Overlay, [0, 255, 64, 255], WHITE, [0, 255, 128, 255]),
+            (BlendMode::Darken, [64, 200, 0, 255], [128, 128, 128, 255], [64, 128, 0, 255]),
+            (BlendMode::Lighten, [64, 200, 0, 255], [128, 128, 128, 255], [128, 200, 128, 255]),
+            (BlendMode::Add, [200, 100, 0, 255], [100, 100, 100, 255], [255, 200, 100, 255]),
+            (BlendMode::Difference, [64, 128, 255, 255], WHITE, [191, 127, 0, 255]),
+        ];
+        for (mode, backdrop, source, expected) in cases.iter() {
+            assert_eq!(flatten_one(*backdrop, *source, *mode, 1.0, true), *expected, "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn opacity_mixes_with_the_backdrop() {
+        assert_eq!(flatten_one(WHITE, [0, 0, 0, 255], BlendMode::Normal, 0.5, true), [128, 128, 128, 255]);
+        assert_eq!(flatten_one(WHITE, [0, 0, 0, 128], BlendMode::Normal, 1.0, true), [127, 127, 127, 255]);
+        assert_eq!(flatten_one(WHITE, [0, 0, 0, 255], BlendMode::Multiply, 0.0, true), WHITE);
+    }
+
+    #[test]
+    fn hidden_layers_are_left_out() {
+        assert_eq!(flatten_one(WHITE, [0, 0, 0, 255], BlendMode::Normal, 1.0, false), WHITE);
+    }
+
+    #[test]
+    fn blending_only_applies_over_opaque_backdrop() {
+        let transparent = [0, 0, 0, 0];
+        let red = [255, 0, 0, 255];
+        assert_eq!(composite_pixel([0.0; 4], [1.0, 0.0, 0.0, 1.0], 1.0, BlendMode::Multiply), [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(flatten_one(transparent, red, BlendMode::Multiply, 1.0, true), red);
+        assert_eq!(flatten_one(transparent, red, BlendMode::Normal, 0.5, true), [255, 0, 0, 128]);
+    }
+
+    #[test]
+    fn regions_outside_the_rect_are_untouched() {
+        let mut stack = LayerStack::new(4, 4, WHITE);
+        stack.layer_mut(stack.active()).unwrap().image = Image::filled(4, 4, [10, 20, 30, 255]);
+        let mut target = Image::new(4, 4);
+        flatten_region(&stack, Rect::new(1, 1, 2, 2), &mut target);
+        assert_eq!(target.pixel(1, 1), [10, 20, 30, 255]);
+        assert_eq!(target.pixel(2, 2), [10, 20, 30, 255]);
+        assert_eq!(target.pixel(0, 0), [0, 0, 0, 0]);
+        assert_eq!(target.pixel(3, 3), [0, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,56 @@
+use std::collections::BTreeMap;
+use crate::layer::LayerStack;
+
+/// Metadata keys with a meaning of their own. Any other key is kept as is.
+pub const TITLE: &str = "title";
+pub const AUTHOR: &str = "author";
+pub const DESCRIPTION: &str = "description";
+
+/// A named color the artist keeps at hand.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Swatch {
+    pub name: String,
+    pub color: [u8; 4],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Orientation {
+    Horizontal,
+    Vertical,
+}
+
+/// A line across the canvas to line things up against. Horizontal guides
+/// are positioned by their y, vertical ones by their x, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Guide {
+    pub orientation: Orientation,
+    pub position: f32,
+}
+
+/// Everything that is saved with a piece of work.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Document {
+    pub layers: LayerStack,
+    pub palette: Vec<Swatch>,
+    pub guides: Vec<Guide>,
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl Document {
+    pub fn new(layers: LayerStack) -> Self {
+        Document {
+            layers,
+            palette: vec![],
+            guides: vec![],
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.layers.width()
+    }
+
+    pub fn height(&self) -> u32 {
+        self.layers.height()
+    }
+}
@@ -0,0 +1,32 @@
+use std::fmt;
+use std::io;
+
+/// Anything that can go wrong reading or writing a document file.
+#[derive(Debug)]
+pub enum FileError {
+    Io(io::Error),
+    /// The file is damaged or not in the format it claims to be.
+    Decoding(String),
+    Encoding(String),
+    /// The file is valid, but uses something this program does not support.
+    Unsupported(String),
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileError::Io(error) => write!(f, "{}", error),
+            FileError::Decoding(details) => write!(f, "Failed to decode the file: {}", details),
+            FileError::Encoding(details) => write!(f, "Failed to encode the file: {}", details),
+            FileError::Unsupported(details) => write!(f, "Unsupported file: {}", details),
+        }
+    }
+}
+
+impl std::error::Error for FileError {}
+
+impl From<io::Error> for FileError {
+    fn from(error: io::Error) -> Self {
+        FileError::Io(error)
+    }
+}
@@ -0,0 +1,421 @@
+use crate::image::{over, Image, Rect};
+
+/// How the difference between two colors is measured against the fill
+/// tolerance. Both compare colors premultiplied by alpha, so all fully
+/// transparent pixels count as the same color.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorDistance {
+    /// The largest difference of any single channel.
+    Rgba,
+    /// A weighted RGB distance that follows how different colors look more
+    /// closely than plain channel differences do.
+    Perceptual,
+}
+
+/// Which pixels decide where a fill stops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FillSource {
+    CurrentLayer,
+    /// The layers as they are shown, merged together.
+    AllLayers,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FillSettings {
+    pub color: [u8; 4],
+    /// Largest color distance still filled, from 0.0 (the exact color only)
+    /// to 1.0 (everything).
+    pub tolerance: f32,
+    pub distance: ColorDistance,
+    /// Fill only the area connected to the starting pixel, rather than every
+    /// matching pixel of the image.
+    pub contiguous: bool,
+    pub source: FillSource,
+    /// Softens the edge of the filled area by a pixel.
+    pub antialias: bool,
+}
+
+impl Default for FillSettings {
+    fn default() -> Self {
+        FillSettings {
+            color: [0, 0, 0, 255],
+            tolerance: 0.1,
+            distance: ColorDistance::Rgba,
+            contiguous: true,
+            source: FillSource::CurrentLayer,
+            antialias: true,
+        }
+    }
+}
+
+fn premultiply(pixel: [u8; 4]) -> [f32; 4] {
+    let alpha = pixel[3] as f32 / 255.0;
+    [
+        pixel[0] as f32 * alpha,
+        pixel[1] as f32 * alpha,
+        pixel[2] as f32 * alpha,
+        pixel[3] as f32,
+    ]
+}
+
+impl ColorDistance {
+    /// Distance between two colors, normalized to 0.0..=1.0.
+    fn between(self, a: [f32; 4], b: [f32; 4]) -> f32 {
+        let alpha = (a[3] - b[3]).abs() / 255.0;
+        let color = match self {
+            ColorDistance::Rgba => (0..3)
+                .map(|channel| (a[channel] - b[channel]).abs())
+                .fold(0.0, f32::max) / 255.0,
+            ColorDistance::Perceptual => {
+                // The "redmean" approximation: green counts the most, and
+                // red and blue shift weight with how red the colors are.
+                let red_mean = (a[0] + b[0]) / 2.0;
+                let red = a[0] - b[0];
+                let green = a[1] - b[1];
+                let blue = a[2] - b[2];
+                let distance = ((2.0 + red_mean / 256.0) * red * red
+                    + 4.0 * green * green
+                    + (2.0 + (255.0 - red_mean) / 256.0) * blue * blue)
+                    .sqrt();
+                distance / (3.0 * 255.0)
+            },
+        };
+        color.max(alpha)
+    }
+}
+
+/// Fills `target` with the color of `settings` wherever `reference` matches
+/// the color at (`x`, `y`). `reference` is the current layer or a merged
+/// copy of all layers, as `settings.source` asks for, and has to be the same
+/// size as `target`. Returns the pixels that changed.
+///
+/// Connected areas are found with a scanline fill, which visits every pixel
+/// at most a few times and needs no recursion.
+pub fn flood_fill(
+    target: &mut Image,
+    reference: &Image,
+    x: u32,
+    y: u32,
+    settings: &FillSettings,
+) -> Rect {
+    assert_eq!(
+        (target.width(), target.height()),
+        (reference.width(), reference.height()),
+        "The fill reference has to be the size of the target!"
+    );
+    if x >= reference.width() || y >= reference.height() {
+        return Rect::default();
+    }
+    let width = reference.width() as usize;
+    let seed = premultiply(reference.pixel(x, y));
+    let tolerance = settings.tolerance.clamp(0.0, 1.0);
+    // Images tend to have long runs of one color, so the last answer is
+    // worth keeping around.
+    let mut last: Option<([u8; 4], bool)> = None;
+    let matches: Vec<bool> = reference
+        .pixels()
+        .chunks_exact(4)
+        .map(|pixel| {
+            let pixel = [pixel[0], pixel[1], pixel[2], pixel[3]];
+            match last {
+                Some((last_pixel, matched)) if last_pixel == pixel => matched,
+                _ => {
+                    let matched = settings.distance.between(seed, premultiply(pixel)) <= tolerance;
+                    last = Some((pixel, matched));
+                    matched
+                },
+            }
+        })
+        .collect();
+
+    let filled = if settings.contiguous {
+        scanline_fill(&matches, width, x as usize, y as usize)
+    } else {
+        matches
+    };
+    let coverage = if settings.antialias {
+        soften_edges(&filled, width)
+    } else {
+        filled.iter().map(|&filled| if filled { 1.0 } else { 0.0 }).collect()
+    };
+
+    let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+    for (index, &coverage) in coverage.iter().enumerate() {
+        if coverage <= 0.0 {
+            continue;
+        }
+        let (x, y) = ((index % width) as u32, (index / width) as u32);
+        target.set_pixel(x, y, over(target.pixel(x, y), settings.color, coverage));
+        left = left.min(x);
+        top = top.min(y);
+        right = right.max(x + 1);
+        bottom = bottom.max(y + 1);
+    }
+    if right == 0 {
+        return Rect::default();
+    }
+    Rect::new(left, top, right - left, bottom - top)
+}
+
+/// The matching pixels connected to the starting one. Each stack entry is a
+/// pixel to grow a horizontal span from; the rows above and below a span
+/// then get one entry per run of matching pixels.
+fn scanline_fill(matches: &[bool], width: usize, x: usize, y: usize) -> Vec<bool> {
+    let height = matches.len() / width;
+    let mut filled = vec![false; matches.len()];
+    let mut stack = vec![(x, y)];
+    while let Some((x, y)) = stack.pop() {
+        let row = y * width;
+        if filled[row + x] || !matches[row + x] {
+            continue;
+        }
+        let mut left = x;
+        while left > 0 && matches[row + left - 1] && !filled[row + left - 1] {
+            left -= 1;
+        }
+        let mut right = x + 1;
+        while right < width && matches[row + right] && !filled[row + right] {
+            right += 1;
+        }
+        filled[row + left..row + right].iter_mut().for_each(|filled| *filled = true);
+
+        let neighbours = [y.checked_sub(1), Some(y + 1).filter(|&below| below < height)];
+        for neighbour in neighbours.iter().flatten() {
+            let neighbour_row = neighbour * width;
+            let mut in_run = false;
+            for x in left..right {
+                let open = matches[neighbour_row + x] && !filled[neighbour_row + x];
+                if open && !in_run {
+                    stack.push((x, *neighbour));
+                }
+                in_run = open;
+            }
+        }
+    }
+    filled
+}
+
+/// Full coverage inside the filled area, and partial coverage for the
+/// pixels just outside it, in proportion to how many of their neighbours
+/// are filled.
+fn soften_edges(filled: &[bool], width: usize) -> Vec<f32> {
+    let height = filled.len() / width;
+    let mut coverage = vec![0.0; filled.len()];
+    for y in 0..height {
+        for x in 0..width {
+            let index = y * width + x;
+            if filled[index] {
+                coverage[index] = 1.0;
+                continue;
+            }
+            let next_to_fill = (x > 0 && filled[index - 1])
+                || (x + 1 < width && filled[index + 1])
+                || (y > 0 && filled[index - width])
+                || (y + 1 < height && filled[index + width]);
+            if !next_to_fill {
+                continue;
+            }
+            let mut neighbours = 0;
+            for ny in y.saturating_sub(1)..(y + 2).min(height) {
+                for nx in x.saturating_sub(1)..(x + 2).min(width) {
+                    if filled[ny * width + nx] {
+                        neighbours += 1;
+                    }
+                }
+            }
+            coverage[index] = neighbours as f32 / 9.0;
+        }
+    }
+    coverage
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::composite::flatten;
+    use crate::layer::LayerStack;
+
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+    const BLACK: [u8; 4] = [0, 0, 0, 255];
+    const RED: [u8; 4] = [255, 0, 0, 255];
+
+    /// An image drawn with `.` for white and `#` for black.
+    fn image(rows: &[&str]) -> Image {
+        let mut image = Image::new(rows[0].len() as u32, rows.len() as u32);
+        for (y, row) in rows.iter().enumerate() {
+            for (x, pixel) in row.chars().enumerate() {
+                image.set_pixel(x as u32, y as u32, if pixel == '#' { BLACK } else { WHITE });
+            }
+        }
+        image
+    }
+
+    /// The image drawn back with `*` for the fill color and `?` for
+    /// anything that is not white, black or the fill color.
+    fn drawing(image: &Image) -> Vec<String> {
+        (0..image.height())
+            .map(|y| {
+                (0..image.width())
+                    .map(|x| match image.pixel(x, y) {
+                        WHITE => '.',
+                        BLACK => '#',
+                        RED => '*',
+                        _ => '?',
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn settings(tolerance: f32, distance: ColorDistance, contiguous: bool) -> FillSettings {
+        FillSettings {
+            color: RED,
+            tolerance,
+            distance,
+            contiguous,
+            source: FillSource::CurrentLayer,
+            antialias: false,
+        }
+    }
+
+    fn fill(rows: &[&str], x: u32, y: u32, settings: &FillSettings) -> (Vec<String>, Rect) {
+        let mut target = image(rows);
+        let reference = target.clone();
+        let dirty = flood_fill(&mut target, &reference, x, y, settings);
+        (drawing(&target), dirty)
+    }
+
+    /// A row of white, a gray one step away from white, white and black,
+    /// with the fill started on the first pixel.
+    fn fill_near_white(settings: &FillSettings) -> Vec<String> {
+        let mut target = image(&["...#"]);
+        target.set_pixel(1, 0, [250, 250, 250, 255]);
+        let reference = target.clone();
+        flood_fill(&mut target, &reference, 0, 0, settings);
+        drawing(&target)
+    }
+
+    #[test]
+    fn zero_tolerance_fills_the_exact_color_only() {
+        for &distance in [ColorDistance::Rgba, ColorDistance::Perceptual].iter() {
+            assert_eq!(fill_near_white(&settings(0.0, distance, true)), ["*?.#"], "{:?}", distance);
+            assert_eq!(fill_near_white(&settings(0.0, distance, false)), ["*?*#"], "{:?}", distance);
+            assert_eq!(fill_near_white(&settings(0.05, distance, true)), ["***#"], "{:?}", distance);
+            assert_eq!(fill_near_white(&settings(1.0, distance, true)), ["****"], "{:?}", distance);
+        }
+    }
+
+    #[test]
+    fn perceptual_distance_weighs_blue_less_than_rgba() {
+        // 30 steps of blue are 30 / 255 = 0.118 apart per channel, but only
+        // sqrt(2.996 * 30^2) / 765 = 0.068 apart perceptually.
+        let fill_dark_blue = |distance| {
+            let mut target = Image::filled(2, 1, BLACK);
+            target.set_pixel(1, 0, [0, 0, 30, 255]);
+            let reference = target.clone();
+            flood_fill(&mut target, &reference, 0, 0, &settings(0.1, distance, true));
+            target.pixel(1, 0)
+        };
+        assert_eq!(fill_dark_blue(ColorDistance::Rgba), [0, 0, 30, 255]);
+        assert_eq!(fill_dark_blue(ColorDistance::Perceptual), RED);
+    }
+
+    #[test]
+    fn transparent_pixels_match_whatever_their_color() {
+        let mut target = Image::new(3, 1);
+        target.set_pixel(1, 0, [255, 255, 255, 0]);
+        let reference = target.clone();
+        flood_fill(&mut target, &reference, 0, 0, &settings(0.0, ColorDistance::Rgba, true));
+        assert_eq!(drawing(&target), ["***"]);
+    }
+
+    #[test]
+    fn contiguous_fills_stop_at_borders_and_global_ones_do_not() {
+        let rows = ["..#..", "..#..", "..#.."];
+        let (contiguous, dirty) = fill(&rows, 0, 1, &settings(0.0, ColorDistance::Rgba, true));
+        assert_eq!(contiguous, ["**#..", "**#..", "**#.."]);
+        assert_eq!(dirty, Rect::new(0, 0, 2, 3));
+        let (global, dirty) = fill(&rows, 0, 1, &settings(0.0, ColorDistance::Rgba, false));
+        assert_eq!(global, ["**#**", "**#**", "**#**"]);
+        assert_eq!(dirty, Rect::new(0, 0, 5, 3));
+    }
+
+    #[test]
+    fn fills_leak_through_a_one_pixel_gap() {
+        let walled = ["..#..", "..#..", "..#..", "..#.."];
+        let (filled, _) = fill(&walled, 0, 0, &settings(0.0, ColorDistance::Rgba, true));
+        assert_eq!(filled, ["**#..", "**#..", "**#..", "**#.."]);
+        let gap = ["..#..", "..#..", ".....", "..#.."];
+        let (filled, dirty) = fill(&gap, 0, 0, &settings(0.0, ColorDistance::Rgba, true));
+        assert_eq!(filled, ["**#**", "**#**", "*****", "**#**"]);
+        assert_eq!(dirty, Rect::new(0, 0, 5, 4));
+        // Pixels touching only at a corner are not connected.
+        let diagonal = ["..#", ".#.", "#.."];
+        let (filled, _) = fill(&diagonal, 0, 0, &settings(0.0, ColorDistance::Rgba, true));
+        assert_eq!(filled, ["**#", "*#.", "#.."]);
+    }
+
+    #[test]
+    fn seeds_on_edges_and_corners_fill_everything_reachable() {
+        let rows = [".#..", "##..", "...."];
+        let settings = settings(0.0, ColorDistance::Rgba, true);
+        let (filled, dirty) = fill(&rows, 0, 0, &settings);
+        assert_eq!(filled, ["*#..", "##..", "...."]);
+        assert_eq!(dirty, Rect::new(0, 0, 1, 1));
+        for &(x, y) in [(3, 0), (3, 2), (0, 2), (2, 0), (3, 1), (1, 2)].iter() {
+            let (filled, dirty) = fill(&rows, x, y, &settings);
+            assert_eq!(filled, [".#**", "##**", "****"], "seed at {}, {}", x, y);
+            assert_eq!(dirty, Rect::new(0, 0, 4, 3), "seed at {}, {}", x, y);
+        }
+    }
+
+    #[test]
+    fn seeds_outside_the_image_fill_nothing() {
+        let rows = ["..", ".."];
+        let settings = settings(1.0, ColorDistance::Rgba, true);
+        for &(x, y) in [(2, 0), (0, 2), (u32::MAX, u32::MAX)].iter() {
+            let (filled, dirty) = fill(&rows, x, y, &settings);
+            assert_eq!(filled, ["..", ".."]);
+            assert!(dirty.is_empty());
+        }
+    }
+
+    #[test]
+    fn the_reference_decides_where_fills_stop() {
+        let mut layers = LayerStack::new(5, 1, WHITE);
+        let background = layers.active();
+        let wall = layers.add_layer("Wall");
+        layers.layer_mut(wall).unwrap().image.set_pixel(2, 0, BLACK);
+        let settings = settings(0.0, ColorDistance::Rgba, true);
+        let fill_background = |reference: &Image| {
+            let mut target = layers.layer(background).unwrap().image.clone();
+            flood_fill(&mut target, reference, 0, 0, &settings);
+            drawing(&target)
+        };
+        let current_layer = layers.layer(background).unwrap().image.clone();
+        assert_eq!(fill_background(&current_layer), ["*****"]);
+        assert_eq!(fill_background(&flatten(&layers)), ["**..."]);
+    }
+
+    #[test]
+    fn soften_edges_only_covers_pixels_next_to_the_fill() {
+        let mut filled = vec![false; 9];
+        filled[4] = true;
+        let third = 1.0 / 9.0;
+        assert_eq!(soften_edges(&filled, 3), [0.0, third, 0.0, third, 1.0, third, 0.0, third, 0.0]);
+    }
+
+    #[test]
+    fn antialiased_fills_blend_into_the_border() {
+        let rows = ["..#..", "..#..", "..#.."];
+        let mut target = image(&rows);
+        let reference = target.clone();
+        let settings = FillSettings { antialias: true, ..settings(0.0, ColorDistance::Rgba, true) };
+        let dirty = flood_fill(&mut target, &reference, 0, 1, &settings);
+        assert_eq!(drawing(&target), ["**?..", "**?..", "**?.."]);
+        assert_eq!(target.pixel(2, 0), over(BLACK, RED, 2.0 / 9.0));
+        assert_eq!(target.pixel(2, 1), over(BLACK, RED, 3.0 / 9.0));
+        assert_eq!(target.pixel(2, 2), over(BLACK, RED, 2.0 / 9.0));
+        assert_eq!(dirty, Rect::new(0, 0, 3, 3));
+    }
+}
@@ -0,0 +1,630 @@
+use std::collections::VecDeque;
+use crate::image::{Image, Rect};
+use crate::layer::{Layer, LayerId, LayerProperties, LayerStack};
+use crate::tile::{tile_rect, TileSnapshot};
+
+/// History kept when no budget is given, in bytes.
+pub const DEFAULT_BUDGET: usize = 256 * 1024 * 1024;
+
+/// What every entry costs besides its pixels, so that entries without any,
+/// like layer moves, still count against the budget.
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<Change>();
+
+/// What an undo or redo changed, so views of the document can catch up.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Changed {
+    /// Pixels of one layer.
+    Pixels(LayerId, Rect),
+    /// Which layers there are, their order or their properties.
+    Layers,
+}
+
+/// One tile of a layer before and after an operation, compressed.
+#[derive(Clone, Debug)]
+struct TileDelta {
+    column: u32,
+    row: u32,
+    before: Vec<u8>,
+    after: Vec<u8>,
+}
+
+/// A whole layer, with its pixels compressed.
+#[derive(Clone, Debug)]
+struct StoredLayer {
+    id: LayerId,
+    properties: LayerProperties,
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl StoredLayer {
+    fn new(layer: &Layer) -> Self {
+        StoredLayer {
+            id: layer.id(),
+            properties: layer.properties.clone(),
+            width: layer.image.width(),
+            height: layer.image.height(),
+            pixels: compress(layer.image.pixels()),
+        }
+    }
+
+    fn restore(&self) -> Layer {
+        let image = Image::from_pixels(self.width, self.height, decompress(&self.pixels));
+        Layer::new(self.id, self.properties.clone(), image)
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Change {
+    Pixels {
+        layer: LayerId,
+        tiles: Vec<TileDelta>,
+    },
+    AddLayer {
+        index: usize,
+        layer: StoredLayer,
+    },
+    RemoveLayer {
+        index: usize,
+        layer: StoredLayer,
+    },
+    MoveLayer {
+        layer: LayerId,
+        from: usize,
+        to: usize,
+    },
+    Properties {
+        layer: LayerId,
+        before: LayerProperties,
+        after: LayerProperties,
+    },
+}
+
+impl Change {
+    fn size(&self) -> usize {
+        ENTRY_OVERHEAD + match self {
+            Change::Pixels { tiles, .. } => tiles
+                .iter()
+                .map(|tile| std::mem::size_of::<TileDelta>() + tile.before.len() + tile.after.len())
+                .sum(),
+            Change::AddLayer { layer, .. } | Change::RemoveLayer { layer, .. } =>
+                layer.properties.name.len() + layer.pixels.len(),
+            Change::MoveLayer { .. } => 0,
+            Change::Properties { before, after, .. } => before.name.len() + after.name.len(),
+        }
+    }
+}
+
+/// Undo and redo for destructive operations. Only the tiles an operation
+/// changed are stored, compressed, and the oldest operations are forgotten
+/// once the history outgrows its budget. Layers are found by their id, so
+/// entries stay valid as layers are added, removed and reordered; those
+/// operations go through the history too.
+#[derive(Clone, Debug)]
+pub struct History {
+    undo: VecDeque<Change>,
+    redo: Vec<Change>,
+    budget: usize,
+    used: usize,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::new(DEFAULT_BUDGET)
+    }
+}
+
+impl History {
+    pub fn new(budget: usize) -> Self {
+        History {
+            undo: VecDeque::new(),
+            redo: vec![],
+            budget,
+            used: 0,
+        }
+    }
+
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Bytes held for undo and redo: compressed tiles and layers, plus a
+    /// fixed overhead for every entry.
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.trim();
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+        self.used = 0;
+    }
+
+    /// Records an operation that changed the pixels of `layer`, given the
+    /// tiles as they were before it and the image as it is now. Tiles that
+    /// ended up unchanged are left out, and so are operations that changed
+    /// nothing. Recording drops everything that could be redone.
+    pub fn record_pixels(&mut self, layer: LayerId, before: &TileSnapshot, image: &Image) {
+        let tiles: Vec<TileDelta> = before
+            .tiles()
+            .iter()
+            .filter_map(|(column, row, before)| {
+                let after = image.region(tile_rect(column, row, image.width(), image.height()));
+                if *before == after {
+                    return None;
+                }
+                Some(TileDelta {
+                    column,
+                    row,
+                    before: compress(before),
+                    after: compress(&after),
+                })
+            })
+            .collect();
+        if !tiles.is_empty() {
+            self.push(Change::Pixels { layer, tiles });
+        }
+    }
+
+    /// Adds a transparent layer above the active one.
+    pub fn add_layer(&mut self, stack: &mut LayerStack, name: &str) -> LayerId {
+        let id = stack.add_layer(name);
+        let index = stack.index_of(id).unwrap();
+        let layer = StoredLayer::new(stack.layer(id).unwrap());
+        self.push(Change::AddLayer { index, layer });
+        id
+    }
+
+    /// Puts a layer with pixels of its own at `index` from the bottom.
+    pub fn insert_layer(&mut self, stack: &mut LayerStack, index: usize, layer: Layer) -> LayerId {
+        let id = layer.id();
+        stack.insert_layer(index, layer);
+        let index = stack.index_of(id).unwrap();
+        let layer = StoredLayer::new(stack.layer(id).unwrap());
+        self.push(Change::AddLayer { index, layer });
+        id
+    }
+
+    /// Removes a layer, unless it is the last one.
+    pub fn remove_layer(&mut self, stack: &mut LayerStack, id: LayerId) -> bool {
+        match stack.remove_layer(id) {
+            Some((index, layer)) => {
+                self.push(Change::RemoveLayer { index, layer: StoredLayer::new(&layer) });
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Moves a layer to `index` from the bottom.
+    pub fn move_layer(&mut self, stack: &mut LayerStack, id: LayerId, index: usize) -> bool {
+        let from = match stack.move_layer(id, index) {
+            Some(from) => from,
+            None => return false,
+        };
+        let to = stack.index_of(id).unwrap();
+        if from != to {
+            self.push(Change::MoveLayer { layer: id, from, to });
+        }
+        true
+    }
+
+    pub fn set_properties(
+        &mut self,
+        stack: &mut LayerStack,
+        id: LayerId,
+        properties: LayerProperties,
+    ) -> bool {
+        let layer = match stack.layer_mut(id) {
+            Some(layer) => layer,
+            None => return false,
+        };
+        if layer.properties != properties {
+            let before = std::mem::replace(&mut layer.properties, properties.clone());
+            self.push(Change::Properties { layer: id, before, after: properties });
+        }
+        true
+    }
+
+    fn push(&mut self, change: Change) {
+        self.used -= self.redo.drain(..).map(|change| change.size()).sum::<usize>();
+        self.used += change.size();
+        self.undo.push_back(change);
+        self.trim();
+    }
+
+    /// Forgets the oldest operations, then the ones furthest down the redo
+    /// stack, until the history fits its budget. The latest operation is
+    /// always kept, however large.
+    fn trim(&mut self) {
+        while self.used > self.budget && self.undo.len() > 1 {
+            if let Some(change) = self.undo.pop_front() {
+                self.used -= change.size();
+            }
+        }
+        while self.used > self.budget && self.undo.len() + self.redo.len() > 1 {
+            let change = self.redo.remove(0);
+            self.used -= change.size();
+        }
+    }
+
+    /// Reverts the latest operation. An operation that no longer applies,
+    /// because its layer was changed behind the history's back, is
+    /// forgotten instead of becoming one to redo.
+    pub fn undo(&mut self, stack: &mut LayerStack) -> Option<Changed> {
+        let change = self.undo.pop_back()?;
+        match apply(&change, stack, false) {
+            Some(changed) => {
+                self.redo.push(change);
+                Some(changed)
+            },
+            None => {
+                self.used -= change.size();
+                None
+            },
+        }
+    }
+
+    /// Repeats the latest undone operation, or forgets it like `undo` does
+    /// when it no longer applies.
+    pub fn redo(&mut self, stack: &mut LayerStack) -> Option<Changed> {
+        let change = self.redo.pop()?;
+        match apply(&change, stack, true) {
+            Some(changed) => {
+                self.undo.push_back(change);
+                self.trim();
+                Some(changed)
+            },
+            None => {
+                self.used -= change.size();
+                None
+            },
+        }
+    }
+}
+
+fn apply(change: &Change, stack: &mut LayerStack, forward: bool) -> Option<Changed> {
+    match change {
+        Change::Pixels { layer, tiles } => {
+            let image = &mut stack.layer_mut(*layer)?.image;
+            let mut changed = Rect::default();
+            for tile in tiles {
+                let rect = tile_rect(tile.column, tile.row, image.width(), image.height());
+                let pixels = if forward { &tile.after } else { &tile.before };
+                image.write_region(rect, &decompress(pixels));
+                changed = changed.union(&rect);
+            }
+            stack.set_active(*layer);
+            Some(Changed::Pixels(*layer, changed))
+        },
+        Change::AddLayer { index, layer } | Change::RemoveLayer { index, layer } => {
+            let adding = matches!(change, Change::AddLayer { .. }) == forward;
+            if adding {
+                stack.insert_layer(*index, layer.restore());
+            } else {
+                stack.remove_layer(layer.id)?;
+            }
+            Some(Changed::Layers)
+        },
+        Change::MoveLayer { layer, from, to } => {
+            stack.move_layer(*layer, if forward { *to } else { *from })?;
+            Some(Changed::Layers)
+        },
+        Change::Properties { layer, before, after } => {
+            stack.layer_mut(*layer)?.properties = if forward { after } else { before }.clone();
+            Some(Changed::Layers)
+        },
+    }
+}
+
+/// Longest run or literal a single packet can hold, in pixels.
+const MAX_PACKET: usize = 128;
+
+/// Run-length encodes RGBA8 pixels in packets. A header byte with the high
+/// bit set repeats the following pixel up to 128 times; without it, up to
+/// 128 literal pixels follow. Painted tiles are mostly runs of one color,
+/// and unfavorable ones grow by less than one percent.
+fn compress(rgba: &[u8]) -> Vec<u8> {
+    let pixels: Vec<&[u8]> = rgba.chunks_exact(4).collect();
+    let mut packed = Vec::with_capacity(rgba.len() / 4);
+    let mut start = 0;
+    while start < pixels.len() {
+        let run = pixels[start..]
+            .iter()
+            .take(MAX_PACKET)
+            .take_while(|&&pixel| pixel == pixels[start])
+            .count();
+        if run > 1 {
+            packed.push(0x80 | (run - 1) as u8);
+            packed.extend_from_slice(pixels[start]);
+            start += run;
+            continue;
+        }
+        // A literal ends where the next run of at least two begins.
+        let mut end = start + 1;
+        while end < pixels.len() && end - start < MAX_PACKET
+            && !(end + 1 < pixels.len() && pixels[end] == pixels[end + 1]) {
+            end += 1;
+        }
+        packed.push((end - start - 1) as u8);
+        pixels[start..end].iter().for_each(|pixel| packed.extend_from_slice(pixel));
+        start = end;
+    }
+    packed
+}
+
+fn decompress(packed: &[u8]) -> Vec<u8> {
+    let mut rgba = vec![];
+    let mut position = 0;
+    while position < packed.len() {
+        let header = packed[position] as usize;
+        position += 1;
+        let count = (header & 0x7f) + 1;
+        if header & 0x80 != 0 {
+            let pixel = &packed[position..position + 4];
+            (0..count).for_each(|_| rgba.extend_from_slice(pixel));
+            position += 4;
+        } else {
+            rgba.extend_from_slice(&packed[position..position + count * 4]);
+            position += count * 4;
+        }
+    }
+    rgba
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tile::TILE_SIZE;
+
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+    const RED: [u8; 4] = [255, 0, 0, 255];
+
+    /// Fills `rect` of a layer with a color through the history, the way
+    /// tools record their strokes.
+    fn paint(history: &mut History, stack: &mut LayerStack, id: LayerId, rect: Rect, color: [u8; 4]) {
+        let image = &mut stack.layer_mut(id).unwrap().image;
+        let mut before = TileSnapshot::new(image.width(), image.height());
+        before.preserve(image, rect);
+        for y in rect.y..rect.bottom() {
+            for x in rect.x..rect.right() {
+                image.set_pixel(x, y, color);
+            }
+        }
+        history.record_pixels(id, &before, image);
+    }
+
+    fn names(stack: &LayerStack) -> Vec<&str> {
+        stack.layers().iter().map(|layer| layer.properties.name.as_str()).collect()
+    }
+
+    #[test]
+    fn compression_round_trips() {
+        let mut rgba = vec![];
+        rgba.extend([1, 2, 3, 4].repeat(300));
+        rgba.extend((0..=255).cycle().take(4 * 200));
+        rgba.extend([9, 9, 9, 9].repeat(2));
+        rgba.extend([5, 6, 7, 8]);
+        assert_eq!(decompress(&compress(&rgba)), rgba);
+        assert!(compress(&[0; 4 * 4096]).len() < 200);
+        assert!(compress(&[]).is_empty());
+    }
+
+    #[test]
+    fn only_changed_tiles_are_stored() {
+        let mut stack = LayerStack::new(TILE_SIZE * 3, TILE_SIZE * 2, WHITE);
+        let id = stack.active();
+        let mut history = History::default();
+        let mut before = TileSnapshot::new(stack.width(), stack.height());
+        before.preserve(&stack.layer(id).unwrap().image, Rect::new(0, 0, TILE_SIZE * 3, TILE_SIZE * 2));
+        stack.layer_mut(id).unwrap().image.set_pixel(TILE_SIZE + 1, 1, RED);
+        history.record_pixels(id, &before, &stack.layer(id).unwrap().image);
+        match history.undo.back() {
+            Some(Change::Pixels { tiles, .. }) => {
+                assert_eq!(tiles.len(), 1);
+                assert_eq!((tiles[0].column, tiles[0].row), (1, 0));
+            },
+            change => panic!("Unexpected change {:?}", change),
+        }
+    }
+
+    #[test]
+    fn tile_deltas_undo_and_redo_exactly() {
+        let mut stack = LayerStack::new(100, 80, WHITE);
+        let id = stack.active();
+        let mut history = History::default();
+        let original = stack.clone();
+        paint(&mut history, &mut stack, id, Rect::new(50, 10, 40, 60), RED);
+        let painted = stack.clone();
+
+        let changed = history.undo(&mut stack);
+        assert_eq!(changed, Some(Changed::Pixels(id, Rect::new(0, 0, 100, 80))));
+        assert_eq!(stack, original);
+        assert!(!history.can_undo());
+
+        history.redo(&mut stack);
+        assert_eq!(stack, painted);
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn recording_drops_what_could_be_redone() {
+        let mut stack = LayerStack::new(16, 16, WHITE);
+        let id = stack.active();
+        let mut history = History::default();
+        paint(&mut history, &mut stack, id, Rect::new(0, 0, 4, 4), RED);
+        history.undo(&mut stack);
+        paint(&mut history, &mut stack, id, Rect::new(8, 8, 4, 4), RED);
+        assert!(!history.can_redo());
+        assert_eq!(history.used(), history.undo.iter().map(Change::size).sum::<usize>());
+    }
+
+    #[test]
+    fn the_oldest_operations_are_evicted_over_budget() {
+        let mut stack = LayerStack::new(TILE_SIZE * 4, TILE_SIZE, WHITE);
+        let id = stack.active();
+        let mut history = History::new(usize::MAX);
+        for column in 0..4 {
+            // Noisy tiles, so they do not compress away.
+            let image = &mut stack.layer_mut(id).unwrap().image;
+            let mut before = TileSnapshot::new(image.width(), image.height());
+            let tile = tile_rect(column, 0, image.width(), image.height());
+            before.preserve(image, tile);
+            for y in 0..TILE_SIZE {
+                for x in tile.x..tile.right() {
+                    image.set_pixel(x, y, [(x * 7 + y) as u8, (x * y) as u8, column as u8, 255]);
+                }
+            }
+            history.record_pixels(id, &before, image);
+        }
+        let each = history.used() / 4;
+        history.set_budget(each * 2);
+        assert_eq!(history.undo.len(), 2);
+        assert!(history.used() <= history.budget());
+
+        // The latest operation stays, however large.
+        history.set_budget(0);
+        assert_eq!(history.undo.len(), 1);
+        assert!(history.can_undo());
+    }
+
+    #[test]
+    fn redo_keeps_to_the_budget() {
+        let mut stack = LayerStack::new(TILE_SIZE * 2, TILE_SIZE, WHITE);
+        let id = stack.active();
+        let mut history = History::new(usize::MAX);
+        paint(&mut history, &mut stack, id, Rect::new(0, 0, 8, 8), RED);
+        paint(&mut history, &mut stack, id, Rect::new(TILE_SIZE, 0, 8, 8), RED);
+        history.undo(&mut stack);
+        history.undo(&mut stack);
+        history.set_budget(history.used() / 2);
+        history.redo(&mut stack);
+        history.redo(&mut stack);
+        assert_eq!(history.undo.len(), 1);
+        assert!(history.used() <= history.budget());
+    }
+
+    #[test]
+    fn what_could_be_redone_counts_against_the_budget() {
+        let mut stack = LayerStack::new(TILE_SIZE * 3, TILE_SIZE, WHITE);
+        let id = stack.active();
+        let mut history = History::new(usize::MAX);
+        for column in 0..3 {
+            paint(&mut history, &mut stack, id, Rect::new(column * TILE_SIZE, 0, 8, 8), RED);
+        }
+        while history.undo(&mut stack).is_some() {}
+        assert_eq!(history.used(), history.redo.iter().map(Change::size).sum::<usize>());
+
+        // The furthest redo goes first.
+        history.set_budget(history.used() - 1);
+        assert_eq!(history.redo.len(), 2);
+        assert!(history.used() <= history.budget());
+        history.redo(&mut stack);
+        history.redo(&mut stack);
+        assert_eq!(history.redo(&mut stack), None);
+        assert_eq!(stack.layer(id).unwrap().image.pixel(TILE_SIZE + 1, 1), RED);
+        assert_eq!(stack.layer(id).unwrap().image.pixel(TILE_SIZE * 2 + 1, 1), WHITE);
+
+        history.undo(&mut stack);
+        history.undo(&mut stack);
+        history.set_budget(0);
+        assert_eq!(history.undo.len() + history.redo.len(), 1);
+    }
+
+    #[test]
+    fn layer_changes_without_pixels_count_against_the_budget() {
+        let mut stack = LayerStack::new(16, 16, WHITE);
+        let background = stack.active();
+        let mut history = History::new(usize::MAX);
+        history.add_layer(&mut stack, "Ink");
+        history.clear();
+        for index in 0..10 {
+            history.move_layer(&mut stack, background, (index + 1) % 2);
+            let mut properties = stack.layer(background).unwrap().properties.clone();
+            properties.opacity = index as f32 / 10.0;
+            history.set_properties(&mut stack, background, properties);
+        }
+        assert_eq!(history.undo.len(), 20);
+        assert!(history.used() >= 20 * ENTRY_OVERHEAD);
+
+        history.set_budget(history.used() / 2);
+        assert!(history.undo.len() <= 10);
+        assert!(history.used() <= history.budget());
+        assert_eq!(history.used(), history.undo.iter().map(Change::size).sum::<usize>());
+    }
+
+    #[test]
+    fn layers_are_added_removed_and_reordered_through_undo() {
+        let mut stack = LayerStack::new(16, 16, WHITE);
+        let background = stack.active();
+        let mut history = History::default();
+        let ink = history.add_layer(&mut stack, "Ink");
+        paint(&mut history, &mut stack, ink, Rect::new(2, 2, 4, 4), RED);
+        let sketch = history.add_layer(&mut stack, "Sketch");
+        assert!(history.move_layer(&mut stack, sketch, 0));
+        assert!(history.remove_layer(&mut stack, ink));
+        assert_eq!(names(&stack), ["Sketch", "Background"]);
+
+        assert_eq!(history.undo(&mut stack), Some(Changed::Layers));
+        assert_eq!(names(&stack), ["Sketch", "Background", "Ink"]);
+        assert_eq!(stack.layer(ink).unwrap().image.pixel(3, 3), RED);
+        history.undo(&mut stack);
+        assert_eq!(names(&stack), ["Background", "Ink", "Sketch"]);
+        history.undo(&mut stack);
+        assert_eq!(names(&stack), ["Background", "Ink"]);
+        history.undo(&mut stack);
+        assert_eq!(stack.layer(ink).unwrap().image.pixel(3, 3), [0, 0, 0, 0]);
+        history.undo(&mut stack);
+        assert_eq!(names(&stack), ["Background"]);
+        assert_eq!(stack.active(), background);
+
+        while history.redo(&mut stack).is_some() {}
+        assert_eq!(names(&stack), ["Sketch", "Background"]);
+        history.undo(&mut stack);
+        assert_eq!(stack.layer(ink).unwrap().image.pixel(3, 3), RED);
+    }
+
+    #[test]
+    fn changes_that_no_longer_apply_are_forgotten() {
+        let mut stack = LayerStack::new(16, 16, WHITE);
+        let mut history = History::default();
+        let ink = history.add_layer(&mut stack, "Ink");
+        paint(&mut history, &mut stack, ink, Rect::new(0, 0, 4, 4), RED);
+        // Removed without going through the history.
+        stack.remove_layer(ink);
+
+        assert_eq!(history.undo(&mut stack), None);
+        assert!(!history.can_redo());
+        assert!(history.can_undo());
+        assert_eq!(history.used(), history.undo.iter().map(Change::size).sum::<usize>());
+    }
+
+    #[test]
+    fn properties_are_restored() {
+        let mut stack = LayerStack::new(8, 8, WHITE);
+        let id = stack.active();
+        let mut history = History::default();
+        let mut properties = stack.layer(id).unwrap().properties.clone();
+        properties.opacity = 0.25;
+        properties.blend_mode = crate::layer::BlendMode::Multiply;
+        history.set_properties(&mut stack, id, properties.clone());
+        history.undo(&mut stack);
+        assert_eq!(stack.layer(id).unwrap().properties, LayerProperties::new("Background"));
+        history.redo(&mut stack);
+        assert_eq!(stack.layer(id).unwrap().properties, properties);
+    }
+}
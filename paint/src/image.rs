@@ -0,0 +1,234 @@
+/// A rectangle of image pixels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Rect { x, y, width, height }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    pub fn right(&self) -> u32 {
+        self.x + self.width
+    }
+
+    pub fn bottom(&self) -> u32 {
+        self.y + self.height
+    }
+
+    /// The smallest rectangle holding both. Empty rectangles are ignored.
+    pub fn union(&self, other: &Rect) -> Rect {
+        if self.is_empty() {
+            return *other;
+        }
+        if other.is_empty() {
+            return *self;
+        }
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: self.right().max(other.right()) - x,
+            height: self.bottom().max(other.bottom()) - y,
+        }
+    }
+
+    pub fn intersection(&self, other: &Rect) -> Rect {
+        let x = self.x.max(other.x);
+        let y = self.y.max(other.y);
+        let right = self.right().min(other.right());
+        let bottom = self.bottom().min(other.bottom());
+        if right <= x || bottom <= y {
+            return Rect::default();
+        }
+        Rect { x, y, width: right - x, height: bottom - y }
+    }
+}
+
+/// Tightly packed RGBA8 pixels with straight (not premultiplied) alpha, in
+/// the same layout as the engine canvas.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    /// A fully transparent image.
+    pub fn new(width: u32, height: u32) -> Self {
+        Image::filled(width, height, [0, 0, 0, 0])
+    }
+
+    pub fn filled(width: u32, height: u32, color: [u8; 4]) -> Self {
+        let pixels = color
+            .iter()
+            .copied()
+            .cycle()
+            .take(width as usize * height as usize * 4)
+            .collect();
+        Image { width, height, pixels }
+    }
+
+    pub fn from_pixels(width: u32, height: u32, pixels: Vec<u8>) -> Self {
+        assert_eq!(
+            pixels.len(), width as usize * height as usize * 4,
+            "Pixel data does not match a {}x{} RGBA8 image!", width, height
+        );
+        Image { width, height, pixels }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn bounds(&self) -> Rect {
+        Rect::new(0, 0, self.width, self.height)
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    pub fn pixels_mut(&mut self) -> &mut [u8] {
+        &mut self.pixels
+    }
+
+    pub fn into_pixels(self) -> Vec<u8> {
+        self.pixels
+    }
+
+    fn offset(&self, x: u32, y: u32) -> usize {
+        debug_assert!(x < self.width && y < self.height);
+        (y as usize * self.width as usize + x as usize) * 4
+    }
+
+    pub fn pixel(&self, x: u32, y: u32) -> [u8; 4] {
+        let offset = self.offset(x, y);
+        let mut pixel = [0; 4];
+        pixel.copy_from_slice(&self.pixels[offset..offset + 4]);
+        pixel
+    }
+
+    pub fn set_pixel(&mut self, x: u32, y: u32, pixel: [u8; 4]) {
+        let offset = self.offset(x, y);
+        self.pixels[offset..offset + 4].copy_from_slice(&pixel);
+    }
+
+    /// Copies a rectangle out as tightly packed rows, ready to be written to
+    /// the canvas.
+    pub fn region(&self, rect: Rect) -> Vec<u8> {
+        assert_eq!(rect.intersection(&self.bounds()), rect, "Region is outside of the image!");
+        let mut rgba = Vec::with_capacity(rect.width as usize * rect.height as usize * 4);
+        for y in rect.y..rect.bottom() {
+            let start = self.offset(rect.x, y);
+            rgba.extend_from_slice(&self.pixels[start..start + rect.width as usize * 4]);
+        }
+        rgba
+    }
+
+    /// Overwrites a rectangle with tightly packed rows.
+    pub fn write_region(&mut self, rect: Rect, rgba: &[u8]) {
+        assert_eq!(rect.intersection(&self.bounds()), rect, "Region is outside of the image!");
+        let row_length = rect.width as usize * 4;
+        assert_eq!(rgba.len(), row_length * rect.height as usize, "Region data has the wrong size!");
+        for (row, y) in rgba.chunks_exact(row_length).zip(rect.y..rect.bottom()) {
+            let start = self.offset(rect.x, y);
+            self.pixels[start..start + row_length].copy_from_slice(row);
+        }
+    }
+
+    /// The largest difference between any channel of this image and the
+    /// same channel of `other`, or `None` when their sizes differ. Renders
+    /// from the GPU are compared with CPU references against a tolerance.
+    pub fn max_difference(&self, other: &Image) -> Option<u8> {
+        if (self.width, self.height) != (other.width, other.height) {
+            return None;
+        }
+        let difference = self.pixels
+            .iter()
+            .zip(other.pixels.iter())
+            .map(|(a, b)| (*a as i16 - *b as i16).unsigned_abs() as u8)
+            .max();
+        Some(difference.unwrap_or(0))
+    }
+}
+
+/// Source-over compositing of `color`, scaled by `coverage` in 0.0..=1.0,
+/// onto `destination`. Only plain float arithmetic is used, so results are
+/// the same on every platform.
+pub fn over(destination: [u8; 4], color: [u8; 4], coverage: f32) -> [u8; 4] {
+    let source_alpha = color[3] as f32 / 255.0 * coverage;
+    let destination_alpha = destination[3] as f32 / 255.0;
+    let remaining = destination_alpha * (1.0 - source_alpha);
+    let alpha = source_alpha + remaining;
+    if alpha <= 0.0 {
+        return [0, 0, 0, 0];
+    }
+    let mut result = [0; 4];
+    for channel in 0..3 {
+        let value = (color[channel] as f32 * source_alpha
+            + destination[channel] as f32 * remaining) / alpha;
+        result[channel] = to_u8(value);
+    }
+    result[3] = to_u8(alpha * 255.0);
+    result
+}
+
+/// Rounds to the nearest byte value, saturating outside of 0..=255.
+pub(crate) fn to_u8(value: f32) -> u8 {
+    (value + 0.5) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn over_blends_by_coverage() {
+        let white = [255, 255, 255, 255];
+        assert_eq!(over(white, [0, 0, 0, 255], 1.0), [0, 0, 0, 255]);
+        assert_eq!(over(white, [0, 0, 0, 255], 0.5), [128, 128, 128, 255]);
+        assert_eq!(over(white, [0, 0, 0, 255], 0.0), white);
+    }
+
+    #[test]
+    fn over_onto_transparent_keeps_the_color() {
+        assert_eq!(over([0, 0, 0, 0], [255, 0, 0, 255], 0.5), [255, 0, 0, 128]);
+        assert_eq!(over([0, 0, 0, 0], [255, 0, 0, 0], 1.0), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rects_union_and_intersect() {
+        let a = Rect::new(0, 0, 4, 4);
+        let b = Rect::new(2, 3, 4, 4);
+        assert_eq!(a.union(&b), Rect::new(0, 0, 6, 7));
+        assert_eq!(a.intersection(&b), Rect::new(2, 3, 2, 1));
+        assert!(a.intersection(&Rect::new(4, 0, 1, 1)).is_empty());
+        assert_eq!(a.union(&Rect::default()), a);
+    }
+
+    #[test]
+    fn regions_round_trip() {
+        let mut image = Image::new(4, 4);
+        let rect = Rect::new(1, 1, 2, 2);
+        let rgba: Vec<u8> = (0..16).collect();
+        image.write_region(rect, &rgba);
+        assert_eq!(image.region(rect), rgba);
+        assert_eq!(image.pixel(2, 1), [4, 5, 6, 7]);
+        assert_eq!(image.pixel(0, 0), [0, 0, 0, 0]);
+    }
+}
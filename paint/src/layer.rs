@@ -0,0 +1,348 @@
+use crate::image::Image;
+
+/// Identifies a layer for as long as the document exists, whatever position
+/// it is moved to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LayerId(pub u64);
+
+/// How a layer's colors combine with the layers below it. The values are
+/// shared with the engine's compositing shader.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BlendMode {
+    Normal = 0,
+    Multiply = 1,
+    Screen = 2,
+    Overlay = 3,
+    Darken = 4,
+    Lighten = 5,
+    ColorDodge = 6,
+    ColorBurn = 7,
+    HardLight = 8,
+    SoftLight = 9,
+    Difference = 10,
+    Exclusion = 11,
+    Add = 12,
+    Subtract = 13,
+}
+
+impl BlendMode {
+    pub const ALL: [BlendMode; 14] = [
+        BlendMode::Normal,
+        BlendMode::Multiply,
+        BlendMode::Screen,
+        BlendMode::Overlay,
+        BlendMode::Darken,
+        BlendMode::Lighten,
+        BlendMode::ColorDodge,
+        BlendMode::ColorBurn,
+        BlendMode::HardLight,
+        BlendMode::SoftLight,
+        BlendMode::Difference,
+        BlendMode::Exclusion,
+        BlendMode::Add,
+        BlendMode::Subtract,
+    ];
+
+    /// The mode with the given value, as stored in files and passed to the
+    /// shader.
+    pub fn from_value(value: u32) -> Option<BlendMode> {
+        BlendMode::ALL.iter().copied().find(|mode| *mode as u32 == value)
+    }
+
+    /// The blend of one channel of a source color over a backdrop, both in
+    /// 0.0..=1.0, as the W3C compositing specification defines them.
+    pub fn blend(self, backdrop: f32, source: f32) -> f32 {
+        match self {
+            BlendMode::Normal => source,
+            BlendMode::Multiply => backdrop * source,
+            BlendMode::Screen => backdrop + source - backdrop * source,
+            BlendMode::Overlay => BlendMode::HardLight.blend(source, backdrop),
+            BlendMode::Darken => backdrop.min(source),
+            BlendMode::Lighten => backdrop.max(source),
+            BlendMode::ColorDodge => {
+                if backdrop <= 0.0 {
+                    0.0
+                } else if source >= 1.0 {
+                    1.0
+                } else {
+                    (backdrop / (1.0 - source)).min(1.0)
+                }
+            },
+            BlendMode::ColorBurn => {
+                if backdrop >= 1.0 {
+                    1.0
+                } else if source <= 0.0 {
+                    0.0
+                } else {
+                    1.0 - ((1.0 - backdrop) / source).min(1.0)
+                }
+            },
+            BlendMode::HardLight => {
+                if source <= 0.5 {
+                    BlendMode::Multiply.blend(backdrop, 2.0 * source)
+                } else {
+                    BlendMode::Screen.blend(backdrop, 2.0 * source - 1.0)
+                }
+            },
+            BlendMode::SoftLight => {
+                if source <= 0.5 {
+                    backdrop - (1.0 - 2.0 * source) * backdrop * (1.0 - backdrop)
+                } else {
+                    let darkened = if backdrop <= 0.25 {
+                        ((16.0 * backdrop - 12.0) * backdrop + 4.0) * backdrop
+                    } else {
+                        backdrop.sqrt()
+                    };
+                    backdrop + (2.0 * source - 1.0) * (darkened - backdrop)
+                }
+            },
+            BlendMode::Difference => (backdrop - source).abs(),
+            BlendMode::Exclusion => backdrop + source - 2.0 * backdrop * source,
+            BlendMode::Add => (backdrop + source).min(1.0),
+            BlendMode::Subtract => (backdrop - source).max(0.0),
+        }
+    }
+}
+
+/// Everything about a layer apart from its identity and pixels.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerProperties {
+    pub name: String,
+    pub visible: bool,
+    pub opacity: f32,
+    /// Locked layers are not painted on.
+    pub locked: bool,
+    pub blend_mode: BlendMode,
+}
+
+impl LayerProperties {
+    pub fn new(name: &str) -> Self {
+        LayerProperties {
+            name: name.to_string(),
+            visible: true,
+            opacity: 1.0,
+            locked: false,
+            blend_mode: BlendMode::Normal,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Layer {
+    id: LayerId,
+    pub properties: LayerProperties,
+    pub image: Image,
+}
+
+impl Layer {
+    pub fn new(id: LayerId, properties: LayerProperties, image: Image) -> Self {
+        Layer { id, properties, image }
+    }
+
+    pub fn id(&self) -> LayerId {
+        self.id
+    }
+}
+
+/// The layers of a document from bottom to top, all the size of the
+/// document. The stack is never left without a layer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LayerStack {
+    width: u32,
+    height: u32,
+    layers: Vec<Layer>,
+    active: LayerId,
+    next_id: u64,
+}
+
+impl LayerStack {
+    /// A stack holding a single background layer of one color.
+    pub fn new(width: u32, height: u32, background: [u8; 4]) -> Self {
+        let id = LayerId(0);
+        LayerStack {
+            width,
+            height,
+            layers: vec![Layer::new(
+                id,
+                LayerProperties::new("Background"),
+                Image::filled(width, height, background),
+            )],
+            active: id,
+            next_id: 1,
+        }
+    }
+
+    /// A stack of existing layers, from bottom to top, with the top one
+    /// active. Fails when there are no layers, when they are not all
+    /// `width` by `height` or when two share an id.
+    pub fn from_layers(width: u32, height: u32, layers: Vec<Layer>) -> Option<Self> {
+        let active = layers.last()?.id;
+        let mut ids: Vec<u64> = layers.iter().map(|layer| layer.id.0).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let sized = layers
+            .iter()
+            .all(|layer| (layer.image.width(), layer.image.height()) == (width, height));
+        if ids.len() != layers.len() || !sized {
+            return None;
+        }
+        Some(LayerStack {
+            width,
+            height,
+            layers,
+            active,
+            next_id: ids.last().unwrap() + 1,
+        })
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    pub fn index_of(&self, id: LayerId) -> Option<usize> {
+        self.layers.iter().position(|layer| layer.id == id)
+    }
+
+    pub fn layer(&self, id: LayerId) -> Option<&Layer> {
+        self.layers.iter().find(|layer| layer.id == id)
+    }
+
+    pub fn layer_mut(&mut self, id: LayerId) -> Option<&mut Layer> {
+        self.layers.iter_mut().find(|layer| layer.id == id)
+    }
+
+    /// The layer tools paint on.
+    pub fn active(&self) -> LayerId {
+        self.active
+    }
+
+    pub fn set_active(&mut self, id: LayerId) {
+        if self.index_of(id).is_some() {
+            self.active = id;
+        }
+    }
+
+    /// An identifier no layer of this stack has used yet.
+    pub fn allocate_id(&mut self) -> LayerId {
+        let id = LayerId(self.next_id);
+        self.next_id += 1;
+        id
+    }
+
+    /// Adds a transparent layer above the active one and makes it active.
+    pub fn add_layer(&mut self, name: &str) -> LayerId {
+        let id = self.allocate_id();
+        let index = self.index_of(self.active).map_or(self.layers.len(), |index| index + 1);
+        let image = Image::new(self.width, self.height);
+        self.insert_layer(index, Layer::new(id, LayerProperties::new(name), image));
+        id
+    }
+
+    /// Puts a layer at `index` from the bottom and makes it active.
+    pub fn insert_layer(&mut self, index: usize, layer: Layer) {
+        assert_eq!(
+            (layer.image.width(), layer.image.height()),
+            (self.width, self.height),
+            "Layers have to be the size of the document!"
+        );
+        assert!(self.index_of(layer.id).is_none(), "Layer {:?} is already in the stack!", layer.id);
+        self.next_id = self.next_id.max(layer.id.0 + 1);
+        self.active = layer.id;
+        self.layers.insert(index.min(self.layers.len()), layer);
+    }
+
+    /// Takes a layer out of the stack, along with the index it had. The last
+    /// layer cannot be removed.
+    pub fn remove_layer(&mut self, id: LayerId) -> Option<(usize, Layer)> {
+        if self.layers.len() <= 1 {
+            return None;
+        }
+        let index = self.index_of(id)?;
+        let layer = self.layers.remove(index);
+        if self.active == id {
+            self.active = self.layers[index.saturating_sub(1)].id;
+        }
+        Some((index, layer))
+    }
+
+    /// Moves a layer to `index` from the bottom and returns where it was.
+    pub fn move_layer(&mut self, id: LayerId, index: usize) -> Option<usize> {
+        let from = self.index_of(id)?;
+        let layer = self.layers.remove(from);
+        self.layers.insert(index.min(self.layers.len()), layer);
+        Some(from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_blends(mode: BlendMode, cases: &[(f32, f32, f32)]) {
+        for &(backdrop, source, expected) in cases {
+            let blended = mode.blend(backdrop, source);
+            assert!(
+                (blended - expected).abs() < 1e-6,
+                "{:?} of {} over {} is {}, not {}", mode, source, backdrop, blended, expected,
+            );
+        }
+    }
+
+    #[test]
+    fn normal_takes_the_source() {
+        assert_blends(BlendMode::Normal, &[(0.25, 0.5, 0.5), (1.0, 0.0, 0.0)]);
+    }
+
+    #[test]
+    fn multiply_and_screen_darken_and_lighten() {
+        assert_blends(BlendMode::Multiply, &[(0.5, 0.5, 0.25), (1.0, 0.75, 0.75), (0.0, 0.75, 0.0)]);
+        assert_blends(BlendMode::Screen, &[(0.5, 0.5, 0.75), (0.0, 0.75, 0.75), (1.0, 0.25, 1.0)]);
+    }
+
+    #[test]
+    fn overlay_multiplies_dark_and_screens_light_backdrops() {
+        assert_blends(BlendMode::Overlay, &[(0.25, 0.5, 0.25), (0.75, 0.5, 0.75), (0.25, 1.0, 0.5)]);
+    }
+
+    #[test]
+    fn darken_and_lighten_pick_a_side() {
+        assert_blends(BlendMode::Darken, &[(0.25, 0.75, 0.25), (0.75, 0.25, 0.25)]);
+        assert_blends(BlendMode::Lighten, &[(0.25, 0.75, 0.75), (0.75, 0.25, 0.75)]);
+    }
+
+    #[test]
+    fn add_saturates() {
+        assert_blends(BlendMode::Add, &[(0.25, 0.5, 0.75), (0.75, 0.5, 1.0)]);
+        assert_blends(BlendMode::Subtract, &[(0.75, 0.5, 0.25), (0.25, 0.5, 0.0)]);
+    }
+
+    #[test]
+    fn difference_is_symmetric() {
+        assert_blends(BlendMode::Difference, &[(0.25, 0.75, 0.5), (0.75, 0.25, 0.5), (0.5, 0.5, 0.0)]);
+        assert_blends(BlendMode::Exclusion, &[(0.5, 0.5, 0.5), (1.0, 1.0, 0.0)]);
+    }
+
+    #[test]
+    fn dodge_burn_and_lights() {
+        assert_blends(BlendMode::ColorDodge, &[(0.25, 0.5, 0.5), (0.0, 1.0, 0.0), (0.5, 1.0, 1.0)]);
+        assert_blends(BlendMode::ColorBurn, &[(0.75, 0.5, 0.5), (1.0, 0.0, 1.0), (0.5, 0.0, 0.0)]);
+        assert_blends(BlendMode::HardLight, &[(0.5, 0.25, 0.25), (0.5, 0.75, 0.75)]);
+        assert_blends(BlendMode::SoftLight, &[(0.25, 0.25, 0.15625), (0.25, 0.75, 0.375), (0.5, 0.5, 0.5)]);
+    }
+
+    #[test]
+    fn values_round_trip() {
+        for mode in BlendMode::ALL.iter() {
+            assert_eq!(BlendMode::from_value(*mode as u32), Some(*mode));
+        }
+        assert_eq!(BlendMode::from_value(BlendMode::ALL.len() as u32), None);
+    }
+}
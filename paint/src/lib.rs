@@ -0,0 +1,19 @@
+pub mod error;
+pub mod image;
+pub mod tile;
+pub mod rng;
+pub mod layer;
+pub mod composite;
+pub mod brush;
+pub mod spray;
+pub mod fill;
+pub mod history;
+pub mod png;
+pub mod document;
+pub mod splash;
+pub mod ora;
+
+pub use document::Document;
+pub use error::FileError;
+pub use image::{Image, Rect};
+pub use layer::{BlendMode, Layer, LayerId, LayerProperties, LayerStack};
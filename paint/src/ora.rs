@@ -0,0 +1,518 @@
+//! OpenRaster, the layered format shared with Krita, MyPaint and GIMP: a
+//! zip holding `stack.xml`, which describes the layers, a PNG per layer, the
+//! flattened `mergedimage.png` and a thumbnail.
+//!
+//! Layer PNGs are placed at their `x` and `y` offsets and cut off at the
+//! image edges. Nested stacks are flattened into the layer list, passing
+//! their opacity and visibility on to the layers they hold. On export each
+//! layer is cropped to its painted pixels.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::Path;
+use xml::reader::{EventReader, XmlEvent as ReadEvent};
+use xml::writer::{EmitterConfig, XmlEvent as WriteEvent};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use crate::composite::flatten;
+use crate::document::Document;
+use crate::error::FileError;
+use crate::image::{Image, Rect};
+use crate::layer::{BlendMode, Layer, LayerId, LayerProperties, LayerStack};
+use crate::png::{self, PngOptions};
+
+pub const EXTENSION: &str = "ora";
+
+const MIME_TYPE: &str = "image/openraster";
+/// The version of the specification written files follow.
+const SPEC_VERSION: &str = "0.0.5";
+/// Thumbnails fit into a square this size.
+const THUMBNAIL_SIZE: u32 = 256;
+
+/// The `composite-op` name of each blend mode. Subtract has no name in the
+/// specification and uses Krita's.
+const COMPOSITE_OPS: [(BlendMode, &str); 14] = [
+    (BlendMode::Normal, "svg:src-over"),
+    (BlendMode::Multiply, "svg:multiply"),
+    (BlendMode::Screen, "svg:screen"),
+    (BlendMode::Overlay, "svg:overlay"),
+    (BlendMode::Darken, "svg:darken"),
+    (BlendMode::Lighten, "svg:lighten"),
+    (BlendMode::ColorDodge, "svg:color-dodge"),
+    (BlendMode::ColorBurn, "svg:color-burn"),
+    (BlendMode::HardLight, "svg:hard-light"),
+    (BlendMode::SoftLight, "svg:soft-light"),
+    (BlendMode::Difference, "svg:difference"),
+    (BlendMode::Exclusion, "svg:exclusion"),
+    (BlendMode::Add, "svg:plus"),
+    (BlendMode::Subtract, "krita:subtract"),
+];
+
+fn composite_op(mode: BlendMode) -> &'static str {
+    COMPOSITE_OPS.iter().find(|(op_mode, _)| *op_mode == mode).unwrap().1
+}
+
+/// Operations this program cannot composite fall back to normal.
+fn blend_mode(composite_op: &str) -> BlendMode {
+    COMPOSITE_OPS
+        .iter()
+        .find(|(_, name)| *name == composite_op)
+        .map_or(BlendMode::Normal, |(mode, _)| *mode)
+}
+
+fn zip_error(error: zip::result::ZipError) -> FileError {
+    match error {
+        zip::result::ZipError::Io(error) => FileError::Io(error),
+        error => FileError::Decoding(error.to_string()),
+    }
+}
+
+/// A layer as `stack.xml` describes it.
+struct LayerEntry {
+    name: String,
+    source: String,
+    x: i32,
+    y: i32,
+    opacity: f32,
+    visible: bool,
+    composite_op: String,
+    selected: bool,
+}
+
+/// The image size and the layers of `stack.xml`, from top to bottom.
+fn parse_stack<R: Read>(reader: R) -> Result<((u32, u32), Vec<LayerEntry>), FileError> {
+    let mut size = None;
+    let mut layers = vec![];
+    // Opacity and visibility of the stacks the parser is inside of.
+    let mut stacks: Vec<(f32, bool)> = vec![];
+    for event in EventReader::new(reader) {
+        let event = event.map_err(|error| FileError::Decoding(format!("stack.xml: {}", error)))?;
+        match event {
+            ReadEvent::StartElement { name, attributes, .. } => {
+                let attribute = |key: &str| attributes
+                    .iter()
+                    .find(|attribute| attribute.name.local_name == key)
+                    .map(|attribute| attribute.value.as_str());
+                let number = |key: &str, default: f32| -> Result<f32, FileError> {
+                    attribute(key).map_or(Ok(default), |value| value.trim().parse().map_err(|_| {
+                        FileError::Decoding(format!("stack.xml: {} is not a number", key))
+                    }))
+                };
+                let (parent_opacity, parent_visible) = stacks.last().copied().unwrap_or((1.0, true));
+                let opacity = parent_opacity * number("opacity", 1.0)?.clamp(0.0, 1.0);
+                let visible = parent_visible && attribute("visibility") != Some("hidden");
+                match name.local_name.as_str() {
+                    "image" => {
+                        let width = number("w", 0.0)? as u32;
+                        let height = number("h", 0.0)? as u32;
+                        if width == 0 || height == 0 {
+                            return Err(FileError::Decoding("stack.xml: no image size".to_string()));
+                        }
+                        size = Some((width, height));
+                    },
+                    "stack" => stacks.push((opacity, visible)),
+                    "layer" => layers.push(LayerEntry {
+                        name: attribute("name").unwrap_or("Layer").to_string(),
+                        source: attribute("src")
+                            .ok_or_else(|| FileError::Decoding("stack.xml: layer without src".to_string()))?
+                            .to_string(),
+                        x: number("x", 0.0)? as i32,
+                        y: number("y", 0.0)? as i32,
+                        opacity,
+                        visible,
+                        composite_op: attribute("composite-op").unwrap_or("svg:src-over").to_string(),
+                        selected: attribute("selected") == Some("true"),
+                    }),
+                    // Text and vector layers, among others, are left out.
+                    _ => {},
+                }
+            },
+            ReadEvent::EndElement { name } if name.local_name == "stack" => {
+                stacks.pop();
+            },
+            _ => {},
+        }
+    }
+    let size = size.ok_or_else(|| FileError::Decoding("stack.xml: no image element".to_string()))?;
+    Ok((size, layers))
+}
+
+/// Reads an OpenRaster file into a document. Layer ids are handed out from
+/// the bottom up. The `mimetype` and `stack.xml` entries are required.
+pub fn load<R: Read + Seek>(reader: R) -> Result<Document, FileError> {
+    let mut archive = ZipArchive::new(reader).map_err(zip_error)?;
+    let mut mime_type = String::new();
+    archive
+        .by_name("mimetype")
+        .map_err(|_| FileError::Decoding("no mimetype, not an OpenRaster file".to_string()))?
+        .read_to_string(&mut mime_type)?;
+    if mime_type.trim() != MIME_TYPE {
+        return Err(FileError::Decoding(format!("mimetype is {}", mime_type.trim())));
+    }
+    let ((width, height), entries) = parse_stack(archive.by_name("stack.xml").map_err(zip_error)?)?;
+    if entries.is_empty() {
+        return Err(FileError::Unsupported("no raster layers".to_string()));
+    }
+
+    let document_bounds = Rect::new(0, 0, width, height);
+    let mut layers = vec![];
+    let mut active = None;
+    for (index, entry) in entries.iter().rev().enumerate() {
+        if !entry.source.to_ascii_lowercase().ends_with(".png") {
+            return Err(FileError::Unsupported(format!("layer source {}", entry.source)));
+        }
+        let mut data = vec![];
+        archive.by_name(&entry.source).map_err(zip_error)?.read_to_end(&mut data)?;
+        let source = png::decode(&data[..])?;
+
+        // The part of the layer PNG that lands on the document.
+        let left = entry.x.max(0) as u32;
+        let top = entry.y.max(0) as u32;
+        let skip_x = (-entry.x).max(0) as u32;
+        let skip_y = (-entry.y).max(0) as u32;
+        let placed = Rect::new(
+            left,
+            top,
+            source.width().saturating_sub(skip_x),
+            source.height().saturating_sub(skip_y),
+        ).intersection(&document_bounds);
+        let mut image = Image::new(width, height);
+        if !placed.is_empty() {
+            let from = Rect::new(skip_x, skip_y, placed.width, placed.height);
+            image.write_region(placed, &source.region(from));
+        }
+
+        let id = LayerId(index as u64);
+        let mut properties = LayerProperties::new(&entry.name);
+        properties.opacity = entry.opacity;
+        properties.visible = entry.visible;
+        properties.blend_mode = blend_mode(&entry.composite_op);
+        if entry.selected {
+            active = Some(id);
+        }
+        layers.push(Layer::new(id, properties, image));
+    }
+    let mut stack = LayerStack::from_layers(width, height, layers).unwrap();
+    if let Some(active) = active {
+        stack.set_active(active);
+    }
+    Ok(Document::new(stack))
+}
+
+/// The smallest rectangle holding every pixel that is not fully transparent.
+fn painted_bounds(image: &Image) -> Rect {
+    let (mut left, mut top, mut right, mut bottom) = (image.width(), image.height(), 0, 0);
+    for (index, pixel) in image.pixels().chunks_exact(4).enumerate() {
+        if pixel[3] != 0 {
+            let x = index as u32 % image.width();
+            let y = index as u32 / image.width();
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x + 1);
+            bottom = bottom.max(y + 1);
+        }
+    }
+    if right <= left {
+        return Rect::default();
+    }
+    Rect::new(left, top, right - left, bottom - top)
+}
+
+/// Shrinks an image to fit a `size` square, averaging the pixels that fall
+/// into each thumbnail pixel. Colors are weighted by alpha, so transparent
+/// pixels do not darken the edges they border.
+fn thumbnail(image: &Image, size: u32) -> Image {
+    let scale = (size as f32 / image.width().max(image.height()) as f32).min(1.0);
+    let width = ((image.width() as f32 * scale).round() as u32).max(1);
+    let height = ((image.height() as f32 * scale).round() as u32).max(1);
+    let mut thumbnail = Image::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let left = x * image.width() / width;
+            let right = ((x + 1) * image.width() / width).max(left + 1);
+            let top = y * image.height() / height;
+            let bottom = ((y + 1) * image.height() / height).max(top + 1);
+            let mut sum = [0.0f32; 4];
+            for source_y in top..bottom {
+                for source_x in left..right {
+                    let pixel = image.pixel(source_x, source_y);
+                    let alpha = pixel[3] as f32;
+                    (0..3).for_each(|channel| sum[channel] += pixel[channel] as f32 * alpha);
+                    sum[3] += alpha;
+                }
+            }
+            let count = ((right - left) * (bottom - top)) as f32;
+            let color = |channel: usize| if sum[3] > 0.0 { sum[channel] / sum[3] } else { 0.0 };
+            thumbnail.set_pixel(x, y, [
+                (color(0) + 0.5) as u8,
+                (color(1) + 0.5) as u8,
+                (color(2) + 0.5) as u8,
+                (sum[3] / count + 0.5) as u8,
+            ]);
+        }
+    }
+    thumbnail
+}
+
+fn write_png<W: Write + Seek>(zip: &mut ZipWriter<W>, name: &str, image: &Image) -> Result<(), FileError> {
+    // PNGs are compressed already.
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip.start_file(name, options).map_err(zip_error)?;
+    png::encode(zip, image, &PngOptions::default())
+}
+
+fn xml_error(error: xml::writer::Error) -> FileError {
+    FileError::Encoding(format!("stack.xml: {}", error))
+}
+
+fn layer_source(layer: &Layer) -> String {
+    format!("data/layer{}.png", layer.id().0)
+}
+
+fn stack_xml(document: &Document, offsets: &[Rect]) -> Result<Vec<u8>, FileError> {
+    let layers = &document.layers;
+    let mut xml = vec![];
+    let mut writer = EmitterConfig::new().perform_indent(true).create_writer(&mut xml);
+    let (width, height) = (layers.width().to_string(), layers.height().to_string());
+    writer.write(WriteEvent::start_element("image")
+        .attr("version", SPEC_VERSION)
+        .attr("w", &width)
+        .attr("h", &height)).map_err(xml_error)?;
+    writer.write(WriteEvent::start_element("stack")).map_err(xml_error)?;
+    // The stack lists layers from the top down.
+    for (layer, offset) in layers.layers().iter().zip(offsets.iter()).rev() {
+        let properties = &layer.properties;
+        let (source, x, y) = (layer_source(layer), offset.x.to_string(), offset.y.to_string());
+        let opacity = properties.opacity.to_string();
+        let mut element = WriteEvent::start_element("layer")
+            .attr("name", &properties.name)
+            .attr("src", &source)
+            .attr("x", &x)
+            .attr("y", &y)
+            .attr("opacity", &opacity)
+            .attr("visibility", if properties.visible { "visible" } else { "hidden" })
+            .attr("composite-op", composite_op(properties.blend_mode));
+        if layer.id() == layers.active() {
+            element = element.attr("selected", "true");
+        }
+        writer.write(element).map_err(xml_error)?;
+        writer.write(WriteEvent::end_element()).map_err(xml_error)?;
+    }
+    writer.write(WriteEvent::end_element()).map_err(xml_error)?;
+    writer.write(WriteEvent::end_element()).map_err(xml_error)?;
+    Ok(xml)
+}
+
+/// Writes a document as OpenRaster. What the format has no place for, such
+/// as locks, the palette and guides, is left out.
+pub fn save<W: Write + Seek>(writer: W, document: &Document) -> Result<(), FileError> {
+    let mut zip = ZipWriter::new(writer);
+    // The mimetype has to come first and uncompressed, so tools can tell the
+    // format from the first bytes of the file.
+    let stored = FileOptions::default().compression_method(CompressionMethod::Stored);
+    zip.start_file("mimetype", stored).map_err(zip_error)?;
+    zip.write_all(MIME_TYPE.as_bytes())?;
+
+    // Empty layers still need a PNG, which cannot be empty.
+    let offsets: Vec<Rect> = document.layers
+        .layers()
+        .iter()
+        .map(|layer| Some(painted_bounds(&layer.image))
+            .filter(|bounds| !bounds.is_empty())
+            .unwrap_or_else(|| Rect::new(0, 0, 1, 1)))
+        .collect();
+    zip.start_file("stack.xml", FileOptions::default()).map_err(zip_error)?;
+    zip.write_all(&stack_xml(document, &offsets)?)?;
+
+    for (layer, offset) in document.layers.layers().iter().zip(offsets.iter()) {
+        let cropped = Image::from_pixels(offset.width, offset.height, layer.image.region(*offset));
+        write_png(&mut zip, &layer_source(layer), &cropped)?;
+    }
+    let merged = flatten(&document.layers);
+    write_png(&mut zip, "mergedimage.png", &merged)?;
+    write_png(&mut zip, "Thumbnails/thumbnail.png", &thumbnail(&merged, THUMBNAIL_SIZE))?;
+    zip.finish().map_err(zip_error)?;
+    Ok(())
+}
+
+pub fn read(path: &Path) -> Result<Document, FileError> {
+    load(BufReader::new(File::open(path)?))
+}
+
+pub fn write(path: &Path, document: &Document) -> Result<(), FileError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    save(&mut writer, document)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn test_document() -> Document {
+        let mut layers = LayerStack::new(4, 3, [255, 255, 255, 255]);
+        let ink = layers.add_layer("Ink & <lines>");
+        let glaze = layers.add_layer("Glaze");
+        let hidden = layers.add_layer("Hidden");
+        let _empty = layers.add_layer("Empty");
+        let layer = layers.layer_mut(ink).unwrap();
+        layer.image.set_pixel(1, 1, [10, 20, 30, 255]);
+        layer.image.set_pixel(3, 2, [40, 50, 60, 70]);
+        let layer = layers.layer_mut(glaze).unwrap();
+        layer.image = Image::filled(4, 3, [200, 100, 0, 128]);
+        layer.properties.opacity = 0.35;
+        layer.properties.blend_mode = BlendMode::Multiply;
+        let layer = layers.layer_mut(hidden).unwrap();
+        layer.image.set_pixel(0, 0, [1, 2, 3, 4]);
+        layer.properties.visible = false;
+        layer.properties.blend_mode = BlendMode::Subtract;
+        layers.set_active(glaze);
+        Document::new(layers)
+    }
+
+    fn saved(document: &Document) -> Vec<u8> {
+        let mut bytes = Cursor::new(vec![]);
+        save(&mut bytes, document).unwrap();
+        bytes.into_inner()
+    }
+
+    /// A zip of the given entries, in order.
+    fn archive(entries: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = ZipWriter::new(Cursor::new(vec![]));
+        for (name, data) in entries.iter() {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    fn png_bytes(image: &Image) -> Vec<u8> {
+        let mut bytes = vec![];
+        png::encode(&mut bytes, image, &PngOptions::default()).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn documents_round_trip() {
+        let document = test_document();
+        let loaded = load(Cursor::new(saved(&document))).unwrap();
+        assert_eq!(loaded, document);
+        assert_eq!(loaded.layers.active(), LayerId(2));
+    }
+
+    #[test]
+    fn files_hold_what_other_programs_read() {
+        let mut archive = ZipArchive::new(Cursor::new(saved(&test_document()))).unwrap();
+        assert_eq!(archive.by_index(0).unwrap().name(), "mimetype");
+        let mut mime_type = String::new();
+        archive.by_name("mimetype").unwrap().read_to_string(&mut mime_type).unwrap();
+        assert_eq!(mime_type, MIME_TYPE);
+        let mut merged = vec![];
+        archive.by_name("mergedimage.png").unwrap().read_to_end(&mut merged).unwrap();
+        assert_eq!(png::decode(&merged[..]).unwrap(), flatten(&test_document().layers));
+        let mut stack = String::new();
+        archive.by_name("stack.xml").unwrap().read_to_string(&mut stack).unwrap();
+        assert!(stack.contains(r#"composite-op="svg:multiply""#), "{}", stack);
+        assert!(stack.contains(r#"visibility="hidden""#), "{}", stack);
+        assert!(stack.contains(r#"opacity="0.35""#), "{}", stack);
+        // The ink layer is cropped to its painted pixels.
+        assert!(stack.contains(r#"src="data/layer1.png" x="1" y="1""#), "{}", stack);
+    }
+
+    #[test]
+    fn composite_ops_without_a_mode_fall_back_to_normal() {
+        assert_eq!(blend_mode("svg:hue"), BlendMode::Normal);
+        assert_eq!(blend_mode(""), BlendMode::Normal);
+        for (mode, name) in COMPOSITE_OPS.iter() {
+            assert_eq!(blend_mode(name), *mode);
+            assert_eq!(composite_op(*mode), *name);
+        }
+        let stack = br#"<image w="1" h="1"><stack>
+            <layer src="data/top.png" composite-op="svg:luminosity"/>
+            <layer src="data/bottom.png" composite-op="krita:subtract"/>
+        </stack></image>"#;
+        let pixel = png_bytes(&Image::filled(1, 1, [1, 2, 3, 255]));
+        let bytes = archive(&[
+            ("mimetype", MIME_TYPE.as_bytes()),
+            ("stack.xml", stack),
+            ("data/top.png", &pixel),
+            ("data/bottom.png", &pixel),
+        ]);
+        let document = load(Cursor::new(bytes)).unwrap();
+        let modes: Vec<BlendMode> = document.layers
+            .layers()
+            .iter()
+            .map(|layer| layer.properties.blend_mode)
+            .collect();
+        assert_eq!(modes, [BlendMode::Subtract, BlendMode::Normal]);
+    }
+
+    #[test]
+    fn nested_stacks_pass_on_opacity_and_visibility() {
+        let stack = br#"<image w="2" h="2"><stack>
+            <stack opacity="0.5" visibility="hidden">
+                <layer src="inner.png" opacity="0.5" x="1" y="-1"/>
+            </stack>
+            <layer src="outer.png" name="Outer" selected="true"/>
+        </stack></image>"#;
+        let inner = png_bytes(&Image::filled(2, 2, [9, 9, 9, 255]));
+        let outer = png_bytes(&Image::filled(2, 2, [7, 7, 7, 255]));
+        let bytes = archive(&[
+            ("mimetype", MIME_TYPE.as_bytes()),
+            ("stack.xml", stack),
+            ("inner.png", &inner),
+            ("outer.png", &outer),
+        ]);
+        let document = load(Cursor::new(bytes)).unwrap();
+        // The stack lists layers from the top down.
+        let (outer, inner) = (&document.layers.layers()[0], &document.layers.layers()[1]);
+        assert_eq!(inner.properties.opacity, 0.25);
+        assert!(!inner.properties.visible);
+        // Placed one right and one up, so only its bottom left pixel lands.
+        assert_eq!(inner.image.pixels(), [0, 0, 0, 0, 9, 9, 9, 255, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(outer.properties.name, "Outer");
+        assert_eq!(outer.properties.opacity, 1.0);
+        assert!(outer.properties.visible);
+        assert_eq!(document.layers.active(), LayerId(0));
+    }
+
+    #[test]
+    fn required_entries_have_to_be_there() {
+        let stack: &[u8] = br#"<image w="1" h="1"><stack><layer src="layer.png"/></stack></image>"#;
+        let layer = png_bytes(&Image::filled(1, 1, [0, 0, 0, 255]));
+        let complete = archive(&[("mimetype", MIME_TYPE.as_bytes()), ("stack.xml", stack), ("layer.png", &layer)]);
+        assert!(load(Cursor::new(complete)).is_ok());
+
+        let cases: Vec<Vec<u8>> = vec![
+            archive(&[("stack.xml", stack), ("layer.png", &layer)]),
+            archive(&[("mimetype", b"image/png"), ("stack.xml", stack), ("layer.png", &layer)]),
+            archive(&[("mimetype", MIME_TYPE.as_bytes()), ("layer.png", &layer)]),
+            archive(&[("mimetype", MIME_TYPE.as_bytes()), ("stack.xml", stack)]),
+            archive(&[("mimetype", MIME_TYPE.as_bytes()), ("stack.xml", b"<image><stack/>"), ("layer.png", &layer)]),
+            b"not a zip".to_vec(),
+        ];
+        for (index, bytes) in cases.into_iter().enumerate() {
+            let result = load(Cursor::new(bytes));
+            assert!(matches!(result, Err(FileError::Decoding(_))), "case {}: {:?}", index, result);
+        }
+    }
+
+    #[test]
+    fn stacks_without_raster_layers_are_unsupported() {
+        let stack: &[u8] = br#"<image w="1" h="1"><stack><text>Hello</text></stack></image>"#;
+        let bytes = archive(&[("mimetype", MIME_TYPE.as_bytes()), ("stack.xml", stack)]);
+        assert!(matches!(load(Cursor::new(bytes)), Err(FileError::Unsupported(_))));
+    }
+
+    #[test]
+    fn thumbnails_fit_the_square() {
+        let image = Image::filled(600, 300, [10, 20, 30, 255]);
+        let small = thumbnail(&image, THUMBNAIL_SIZE);
+        assert_eq!((small.width(), small.height()), (256, 128));
+        assert_eq!(small.pixel(100, 100), [10, 20, 30, 255]);
+        let tiny = Image::filled(2, 1, [1, 1, 1, 1]);
+        assert_eq!(thumbnail(&tiny, THUMBNAIL_SIZE), tiny);
+    }
+}
@@ -0,0 +1,359 @@
+//! Reading and writing PNG files. Documents hold sRGB encoded, straight
+//! alpha RGBA8 pixels; files in any other PNG color type, bit depth or gamma
+//! are converted to that on the way in.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use ::png::{ColorType, Decoder, Encoder, ScaledFloat, SourceChromaticities, SrgbRenderingIntent, Transformations};
+use crate::error::FileError;
+use crate::image::{over, to_u8, Image};
+
+/// Encoding gammas this close to sRGB's approximate 1/2.2 are taken to be
+/// sRGB, sparing a lossy round trip through linear light.
+const SRGB_GAMMA_TOLERANCE: f32 = 0.01;
+
+/// Bits per channel of a written file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+}
+
+/// How a written file declares its color encoding.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorEncoding {
+    /// An sRGB chunk, with the gAMA and cHRM fallbacks the specification
+    /// recommends for older readers.
+    Srgb,
+    /// A gAMA chunk with this encoding gamma; pixels are converted to it.
+    Gamma(f32),
+    /// No color chunks at all. Readers will mostly assume sRGB.
+    Unmarked,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PngOptions {
+    pub bit_depth: BitDepth,
+    /// Without alpha, pixels are composited onto `background`.
+    pub alpha: bool,
+    pub background: [u8; 3],
+    pub encoding: ColorEncoding,
+}
+
+impl Default for PngOptions {
+    fn default() -> Self {
+        PngOptions {
+            bit_depth: BitDepth::Eight,
+            alpha: true,
+            background: [0xff; 3],
+            encoding: ColorEncoding::Srgb,
+        }
+    }
+}
+
+fn decoding_error(error: ::png::DecodingError) -> FileError {
+    match error {
+        ::png::DecodingError::IoError(error) => FileError::Io(error),
+        error => FileError::Decoding(error.to_string()),
+    }
+}
+
+fn encoding_error(error: ::png::EncodingError) -> FileError {
+    match error {
+        ::png::EncodingError::IoError(error) => FileError::Io(error),
+        error => FileError::Encoding(error.to_string()),
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Reads a PNG of any color type and bit depth.
+pub fn decode<R: Read>(reader: R) -> Result<Image, FileError> {
+    let mut decoder = Decoder::new(reader);
+    // Palettes, low bit depths and tRNS transparency are expanded, leaving
+    // gray or RGB, with or without alpha, at 8 or 16 bits.
+    decoder.set_transformations(Transformations::EXPAND);
+    let mut reader = decoder.read_info().map_err(decoding_error)?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let frame = reader.next_frame(&mut buffer).map_err(decoding_error)?;
+    let info = reader.info();
+    // Files without color information are taken to be sRGB, like browsers do.
+    let gamma = match (info.srgb, info.source_gamma) {
+        (None, Some(gamma)) => Some(gamma.into_value()),
+        _ => None,
+    }.filter(|gamma| *gamma > 0.0 && (gamma - 1.0 / 2.2).abs() > SRGB_GAMMA_TOLERANCE);
+
+    let channels = match frame.color_type {
+        ColorType::Grayscale => 1,
+        ColorType::GrayscaleAlpha => 2,
+        ColorType::Rgb => 3,
+        ColorType::Rgba => 4,
+        ColorType::Indexed => {
+            return Err(FileError::Unsupported("palette was not expanded".to_string()));
+        },
+    };
+    let sixteen_bit = frame.bit_depth == ::png::BitDepth::Sixteen;
+    let max = if sixteen_bit { 65535.0 } else { 255.0 };
+    let sample = |row: &[u8], index: usize| -> f32 {
+        let value = if sixteen_bit {
+            u16::from_be_bytes([row[index * 2], row[index * 2 + 1]]) as f32
+        } else {
+            row[index] as f32
+        };
+        value / max
+    };
+    let to_document = |value: f32| -> u8 {
+        let value = match gamma {
+            Some(gamma) => linear_to_srgb(value.powf(1.0 / gamma)),
+            None => value,
+        };
+        to_u8(value * 255.0)
+    };
+
+    let (width, height) = (frame.width, frame.height);
+    let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+    for row in buffer[..frame.buffer_size()].chunks_exact(frame.line_size) {
+        for x in 0..width as usize {
+            let first = x * channels;
+            let pixel = match channels {
+                1 | 2 => {
+                    let gray = to_document(sample(row, first));
+                    let alpha = if channels == 2 { to_u8(sample(row, first + 1) * 255.0) } else { 255 };
+                    [gray, gray, gray, alpha]
+                },
+                _ => [
+                    to_document(sample(row, first)),
+                    to_document(sample(row, first + 1)),
+                    to_document(sample(row, first + 2)),
+                    if channels == 4 { to_u8(sample(row, first + 3) * 255.0) } else { 255 },
+                ],
+            };
+            pixels.extend_from_slice(&pixel);
+        }
+    }
+    Ok(Image::from_pixels(width, height, pixels))
+}
+
+/// Writes an image as a PNG.
+pub fn encode<W: Write>(writer: W, image: &Image, options: &PngOptions) -> Result<(), FileError> {
+    let mut encoder = Encoder::new(writer, image.width(), image.height());
+    encoder.set_color(if options.alpha { ColorType::Rgba } else { ColorType::Rgb });
+    encoder.set_depth(match options.bit_depth {
+        BitDepth::Eight => ::png::BitDepth::Eight,
+        BitDepth::Sixteen => ::png::BitDepth::Sixteen,
+    });
+    match options.encoding {
+        ColorEncoding::Srgb => {
+            encoder.set_source_srgb(SrgbRenderingIntent::Perceptual);
+            encoder.set_source_gamma(ScaledFloat::from_scaled(45455));
+            encoder.set_source_chromaticities(SourceChromaticities::new(
+                (0.3127, 0.3290),
+                (0.64, 0.33),
+                (0.30, 0.60),
+                (0.15, 0.06),
+            ));
+        },
+        ColorEncoding::Gamma(gamma) => encoder.set_source_gamma(ScaledFloat::new(gamma)),
+        ColorEncoding::Unmarked => {},
+    }
+
+    let from_document = |value: u8| -> f32 {
+        let value = value as f32 / 255.0;
+        match options.encoding {
+            ColorEncoding::Gamma(gamma) => srgb_to_linear(value).powf(gamma),
+            _ => value,
+        }
+    };
+    let mut data = vec![];
+    let mut push = |value: f32| match options.bit_depth {
+        BitDepth::Eight => data.push(to_u8(value * 255.0)),
+        BitDepth::Sixteen => {
+            let value = (value * 65535.0 + 0.5).clamp(0.0, 65535.0) as u16;
+            data.extend_from_slice(&value.to_be_bytes());
+        },
+    };
+    let background = [options.background[0], options.background[1], options.background[2], 255];
+    for pixel in image.pixels().chunks_exact(4) {
+        let pixel = [pixel[0], pixel[1], pixel[2], pixel[3]];
+        let pixel = if options.alpha { pixel } else { over(background, pixel, 1.0) };
+        (0..3).for_each(|channel| push(from_document(pixel[channel])));
+        if options.alpha {
+            push(pixel[3] as f32 / 255.0);
+        }
+    }
+
+    let mut writer = encoder.write_header().map_err(encoding_error)?;
+    writer.write_image_data(&data).map_err(encoding_error)?;
+    writer.finish().map_err(encoding_error)
+}
+
+pub fn read(path: &Path) -> Result<Image, FileError> {
+    decode(BufReader::new(File::open(path)?))
+}
+
+pub fn write(path: &Path, image: &Image, options: &PngOptions) -> Result<(), FileError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    encode(&mut writer, image, options)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_image() -> Image {
+        let mut image = Image::new(3, 2);
+        image.set_pixel(0, 0, [255, 0, 0, 255]);
+        image.set_pixel(1, 0, [0, 128, 255, 128]);
+        image.set_pixel(2, 0, [17, 34, 51, 1]);
+        image.set_pixel(0, 1, [255, 255, 255, 255]);
+        image.set_pixel(1, 1, [1, 2, 3, 254]);
+        image
+    }
+
+    fn round_trip(image: &Image, options: &PngOptions) -> Image {
+        let mut bytes = vec![];
+        encode(&mut bytes, image, options).unwrap();
+        decode(&bytes[..]).unwrap()
+    }
+
+    /// A PNG written straight with the png crate, for color types the
+    /// paint crate never writes itself.
+    fn raw_png(width: u32, height: u32, color: ColorType, depth: ::png::BitDepth, data: &[u8]) -> Vec<u8> {
+        raw_indexed_png(width, height, color, depth, data, &[], None)
+    }
+
+    fn raw_indexed_png(
+        width: u32,
+        height: u32,
+        color: ColorType,
+        depth: ::png::BitDepth,
+        data: &[u8],
+        palette: &[u8],
+        transparency: Option<&[u8]>,
+    ) -> Vec<u8> {
+        let mut bytes = vec![];
+        let mut encoder = Encoder::new(&mut bytes, width, height);
+        encoder.set_color(color);
+        encoder.set_depth(depth);
+        if !palette.is_empty() {
+            encoder.set_palette(palette.to_vec());
+        }
+        if let Some(transparency) = transparency {
+            encoder.set_trns(transparency.to_vec());
+        }
+        let mut writer = encoder.write_header().unwrap();
+        writer.write_image_data(data).unwrap();
+        writer.finish().unwrap();
+        bytes
+    }
+
+    #[test]
+    fn eight_bit_rgba_round_trips_exactly() {
+        let image = test_image();
+        assert_eq!(round_trip(&image, &PngOptions::default()), image);
+        let unmarked = PngOptions { encoding: ColorEncoding::Unmarked, ..PngOptions::default() };
+        assert_eq!(round_trip(&image, &unmarked), image);
+    }
+
+    #[test]
+    fn sixteen_bit_round_trips_exactly() {
+        let image = test_image();
+        let options = PngOptions { bit_depth: BitDepth::Sixteen, ..PngOptions::default() };
+        assert_eq!(round_trip(&image, &options), image);
+    }
+
+    #[test]
+    fn gamma_encoded_files_convert_back_to_srgb() {
+        let image = test_image();
+        let options = PngOptions { encoding: ColorEncoding::Gamma(1.0), bit_depth: BitDepth::Sixteen, ..PngOptions::default() };
+        assert_eq!(round_trip(&image, &options).max_difference(&image), Some(0));
+        let options = PngOptions { encoding: ColorEncoding::Gamma(1.0), ..PngOptions::default() };
+        assert!(round_trip(&image, &options).max_difference(&image).unwrap() <= 13);
+    }
+
+    #[test]
+    fn without_alpha_pixels_are_composited_onto_the_background() {
+        let mut image = Image::new(2, 1);
+        image.set_pixel(0, 0, [0, 0, 0, 128]);
+        image.set_pixel(1, 0, [10, 20, 30, 255]);
+        let options = PngOptions { alpha: false, background: [255, 255, 255], ..PngOptions::default() };
+        let decoded = round_trip(&image, &options);
+        assert_eq!(decoded.pixel(0, 0), [127, 127, 127, 255]);
+        assert_eq!(decoded.pixel(1, 0), [10, 20, 30, 255]);
+    }
+
+    #[test]
+    fn rgb_files_are_opaque() {
+        let bytes = raw_png(2, 1, ColorType::Rgb, ::png::BitDepth::Eight, &[1, 2, 3, 250, 251, 252]);
+        let image = decode(&bytes[..]).unwrap();
+        assert_eq!(image.pixels(), [1, 2, 3, 255, 250, 251, 252, 255]);
+    }
+
+    #[test]
+    fn grayscale_files_spread_over_the_channels() {
+        let gray = raw_png(2, 1, ColorType::Grayscale, ::png::BitDepth::Eight, &[0, 200]);
+        assert_eq!(decode(&gray[..]).unwrap().pixels(), [0, 0, 0, 255, 200, 200, 200, 255]);
+        let gray_alpha = raw_png(1, 1, ColorType::GrayscaleAlpha, ::png::BitDepth::Eight, &[90, 60]);
+        assert_eq!(decode(&gray_alpha[..]).unwrap().pixels(), [90, 90, 90, 60]);
+        // Two bits per pixel, four pixels in one byte: 0, 1, 2 and 3 of 3.
+        let low_depth = raw_png(4, 1, ColorType::Grayscale, ::png::BitDepth::Two, &[0b00_01_10_11]);
+        assert_eq!(
+            decode(&low_depth[..]).unwrap().pixels(),
+            [0, 0, 0, 255, 85, 85, 85, 255, 170, 170, 170, 255, 255, 255, 255, 255],
+        );
+    }
+
+    #[test]
+    fn palette_files_are_expanded_with_their_transparency() {
+        let palette = [255, 0, 0, 0, 255, 0, 0, 0, 255];
+        let bytes = raw_indexed_png(
+            3, 1, ColorType::Indexed, ::png::BitDepth::Eight, &[2, 0, 1], &palette, Some(&[128]),
+        );
+        assert_eq!(decode(&bytes[..]).unwrap().pixels(), [0, 0, 255, 255, 255, 0, 0, 128, 0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn damaged_files_are_errors() {
+        let mut bytes = vec![];
+        encode(&mut bytes, &test_image(), &PngOptions::default()).unwrap();
+        assert!(decode(&[][..]).is_err());
+        assert!(decode(&b"GIF89a not a png at all"[..]).is_err());
+        for length in [8, 20, bytes.len() / 2, bytes.len() - 13].iter() {
+            assert!(decode(&bytes[..*length]).is_err(), "truncated to {} bytes", length);
+        }
+        // The last bytes before the IEND chunk belong to the IDAT chunk,
+        // whose checksum no longer matches.
+        let mut corrupt = bytes.clone();
+        let index = bytes.len() - 16;
+        corrupt[index] ^= 0xff;
+        assert!(matches!(decode(&corrupt[..]), Err(FileError::Decoding(_))));
+    }
+
+    #[test]
+    fn files_round_trip_on_disk() {
+        let path = std::env::temp_dir().join(format!("paint-png-test-{}.png", std::process::id()));
+        let image = test_image();
+        write(&path, &image, &PngOptions::default()).unwrap();
+        let read_back = read(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read_back.unwrap(), image);
+        assert!(matches!(read(&path), Err(FileError::Io(_))));
+    }
+}
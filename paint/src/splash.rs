@@ -0,0 +1,549 @@
+//! The native `.splash` project format, which keeps everything about a
+//! document that flattening would lose.
+//!
+//! A file starts with an 8 byte signature and a little-endian `u32` format
+//! version, followed by chunks until a `DONE` chunk. Like in PNG, a chunk
+//! is a 4 byte tag, a `u32` payload length, the payload and a CRC-32 of tag
+//! and payload. Chunks whose tag starts with an uppercase letter are
+//! critical: a reader that does not know one cannot load the file. Others
+//! are ancillary and readers skip the ones they do not know, so newer
+//! versions can add to the format without locking out older readers.
+//!
+//! | Tag    | Payload                                                        |
+//! |--------|----------------------------------------------------------------|
+//! | `HEAD` | width, height (`u32`), active layer id (`u64`)                 |
+//! | `LAYR` | id (`u64`), name, flags (`u8`: 1 visible, 2 locked), opacity   |
+//! |        | (`f32`), blend mode (`u8`), length (`u32`) and zlib compressed |
+//! |        | RGBA8 pixels                                                   |
+//! | `pltt` | count (`u32`), then name and RGBA8 color per swatch            |
+//! | `guid` | count (`u32`), then orientation (`u8`: 0 horizontal, 1         |
+//! |        | vertical) and position (`f32`) per guide                       |
+//! | `meta` | count (`u32`), then key and value per entry                    |
+//! | `DONE` | empty                                                          |
+//!
+//! Strings are a `u32` byte length followed by UTF-8. Layers are stored
+//! from bottom to top and `HEAD` comes before all of them. Newer versions
+//! may append fields to a chunk; readers ignore what follows the fields
+//! they know.
+
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use crate::document::{Document, Guide, Orientation, Swatch};
+use crate::error::FileError;
+use crate::image::Image;
+use crate::layer::{BlendMode, Layer, LayerId, LayerProperties, LayerStack};
+
+pub const EXTENSION: &str = "splash";
+
+/// The high byte catches transfers that strip the eighth bit, the newline
+/// ones that convert line endings.
+const SIGNATURE: [u8; 8] = *b"\x8aSPLASH\n";
+
+/// The version files are written with.
+pub const FORMAT_VERSION: u32 = 1;
+
+type Tag = [u8; 4];
+
+const HEADER: Tag = *b"HEAD";
+const LAYER: Tag = *b"LAYR";
+const PALETTE: Tag = *b"pltt";
+const GUIDES: Tag = *b"guid";
+const METADATA: Tag = *b"meta";
+const END: Tag = *b"DONE";
+
+const VISIBLE: u8 = 1;
+const LOCKED: u8 = 2;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Chunk {
+    tag: Tag,
+    data: Vec<u8>,
+}
+
+fn is_critical(tag: Tag) -> bool {
+    tag[0].is_ascii_uppercase()
+}
+
+fn tag_name(tag: Tag) -> String {
+    String::from_utf8_lossy(&tag).into_owned()
+}
+
+/// Upgrades the chunks of a file written at one version to the next. Entry
+/// `n` takes version `n + 1` to `n + 2`; every change to the meaning of an
+/// existing chunk adds one, so files of any older version still open.
+type Migration = fn(Vec<Chunk>) -> Result<Vec<Chunk>, FileError>;
+
+const MIGRATIONS: [Migration; FORMAT_VERSION as usize - 1] = [];
+
+fn migrate(version: u32, chunks: Vec<Chunk>) -> Result<Vec<Chunk>, FileError> {
+    let first = version.saturating_sub(1) as usize;
+    MIGRATIONS
+        .iter()
+        .skip(first)
+        .try_fold(chunks, |chunks, migration| migration(chunks))
+}
+
+/// Builds a chunk payload.
+#[derive(Default)]
+struct Payload(Vec<u8>);
+
+impl Payload {
+    fn u8(&mut self, value: u8) -> &mut Self {
+        self.0.push(value);
+        self
+    }
+
+    fn u32(&mut self, value: u32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u64(&mut self, value: u64) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn f32(&mut self, value: f32) -> &mut Self {
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.0.extend_from_slice(bytes);
+        self
+    }
+
+    fn string(&mut self, value: &str) -> &mut Self {
+        self.u32(value.len() as u32).bytes(value.as_bytes())
+    }
+
+    fn chunk(&mut self, tag: Tag) -> Chunk {
+        Chunk { tag, data: std::mem::take(&mut self.0) }
+    }
+}
+
+/// Reads the fields of a chunk payload in order.
+struct Fields<'a> {
+    tag: Tag,
+    data: &'a [u8],
+}
+
+impl<'a> Fields<'a> {
+    fn new(chunk: &'a Chunk) -> Self {
+        Fields { tag: chunk.tag, data: &chunk.data }
+    }
+
+    fn bytes(&mut self, length: usize) -> Result<&'a [u8], FileError> {
+        if self.data.len() < length {
+            return Err(FileError::Decoding(format!("{} chunk is too short", tag_name(self.tag))));
+        }
+        let (bytes, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], FileError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    fn u8(&mut self) -> Result<u8, FileError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, FileError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, FileError> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    fn f32(&mut self) -> Result<f32, FileError> {
+        Ok(f32::from_le_bytes(self.array()?))
+    }
+
+    fn string(&mut self) -> Result<String, FileError> {
+        let length = self.u32()? as usize;
+        String::from_utf8(self.bytes(length)?.to_vec()).map_err(|_| {
+            FileError::Decoding(format!("{} chunk has a string that is not UTF-8", tag_name(self.tag)))
+        })
+    }
+}
+
+/// Writes a document in the current format version.
+pub fn save<W: Write>(mut writer: W, document: &Document) -> Result<(), FileError> {
+    let layers = &document.layers;
+    let mut payload = Payload::default();
+    let mut chunks = vec![
+        payload
+            .u32(layers.width())
+            .u32(layers.height())
+            .u64(layers.active().0)
+            .chunk(HEADER),
+    ];
+    for layer in layers.layers() {
+        let properties = &layer.properties;
+        let mut flags = 0;
+        if properties.visible {
+            flags |= VISIBLE;
+        }
+        if properties.locked {
+            flags |= LOCKED;
+        }
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(layer.image.pixels())?;
+        let pixels = encoder.finish()?;
+        chunks.push(payload
+            .u64(layer.id().0)
+            .string(&properties.name)
+            .u8(flags)
+            .f32(properties.opacity)
+            .u8(properties.blend_mode as u8)
+            .u32(pixels.len() as u32)
+            .bytes(&pixels)
+            .chunk(LAYER));
+    }
+    if !document.palette.is_empty() {
+        payload.u32(document.palette.len() as u32);
+        for swatch in document.palette.iter() {
+            payload.string(&swatch.name).bytes(&swatch.color);
+        }
+        chunks.push(payload.chunk(PALETTE));
+    }
+    if !document.guides.is_empty() {
+        payload.u32(document.guides.len() as u32);
+        for guide in document.guides.iter() {
+            let orientation = match guide.orientation {
+                Orientation::Horizontal => 0,
+                Orientation::Vertical => 1,
+            };
+            payload.u8(orientation).f32(guide.position);
+        }
+        chunks.push(payload.chunk(GUIDES));
+    }
+    if !document.metadata.is_empty() {
+        payload.u32(document.metadata.len() as u32);
+        for (key, value) in document.metadata.iter() {
+            payload.string(key).string(value);
+        }
+        chunks.push(payload.chunk(METADATA));
+    }
+    chunks.push(payload.chunk(END));
+
+    writer.write_all(&SIGNATURE)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    for chunk in chunks.iter() {
+        write_chunk(&mut writer, chunk)?;
+    }
+    Ok(())
+}
+
+fn write_chunk<W: Write>(writer: &mut W, chunk: &Chunk) -> Result<(), FileError> {
+    let length = u32::try_from(chunk.data.len()).map_err(|_| {
+        FileError::Encoding(format!("{} chunk is larger than 4 GiB", tag_name(chunk.tag)))
+    })?;
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&chunk.tag);
+    crc.update(&chunk.data);
+    writer.write_all(&chunk.tag)?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(&chunk.data)?;
+    writer.write_all(&crc.finalize().to_le_bytes())?;
+    Ok(())
+}
+
+/// Reads a 4 byte field, telling a file that ends early from other errors.
+fn read_field<R: Read>(reader: &mut R) -> Result<[u8; 4], FileError> {
+    let mut field = [0; 4];
+    reader.read_exact(&mut field).map_err(|error| match error.kind() {
+        std::io::ErrorKind::UnexpectedEof => FileError::Decoding("the file is truncated".to_string()),
+        _ => FileError::Io(error),
+    })?;
+    Ok(field)
+}
+
+fn read_chunk<R: Read>(reader: &mut R) -> Result<Chunk, FileError> {
+    let tag = read_field(reader)?;
+    let length = u32::from_le_bytes(read_field(reader)?) as u64;
+    // Reading through `take` keeps a damaged length from allocating more
+    // than the file holds.
+    let mut data = vec![];
+    reader.take(length).read_to_end(&mut data)?;
+    if data.len() as u64 != length {
+        return Err(FileError::Decoding("the file is truncated".to_string()));
+    }
+    let crc = u32::from_le_bytes(read_field(reader)?);
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&tag);
+    hasher.update(&data);
+    if hasher.finalize() != crc {
+        return Err(FileError::Decoding(format!("{} chunk is damaged", tag_name(tag))));
+    }
+    Ok(Chunk { tag, data })
+}
+
+/// Reads a document written at any format version. Files from newer
+/// versions open as long as they need no critical chunk this one does not
+/// know; what their ancillary chunks hold is dropped.
+pub fn load<R: Read>(mut reader: R) -> Result<Document, FileError> {
+    let mut signature = [0; 8];
+    reader.read_exact(&mut signature)?;
+    if signature != SIGNATURE {
+        return Err(FileError::Decoding("not a .splash file".to_string()));
+    }
+    let version = u32::from_le_bytes(read_field(&mut reader)?);
+    if version == 0 {
+        return Err(FileError::Decoding("format version 0 does not exist".to_string()));
+    }
+    let mut chunks = vec![];
+    loop {
+        let chunk = read_chunk(&mut reader)?;
+        if chunk.tag == END {
+            break;
+        }
+        chunks.push(chunk);
+    }
+    let chunks = migrate(version, chunks)?;
+
+    let mut size = None;
+    let mut active = None;
+    let mut layers = vec![];
+    let mut palette = vec![];
+    let mut guides = vec![];
+    let mut metadata = BTreeMap::new();
+    for chunk in chunks.iter() {
+        let mut fields = Fields::new(chunk);
+        match chunk.tag {
+            HEADER => {
+                size = Some((fields.u32()?, fields.u32()?));
+                active = Some(LayerId(fields.u64()?));
+            },
+            LAYER => {
+                let (width, height) = size.ok_or_else(|| {
+                    FileError::Decoding("LAYR chunk before the HEAD chunk".to_string())
+                })?;
+                layers.push(read_layer(&mut fields, width, height)?);
+            },
+            PALETTE => {
+                for _ in 0..fields.u32()? {
+                    let name = fields.string()?;
+                    palette.push(Swatch { name, color: fields.array()? });
+                }
+            },
+            GUIDES => {
+                for _ in 0..fields.u32()? {
+                    let orientation = match fields.u8()? {
+                        0 => Orientation::Horizontal,
+                        _ => Orientation::Vertical,
+                    };
+                    guides.push(Guide { orientation, position: fields.f32()? });
+                }
+            },
+            METADATA => {
+                for _ in 0..fields.u32()? {
+                    let key = fields.string()?;
+                    metadata.insert(key, fields.string()?);
+                }
+            },
+            tag if is_critical(tag) => {
+                return Err(FileError::Unsupported(format!(
+                    "unknown critical chunk {} in a version {} file",
+                    tag_name(tag),
+                    version,
+                )));
+            },
+            _ => {},
+        }
+    }
+
+    let (width, height) = size.ok_or_else(|| FileError::Decoding("no HEAD chunk".to_string()))?;
+    let mut stack = LayerStack::from_layers(width, height, layers)
+        .ok_or_else(|| FileError::Decoding("no layers, or layers sharing an id".to_string()))?;
+    if let Some(active) = active {
+        stack.set_active(active);
+    }
+    let mut document = Document::new(stack);
+    document.palette = palette;
+    document.guides = guides;
+    document.metadata = metadata;
+    Ok(document)
+}
+
+fn read_layer(fields: &mut Fields, width: u32, height: u32) -> Result<Layer, FileError> {
+    let id = LayerId(fields.u64()?);
+    let mut properties = LayerProperties::new(&fields.string()?);
+    let flags = fields.u8()?;
+    properties.visible = flags & VISIBLE != 0;
+    properties.locked = flags & LOCKED != 0;
+    properties.opacity = fields.f32()?.clamp(0.0, 1.0);
+    // Modes added by newer versions show as normal rather than failing.
+    properties.blend_mode = BlendMode::from_value(fields.u8()? as u32).unwrap_or(BlendMode::Normal);
+
+    let size = width as usize * height as usize * 4;
+    let mut pixels = Vec::with_capacity(size);
+    let compressed = fields.u32()? as usize;
+    ZlibDecoder::new(fields.bytes(compressed)?)
+        .take(size as u64 + 1)
+        .read_to_end(&mut pixels)
+        .map_err(|error| FileError::Decoding(format!("layer {} pixels: {}", id.0, error)))?;
+    if pixels.len() != size {
+        return Err(FileError::Decoding(format!("layer {} has the wrong number of pixels", id.0)));
+    }
+    Ok(Layer::new(id, properties, Image::from_pixels(width, height, pixels)))
+}
+
+pub fn read(path: &Path) -> Result<Document, FileError> {
+    load(BufReader::new(File::open(path)?))
+}
+
+pub fn write(path: &Path, document: &Document) -> Result<(), FileError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    save(&mut writer, document)?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::document::{AUTHOR, TITLE};
+
+    fn test_document() -> Document {
+        let mut layers = LayerStack::new(3, 2, [255, 255, 255, 255]);
+        let background = layers.active();
+        let sketch = layers.add_layer("Sketch");
+        let shading = layers.add_layer("Shading ✏");
+        let layer = layers.layer_mut(sketch).unwrap();
+        layer.image.set_pixel(1, 1, [10, 20, 30, 40]);
+        layer.properties.visible = false;
+        layer.properties.locked = true;
+        let layer = layers.layer_mut(shading).unwrap();
+        layer.image = Image::filled(3, 2, [0, 0, 0, 128]);
+        layer.properties.opacity = 0.25;
+        layer.properties.blend_mode = BlendMode::Multiply;
+        layers.set_active(background);
+        let mut document = Document::new(layers);
+        document.palette = vec![
+            Swatch { name: "Sky".to_string(), color: [135, 206, 235, 255] },
+            Swatch { name: String::new(), color: [0, 0, 0, 0] },
+        ];
+        document.guides = vec![
+            Guide { orientation: Orientation::Horizontal, position: 0.5 },
+            Guide { orientation: Orientation::Vertical, position: 2.75 },
+        ];
+        document.metadata.insert(TITLE.to_string(), "Harbour at dusk".to_string());
+        document.metadata.insert(AUTHOR.to_string(), "A. Painter".to_string());
+        document.metadata.insert("x-custom".to_string(), String::new());
+        document
+    }
+
+    fn saved(document: &Document) -> Vec<u8> {
+        let mut bytes = vec![];
+        save(&mut bytes, document).unwrap();
+        bytes
+    }
+
+    /// A saved file with `chunk` added right before the closing `DONE`
+    /// chunk, which is the last 12 bytes.
+    fn with_chunk(document: &Document, chunk: &Chunk) -> Vec<u8> {
+        let mut bytes = saved(document);
+        let end = bytes.split_off(bytes.len() - 12);
+        write_chunk(&mut bytes, chunk).unwrap();
+        bytes.extend_from_slice(&end);
+        bytes
+    }
+
+    #[test]
+    fn documents_round_trip() {
+        let document = test_document();
+        let loaded = load(&saved(&document)[..]).unwrap();
+        assert_eq!(loaded, document);
+        assert_eq!(loaded.layers.active(), LayerId(0));
+    }
+
+    #[test]
+    fn empty_extras_are_left_out() {
+        let document = Document::new(LayerStack::new(1, 1, [1, 2, 3, 4]));
+        let bytes = saved(&document);
+        for tag in [PALETTE, GUIDES, METADATA].iter() {
+            assert!(!bytes.windows(4).any(|window| window == tag), "{}", tag_name(*tag));
+        }
+        assert_eq!(load(&bytes[..]).unwrap(), document);
+    }
+
+    #[test]
+    fn unknown_ancillary_chunks_are_skipped() {
+        let document = test_document();
+        let chunk = Chunk { tag: *b"ndew", data: vec![1, 2, 3] };
+        assert_eq!(load(&with_chunk(&document, &chunk)[..]).unwrap(), document);
+    }
+
+    #[test]
+    fn unknown_critical_chunks_are_refused() {
+        let chunk = Chunk { tag: *b"NDEW", data: vec![] };
+        let result = load(&with_chunk(&test_document(), &chunk)[..]);
+        assert!(matches!(result, Err(FileError::Unsupported(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn fields_appended_by_newer_versions_are_ignored() {
+        let document = Document::new(LayerStack::new(2, 2, [9, 9, 9, 255]));
+        let header = Payload::default().u32(2).u32(2).u64(0).u32(0xdead).chunk(HEADER);
+        let mut bytes = SIGNATURE.to_vec();
+        bytes.extend_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        write_chunk(&mut bytes, &header).unwrap();
+        // The saved layer and end chunks follow the signature, the version
+        // and a header chunk of 28 bytes.
+        bytes.extend_from_slice(&saved(&document)[8 + 4 + 28..]);
+        assert_eq!(load(&bytes[..]).unwrap(), document);
+    }
+
+    #[test]
+    fn files_that_are_not_splash_files_are_refused() {
+        let mut bytes = saved(&test_document());
+        bytes[1] = b's';
+        assert!(matches!(load(&bytes[..]), Err(FileError::Decoding(_))));
+        assert!(load(&b"\x89PNG\r\n\x1a\n"[..]).is_err());
+        assert!(load(&[][..]).is_err());
+    }
+
+    #[test]
+    fn version_zero_is_refused() {
+        let mut bytes = saved(&test_document());
+        bytes[8..12].copy_from_slice(&0u32.to_le_bytes());
+        assert!(matches!(load(&bytes[..]), Err(FileError::Decoding(_))));
+    }
+
+    #[test]
+    fn truncated_files_are_refused() {
+        let bytes = saved(&test_document());
+        for length in (8..bytes.len()).step_by(7).chain(Some(bytes.len() - 1)) {
+            let result = load(&bytes[..length]);
+            assert!(matches!(result, Err(FileError::Decoding(_))), "{} bytes: {:?}", length, result);
+        }
+    }
+
+    #[test]
+    fn damaged_chunks_are_refused() {
+        let mut bytes = saved(&test_document());
+        // The first byte of the HEAD payload, the width.
+        bytes[20] ^= 1;
+        assert!(matches!(load(&bytes[..]), Err(FileError::Decoding(_))));
+    }
+
+    #[test]
+    fn chunks_shorter_than_their_fields_are_refused() {
+        let document = Document { palette: vec![], ..test_document() };
+        let chunk = Payload::default().u32(3).string("only one").chunk(PALETTE);
+        let bytes = with_chunk(&document, &chunk);
+        assert!(matches!(load(&bytes[..]), Err(FileError::Decoding(_))));
+    }
+}
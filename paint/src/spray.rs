@@ -0,0 +1,242 @@
+use crate::brush::{stamp, Dab, Sample};
+use crate::image::{Image, Rect};
+use crate::rng::Rng;
+use crate::tile::TileSnapshot;
+
+/// Droplets are deposited on fixed steps of this rate, so how often the
+/// spray is advanced does not change what it paints.
+pub const TICKS_PER_SECOND: u32 = 120;
+
+/// How droplet density falls off from the center of the spray to its edge.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Falloff {
+    /// Even density over the whole disk.
+    Flat,
+    Linear,
+    /// Dense in the center, thinning out smoothly towards the edge.
+    Smooth,
+}
+
+impl Falloff {
+    /// Relative density at a distance from the center, with 1.0 at the edge.
+    fn density(self, distance: f32) -> f32 {
+        match self {
+            Falloff::Flat => 1.0,
+            Falloff::Linear => 1.0 - distance,
+            Falloff::Smooth => {
+                let falloff = 1.0 - distance * distance;
+                falloff * falloff
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpraySettings {
+    pub color: [u8; 4],
+    /// Radius of the area droplets land in, in pixels.
+    pub radius: f32,
+    /// Average droplet diameter in pixels.
+    pub droplet_size: f32,
+    /// How far droplet sizes stray from the average, as a fraction of it.
+    pub size_variance: f32,
+    pub falloff: Falloff,
+    /// Droplets per second at full pressure.
+    pub flow: f32,
+    /// Opacity of a single droplet.
+    pub opacity: f32,
+    /// Hardness of the droplet edges, as for brush dabs.
+    pub hardness: f32,
+}
+
+impl Default for SpraySettings {
+    fn default() -> Self {
+        SpraySettings {
+            color: [0, 0, 0, 255],
+            radius: 30.0,
+            droplet_size: 2.0,
+            size_variance: 0.5,
+            falloff: Falloff::Smooth,
+            flow: 2000.0,
+            opacity: 0.6,
+            hardness: 0.5,
+        }
+    }
+}
+
+/// A spray stroke in progress. Droplets keep landing for as long as the
+/// stroke is advanced, so density builds up where the pointer rests.
+///
+/// The nozzle holds still between samples. Together with the fixed tick
+/// rate this means a stroke replayed from the same seed, samples and times
+/// paints the same pixels however often it is advanced in between.
+pub struct Spray {
+    settings: SpraySettings,
+    rng: Rng,
+    start_time: f64,
+    ticks: u64,
+    nozzle: Option<Sample>,
+    /// Fraction of a droplet carried over to the next tick.
+    pending: f32,
+    original: TileSnapshot,
+}
+
+impl Spray {
+    /// Starts a stroke at `time`, in seconds on the same clock as later
+    /// calls.
+    pub fn new(settings: SpraySettings, image: &Image, seed: u64, time: f64) -> Self {
+        Spray {
+            settings,
+            rng: Rng::new(seed),
+            start_time: time,
+            ticks: 0,
+            nozzle: None,
+            pending: 0.0,
+            original: TileSnapshot::new(image.width(), image.height()),
+        }
+    }
+
+    pub fn settings(&self) -> &SpraySettings {
+        &self.settings
+    }
+
+    /// The tiles the stroke has touched, as they were before it.
+    pub fn original(&self) -> &TileSnapshot {
+        &self.original
+    }
+
+    /// Moves the nozzle, after depositing the droplets due until `time`
+    /// at its previous position.
+    pub fn move_to(&mut self, image: &mut Image, sample: Sample, time: f64) -> Rect {
+        let dirty = self.advance(image, time);
+        self.nozzle = Some(sample);
+        dirty
+    }
+
+    /// Deposits the droplets due until `time` and returns the pixels that
+    /// may have changed.
+    pub fn advance(&mut self, image: &mut Image, time: f64) -> Rect {
+        let due = ((time - self.start_time).max(0.0) * TICKS_PER_SECOND as f64).floor() as u64;
+        let mut dirty = Rect::default();
+        while self.ticks < due {
+            self.ticks += 1;
+            if let Some(nozzle) = self.nozzle {
+                dirty = dirty.union(&self.tick(image, nozzle));
+            }
+        }
+        dirty
+    }
+
+    fn tick(&mut self, image: &mut Image, nozzle: Sample) -> Rect {
+        self.pending += self.settings.flow.max(0.0) * nozzle.pressure.clamp(0.0, 1.0)
+            / TICKS_PER_SECOND as f32;
+        let count = self.pending.floor();
+        self.pending -= count;
+        (0..count as u32).fold(Rect::default(), |dirty, _| {
+            dirty.union(&self.droplet(image, nozzle))
+        })
+    }
+
+    fn droplet(&mut self, image: &mut Image, nozzle: Sample) -> Rect {
+        // Rejection sampling: points spread evenly over the disk are kept
+        // in proportion to the density at their distance.
+        let (dx, dy) = loop {
+            let dx = self.rng.next_signed();
+            let dy = self.rng.next_signed();
+            let distance_squared = dx * dx + dy * dy;
+            if distance_squared >= 1.0 {
+                continue;
+            }
+            if self.rng.next_f32() < self.settings.falloff.density(distance_squared.sqrt()) {
+                break (dx, dy);
+            }
+        };
+        let variance = self.settings.size_variance.clamp(0.0, 1.0) * self.rng.next_signed();
+        let size = self.settings.droplet_size * (1.0 + variance);
+        let dab = Dab {
+            x: nozzle.x + dx * self.settings.radius,
+            y: nozzle.y + dy * self.settings.radius,
+            radius: size.max(0.0) / 2.0,
+            hardness: self.settings.hardness.clamp(0.0, 1.0),
+            opacity: self.settings.opacity.clamp(0.0, 1.0),
+            flow: 1.0,
+        };
+        self.original.preserve(image, dab.bounds(image));
+        stamp(image, &dab, self.settings.color)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WHITE: [u8; 4] = [255, 255, 255, 255];
+
+    /// Sprays a short stroke, advancing it once per `step` seconds.
+    fn spray(seed: u64, step: f64) -> Image {
+        let settings = SpraySettings { radius: 10.0, flow: 600.0, ..SpraySettings::default() };
+        let mut image = Image::filled(48, 48, WHITE);
+        let mut spray = Spray::new(settings, &image, seed, 1.0);
+        let samples = [
+            (1.0, Sample::new(12.0, 12.0, 1.0)),
+            (1.25, Sample::new(24.0, 20.0, 0.5)),
+            (1.5, Sample::new(36.0, 30.0, 0.8)),
+        ];
+        let mut now = 1.0;
+        for (time, sample) in samples.iter() {
+            while now + step < *time {
+                now += step;
+                spray.advance(&mut image, now);
+            }
+            spray.move_to(&mut image, *sample, *time);
+            now = *time;
+        }
+        spray.advance(&mut image, 1.75);
+        image
+    }
+
+    #[test]
+    fn the_same_seed_and_samples_paint_the_same_pixels() {
+        let first = spray(7, 0.01);
+        let second = spray(7, 0.01);
+        assert_eq!(first.pixels(), second.pixels());
+        assert_ne!(first.pixels(), Image::filled(48, 48, WHITE).pixels());
+    }
+
+    #[test]
+    fn a_different_seed_paints_different_pixels() {
+        assert_ne!(spray(7, 0.01).pixels(), spray(8, 0.01).pixels());
+    }
+
+    #[test]
+    fn how_often_the_spray_is_advanced_does_not_matter() {
+        assert_eq!(spray(7, 0.01).pixels(), spray(7, 0.1).pixels());
+        assert_eq!(spray(7, 0.01).pixels(), spray(7, 1.0).pixels());
+    }
+
+    #[test]
+    fn density_builds_up_while_the_nozzle_rests() {
+        let settings = SpraySettings { radius: 8.0, flow: 240.0, ..SpraySettings::default() };
+        let mut image = Image::filled(32, 32, WHITE);
+        let mut spray = Spray::new(settings, &image, 3, 0.0);
+        spray.move_to(&mut image, Sample::new(16.0, 16.0, 1.0), 0.0);
+        let painted = |image: &Image| {
+            image.pixels().chunks(4).filter(|pixel| *pixel != WHITE).count()
+        };
+        spray.advance(&mut image, 0.1);
+        let early = painted(&image);
+        spray.advance(&mut image, 1.0);
+        assert!(early > 0);
+        assert!(painted(&image) > early);
+    }
+
+    #[test]
+    fn no_droplets_land_before_the_first_sample_or_without_pressure() {
+        let mut image = Image::filled(32, 32, WHITE);
+        let mut spray = Spray::new(SpraySettings::default(), &image, 1, 0.0);
+        assert!(spray.advance(&mut image, 1.0).is_empty());
+        spray.move_to(&mut image, Sample::new(16.0, 16.0, 0.0), 1.0);
+        assert!(spray.advance(&mut image, 2.0).is_empty());
+        assert_eq!(image.pixels(), Image::filled(32, 32, WHITE).pixels());
+    }
+}
@@ -0,0 +1,128 @@
+use crate::image::{Image, Rect};
+
+/// Edge length of the square tiles images are split into for bookkeeping.
+pub const TILE_SIZE: u32 = 64;
+
+/// A sparse grid of per-tile data covering an image, filled in on first use.
+#[derive(Clone, Debug)]
+pub struct TileMap<T> {
+    columns: u32,
+    rows: u32,
+    tiles: Vec<Option<T>>,
+}
+
+impl<T> TileMap<T> {
+    pub fn new(width: u32, height: u32) -> Self {
+        let columns = width.div_ceil(TILE_SIZE);
+        let rows = height.div_ceil(TILE_SIZE);
+        let mut tiles = Vec::new();
+        tiles.resize_with(columns as usize * rows as usize, || None);
+        TileMap { columns, rows, tiles }
+    }
+
+    pub fn columns(&self) -> u32 {
+        self.columns
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    fn index(&self, column: u32, row: u32) -> usize {
+        debug_assert!(column < self.columns && row < self.rows);
+        row as usize * self.columns as usize + column as usize
+    }
+
+    pub fn get(&self, column: u32, row: u32) -> Option<&T> {
+        self.tiles[self.index(column, row)].as_ref()
+    }
+
+    pub fn get_mut(&mut self, column: u32, row: u32) -> Option<&mut T> {
+        let index = self.index(column, row);
+        self.tiles[index].as_mut()
+    }
+
+    pub fn get_or_insert_with<F: FnOnce() -> T>(&mut self, column: u32, row: u32, create: F) -> &mut T {
+        let index = self.index(column, row);
+        self.tiles[index].get_or_insert_with(create)
+    }
+
+    pub fn insert(&mut self, column: u32, row: u32, tile: T) -> Option<T> {
+        let index = self.index(column, row);
+        self.tiles[index].replace(tile)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tiles.iter().all(Option::is_none)
+    }
+
+    /// Filled tiles with their column and row, in row-major order.
+    pub fn iter(&self) -> impl Iterator<Item = (u32, u32, &T)> {
+        let columns = self.columns;
+        self.tiles
+            .iter()
+            .enumerate()
+            .filter_map(move |(index, tile)| {
+                tile.as_ref().map(|tile| (index as u32 % columns, index as u32 / columns, tile))
+            })
+    }
+
+    pub fn into_tiles(self) -> impl Iterator<Item = (u32, u32, T)> {
+        let columns = self.columns;
+        self.tiles
+            .into_iter()
+            .enumerate()
+            .filter_map(move |(index, tile)| {
+                tile.map(|tile| (index as u32 % columns, index as u32 / columns, tile))
+            })
+    }
+}
+
+/// The pixels a tile covers, cut off at the image edge.
+pub fn tile_rect(column: u32, row: u32, width: u32, height: u32) -> Rect {
+    let x = column * TILE_SIZE;
+    let y = row * TILE_SIZE;
+    Rect::new(x, y, TILE_SIZE.min(width - x), TILE_SIZE.min(height - y))
+}
+
+/// Columns and rows of the tiles overlapping `rect`, as half-open ranges.
+pub fn tiles_in(rect: Rect) -> (std::ops::Range<u32>, std::ops::Range<u32>) {
+    if rect.is_empty() {
+        return (0..0, 0..0);
+    }
+    (
+        rect.x / TILE_SIZE..rect.right().div_ceil(TILE_SIZE),
+        rect.y / TILE_SIZE..rect.bottom().div_ceil(TILE_SIZE),
+    )
+}
+
+/// Copies of image tiles taken just before they are first changed, so an
+/// operation can tell what it painted over.
+#[derive(Clone, Debug)]
+pub struct TileSnapshot {
+    tiles: TileMap<Vec<u8>>,
+}
+
+impl TileSnapshot {
+    pub fn new(width: u32, height: u32) -> Self {
+        TileSnapshot { tiles: TileMap::new(width, height) }
+    }
+
+    /// Keeps the tiles overlapping `rect` that have not been kept yet. Call
+    /// before changing the pixels in `rect`.
+    pub fn preserve(&mut self, image: &Image, rect: Rect) {
+        let (columns, rows) = tiles_in(rect.intersection(&image.bounds()));
+        for row in rows {
+            for column in columns.clone() {
+                self.tiles.get_or_insert_with(column, row, || {
+                    image.region(tile_rect(column, row, image.width(), image.height()))
+                });
+            }
+        }
+    }
+
+    /// Tight RGBA8 rows of each kept tile, as it was before.
+    pub fn tiles(&self) -> &TileMap<Vec<u8>> {
+        &self.tiles
+    }
+}
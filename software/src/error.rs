@@ -0,0 +1,49 @@
+use std::fmt;
+use cgci::CanvasLayerId;
+
+#[derive(Debug)]
+pub enum SoftwareError {
+    /// The windowing system is not one the renderer can present to. Only
+    /// X11 is, which on Wayland means running under XWayland with
+    /// `WINIT_UNIX_BACKEND=x11`.
+    UnsupportedPlatform,
+    /// The window could not be prepared for presenting frames, or a frame
+    /// could not be presented.
+    Presentation(String),
+    /// A layer that is not on the canvas.
+    UnknownLayer(CanvasLayerId),
+    /// A region reaching past the edge of the canvas.
+    RegionOutOfBounds {
+        region: (u32, u32, u32, u32),
+        canvas: (u32, u32),
+    },
+    /// Pixels that do not fill the region they are written to.
+    RegionSize {
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for SoftwareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SoftwareError::UnsupportedPlatform =>
+                write!(f, "The software renderer can only present to X11 windows, try WINIT_UNIX_BACKEND=x11"),
+            SoftwareError::Presentation(error) =>
+                write!(f, "Failed to present to the window: {}", error),
+            SoftwareError::UnknownLayer(layer) => write!(f, "Layer {:?} is not on the canvas", layer),
+            SoftwareError::RegionOutOfBounds { region: (x, y, width, height), canvas } => write!(
+                f,
+                "The region of {}x{} at {}, {} is outside of the {}x{} canvas",
+                width, height, x, y, canvas.0, canvas.1,
+            ),
+            SoftwareError::RegionSize { expected, actual } => write!(
+                f,
+                "The region needs {} bytes of pixels, {} were given",
+                expected, actual,
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SoftwareError {}
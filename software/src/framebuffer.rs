@@ -0,0 +1,118 @@
+use crate::error::SoftwareError;
+
+/// Shows frames in a winit window by handing pixels to the windowing system,
+/// without any graphics API in between.
+#[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
+pub struct Framebuffer {
+    xlib: x11_dl::xlib::Xlib,
+    display: *mut x11_dl::xlib::Display,
+    window: std::os::raw::c_ulong,
+    visual: *mut x11_dl::xlib::Visual,
+    depth: u32,
+    gc: x11_dl::xlib::GC,
+}
+
+#[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
+impl Framebuffer {
+    /// Presents to the X11 connection winit opened for the window, so both
+    /// share a display and no second connection is needed.
+    pub fn new(window: &winit::window::Window) -> Result<Self, SoftwareError> {
+        use std::{mem, ptr};
+        use winit::platform::unix::WindowExtUnix;
+        use x11_dl::xlib;
+
+        let display = window.xlib_display().ok_or(SoftwareError::UnsupportedPlatform)?
+            as *mut xlib::Display;
+        let x11_window = window.xlib_window().ok_or(SoftwareError::UnsupportedPlatform)?;
+        let xlib = xlib::Xlib::open()
+            .map_err(|error| SoftwareError::Presentation(error.to_string()))?;
+        let mut attributes: xlib::XWindowAttributes = unsafe { mem::zeroed() };
+        if unsafe { (xlib.XGetWindowAttributes)(display, x11_window, &mut attributes) } == 0 {
+            return Err(SoftwareError::Presentation("the window has no attributes".to_string()));
+        }
+        // Frames are written as 0x00RRGGBB words, which is how 24 and 32 bit
+        // true color visuals lay pixels out on every common server.
+        let visual = unsafe { &*attributes.visual };
+        if visual.class != xlib::TrueColor
+            || visual.red_mask != 0xff_0000
+            || visual.green_mask != 0xff00
+            || visual.blue_mask != 0xff
+            || attributes.depth < 24
+        {
+            return Err(SoftwareError::Presentation(format!(
+                "a {} bit visual with masks {:x}/{:x}/{:x} is not supported",
+                attributes.depth, visual.red_mask, visual.green_mask, visual.blue_mask,
+            )));
+        }
+        let gc = unsafe { (xlib.XCreateGC)(display, x11_window, 0, ptr::null_mut()) };
+        if gc.is_null() {
+            return Err(SoftwareError::Presentation("no graphics context".to_string()));
+        }
+        Ok(Framebuffer {
+            xlib,
+            display,
+            window: x11_window,
+            visual: attributes.visual,
+            depth: attributes.depth as u32,
+            gc,
+        })
+    }
+
+    /// Copies `pixels`, `width` by `height` 0x00RRGGBB words, to the top
+    /// left of the window.
+    pub fn present(&mut self, pixels: &mut [u32], width: u32, height: u32) -> Result<(), SoftwareError> {
+        use std::os::raw::c_void;
+        use x11_dl::xlib;
+
+        assert_eq!(pixels.len(), width as usize * height as usize);
+        if pixels.is_empty() {
+            return Ok(());
+        }
+        unsafe {
+            let image = (self.xlib.XCreateImage)(
+                self.display,
+                self.visual,
+                self.depth,
+                xlib::ZPixmap,
+                0,
+                pixels.as_mut_ptr() as *mut _,
+                width,
+                height,
+                32,
+                (width * 4) as i32,
+            );
+            if image.is_null() {
+                return Err(SoftwareError::Presentation(format!("no image of {}x{}", width, height)));
+            }
+            (self.xlib.XPutImage)(self.display, self.window, self.gc, image, 0, 0, 0, 0, width, height);
+            // XDestroyImage would free the pixels too, which belong to the
+            // caller, so only the image header is released.
+            (self.xlib.XFree)(image as *mut c_void);
+            (self.xlib.XFlush)(self.display);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(unix, not(target_os = "android"), not(target_os = "macos")))]
+impl Drop for Framebuffer {
+    fn drop(&mut self) {
+        unsafe {
+            (self.xlib.XFreeGC)(self.display, self.gc);
+        }
+    }
+}
+
+#[cfg(not(all(unix, not(target_os = "android"), not(target_os = "macos"))))]
+pub struct Framebuffer;
+
+#[cfg(not(all(unix, not(target_os = "android"), not(target_os = "macos"))))]
+impl Framebuffer {
+    pub fn new(_window: &winit::window::Window) -> Result<Self, SoftwareError> {
+        Err(SoftwareError::UnsupportedPlatform)
+    }
+
+    pub fn present(&mut self, _pixels: &mut [u32], _width: u32, _height: u32) -> Result<(), SoftwareError> {
+        Err(SoftwareError::UnsupportedPlatform)
+    }
+}
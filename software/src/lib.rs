@@ -0,0 +1,291 @@
+//! A CPU renderer for the canvas, used when the Vulkan engine cannot start.
+//!
+//! Rendering works everywhere, but presenting to a window is only
+//! implemented for X11, through Xlib. On Wayland the window has to be opened
+//! through XWayland, with `WINIT_UNIX_BACKEND=x11`; on Windows, macOS and
+//! other platforms `SoftwareRenderer::new` returns
+//! `SoftwareError::UnsupportedPlatform`, and the renderer can only be used
+//! headless.
+
+use std::collections::HashMap;
+use std::error::Error;
+use cgci::{CanvasBlendMode, CanvasLayer, CanvasLayerId, CanvasRenderer, Draw, InputEvent, InputHandler};
+use paint::composite::composite_region;
+use paint::{BlendMode, Image, Rect};
+use framebuffer::Framebuffer;
+pub use error::SoftwareError;
+
+mod error;
+mod framebuffer;
+
+/// Renders the canvas on the CPU with the same compositing as the paint
+/// crate's reference, for machines where the Vulkan engine cannot start.
+/// Frames match what the engine draws: the composited canvas stretched over
+/// the window with nearest sampling, alpha written as is.
+pub struct SoftwareRenderer {
+    framebuffer: Option<Framebuffer>,
+    extent: (u32, u32),
+    layers: Vec<CanvasLayer>,
+    images: HashMap<CanvasLayerId, Image>,
+    /// The layers composited at canvas size.
+    canvas: Image,
+    /// Part of `canvas` that is out of date.
+    dirty: Rect,
+    /// The last frame, window sized.
+    frame: Image,
+    frame_outdated: bool,
+    /// `frame` as 0x00RRGGBB words for the framebuffer.
+    presented: Vec<u32>,
+}
+
+impl SoftwareRenderer {
+    pub fn new(window: &gui::MainWindow) -> Result<Self, SoftwareError> {
+        let framebuffer = Framebuffer::new(&window.window)?;
+        Ok(SoftwareRenderer::create(
+            Some(framebuffer),
+            window.window_width,
+            window.window_height,
+        ))
+    }
+
+    /// Creates a renderer without a window. Frames are only kept in memory,
+    /// where `read_rgba8` copies them from.
+    pub fn new_headless(width: u32, height: u32) -> Self {
+        SoftwareRenderer::create(None, width, height)
+    }
+
+    fn create(framebuffer: Option<Framebuffer>, width: u32, height: u32) -> Self {
+        let background = CanvasLayer {
+            id: CanvasLayerId(0),
+            blend_mode: CanvasBlendMode::Normal,
+            opacity: 1.0,
+            visible: true,
+        };
+        let mut images = HashMap::new();
+        images.insert(background.id, Image::filled(width, height, [0xff; 4]));
+        SoftwareRenderer {
+            framebuffer,
+            extent: (width, height),
+            layers: vec![background],
+            images,
+            canvas: Image::new(width, height),
+            dirty: Rect::new(0, 0, width, height),
+            frame: Image::new(width, height),
+            frame_outdated: true,
+            presented: vec![],
+        }
+    }
+
+    /// The last frame drawn, as tightly packed RGBA8 rows.
+    pub fn read_rgba8(&self) -> Vec<u8> {
+        self.frame.pixels().to_vec()
+    }
+
+    fn invalidate(&mut self, rect: Rect) {
+        self.dirty = self.dirty.union(&rect);
+    }
+
+    fn composite(&mut self) {
+        if self.dirty.is_empty() {
+            return;
+        }
+        let images = &self.images;
+        let layers: Vec<_> = self.layers
+            .iter()
+            .filter(|layer| layer.visible)
+            .filter_map(|layer| Some((images.get(&layer.id)?, layer.opacity, blend_mode(layer.blend_mode))))
+            .collect();
+        composite_region(&layers, self.dirty, &mut self.canvas);
+        self.dirty = Rect::default();
+        self.frame_outdated = true;
+    }
+
+    /// Stretches the canvas over the frame, sampling the canvas pixel under
+    /// each frame pixel's center.
+    fn scale(&mut self) {
+        let (width, height) = self.extent;
+        if self.frame.width() != width || self.frame.height() != height {
+            self.frame = Image::new(width, height);
+        }
+        let (canvas_width, canvas_height) = (self.canvas.width(), self.canvas.height());
+        if canvas_width == 0 || canvas_height == 0 {
+            return;
+        }
+        let columns: Vec<u32> = (0..width)
+            .map(|x| (((x as u64 * 2 + 1) * canvas_width as u64) / (width as u64 * 2)) as u32)
+            .collect();
+        for y in 0..height {
+            let source_y = (((y as u64 * 2 + 1) * canvas_height as u64) / (height as u64 * 2)) as u32;
+            for (x, source_x) in columns.iter().enumerate() {
+                let pixel = self.canvas.pixel(*source_x, source_y);
+                self.frame.set_pixel(x as u32, y, pixel);
+            }
+        }
+    }
+
+    fn present(&mut self) -> Result<(), SoftwareError> {
+        let framebuffer = match self.framebuffer.as_mut() {
+            Some(framebuffer) => framebuffer,
+            None => return Ok(()),
+        };
+        self.presented.clear();
+        self.presented.extend(self.frame.pixels().chunks_exact(4).map(|pixel| {
+            (pixel[0] as u32) << 16 | (pixel[1] as u32) << 8 | pixel[2] as u32
+        }));
+        framebuffer.present(&mut self.presented, self.frame.width(), self.frame.height())
+    }
+}
+
+/// The paint crate's counterpart of a canvas blend mode.
+fn blend_mode(mode: CanvasBlendMode) -> BlendMode {
+    match mode {
+        CanvasBlendMode::Normal => BlendMode::Normal,
+        CanvasBlendMode::Multiply => BlendMode::Multiply,
+        CanvasBlendMode::Screen => BlendMode::Screen,
+        CanvasBlendMode::Overlay => BlendMode::Overlay,
+        CanvasBlendMode::Darken => BlendMode::Darken,
+        CanvasBlendMode::Lighten => BlendMode::Lighten,
+        CanvasBlendMode::ColorDodge => BlendMode::ColorDodge,
+        CanvasBlendMode::ColorBurn => BlendMode::ColorBurn,
+        CanvasBlendMode::HardLight => BlendMode::HardLight,
+        CanvasBlendMode::SoftLight => BlendMode::SoftLight,
+        CanvasBlendMode::Difference => BlendMode::Difference,
+        CanvasBlendMode::Exclusion => BlendMode::Exclusion,
+        CanvasBlendMode::Add => BlendMode::Add,
+        CanvasBlendMode::Subtract => BlendMode::Subtract,
+    }
+}
+
+impl Draw for SoftwareRenderer {
+    fn draw_frame(&mut self) {
+        self.composite();
+        if self.frame_outdated {
+            self.scale();
+            self.frame_outdated = false;
+        }
+        // The window may have been covered since the last frame, so it is
+        // presented again even when nothing changed.
+        if let Err(error) = self.present() {
+            log::warn!("Skipped presenting a frame: {}", error);
+        }
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        if self.extent != (width, height) {
+            self.extent = (width, height);
+            self.frame_outdated = true;
+        }
+    }
+}
+
+impl CanvasRenderer for SoftwareRenderer {
+    fn extent(&self) -> (u32, u32) {
+        self.extent
+    }
+
+    fn canvas_extent(&self) -> (u32, u32) {
+        (self.canvas.width(), self.canvas.height())
+    }
+
+    fn resize_canvas(&mut self, width: u32, height: u32) -> Result<(), Box<dyn Error>> {
+        self.layers.clear();
+        self.images.clear();
+        self.canvas = Image::new(width, height);
+        self.dirty = self.canvas.bounds();
+        Ok(())
+    }
+
+    fn set_canvas_layers(&mut self, layers: &[CanvasLayer]) -> Result<Vec<CanvasLayerId>, Box<dyn Error>> {
+        self.images.retain(|id, _| layers.iter().any(|layer| layer.id == *id));
+        let (width, height) = self.canvas_extent();
+        let missing: Vec<CanvasLayerId> = layers
+            .iter()
+            .map(|layer| layer.id)
+            .filter(|id| !self.images.contains_key(id))
+            .collect();
+        for id in missing.iter() {
+            self.images.insert(*id, Image::new(width, height));
+        }
+        self.layers = layers.to_vec();
+        self.dirty = self.canvas.bounds();
+        Ok(missing)
+    }
+
+    fn write_canvas_region(
+        &mut self,
+        layer: CanvasLayerId,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        rgba: &[u8],
+    ) -> Result<(), Box<dyn Error>> {
+        let rect = Rect::new(x, y, width, height);
+        let image = self.images.get_mut(&layer).ok_or(SoftwareError::UnknownLayer(layer))?;
+        if x as u64 + width as u64 > image.width() as u64 || y as u64 + height as u64 > image.height() as u64 {
+            return Err(SoftwareError::RegionOutOfBounds {
+                region: (x, y, width, height),
+                canvas: (image.width(), image.height()),
+            }.into());
+        }
+        let expected = width as usize * height as usize * 4;
+        if rgba.len() != expected {
+            return Err(SoftwareError::RegionSize { expected, actual: rgba.len() }.into());
+        }
+        if rect.is_empty() {
+            return Ok(());
+        }
+        image.write_region(rect, rgba);
+        self.invalidate(rect);
+        Ok(())
+    }
+
+    fn read_canvas_layer(&mut self, layer: CanvasLayerId) -> Result<Vec<u8>, Box<dyn Error>> {
+        let image = self.images.get(&layer).ok_or(SoftwareError::UnknownLayer(layer))?;
+        Ok(image.pixels().to_vec())
+    }
+}
+
+impl InputHandler for SoftwareRenderer {
+    fn handle_input(&mut self, _event: &InputEvent) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blend_modes_keep_their_shader_values() {
+        use CanvasBlendMode::*;
+        let modes = [
+            Normal, Multiply, Screen, Overlay, Darken, Lighten, ColorDodge, ColorBurn, HardLight,
+            SoftLight, Difference, Exclusion, Add, Subtract,
+        ];
+        assert_eq!(modes.len(), BlendMode::ALL.len());
+        for &mode in modes.iter() {
+            assert_eq!(blend_mode(mode) as u32, mode as u32);
+        }
+    }
+
+    #[test]
+    fn unknown_layers_are_errors() {
+        let mut renderer = SoftwareRenderer::new_headless(4, 4);
+        assert!(renderer.read_canvas_layer(CanvasLayerId(7)).is_err());
+        assert!(renderer.write_canvas_region(CanvasLayerId(7), 0, 0, 1, 1, &[0; 4]).is_err());
+        assert_eq!(renderer.read_canvas_layer(CanvasLayerId(0)).unwrap(), vec![0xff; 64]);
+    }
+
+    #[test]
+    fn regions_have_to_fit_the_canvas() {
+        let mut renderer = SoftwareRenderer::new_headless(4, 4);
+        for &(x, y, width, height) in [(3, 0, 2, 1), (0, 4, 1, 1), (u32::MAX, 0, 2, 1)].iter() {
+            let rgba = vec![0; width as usize * height as usize * 4];
+            assert!(renderer.write_canvas_region(CanvasLayerId(0), x, y, width, height, &rgba).is_err());
+        }
+        assert!(renderer.write_canvas_region(CanvasLayerId(0), 0, 0, 2, 2, &[0; 12]).is_err());
+        renderer.write_canvas_region(CanvasLayerId(0), 2, 3, 2, 1, &[0; 8]).unwrap();
+        let pixels = renderer.read_canvas_layer(CanvasLayerId(0)).unwrap();
+        assert_eq!(pixels[(3 * 4 + 2) * 4..], [0; 8]);
+        assert_eq!(pixels[..(3 * 4 + 2) * 4], vec![0xff; (3 * 4 + 2) * 4][..]);
+    }
+}